@@ -1,3 +1,4 @@
+#include <stdbool.h>
 #include <stddef.h>
 #include <stdint.h>
 #include <stdio.h>
@@ -7,11 +8,155 @@
 extern "C" {
 #endif
 
+// Sample type of AgnoImage.data. The layout comes from AgnoImage.channels
+// alone: grey, RGB or RGBA samples, interleaved. 16-bit and float samples are
+// native-endian.
+enum PixelFormat {
+  PIXEL_FORMAT_U8 = 0,
+  PIXEL_FORMAT_U16 = 1,
+  PIXEL_FORMAT_F32 = 2, // linear light
+  // Names from before grey and RGBA images; they mean the same sample types
+  PIXEL_FORMAT_RGB8 = PIXEL_FORMAT_U8,
+  PIXEL_FORMAT_RGB16 = PIXEL_FORMAT_U16,
+  PIXEL_FORMAT_RGB_F32 = PIXEL_FORMAT_F32,
+};
+
 struct AgnoImage {
   unsigned char *data;
-  size_t len;
+  size_t len; // in bytes
   unsigned long long width;
   unsigned long long height;
+  enum PixelFormat format;
+  uint32_t channels; // 1 for grey, 3 for RGB, 4 for RGBA (straight alpha)
+};
+
+enum RawScale {
+  RAW_SCALE_FULL = 0,
+  RAW_SCALE_HALF = 1,
+  RAW_SCALE_QUARTER = 2,
+  RAW_SCALE_EIGHTH = 3,
+};
+
+enum WhiteBalanceMode {
+  WB_AS_SHOT = 0,
+  WB_CUSTOM = 1,
+  WB_NONE = 2,
+  WB_GRAY_WORLD = 3,
+  WB_WHITE_PATCH = 4,
+  WB_TEMPERATURE = 5,
+  WB_DAYLIGHT = 6,
+  WB_CLOUDY = 7,
+  WB_TUNGSTEN = 8,
+  WB_FLASH = 9,
+  WB_FLUORESCENT = 10,
+};
+
+enum DemosaicAlgorithm {
+  DEMOSAIC_BILINEAR = 0,
+  DEMOSAIC_MALVAR_HE_CUTLER = 1,
+};
+
+enum HighlightMode {
+  HIGHLIGHT_CLIP = 0,
+  HIGHLIGHT_UNCLIP = 1,
+  HIGHLIGHT_BLEND = 2,
+  HIGHLIGHT_RECONSTRUCT = 3,
+};
+
+enum OutputColorSpace {
+  COLOR_SPACE_CAMERA = 0,
+  COLOR_SPACE_SRGB = 1,
+  COLOR_SPACE_DISPLAY_P3 = 2,
+  COLOR_SPACE_ADOBE_RGB = 3,
+  COLOR_SPACE_REC2020 = 4,
+};
+
+enum ToneCurve {
+  TONE_CURVE_SRGB = 0,
+  TONE_CURVE_REC709 = 1,
+  TONE_CURVE_LINEAR = 2,
+  TONE_CURVE_GAMMA = 3, // uses gamma and toe_slope
+  TONE_CURVE_FILMIC = 4,
+};
+
+enum BadPixelMode {
+  BAD_PIXELS_OFF = 0,  // only the defect map, if any
+  BAD_PIXELS_AUTO = 1, // also detect hot/dead pixels
+};
+
+enum NoiseReduction {
+  NOISE_REDUCTION_OFF = 0,
+  NOISE_REDUCTION_AUTO = 1,   // strengths picked from the ISO
+  NOISE_REDUCTION_MANUAL = 2, // wavelet_threshold and chroma_denoise
+};
+
+// Bits of RawDevelopSettings.lens_corrections, applied from the lens
+// parameters Sony cameras embed in the raw
+enum LensCorrection {
+  LENS_CORRECT_DISTORTION = 1,
+  LENS_CORRECT_VIGNETTING = 2,
+  LENS_CORRECT_CA = 4, // lateral chromatic aberration
+};
+
+struct RawDevelopSettings {
+  enum WhiteBalanceMode wb_mode;
+  float wb_multipliers[4]; // RGGB, used with WB_CUSTOM
+  float wb_temperature;    // kelvin, used with WB_TEMPERATURE
+  float wb_tint;           // green (-) / magenta (+), used with WB_TEMPERATURE
+  float exposure_ev;
+  int32_t black_level; // override when >= 0
+  int32_t white_level; // override when > 0
+  enum DemosaicAlgorithm demosaic;
+  enum HighlightMode highlight_mode;
+  enum OutputColorSpace color_space;
+  enum ToneCurve tone_curve;
+  float gamma;     // TONE_CURVE_GAMMA only
+  float toe_slope; // TONE_CURVE_GAMMA only, 0 for a pure power law
+  uint32_t bit_depth; // 8, 16, or 32 for linear float
+  enum RawScale scale;
+  enum BadPixelMode bad_pixel_mode;
+  float bad_pixel_threshold; // BAD_PIXELS_AUTO sensitivity, 0 for the default
+  const char *defect_map;    // dcraw -P defect map path, NULL for none
+  size_t defect_map_len;
+  const char *dark_frame; // raw or master DNG to subtract, NULL for none
+  size_t dark_frame_len;
+  const char *flat_field; // raw or master DNG to divide by, NULL for none
+  size_t flat_field_len;
+  uint32_t lens_corrections; // LensCorrection flags, 0 for none
+  // Relative green mismatch marking motion in pixel-shift sequences,
+  // 0 for the default, negative to never fall back to the first frame
+  float pixel_shift_motion_threshold;
+  enum NoiseReduction noise_reduction;
+  float wavelet_threshold; // NOISE_REDUCTION_MANUAL, dcraw -n units
+  float chroma_denoise;    // NOISE_REDUCTION_MANUAL, 0 to 1
+};
+
+// Space JPEG/PNG/WebP pixels with an embedded ICC profile are converted to
+enum ColorTarget {
+  COLOR_TARGET_SRGB = 0,
+  COLOR_TARGET_ORIGINAL = 1, // keep the embedded profile's values
+  COLOR_TARGET_PROFILE = 2,  // the ICC file at target_profile
+};
+
+struct ImageLoadSettings {
+  enum ColorTarget color_target;
+  const char *target_profile; // ICC file path, COLOR_TARGET_PROFILE only
+  size_t target_profile_len;
+  // Load grey sources (without alpha) as a single channel instead of RGB
+  bool keep_grayscale;
+};
+
+struct LoadOptions {
+  struct RawDevelopSettings raw;
+  struct ImageLoadSettings image;
+  // Skip turning pixels upright by EXIF Orientation; otherwise every loader
+  // applies it and resets the tag to 1
+  bool keep_orientation;
+};
+
+enum DngCompression {
+  DNG_COMPRESSION_UNCOMPRESSED = 0,
+  DNG_COMPRESSION_LOSSLESS_JPEG = 1,
 };
 
 struct ExifData {
@@ -20,15 +165,179 @@ struct ExifData {
   int16_t typ;
 };
 
+// malloc'd bytes owned by the caller, release with free_agno_buffer.
+// JSON results are nul-terminated; len excludes the terminator.
+struct AgnoBuffer {
+  unsigned char *data;
+  size_t len;
+};
+
 void init_agno();
 
 struct AgnoImage *load_image_from_path(char *path, size_t len);
 
+// NULL options loads with the defaults; NULL is returned if any enum in them
+// is out of range or a path in them isn't UTF-8
+struct AgnoImage *load_image_with_options(char *path, size_t len,
+                                          struct LoadOptions *options);
+
+// Scales with RESAMPLE_LANCZOS3
 struct AgnoImage *resize_image(struct AgnoImage *img, size_t new_width,
                                size_t new_height);
 
+enum ResampleFilter {
+  RESAMPLE_NEAREST = 0,
+  RESAMPLE_BILINEAR = 1,
+  RESAMPLE_CATMULL_ROM = 2,
+  RESAMPLE_MITCHELL = 3,
+  RESAMPLE_LANCZOS3 = 4,
+};
+
+// Takes ownership of img like resize_image; NULL on failure, including an
+// out-of-range filter
+struct AgnoImage *resize_image_with_filter(struct AgnoImage *img,
+                                           size_t new_width, size_t new_height,
+                                           enum ResampleFilter filter);
+
+enum ResizeMode {
+  RESIZE_EXACT = 0,         // stretch to width x height
+  RESIZE_FIT = 1,           // fit inside width x height
+  RESIZE_COVER = 2,         // cover width x height, cropping by gravity
+  RESIZE_LONGEST_EDGE = 3,  // longest edge becomes width
+  RESIZE_SHORTEST_EDGE = 4, // shortest edge becomes width
+};
+
+enum Gravity {
+  GRAVITY_CENTER = 0,
+  GRAVITY_TOP = 1,
+  GRAVITY_ENTROPY = 2, // keep the busiest part of the image
+};
+
+struct ResizeSpec {
+  enum ResizeMode mode;
+  uint32_t width;
+  uint32_t height; // unused by the edge modes
+  enum Gravity gravity;
+  bool never_upscale; // RESIZE_EXACT shrinks the box to fit the image
+  enum ResampleFilter filter;
+};
+
+// Takes ownership of img like resize_image; NULL on failure, including an
+// out-of-range enum in spec
+struct AgnoImage *resize_image_with_spec(struct AgnoImage *img,
+                                         const struct ResizeSpec *spec);
+
+enum FlipAxis {
+  FLIP_HORIZONTAL = 0,
+  FLIP_VERTICAL = 1,
+};
+
+enum RotateMode {
+  ROTATE_FILL = 0,      // grow the canvas, fill the corners with the background
+  ROTATE_AUTO_CROP = 1, // largest centred crop of the same aspect ratio
+};
+
+// Like resize_image, these take ownership of img and return a new image, or
+// NULL on failure, including an out-of-range axis or mode (img is released
+// either way)
+struct AgnoImage *crop_agno_image(struct AgnoImage *img, size_t x, size_t y,
+                                  size_t width, size_t height);
+
+// Clockwise by 90, 180 or 270 degrees
+struct AgnoImage *rotate_agno_image(struct AgnoImage *img, int32_t degrees);
+
+struct AgnoImage *flip_agno_image(struct AgnoImage *img, enum FlipAxis axis);
+
+// Clockwise by any angle; bg_* (0-1) fill the corners with ROTATE_FILL, which
+// stay transparent for RGBA images
+struct AgnoImage *rotate_agno_image_by(struct AgnoImage *img, float degrees,
+                                       enum RotateMode mode, float bg_r,
+                                       float bg_g, float bg_b);
+
+// Composites an RGBA image over the background (0-1 per channel, linear for
+// float images), returning RGB. RGB images come back unchanged. Takes ownership
+// of img like the transforms above.
+struct AgnoImage *flatten_agno_image(struct AgnoImage *img, float bg_r,
+                                     float bg_g, float bg_b);
+
 void write_agno_image_to_webp(char *path, size_t len, struct AgnoImage *img);
 
+void write_agno_image_to_jpeg(char *path, size_t len, struct AgnoImage *img);
+
+void write_agno_image_to_png(char *path, size_t len, struct AgnoImage *img);
+
+void write_agno_image_to_tiff(char *path, size_t len, struct AgnoImage *img);
+
+// Returns false when the raw can't be decoded, the DNG can't be written or
+// compression is out of range
+bool convert_to_dng(char *path, size_t len, char *out_path, size_t out_len,
+                    enum DngCompression compression);
+
+// Per-CFA-channel histograms, clipping and headroom of the raw mosaic, as JSON
+struct AgnoBuffer get_raw_stats(char *path, size_t len);
+
+// RGB and luma histograms of a rendered image, as JSON
+struct AgnoBuffer get_image_stats(struct AgnoImage *img);
+
+// Placeholders computed from a small downscale of the image. BlurHash takes
+// 1-9 components per axis (NULL data otherwise) and ignores alpha; the result is
+// a nul-terminated string. ThumbHash keeps alpha and the aspect ratio.
+struct AgnoBuffer get_blurhash(struct AgnoImage *img, uint32_t x_components,
+                               uint32_t y_components);
+
+struct AgnoBuffer get_thumbhash(struct AgnoImage *img);
+
+enum ImageHashKind {
+  IMAGE_HASH_AVERAGE = 0,    // aHash: 8x8 grey thumbnail against its mean
+  IMAGE_HASH_DIFFERENCE = 1, // dHash: neighbouring pixels of a 9x8 thumbnail
+  IMAGE_HASH_PERCEPTUAL = 2, // pHash: low frequencies of a 32x32 DCT
+};
+
+// 64-bit perceptual hash of the image, upright by its EXIF orientation even if
+// loaded with keep_orientation; 0 if it can't be computed or kind is out of
+// range
+uint64_t get_image_hash(struct AgnoImage *img, enum ImageHashKind kind);
+
+// Differing bits between two hashes of the same kind; pHashes of the same
+// picture typically stay within 10
+uint32_t image_hash_distance(uint64_t a, uint64_t b);
+
+// Hashes the files and groups those within threshold bits of each other (links
+// chain), as JSON: {"clusters": [[0, 3], [1], ...], "hashes": ["9f3a...", null]}.
+// Clusters hold indices into paths; files that can't be loaded are left out and
+// get a null hash. Raws are developed at RAW_SCALE_EIGHTH. options may be NULL;
+// a null buffer is returned if kind or any enum in options is out of range, or
+// a path in options isn't UTF-8.
+struct AgnoBuffer find_near_duplicates(const char **paths, const size_t *lens,
+                                       size_t count, enum ImageHashKind kind,
+                                       uint32_t threshold,
+                                       struct LoadOptions *options);
+
+// ICC profile embedded in the file the image was loaded from, NULL data if none
+struct AgnoBuffer get_icc_profile(struct AgnoImage *img);
+
+// ICC profile describing the image's pixels, which the writers embed; NULL data
+// for sRGB. Raws get one for their color_space and tone_curve.
+struct AgnoBuffer get_color_profile(struct AgnoImage *img);
+
+// ICC profile embedded in a JPEG, PNG, WebP, TIFF or HEIF file, read without
+// decoding the pixels; NULL data if none
+struct AgnoBuffer get_file_icc_profile(char *path, size_t len);
+
+void free_agno_buffer(struct AgnoBuffer buf);
+
+// Averages dark or flat frames into a master DNG for RawDevelopSettings.
+// All frames must share dimensions and ISO.
+bool create_master_frame(const char **paths, const size_t *lens, size_t count,
+                         char *out_path, size_t out_len);
+
+// Composes a Sony Pixel Shift sequence: 4 or 16 ARWs in shooting order,
+// or a single ARQ. options may be NULL; NULL is returned if any enum in them
+// is out of range or a path in them isn't UTF-8.
+struct AgnoImage *load_pixel_shift_images(const char **paths,
+                                          const size_t *lens, size_t count,
+                                          struct LoadOptions *options);
+
 void free_agno_image(struct AgnoImage *img);
 
 struct ExifData get_exif_value(struct AgnoImage *img, int16_t img_tag);
@@ -2,7 +2,99 @@ use std::{os::raw::c_void, ptr::null_mut};
 
 use libc::c_uchar;
 
-use crate::exif::ExifContext;
+use crate::{exif::ExifContext, tone::TransferFn};
+
+/// Sample type of the pixels behind `AgnoImage::data`. It says nothing about
+/// the layout: that's grey, RGB or RGBA per `AgnoImage::channels`. 16-bit and
+/// float samples are stored in native byte order.
+#[repr(C)]
+#[allow(dead_code)] // variants are constructed from C
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// 8 bits per sample, gamma encoded
+    #[default]
+    U8 = 0,
+    /// 16 bits per sample, gamma encoded
+    U16 = 1,
+    /// 32-bit float per sample, linear light (1.0 is the raw white point)
+    F32 = 2,
+}
+
+impl PixelFormat {
+    /// True when samples hold linear light instead of gamma-encoded values.
+    pub fn is_linear(self) -> bool {
+        self == PixelFormat::F32
+    }
+
+    /// Format for a raw develop `bit_depth` (8, 16 or 32 for float).
+    pub fn from_bit_depth(bits: u32) -> Option<Self> {
+        match bits {
+            8 => Some(PixelFormat::U8),
+            16 => Some(PixelFormat::U16),
+            32 => Some(PixelFormat::F32),
+            _ => None,
+        }
+    }
+}
+
+/// A channel type an `AgnoImage` can hold.
+pub trait Sample: image::Primitive + Send + Sync + 'static {
+    const FORMAT: PixelFormat;
+
+    /// The `image` crate pixel holding three of these, for handing buffers to imageops
+    type Rgb: image::Pixel<Subpixel = Self> + 'static;
+
+    /// Converts a value in [0, 1] (gamma encoded or linear, per `FORMAT`) into a sample.
+    fn from_unit(v: f32) -> Self;
+
+    fn to_unit(self) -> f32;
+}
+
+impl Sample for u8 {
+    const FORMAT: PixelFormat = PixelFormat::U8;
+    type Rgb = image::Rgb<u8>;
+
+    #[inline(always)]
+    fn from_unit(v: f32) -> Self {
+        (v.clamp(0.0, 1.0) * 255.0 + 0.5) as u8
+    }
+
+    #[inline(always)]
+    fn to_unit(self) -> f32 {
+        self as f32 / 255.0
+    }
+}
+
+impl Sample for u16 {
+    const FORMAT: PixelFormat = PixelFormat::U16;
+    type Rgb = image::Rgb<u16>;
+
+    #[inline(always)]
+    fn from_unit(v: f32) -> Self {
+        (v.clamp(0.0, 1.0) * 65535.0 + 0.5) as u16
+    }
+
+    #[inline(always)]
+    fn to_unit(self) -> f32 {
+        self as f32 / 65535.0
+    }
+}
+
+impl Sample for f32 {
+    const FORMAT: PixelFormat = PixelFormat::F32;
+    type Rgb = image::Rgb<f32>;
+
+    // Linear output is left unclamped above 1.0 so highlight detail survives
+    #[inline(always)]
+    fn from_unit(v: f32) -> Self {
+        v.max(0.0)
+    }
+
+    #[inline(always)]
+    fn to_unit(self) -> f32 {
+        self
+    }
+}
 
 #[repr(C)] // Ensure C-compatible layout
 pub struct AgnoImage {
@@ -11,36 +103,77 @@ pub struct AgnoImage {
 
     pub width: u64,
     pub height: u64,
+    pub format: PixelFormat,
+    /// 1 for grey, 3 for RGB, 4 for RGBA with straight (not premultiplied) alpha
+    pub channels: u32,
 
     pub exif: ExifContext,
+    /// ICC profile embedded in the source file, kept even after converting away from it
+    pub icc_profile: Option<Vec<u8>>,
+    /// ICC profile describing the pixels as stored, None for sRGB. Writers tag files with it.
+    pub color_profile: Option<Vec<u8>>,
 }
 
 impl AgnoImage {
     pub fn new(data: Vec<u8>, width: u64, height: u64, exif_ctx: ExifContext) -> Self {
-        let pixels_rgb = unsafe { libc::malloc((width * height * 3) as usize) as *mut c_uchar };
-        if pixels_rgb.is_null() {
+        Self::from_samples(&data, width, height, exif_ctx)
+    }
+
+    /// Copies `data` (interleaved RGB in `T`'s format) into a malloc'd buffer C can free.
+    pub fn from_samples<T: Sample>(
+        data: &[T],
+        width: u64,
+        height: u64,
+        exif_ctx: ExifContext,
+    ) -> Self {
+        Self::from_channels(data, width, height, 3, exif_ctx)
+    }
+
+    /// Like `from_samples` for grey (1 channel), interleaved RGB (3) or RGBA (4).
+    pub fn from_channels<T: Sample>(
+        data: &[T],
+        width: u64,
+        height: u64,
+        channels: u32,
+        exif_ctx: ExifContext,
+    ) -> Self {
+        let len = std::mem::size_of_val(data);
+        let pixels = unsafe { libc::malloc(len.max(1)) as *mut c_uchar };
+        if pixels.is_null() {
             return AgnoImage {
                 data: null_mut(),
                 exif: exif_ctx,
+                icc_profile: None,
+                color_profile: None,
                 len: 0,
                 height: 0,
                 width: 0,
+                format: T::FORMAT,
+                channels,
             };
         }
 
         unsafe {
-            pixels_rgb.copy_from_nonoverlapping(data.as_ptr(), data.len());
+            pixels.copy_from_nonoverlapping(data.as_ptr() as *const c_uchar, len);
         }
 
         AgnoImage {
-            data: pixels_rgb,
+            data: pixels,
             exif: exif_ctx,
-            len: data.len(),
+            icc_profile: None,
+            color_profile: None,
+            len,
             height,
             width,
+            format: T::FORMAT,
+            channels,
         }
     }
 
+    pub fn has_alpha(&self) -> bool {
+        self.channels == 4
+    }
+
     pub fn null() -> *mut AgnoImage {
         null_mut()
     }
@@ -57,6 +190,129 @@ impl AgnoImage {
     }
 
     pub fn as_slice(&self) -> &[u8] {
+        if self.data.is_null() {
+            return &[];
+        }
         unsafe { std::slice::from_raw_parts(self.data, self.len) }
     }
+
+    /// The pixels as `T`, or None when the image holds another format.
+    pub fn as_samples<T: Sample>(&self) -> Option<&[T]> {
+        if self.format != T::FORMAT {
+            return None;
+        }
+        if self.data.is_null() {
+            return Some(&[]);
+        }
+        // malloc returns memory aligned for any primitive type
+        unsafe {
+            Some(std::slice::from_raw_parts(
+                self.data as *const T,
+                self.len / std::mem::size_of::<T>(),
+            ))
+        }
+    }
+
+    /// The pixels as mutable `T`, or None when the image holds another format.
+    pub fn as_samples_mut<T: Sample>(&mut self) -> Option<&mut [T]> {
+        if self.format != T::FORMAT {
+            return None;
+        }
+        if self.data.is_null() {
+            return Some(&mut []);
+        }
+        unsafe {
+            Some(std::slice::from_raw_parts_mut(
+                self.data as *mut T,
+                self.len / std::mem::size_of::<T>(),
+            ))
+        }
+    }
+
+    /// Reallocates the pixel buffer in place for new dimensions, keeping the format.
+    /// The leading samples survive and any growth is zeroed. Returns false (leaving
+    /// the image untouched) if allocation fails.
+    pub fn reshape(&mut self, width: u64, height: u64) -> bool {
+        let sample_size = match self.format {
+            PixelFormat::U8 => 1,
+            PixelFormat::U16 => 2,
+            PixelFormat::F32 => 4,
+        };
+        let len = width as usize * height as usize * self.channels as usize * sample_size;
+        let pixels = unsafe { libc::realloc(self.data as *mut c_void, len.max(1)) as *mut c_uchar };
+        if pixels.is_null() {
+            return false;
+        }
+        if len > self.len {
+            unsafe { pixels.add(self.len).write_bytes(0, len - self.len) };
+        }
+
+        self.data = pixels;
+        self.len = len;
+        self.width = width;
+        self.height = height;
+        true
+    }
+
+    /// Converts the pixels to another sample type, sRGB encoding or decoding
+    /// when going between linear float and the integer formats. Alpha is only rescaled.
+    pub fn to_samples<T: Sample>(&self) -> Vec<T> {
+        let channels = self.channels as usize;
+        match self.format {
+            PixelFormat::U8 => convert::<u8, T>(self.as_samples().unwrap_or_default(), channels),
+            PixelFormat::U16 => convert::<u16, T>(self.as_samples().unwrap_or_default(), channels),
+            PixelFormat::F32 => convert::<f32, T>(self.as_samples().unwrap_or_default(), channels),
+        }
+    }
+}
+
+fn convert<S: Sample, T: Sample>(src: &[S], channels: usize) -> Vec<T> {
+    let srgb = TransferFn::srgb();
+    let curve: fn(&TransferFn, f32) -> f32 = match (S::FORMAT.is_linear(), T::FORMAT.is_linear()) {
+        (true, false) => |srgb, v| srgb.encode(v),
+        (false, true) => |srgb, v| srgb.decode(v),
+        _ => |_, v| v,
+    };
+    src.iter()
+        .enumerate()
+        .map(|(i, &v)| {
+            if i % channels == 3 {
+                T::from_unit(v.to_unit())
+            } else {
+                T::from_unit(curve(&srgb, v.to_unit()))
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_formats_convert_between_each_other() {
+        // Black, mid grey, white and an opaque-ish alpha
+        let rgba = [0u8, 128, 255, 200];
+        let img = AgnoImage::from_channels(&rgba, 1, 1, 4, ExifContext::new());
+
+        assert_eq!(img.to_samples::<u16>(), [0, 128 * 257, 65535, 200 * 257]);
+
+        // Float is linear: colour is sRGB decoded, alpha only rescaled
+        let linear = img.to_samples::<f32>();
+        let expected = [0.0, 0.2158605, 1.0, 200.0 / 255.0];
+        assert!(
+            linear
+                .iter()
+                .zip(expected)
+                .all(|(v, e)| (v - e).abs() < 1e-5),
+            "{:?}",
+            linear
+        );
+
+        let back = AgnoImage::from_channels(&linear, 1, 1, 4, ExifContext::new());
+        assert_eq!(back.format, PixelFormat::F32);
+        assert_eq!(back.to_samples::<u8>(), rgba);
+        AgnoImage::free(&img);
+        AgnoImage::free(&back);
+    }
 }
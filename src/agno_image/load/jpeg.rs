@@ -0,0 +1,293 @@
+use std::error::Error;
+
+use log::debug;
+use rayon::{iter::ParallelIterator, slice::ParallelSliceMut};
+use zune_core::{colorspace::ColorSpace, options::DecoderOptions};
+use zune_jpeg::JpegDecoder;
+
+use crate::{
+    agno_image::{AgnoImage, load::ImageLoadSettings},
+    exif::ExifContext,
+    icc::{cmyk_to_rgb, extract_icc_profile},
+};
+
+/// What a JPEG's components hold, going by the frame header's component count and
+/// the Adobe APP14 transform flag.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JpegColorModel {
+    Gray,
+    YCbCr,
+    Rgb,
+    Cmyk,
+    /// YCbCr-encoded CMY plus a plain K channel
+    Ycck,
+}
+
+/// Colour model of a JPEG plus whether an Adobe APP14 marker is present, in which
+/// case CMYK and YCCK data are stored inverted (0 is full ink), as Photoshop writes them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct JpegColor {
+    pub model: JpegColorModel,
+    pub adobe: bool,
+}
+
+// Payload of an APP14 segment written by Adobe software: "Adobe", version,
+// two flag words, then the transform byte
+const ADOBE_SIGNATURE: &[u8] = b"Adobe";
+const ADOBE_TRANSFORM_OFFSET: usize = 11;
+
+/// Reads the colour model from the markers ahead of the first scan, None when the
+/// data isn't a JPEG or has no frame header.
+pub fn jpeg_color(bytes: &[u8]) -> Option<JpegColor> {
+    if bytes.get(..2)? != [0xFF, 0xD8] {
+        return None;
+    }
+
+    let mut transform = None;
+    let mut at = 2;
+    loop {
+        // Markers may be preceded by fill bytes
+        while *bytes.get(at)? == 0xFF && *bytes.get(at + 1)? == 0xFF {
+            at += 1;
+        }
+        let marker = *bytes.get(at + 1)?;
+        if *bytes.get(at)? != 0xFF || marker == 0xDA || marker == 0xD9 {
+            return None;
+        }
+        let len = u16::from_be_bytes([*bytes.get(at + 2)?, *bytes.get(at + 3)?]) as usize;
+        let payload = bytes.get(at + 4..at + 2 + len)?;
+
+        match marker {
+            0xEE if payload.starts_with(ADOBE_SIGNATURE) => {
+                transform = payload.get(ADOBE_TRANSFORM_OFFSET).copied();
+            }
+            // SOF0-SOF15, except DHT (C4), JPG (C8) and DAC (CC)
+            0xC0..=0xCF if !matches!(marker, 0xC4 | 0xC8 | 0xCC) => {
+                let components = *payload.get(5)?;
+                let model = match (components, transform) {
+                    (1, _) => JpegColorModel::Gray,
+                    (3, Some(0)) => JpegColorModel::Rgb,
+                    (3, _) => JpegColorModel::YCbCr,
+                    (4, Some(2)) => JpegColorModel::Ycck,
+                    (4, _) => JpegColorModel::Cmyk,
+                    _ => return None,
+                };
+                return Some(JpegColor {
+                    model,
+                    adobe: transform.is_some(),
+                });
+            }
+            _ => {}
+        }
+        at += 2 + len;
+    }
+}
+
+// JFIF YCbCr to RGB, full range
+fn ycc_to_rgb(y: u8, cb: u8, cr: u8) -> [u8; 3] {
+    let (y, cb, cr) = (y as f32, cb as f32 - 128.0, cr as f32 - 128.0);
+    [
+        y + 1.402 * cr,
+        y - 0.344_136 * cb - 0.714_136 * cr,
+        y + 1.772 * cb,
+    ]
+    .map(|v| v.round().clamp(0.0, 255.0) as u8)
+}
+
+/// Decodes a CMYK or YCCK JPEG to RGB, undoing the YCC encoding and Adobe inversion
+/// itself and converting the inks through the embedded CMYK profile when there is one.
+pub fn load_cmyk_jpeg(
+    bytes: &[u8],
+    color: JpegColor,
+    exif: ExifContext,
+    settings: &ImageLoadSettings,
+) -> Result<AgnoImage, Box<dyn Error>> {
+    // Ask for the components as stored so zune-jpeg does no colour conversion of its own
+    let stored = match color.model {
+        JpegColorModel::Ycck => ColorSpace::YCCK,
+        _ => ColorSpace::CMYK,
+    };
+    let options = DecoderOptions::default()
+        .jpeg_set_out_colorspace(stored)
+        .set_strict_mode(false)
+        .set_max_width(usize::MAX)
+        .set_max_height(usize::MAX);
+    let mut decoder = JpegDecoder::new_with_options(bytes, options);
+    let mut inks = decoder
+        .decode()
+        .map_err(|e| format!("Failed to decode {:?} JPEG: {:?}", color.model, e))?;
+    let (width, height) = decoder.dimensions().ok_or("JPEG has no dimensions")?;
+    debug!(
+        "Decoded {}x{} {:?} JPEG (Adobe: {})",
+        width, height, color.model, color.adobe
+    );
+
+    // Turn every pixel into ink amounts, 0 for none
+    inks.par_chunks_mut(4).for_each(|px| match color.model {
+        // YCC decodes straight to C, M and Y ink; K is stored inverted like Adobe CMYK
+        JpegColorModel::Ycck => {
+            let [c, m, y] = ycc_to_rgb(px[0], px[1], px[2]);
+            px.copy_from_slice(&[c, m, y, 255 - px[3]]);
+        }
+        _ if color.adobe => px.iter_mut().for_each(|v| *v = 255 - *v),
+        _ => {}
+    });
+
+    let profile = extract_icc_profile(bytes);
+    let (rgb, color_profile) = cmyk_to_rgb(&inks, profile.as_deref(), settings)?;
+
+    let mut a_img = AgnoImage::from_samples(&rgb, width as u64, height as u64, exif);
+    a_img.color_profile = color_profile;
+    a_img.icc_profile = profile;
+    Ok(a_img)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(out: &mut Vec<u8>, marker: u8, payload: &[u8]) {
+        out.extend_from_slice(&[0xFF, marker]);
+        out.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        out.extend_from_slice(payload);
+    }
+
+    // A baseline 8x8 JPEG whose components each hold one flat value, with an Adobe
+    // APP14 marker carrying `transform` when given. Quantisation is all ones, DC
+    // categories get 4-bit codes and the only AC code is EOB, so every block is a DC
+    // code, its extra bits and a single 0 bit.
+    fn flat_jpeg(transform: Option<u8>, stored: &[u8]) -> Vec<u8> {
+        let mut jpeg = vec![0xFF, 0xD8];
+        if let Some(t) = transform {
+            segment(
+                &mut jpeg,
+                0xEE,
+                &[b'A', b'd', b'o', b'b', b'e', 0, 100, 0, 0, 0, 0, t],
+            );
+        }
+        segment(&mut jpeg, 0xDB, &[[0].as_slice(), &[1; 64]].concat());
+        let mut sof = vec![8, 0, 8, 0, 8, stored.len() as u8];
+        for id in 1..=stored.len() as u8 {
+            sof.extend_from_slice(&[id, 0x11, 0]);
+        }
+        segment(&mut jpeg, 0xC0, &sof);
+        let mut dc = vec![0x00, 0, 0, 0, 12];
+        dc.extend_from_slice(&[0; 12]);
+        dc.extend(0..12);
+        segment(&mut jpeg, 0xC4, &dc);
+        let mut ac = vec![0x10, 1];
+        ac.extend_from_slice(&[0; 15]);
+        ac.push(0x00);
+        segment(&mut jpeg, 0xC4, &ac);
+        let mut sos = vec![stored.len() as u8];
+        for id in 1..=stored.len() as u8 {
+            sos.extend_from_slice(&[id, 0x00]);
+        }
+        sos.extend_from_slice(&[0, 63, 0]);
+        segment(&mut jpeg, 0xDA, &sos);
+
+        let mut bits: Vec<bool> = Vec::new();
+        let mut put = |value: u32, len: u32| {
+            bits.extend((0..len).rev().map(|i| value >> i & 1 == 1));
+        };
+        for &v in stored {
+            let diff = (v as i32 - 128) * 8;
+            let category = 32 - diff.unsigned_abs().leading_zeros();
+            put(category, 4);
+            let extra = if diff < 0 {
+                diff + (1 << category) - 1
+            } else {
+                diff
+            };
+            put(extra as u32, category);
+            put(0, 1);
+        }
+        while !bits.len().is_multiple_of(8) {
+            bits.push(true);
+        }
+        for byte in bits.chunks(8) {
+            let b = byte.iter().fold(0u8, |acc, &bit| acc << 1 | bit as u8);
+            jpeg.push(b);
+            if b == 0xFF {
+                jpeg.push(0);
+            }
+        }
+        jpeg.extend_from_slice(&[0xFF, 0xD9]);
+        jpeg
+    }
+
+    fn color(model: JpegColorModel, adobe: bool) -> Option<JpegColor> {
+        Some(JpegColor { model, adobe })
+    }
+
+    #[test]
+    fn app14_transform_picks_the_colour_model() {
+        use JpegColorModel::*;
+        assert_eq!(jpeg_color(&flat_jpeg(None, &[90])), color(Gray, false));
+        assert_eq!(jpeg_color(&flat_jpeg(None, &[90; 3])), color(YCbCr, false));
+        assert_eq!(jpeg_color(&flat_jpeg(Some(0), &[90; 3])), color(Rgb, true));
+        assert_eq!(
+            jpeg_color(&flat_jpeg(Some(1), &[90; 3])),
+            color(YCbCr, true)
+        );
+        assert_eq!(jpeg_color(&flat_jpeg(None, &[90; 4])), color(Cmyk, false));
+        assert_eq!(jpeg_color(&flat_jpeg(Some(0), &[90; 4])), color(Cmyk, true));
+        assert_eq!(jpeg_color(&flat_jpeg(Some(2), &[90; 4])), color(Ycck, true));
+
+        // Fill bytes ahead of a marker are skipped
+        let mut filled = flat_jpeg(Some(2), &[90; 4]);
+        filled.insert(2, 0xFF);
+        assert_eq!(jpeg_color(&filled), color(Ycck, true));
+
+        assert_eq!(jpeg_color(b"\x89PNG\r\n\x1a\n"), None);
+        assert_eq!(jpeg_color(&[0xFF, 0xD8, 0xFF, 0xD9]), None);
+    }
+
+    // The one colour of a flat CMYK or YCCK JPEG after loading
+    fn loaded_rgb(jpeg: &[u8]) -> [u8; 3] {
+        let color = jpeg_color(jpeg).unwrap();
+        let img = load_cmyk_jpeg(
+            jpeg,
+            color,
+            ExifContext::new(),
+            &ImageLoadSettings::default(),
+        )
+        .unwrap();
+        assert_eq!((img.width, img.height, img.channels), (8, 8, 3));
+        let samples = img.as_samples::<u8>().unwrap();
+        let first = [samples[0], samples[1], samples[2]];
+        assert!(samples.chunks_exact(3).all(|px| px == first));
+        AgnoImage::free(&img);
+        first
+    }
+
+    fn assert_near(actual: [u8; 3], expected: [u8; 3]) {
+        assert!(
+            actual
+                .iter()
+                .zip(expected)
+                .all(|(&a, e)| a.abs_diff(e) <= 2),
+            "{:?} vs {:?}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn adobe_cmyk_and_ycck_are_inverted() {
+        let stored = [40, 80, 120, 30];
+        // Without APP14, values are ink amounts: light ink, little black
+        assert_near(loaded_rgb(&flat_jpeg(None, &stored)), [190, 154, 119]);
+        // Adobe stores 255 - ink, so the same values mean heavy ink
+        assert_near(loaded_rgb(&flat_jpeg(Some(0), &stored)), [5, 9, 14]);
+        // YCCK: neutral chroma decodes to equal C, M and Y; K is inverted
+        assert_near(
+            loaded_rgb(&flat_jpeg(Some(2), &[100, 128, 128, 30])),
+            [18, 18, 18],
+        );
+        assert_near(
+            loaded_rgb(&flat_jpeg(Some(2), &[0, 128, 128, 255])),
+            [255, 255, 255],
+        );
+    }
+}
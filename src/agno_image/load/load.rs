@@ -4,12 +4,15 @@ use std::{
     io::{Cursor, Read, Seek, SeekFrom},
 };
 
+use image::DynamicImage;
+
 use crate::{
     agno_image::{
-        AgnoImage,
-        load::{load_pdf, load_sony_raw},
+        AgnoImage, auto_orient,
+        load::{JpegColorModel, LoadOptions, jpeg_color, load_cmyk_jpeg, load_pdf, load_sony_raw},
     },
     exif::ExifContext,
+    icc::{convert_from_profile, extract_icc_profile},
     tiff::{TiffDetectResult, detect_sony_raw},
 };
 
@@ -41,36 +44,94 @@ pub fn detect_image_type(reader: &mut File) -> Result<ImageType, Box<dyn Error>>
 }
 
 pub fn load_agno_image_from_file(path: &str) -> Result<AgnoImage, Box<dyn Error>> {
+    load_agno_image_with_options(path, &LoadOptions::default())
+}
+
+pub fn load_agno_image_with_options(
+    path: &str,
+    options: &LoadOptions,
+) -> Result<AgnoImage, Box<dyn Error>> {
     let mut file = File::open(path)?;
 
     let exif = ExifContext::from_reader_auto(&mut file)?;
 
-    match detect_image_type(&mut file)? {
+    let img = match detect_image_type(&mut file)? {
         ImageType::Jpeg | ImageType::Png | ImageType::Webp => {
-            // For JPEG, use image crate directly
-            let img = image::ImageReader::new(Cursor::new(std::fs::read(path)?))
-                .with_guessed_format()?
-                .decode()?
-                .to_rgb8();
-
-            let (width, height) = img.dimensions();
-            return Ok(AgnoImage::new(
-                img.into_raw(),
-                width as u64,
-                height as u64,
-                exif,
-            ));
+            let bytes = std::fs::read(path)?;
+            // The image crate turns CMYK into RGB without regard for Adobe's inversion
+            // or the profile, so those take their own path
+            match jpeg_color(&bytes) {
+                Some(color)
+                    if matches!(color.model, JpegColorModel::Cmyk | JpegColorModel::Ycck) =>
+                {
+                    load_cmyk_jpeg(&bytes, color, exif, &options.image)?
+                }
+                _ => decode_image(&bytes, exif, options)?,
+            }
         }
         ImageType::Pdf => {
             if cfg!(feature = "pdf") {
-                load_pdf(path, exif)
+                load_pdf(path, exif)?
             } else {
-                Err("PDF support is not enabled. Please enable the 'pdf' feature.".into())
+                return Err("PDF support is not enabled. Please enable the 'pdf' feature.".into());
             }
         }
         ImageType::SonyRaw(det) => {
             // For Sony RAW, proceed with ARW decoding
-            load_sony_raw(det, &mut file, exif)
+            load_sony_raw(det, &mut file, exif, options)?
         }
+    };
+
+    Ok(if options.keep_orientation {
+        img
+    } else {
+        auto_orient(img)
+    })
+}
+
+// JPEG, PNG and WebP through the image crate
+fn decode_image(
+    bytes: &[u8],
+    exif: ExifContext,
+    options: &LoadOptions,
+) -> Result<AgnoImage, Box<dyn Error>> {
+    let img = image::ImageReader::new(Cursor::new(bytes))
+        .with_guessed_format()?
+        .decode()?;
+
+    let (width, height) = (img.width() as u64, img.height() as u64);
+
+    // Keep whatever precision the file has: 16-bit PNGs stay 16-bit, float stays float.
+    // Transparency is kept as a fourth channel.
+    let alpha = img.color().has_alpha();
+    let grey = options.image.keep_grayscale;
+    let mut a_img = match img {
+        DynamicImage::ImageLuma8(ref luma) if grey => {
+            AgnoImage::from_channels(luma.as_raw(), width, height, 1, exif)
+        }
+        DynamicImage::ImageLuma16(ref luma) if grey => {
+            AgnoImage::from_channels(luma.as_raw(), width, height, 1, exif)
+        }
+        DynamicImage::ImageLumaA16(_) | DynamicImage::ImageRgba16(_) => {
+            AgnoImage::from_channels(img.to_rgba16().as_raw(), width, height, 4, exif)
+        }
+        DynamicImage::ImageLuma16(_) | DynamicImage::ImageRgb16(_) => {
+            AgnoImage::from_samples(img.to_rgb16().as_raw(), width, height, exif)
+        }
+        DynamicImage::ImageRgba32F(_) => {
+            AgnoImage::from_channels(img.to_rgba32f().as_raw(), width, height, 4, exif)
+        }
+        DynamicImage::ImageRgb32F(_) => {
+            AgnoImage::from_samples(img.to_rgb32f().as_raw(), width, height, exif)
+        }
+        _ if alpha => AgnoImage::from_channels(img.to_rgba8().as_raw(), width, height, 4, exif),
+        _ => AgnoImage::new(img.to_rgb8().into_raw(), width, height, exif),
+    };
+
+    // Grey images kept as one channel only take grey profiles, the rest RGB ones
+    if let Some(profile) = extract_icc_profile(bytes) {
+        a_img.color_profile = convert_from_profile(&mut a_img, &profile, &options.image)?;
+        a_img.icc_profile = Some(profile);
     }
+    Ok(a_img)
 }
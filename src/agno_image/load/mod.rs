@@ -1,7 +1,13 @@
+pub mod jpeg;
 pub mod load;
+pub mod options;
 pub mod pdf;
+pub mod pixel_shift;
 pub mod sony;
 
+pub use jpeg::*;
 pub use load::*;
+pub use options::*;
 pub use pdf::*;
+pub use pixel_shift::*;
 pub use sony::*;
@@ -0,0 +1,138 @@
+use std::path::PathBuf;
+
+use crate::{
+    bad_pixels::BadPixelMode, color::OutputColorSpace, demosaic::DemosaicAlgorithm,
+    denoise::NoiseReduction, highlight::HighlightMode, icc::ColorTarget, tone::ToneCurve,
+    white_balance::WhiteBalanceMode,
+};
+
+/// Output scale for raw decodes. Anything below `Full` skips demosaicing and
+/// bins whole CFA quads into RGB pixels, which is what thumbnails want.
+#[repr(C)]
+#[allow(dead_code)] // variants are constructed from C
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RawScale {
+    #[default]
+    Full = 0,
+    Half = 1,
+    Quarter = 2,
+    Eighth = 3,
+}
+
+impl RawScale {
+    // Number of 2x2 quads averaged along each axis into one output pixel, None for a full demosaic
+    pub fn quads_per_pixel(self) -> Option<usize> {
+        match self {
+            RawScale::Full => None,
+            RawScale::Half => Some(1),
+            RawScale::Quarter => Some(2),
+            RawScale::Eighth => Some(4),
+        }
+    }
+}
+
+/// How raws are developed. C passes these as `struct RawDevelopSettings`.
+#[derive(Clone, Debug)]
+pub struct RawDevelopSettings {
+    pub wb_mode: WhiteBalanceMode,
+    /// RGGB multipliers, only read when `wb_mode` is `Custom`
+    pub wb_multipliers: [f32; 4],
+    /// Colour temperature in kelvin, only read when `wb_mode` is `Temperature`
+    pub wb_temperature: f32,
+    /// Green (-) / magenta (+) shift that goes with `wb_temperature`
+    pub wb_tint: f32,
+    /// Exposure compensation in stops, applied in linear space
+    pub exposure_ev: f32,
+    /// Overrides the black level when >= 0
+    pub black_level: i32,
+    /// Overrides the white level when > 0
+    pub white_level: i32,
+    pub demosaic: DemosaicAlgorithm,
+    pub highlight_mode: HighlightMode,
+    pub color_space: OutputColorSpace,
+    pub tone_curve: ToneCurve,
+    /// Display gamma for `ToneCurve::Gamma`
+    pub gamma: f32,
+    /// Slope of the linear toe for `ToneCurve::Gamma`, 0 for a pure power law
+    pub toe_slope: f32,
+    /// Bits per output channel
+    pub bit_depth: u32,
+    pub scale: RawScale,
+    pub bad_pixel_mode: BadPixelMode,
+    /// How far from its same-colour neighbours (in multiples of their spread) a pixel
+    /// must be for `BadPixelMode::Auto` to repair it, 0 for the default
+    pub bad_pixel_threshold: f32,
+    /// dcraw `-P` defect map
+    pub defect_map: Option<PathBuf>,
+    /// Raw (or master DNG) of a dark frame to subtract
+    pub dark_frame: Option<PathBuf>,
+    /// Raw (or master DNG) of a flat field to divide by
+    pub flat_field: Option<PathBuf>,
+    /// `LENS_CORRECT_*` flags picking which of the camera's embedded lens
+    /// corrections to apply, 0 for none
+    pub lens_corrections: u32,
+    /// How far (relative) the two green readings of a pixel-shift pixel may differ
+    /// before it's treated as moving and taken from the first frame alone; 0 for the
+    /// default, negative to never fall back
+    pub pixel_shift_motion_threshold: f32,
+    pub noise_reduction: NoiseReduction,
+    /// Wavelet threshold on the mosaic in dcraw `-n` units, only read when
+    /// `noise_reduction` is `Manual`
+    pub wavelet_threshold: f32,
+    /// Chroma smoothing after demosaicing, 0 to 1, only read when
+    /// `noise_reduction` is `Manual`
+    pub chroma_denoise: f32,
+}
+
+impl Default for RawDevelopSettings {
+    fn default() -> Self {
+        RawDevelopSettings {
+            wb_mode: WhiteBalanceMode::default(),
+            wb_multipliers: [1.0; 4],
+            wb_temperature: 5500.0,
+            wb_tint: 0.0,
+            exposure_ev: 0.0,
+            black_level: -1,
+            white_level: 0,
+            demosaic: DemosaicAlgorithm::default(),
+            highlight_mode: HighlightMode::default(),
+            color_space: OutputColorSpace::default(),
+            tone_curve: ToneCurve::default(),
+            gamma: 2.2,
+            toe_slope: 0.0,
+            bit_depth: 8,
+            scale: RawScale::default(),
+            bad_pixel_mode: BadPixelMode::default(),
+            bad_pixel_threshold: 0.0,
+            defect_map: None,
+            dark_frame: None,
+            flat_field: None,
+            lens_corrections: 0,
+            pixel_shift_motion_threshold: 0.0,
+            noise_reduction: NoiseReduction::default(),
+            wavelet_threshold: 0.0,
+            chroma_denoise: 0.0,
+        }
+    }
+}
+
+/// How JPEG, PNG and WebP files are decoded. C passes these as `struct ImageLoadSettings`.
+#[derive(Clone, Debug, Default)]
+pub struct ImageLoadSettings {
+    /// Space pixels with an embedded ICC profile are converted to
+    pub color_target: ColorTarget,
+    /// ICC profile file for `ColorTarget::Profile`
+    pub target_profile: Option<PathBuf>,
+    /// Load grey sources without alpha as one channel instead of expanding them to RGB
+    pub keep_grayscale: bool,
+}
+
+/// Everything a load can be tuned with. C passes these as `struct LoadOptions`.
+#[derive(Clone, Debug, Default)]
+pub struct LoadOptions {
+    pub raw: RawDevelopSettings,
+    pub image: ImageLoadSettings,
+    /// Leave pixels as stored instead of turning them upright by the EXIF
+    /// Orientation tag, which is then left as it was too
+    pub keep_orientation: bool,
+}
@@ -0,0 +1,119 @@
+use std::error::Error;
+
+use crate::{
+    agno_image::{
+        AgnoImage, PixelFormat, auto_orient,
+        load::{
+            LoadOptions, apply_mosaic_stages, finish_raw_image, load_agno_image_with_options,
+            load_mosaic_stages, raw_levels, raw_render_params,
+        },
+    },
+    calibration::{check_compatible, load_raw_frame},
+    demosaic::develop_rgb,
+    pixel_shift::compose_pixel_shift,
+    sony_decoder::Dimensions,
+};
+
+/// Loads a Sony Pixel Shift sequence: 4 or 16 ARWs in shooting order, or a single
+/// ARQ the camera software already composed. Always renders at full resolution
+/// (twice the sensor's for 16 shots).
+pub fn load_pixel_shift(
+    paths: &[&str],
+    options: &LoadOptions,
+) -> Result<AgnoImage, Box<dyn Error>> {
+    // Checked before anything is decoded, and so an empty list can't reach frames[0]
+    match paths {
+        [path] => return load_agno_image_with_options(path, options),
+        _ if matches!(paths.len(), 4 | 16) => {}
+        _ => {
+            return Err(format!(
+                "Pixel shift needs 4 or 16 ARWs or a single ARQ, got {} files",
+                paths.len()
+            )
+            .into());
+        }
+    }
+
+    let settings = &options.raw;
+    let Some(format) = PixelFormat::from_bit_depth(settings.bit_depth) else {
+        return Err(format!("Unsupported raw output bit depth: {}", settings.bit_depth).into());
+    };
+
+    let mut frames = paths
+        .iter()
+        .map(|path| load_raw_frame(path))
+        .collect::<Result<Vec<_>, _>>()?;
+    for (frame, path) in frames.iter().zip(paths).skip(1) {
+        check_compatible(frame, frames[0].dims, frames[0].iso, path)?;
+    }
+
+    // Every frame goes through the same mosaic clean-up a single raw would, with the
+    // calibration frames and defect map read once for the whole sequence
+    let stages = load_mosaic_stages(&frames[0].ctx, settings, frames[0].dims)?;
+    for frame in &mut frames {
+        let (black_level, white_level) = raw_levels(
+            &frame.ctx,
+            settings,
+            &frame.pixels,
+            frame.white_level,
+            frame.dims,
+        );
+        apply_mosaic_stages(
+            &stages,
+            settings,
+            &mut frame.pixels,
+            frame.dims,
+            black_level,
+            white_level,
+        );
+        frame.black_level = black_level;
+        frame.white_level = white_level;
+    }
+
+    let first = &frames[0];
+    let params = raw_render_params(
+        &first.ctx,
+        settings,
+        &first.pixels,
+        first.dims,
+        first.black_level,
+        first.white_level,
+    );
+
+    let composite = compose_pixel_shift(&frames, settings.pixel_shift_motion_threshold)?;
+    let dims = Dimensions {
+        raw_width: composite.width,
+        raw_height: composite.height,
+        output_width: composite.width,
+        output_height: composite.height,
+    };
+
+    let exif = first.ctx.clone();
+    let (rgb, width) = (&composite.rgb, composite.width);
+    let img = match format {
+        PixelFormat::U8 => finish_raw_image(
+            develop_rgb::<u8>(rgb, width, &params),
+            dims,
+            &stages.rgb,
+            exif,
+        ),
+        PixelFormat::U16 => finish_raw_image(
+            develop_rgb::<u16>(rgb, width, &params),
+            dims,
+            &stages.rgb,
+            exif,
+        ),
+        PixelFormat::F32 => finish_raw_image(
+            develop_rgb::<f32>(rgb, width, &params),
+            dims,
+            &stages.rgb,
+            exif,
+        ),
+    }?;
+
+    Ok(if options.keep_orientation {
+        img
+    } else {
+        auto_orient(img)
+    })
+}
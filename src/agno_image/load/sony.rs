@@ -2,24 +2,51 @@ use std::{
     error::Error,
     fs::File,
     io::{Cursor, Seek, SeekFrom},
+    path::PathBuf,
 };
 
 use crate::{
-    agno_image::{AgnoImage, auto_rotate_image},
-    demosaic::{BayerPattern, demosaic_bilinear_to_rgb8},
-    exif::{
-        ExifContext, ExifValue,
-        spec::{BLACK_LEVEL, WB_RGGBLEVELS},
+    agno_image::{
+        AgnoImage, PixelFormat, Sample,
+        load::{LoadOptions, RawDevelopSettings},
     },
-    sony_decoder::{self, DecodeError, Dimensions},
+    bad_pixels::{
+        BadPixelMode, correct_bad_pixels, defects_for_shot, detect_bad_pixels, load_defect_map,
+        shot_timestamp,
+    },
+    black_level::{BlackLevels, resolve_black_levels},
+    calibration::{RawFrame, apply_calibration, check_compatible, iso, load_raw_frame},
+    color::{camera_to_output, xyz_to_camera},
+    demosaic::{
+        BayerPattern, DemosaicAlgorithm, RenderParams, bin_quads, demosaic_bilinear, demosaic_mhc,
+    },
+    denoise::{chroma_denoise, resolve_denoise, wavelet_denoise},
+    exif::{ExifContext, ExifValue, spec::WHITE_LEVEL},
+    icc::output_profile,
+    lens_correction::{LensProfile, correct_vignetting, warp},
+    pixel_shift::arq_mosaic,
+    sony_decoder::{self, DecodeError, Dimensions, SonyLoadResult},
     tiff::{SonyVariant, TiffDetectResult},
+    tone::{ToneLut, TransferFn},
+    white_balance::resolve_wb_gains,
 };
 
-pub fn load_sony_raw(
-    det: TiffDetectResult,
+// DNG files carry their white point in a tag rather than implying it from the format
+fn dng_white_level(ctx: &ExifContext) -> u16 {
+    match ctx.get_tag_value(WHITE_LEVEL) {
+        Some(ExifValue::Short(v)) if !v.is_empty() => v[0],
+        Some(ExifValue::Long(v)) if !v.is_empty() => v[0].min(u16::MAX as u32) as u16,
+        _ => u16::MAX,
+    }
+}
+
+/// Reads and decodes the raw mosaic picked by `detect_sony_raw`.
+/// Returns the samples with the dimensions describing their layout.
+pub fn decode_sony_raw(
+    det: &TiffDetectResult,
     mut file: &mut File,
-    exif: ExifContext,
-) -> Result<AgnoImage, Box<dyn Error>> {
+    ctx: &ExifContext,
+) -> Result<(SonyLoadResult, Dimensions), Box<dyn Error>> {
     let mut dims = Dimensions {
         raw_width: det.raw.width as usize,
         raw_height: det.raw.height as usize,
@@ -36,9 +63,6 @@ pub fn load_sony_raw(
 
     let mut cursor = Cursor::new(buf);
 
-    file.seek(SeekFrom::Start(0))?;
-    let mut ctx = ExifContext::from_reader_auto(&mut file)?;
-
     // Auto-select decoder based on detection
     let decoded = match det.variant {
         SonyVariant::Arw2Compressed => {
@@ -72,48 +96,378 @@ pub fn load_sony_raw(
                 Err(e) => return Err(Box::new(e)),
             }
         }
-        SonyVariant::Unknown => {
+        SonyVariant::DngUncompressed => {
+            sony_decoder::dng_uncompressed16_load_raw(&mut cursor, dims, dng_white_level(ctx))?
+        }
+        SonyVariant::DngLjpeg => {
+            sony_decoder::dng_ljpeg_load_raw(&mut cursor, dims, dng_white_level(ctx))?
+        }
+        // Composed RGB rather than a mosaic; `load_arq` reads it
+        SonyVariant::Arq | SonyVariant::Unknown => {
             return Err(Box::new(DecodeError::UnsupportedFormat(det.variant)));
         }
     };
 
-    // Write a JPEG (choose your CFA pattern and black level)
-    let pattern = BayerPattern::RGGB; // Adjust per camera/margins
+    Ok((decoded, dims))
+}
 
-    let black_level = match ctx.get_tag_value(BLACK_LEVEL) {
-        Some(ExifValue::Short(v)) if !v.is_empty() => v[0],
-        _ => 512,
+pub fn load_sony_raw(
+    det: TiffDetectResult,
+    file: &mut File,
+    exif: ExifContext,
+    options: &LoadOptions,
+) -> Result<AgnoImage, Box<dyn Error>> {
+    file.seek(SeekFrom::Start(0))?;
+    let ctx = ExifContext::from_reader_auto(file)?;
+
+    if det.variant == SonyVariant::Arq {
+        let (decoded, dims) = decode_arq(&det, file)?;
+        return develop_raw(decoded, dims, true, ctx, exif, options);
+    }
+
+    let (decoded, dims) = decode_sony_raw(&det, file, &ctx)?;
+    develop_raw(decoded, dims, false, ctx, exif, options)
+}
+
+// Reads an ARQ into the quad mosaic `pixel_shift::arq_mosaic` builds
+fn decode_arq(
+    det: &TiffDetectResult,
+    file: &mut File,
+) -> Result<(SonyLoadResult, Dimensions), Box<dyn Error>> {
+    let (width, height) = (det.raw.width as usize, det.raw.height as usize);
+    let buf = sony_decoder::read_concatenated_strips(
+        file,
+        &det.raw.strip_offsets,
+        &det.raw.strip_byte_counts,
+    )?;
+    let arq_dims = Dimensions {
+        raw_width: width,
+        raw_height: height,
+        output_width: width,
+        output_height: height,
     };
+    let decoded = sony_decoder::sony_arq_load_raw(&mut Cursor::new(buf), arq_dims)?;
 
-    let wb_raw = match ctx.get_tag_value(WB_RGGBLEVELS) {
-        Some(ExifValue::Short(v)) if v.len() >= 4 => v,
-        _ => &vec![1000, 1000, 1000, 1000],
+    let dims = Dimensions {
+        raw_width: width * 2,
+        raw_height: height * 2,
+        output_width: width * 2,
+        output_height: height * 2,
     };
+    Ok((
+        SonyLoadResult {
+            pixels: arq_mosaic(&decoded.pixels, width, height),
+            white_level: decoded.white_level,
+        },
+        dims,
+    ))
+}
 
-    let gamma = 2.2;
-    let wb: [f32; 3] = [
-        wb_raw[0] as f32 / 1000.0,
-        wb_raw[1] as f32 / 1000.0,
-        wb_raw[3] as f32 / 1000.0,
-    ];
+// Everything after decoding. A composite mosaic (an ARQ's quads) is binned rather
+// than demosaiced, since each quad already holds a full pixel.
+fn develop_raw(
+    mut decoded: SonyLoadResult,
+    dims: Dimensions,
+    composite: bool,
+    ctx: ExifContext,
+    exif: ExifContext,
+    options: &LoadOptions,
+) -> Result<AgnoImage, Box<dyn Error>> {
+    let settings = &options.raw;
+    let Some(format) = PixelFormat::from_bit_depth(settings.bit_depth) else {
+        return Err(format!("Unsupported raw output bit depth: {}", settings.bit_depth).into());
+    };
 
-    let rgb = demosaic_bilinear_to_rgb8(
+    let (black_level, white_level) =
+        raw_levels(&ctx, settings, &decoded.pixels, decoded.white_level, dims);
+    let stages = prepare_mosaic(
+        &ctx,
+        settings,
+        &mut decoded.pixels,
+        dims,
+        black_level,
+        white_level,
+    )?;
+    let params = raw_render_params(
+        &ctx,
+        settings,
         &decoded.pixels,
         dims,
-        pattern,
         black_level,
-        decoded.white_level,
-        wb, // wb_gains (EXIF if present; None falls back to gray-world)
-        gamma,
+        white_level,
     );
 
-    // Auto-rotate based on EXIF Orientation tag
-    let img = auto_rotate_image(&mut ctx, &rgb, &mut dims)?;
+    let raw = &decoded.pixels;
+    match format {
+        PixelFormat::U8 => {
+            let (rgb, dims) = render::<u8>(raw, dims, composite, &params, settings);
+            finish_raw_image(rgb, dims, &stages, exif)
+        }
+        PixelFormat::U16 => {
+            let (rgb, dims) = render::<u16>(raw, dims, composite, &params, settings);
+            finish_raw_image(rgb, dims, &stages, exif)
+        }
+        PixelFormat::F32 => {
+            let (rgb, dims) = render::<f32>(raw, dims, composite, &params, settings);
+            finish_raw_image(rgb, dims, &stages, exif)
+        }
+    }
+}
 
-    Ok(AgnoImage::new(
-        img,
+/// Black and white levels to develop with, honouring the overrides in the settings.
+pub fn raw_levels(
+    ctx: &ExifContext,
+    settings: &RawDevelopSettings,
+    raw: &[u16],
+    decoded_white_level: u16,
+    dims: Dimensions,
+) -> (BlackLevels, u16) {
+    let white_level = if settings.white_level > 0 {
+        settings.white_level.min(u16::MAX as i32) as u16
+    } else {
+        decoded_white_level
+    };
+
+    let black_level = if settings.black_level >= 0 {
+        [settings.black_level.min(u16::MAX as i32) as u16; 4]
+    } else {
+        resolve_black_levels(ctx, raw, dims, white_level)
+    };
+
+    (black_level, white_level)
+}
+
+/// What's left to do once the RGB is rendered, as decided by `prepare_mosaic`.
+#[derive(Clone, Debug, Default)]
+pub struct RgbStages {
+    /// Chroma noise reduction strength in [0, 1]
+    pub chroma_denoise: f32,
+    pub lens: LensProfile,
+    /// ICC profile of the output space and tone curve, None for sRGB
+    pub color_profile: Option<Vec<u8>>,
+}
+
+/// What the mosaic clean-up needs from disk and the EXIF, read once per shot (or
+/// once for a whole pixel-shift sequence), plus what's left for the RGB.
+pub struct MosaicStages {
+    dark: Option<RawFrame>,
+    flat: Option<RawFrame>,
+    /// Defect map entries that apply to the shot, as (row, col)
+    defects: Vec<(usize, usize)>,
+    /// Wavelet threshold in dcraw `-n` units, 0 for none
+    wavelet: f32,
+    pub rgb: RgbStages,
+}
+
+/// Loads the calibration frames and defect map in the settings and picks the noise
+/// reduction, lens corrections and output profile for a mosaic of `dims`.
+pub fn load_mosaic_stages(
+    ctx: &ExifContext,
+    settings: &RawDevelopSettings,
+    dims: Dimensions,
+) -> Result<MosaicStages, Box<dyn Error>> {
+    let iso = iso(ctx);
+    let load_frame =
+        |path: Option<&PathBuf>, what: &str| -> Result<Option<RawFrame>, Box<dyn Error>> {
+            let Some(path) = path else {
+                return Ok(None);
+            };
+            let frame = load_raw_frame(path)?;
+            check_compatible(&frame, dims, iso, what)?;
+            Ok(Some(frame))
+        };
+    let dark = load_frame(settings.dark_frame.as_ref(), "Dark frame")?;
+    let flat = load_frame(settings.flat_field.as_ref(), "Flat field")?;
+
+    let defects = match &settings.defect_map {
+        Some(path) => defects_for_shot(&load_defect_map(path)?, dims, shot_timestamp(ctx)),
+        None => Vec::new(),
+    };
+
+    let (wavelet, chroma_denoise) = resolve_denoise(
+        settings.noise_reduction,
+        settings.wavelet_threshold,
+        settings.chroma_denoise,
+        iso,
+    );
+    let lens = LensProfile::from_exif(ctx).select(settings.lens_corrections);
+
+    // Float output stays linear whatever the tone curve
+    let transfer = match PixelFormat::from_bit_depth(settings.bit_depth) {
+        Some(f) if f.is_linear() => TransferFn::new(1.0, 0.0),
+        _ => settings
+            .tone_curve
+            .transfer_fn(settings.gamma, settings.toe_slope),
+    };
+
+    Ok(MosaicStages {
+        dark,
+        flat,
+        defects,
+        wavelet,
+        rgb: RgbStages {
+            chroma_denoise,
+            lens,
+            color_profile: output_profile(settings.color_space, transfer),
+        },
+    })
+}
+
+/// Mosaic clean-up ahead of demosaicing: calibration frames, bad pixels, noise
+/// reduction and vignetting.
+pub fn apply_mosaic_stages(
+    stages: &MosaicStages,
+    settings: &RawDevelopSettings,
+    raw: &mut [u16],
+    dims: Dimensions,
+    black_level: BlackLevels,
+    white_level: u16,
+) {
+    let pattern = BayerPattern::RGGB; // Adjust per camera/margins
+
+    // Calibration frames go first: a dark frame also takes out most hot pixels
+    apply_calibration(
+        raw,
+        dims,
+        black_level,
+        white_level,
+        stages.dark.as_ref(),
+        stages.flat.as_ref(),
+    );
+
+    // Bad pixels are repaired on the mosaic so WB estimates and demosaicing never see them
+    let mut bad = stages.defects.clone();
+    if settings.bad_pixel_mode == BadPixelMode::Auto {
+        bad.extend(detect_bad_pixels(
+            raw,
+            dims,
+            black_level,
+            white_level,
+            settings.bad_pixel_threshold,
+        ));
+    }
+    correct_bad_pixels(raw, dims, pattern, &bad);
+
+    // Noise is even across the frame until vignetting correction lifts the corners
+    wavelet_denoise(raw, dims, black_level, white_level, stages.wavelet);
+
+    // Vignetting is a gain, so it goes on the linear mosaic; the geometric
+    // corrections wait for RGB in `render`
+    if let Some(falloff) = &stages.rgb.lens.vignetting {
+        correct_vignetting(raw, dims, black_level, white_level, falloff);
+    }
+}
+
+/// `load_mosaic_stages` then `apply_mosaic_stages` for a single shot.
+pub fn prepare_mosaic(
+    ctx: &ExifContext,
+    settings: &RawDevelopSettings,
+    raw: &mut [u16],
+    dims: Dimensions,
+    black_level: BlackLevels,
+    white_level: u16,
+) -> Result<RgbStages, Box<dyn Error>> {
+    let stages = load_mosaic_stages(ctx, settings, dims)?;
+    apply_mosaic_stages(&stages, settings, raw, dims, black_level, white_level);
+    Ok(stages.rgb)
+}
+
+/// White balance, colour and tone for a cleaned-up mosaic.
+pub fn raw_render_params(
+    ctx: &ExifContext,
+    settings: &RawDevelopSettings,
+    raw: &[u16],
+    dims: Dimensions,
+    black_level: BlackLevels,
+    white_level: u16,
+) -> RenderParams {
+    // Exposure rides along with the WB gains so highlight handling sees the real clip points
+    let exposure = 2f32.powf(settings.exposure_ev);
+    let xyz_to_cam = xyz_to_camera(ctx);
+    let wb = resolve_wb_gains(
+        ctx,
+        settings,
+        &xyz_to_cam,
+        raw,
+        dims,
+        black_level,
+        white_level,
+    )
+    .map(|g| g * exposure);
+
+    RenderParams {
+        pattern: BayerPattern::RGGB,
+        black_level,
+        white_level,
+        wb,
+        tone: ToneLut::new(settings.tone_curve, settings.gamma, settings.toe_slope),
+        highlight: settings.highlight_mode,
+        color_matrix: camera_to_output(&xyz_to_cam, settings.color_space),
+    }
+}
+
+fn render<T: Sample>(
+    raw: &[u16],
+    mut dims: Dimensions,
+    composite: bool,
+    params: &RenderParams,
+    settings: &RawDevelopSettings,
+) -> (Vec<T>, Dimensions) {
+    // Each quad of a composite is one pixel already, so its scales bin twice as much
+    let quads = match settings.scale.quads_per_pixel() {
+        q if composite => Some(q.map_or(1, |q| q * 2)),
+        q => q,
+    };
+    let rgb: Vec<T> = match quads {
+        None => match settings.demosaic {
+            DemosaicAlgorithm::Bilinear => demosaic_bilinear(raw, dims, params),
+            DemosaicAlgorithm::MalvarHeCutler => demosaic_mhc(raw, dims, params),
+        },
+        Some(quads) => {
+            // Binned output is its own raster, so the dims describe it from here on
+            let (rgb, width, height) = bin_quads(raw, dims, params, quads);
+            dims = Dimensions {
+                raw_width: width,
+                raw_height: height,
+                output_width: width,
+                output_height: height,
+            };
+            rgb
+        }
+    };
+
+    (rgb, dims)
+}
+
+/// Chroma denoising and lens geometry for a developed raw, then packing into an
+/// image. Orientation is left to the loader.
+pub fn finish_raw_image<T: Sample>(
+    rgb: Vec<T>,
+    dims: Dimensions,
+    stages: &RgbStages,
+    exif: ExifContext,
+) -> Result<AgnoImage, Box<dyn Error>> {
+    let (width, height) = (dims.output_width, dims.output_height);
+    let rgb = if stages.chroma_denoise > 0.0 {
+        chroma_denoise(&rgb, width, height, stages.chroma_denoise)
+    } else {
+        rgb
+    };
+
+    // The splines are laid out on the sensor, so they go before rotation
+    let lens = &stages.lens;
+    let rgb = if lens.distortion.is_some() || lens.ca.is_some() {
+        warp(&rgb, width, height, lens)
+    } else {
+        rgb
+    };
+
+    let mut a_img = AgnoImage::from_samples(
+        &rgb,
         dims.output_width as u64,
         dims.output_height as u64,
         exif,
-    ))
+    );
+    a_img.color_profile = stages.color_profile.clone();
+    Ok(a_img)
 }
@@ -1,6 +1,8 @@
 pub mod image;
 pub mod load;
+pub mod resample;
 pub mod transform;
 
 pub use image::*;
+pub use resample::*;
 pub use transform::*;
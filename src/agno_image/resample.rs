@@ -0,0 +1,487 @@
+use std::error::Error;
+
+use log::debug;
+use rayon::{
+    iter::{IndexedParallelIterator, ParallelIterator},
+    slice::ParallelSliceMut,
+};
+
+use crate::{
+    agno_image::{AgnoImage, Sample},
+    simd::accumulate_row,
+};
+
+/// Reconstruction filter for scaling.
+#[repr(C)]
+#[allow(dead_code)] // variants are constructed from C
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ResampleFilter {
+    /// Nearest source pixel, no blending
+    Nearest = 0,
+    /// Triangle filter over the two nearest pixels per axis
+    Bilinear = 1,
+    /// Cubic (B=0, C=0.5): sharp, with slight ringing
+    CatmullRom = 2,
+    /// Cubic (B=C=1/3): softer, with almost no ringing
+    Mitchell = 3,
+    /// Windowed sinc over three lobes: sharpest, the most ringing
+    #[default]
+    Lanczos3 = 4,
+}
+
+impl ResampleFilter {
+    // Kernel radius in source pixels at 1:1
+    fn support(self) -> f32 {
+        match self {
+            ResampleFilter::Nearest => 0.5,
+            ResampleFilter::Bilinear => 1.0,
+            ResampleFilter::CatmullRom | ResampleFilter::Mitchell => 2.0,
+            ResampleFilter::Lanczos3 => 3.0,
+        }
+    }
+
+    fn weight(self, x: f32) -> f32 {
+        match self {
+            ResampleFilter::Nearest => (x.abs() < 0.5) as u8 as f32,
+            ResampleFilter::Bilinear => (1.0 - x.abs()).max(0.0),
+            ResampleFilter::CatmullRom => cubic(x, 0.0, 0.5),
+            ResampleFilter::Mitchell => cubic(x, 1.0 / 3.0, 1.0 / 3.0),
+            ResampleFilter::Lanczos3 => {
+                if x.abs() < 3.0 {
+                    sinc(x) * sinc(x / 3.0)
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+}
+
+// Mitchell-Netravali family of cubics
+fn cubic(x: f32, b: f32, c: f32) -> f32 {
+    let x = x.abs();
+    let (x2, x3) = (x * x, x * x * x);
+    if x < 1.0 {
+        ((12.0 - 9.0 * b - 6.0 * c) * x3 + (-18.0 + 12.0 * b + 6.0 * c) * x2 + (6.0 - 2.0 * b))
+            / 6.0
+    } else if x < 2.0 {
+        ((-b - 6.0 * c) * x3
+            + (6.0 * b + 30.0 * c) * x2
+            + (-12.0 * b - 48.0 * c) * x
+            + (8.0 * b + 24.0 * c))
+            / 6.0
+    } else {
+        0.0
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x == 0.0 {
+        1.0
+    } else {
+        let px = std::f32::consts::PI * x;
+        px.sin() / px
+    }
+}
+
+// Reductions beyond this many times the final filter's input are box-averaged first.
+// A 3x margin keeps the box filter's blockiness well under what the final filter smooths.
+const PRESHRINK_HEADROOM: usize = 3;
+
+// Source pixels and normalized weights behind one output pixel along an axis
+struct Taps {
+    start: usize,
+    weights: Vec<f32>,
+}
+
+fn axis_taps(src_len: usize, dst_len: usize, filter: ResampleFilter) -> Vec<Taps> {
+    let scale = src_len as f32 / dst_len as f32;
+    // Widen the kernel when shrinking so every source pixel contributes
+    let filter_scale = scale.max(1.0);
+    let support = filter.support() * filter_scale;
+
+    (0..dst_len)
+        .map(|i| {
+            let center = (i as f32 + 0.5) * scale;
+            if filter == ResampleFilter::Nearest {
+                return Taps {
+                    start: (center as usize).min(src_len - 1),
+                    weights: vec![1.0],
+                };
+            }
+
+            let start = (center - support).floor().max(0.0) as usize;
+            let end = ((center + support).ceil() as usize).clamp(start + 1, src_len);
+            let mut weights: Vec<f32> = (start..end)
+                .map(|j| filter.weight((j as f32 + 0.5 - center) / filter_scale))
+                .collect();
+            let sum: f32 = weights.iter().sum();
+            if sum != 0.0 {
+                weights.iter_mut().for_each(|w| *w /= sum);
+            }
+            Taps { start, weights }
+        })
+        .collect()
+}
+
+/// Divides colour back out of a premultiplied RGBA pixel; fully transparent pixels
+/// come out black.
+pub fn unpremultiply(px: [f32; 4]) -> [f32; 4] {
+    let alpha = px[3];
+    if alpha <= 0.0 {
+        return [0.0; 4];
+    }
+    [px[0] / alpha, px[1] / alpha, px[2] / alpha, alpha]
+}
+
+// A row of samples in [0, 1], premultiplied when there's alpha so transparent
+// pixels don't bleed their colour into their neighbours
+fn unit_row<T: Sample>(src: &[T], premultiply: bool, row: &mut [f32]) {
+    row.iter_mut().zip(src).for_each(|(d, &s)| *d = s.to_unit());
+    if premultiply {
+        for px in row.chunks_exact_mut(4) {
+            let alpha = px[3];
+            px[..3].iter_mut().for_each(|v| *v *= alpha);
+        }
+    }
+}
+
+// Output rows filtered per band at most. A band only holds the source rows its
+// taps reach, filtered to the output width, so scratch stays a few hundred rows
+// of the output however large the source is.
+const MAX_BAND_ROWS: usize = 64;
+
+// Per-worker buffers, reused from band to band
+struct Scratch {
+    line: Vec<f32>,
+    sums: Vec<f32>,
+    row: Vec<f32>,
+    band: Vec<f32>,
+    acc: Vec<f32>,
+}
+
+// Source row `y` after averaging fx x fy blocks into one pixel (1 x 1 leaves it
+// alone); partial blocks at the right and bottom edges average what they cover.
+fn shrunk_row<T: Sample>(
+    src: &[T],
+    (width, height, channels): (usize, usize, usize),
+    (fx, fy): (usize, usize),
+    y: usize,
+    scratch: &mut Scratch,
+) {
+    let stride = width * channels;
+    let premultiply = channels == 4;
+    if fx == 1 && fy == 1 {
+        unit_row(
+            &src[y * stride..(y + 1) * stride],
+            premultiply,
+            &mut scratch.row,
+        );
+        return;
+    }
+
+    scratch.sums.fill(0.0);
+    let rows = y * fy..((y + 1) * fy).min(height);
+    let row_count = rows.len();
+    for sy in rows {
+        unit_row(
+            &src[sy * stride..(sy + 1) * stride],
+            premultiply,
+            &mut scratch.line,
+        );
+        accumulate_row(&scratch.line, 1.0, &mut scratch.sums);
+    }
+
+    for (ox, px) in scratch.row.chunks_exact_mut(channels).enumerate() {
+        let cols = ox * fx..((ox + 1) * fx).min(width);
+        let inv = 1.0 / (cols.len() * row_count) as f32;
+        let mut acc = [0.0f32; 4];
+        for sum in scratch.sums[cols.start * channels..cols.end * channels].chunks_exact(channels) {
+            acc.iter_mut().zip(sum).for_each(|(a, s)| *a += s);
+        }
+        px.iter_mut().zip(acc).for_each(|(d, a)| *d = a * inv);
+    }
+}
+
+// Filters one row to `taps.len()` pixels wide
+fn horizontal_pass(row: &[f32], taps: &[Taps], channels: usize, out: &mut [f32]) {
+    for (px, tap) in out.chunks_exact_mut(channels).zip(taps) {
+        let mut acc = [0.0f32; 4];
+        for (k, &w) in tap.weights.iter().enumerate() {
+            let at = (tap.start + k) * channels;
+            for c in 0..channels {
+                acc[c] += row[at + c] * w;
+            }
+        }
+        px.copy_from_slice(&acc[..channels]);
+    }
+}
+
+// Writes a filtered row out, undoing the premultiplication
+fn store_row<O: Sample>(acc: &[f32], out_row: &mut [O], channels: usize) {
+    if channels == 4 {
+        for (d, px) in out_row.chunks_exact_mut(4).zip(acc.chunks_exact(4)) {
+            let px = unpremultiply([px[0], px[1], px[2], px[3]]);
+            d.iter_mut().zip(px).for_each(|(d, v)| *d = O::from_unit(v));
+        }
+    } else {
+        out_row
+            .iter_mut()
+            .zip(acc)
+            .for_each(|(d, &v)| *d = O::from_unit(v));
+    }
+}
+
+fn check_sizes(a_img: &AgnoImage, width: u32, height: u32) -> Result<(), Box<dyn Error>> {
+    if a_img.width == 0 || a_img.height == 0 || width == 0 || height == 0 {
+        return Err(format!(
+            "Cannot scale {}x{} to {}x{}",
+            a_img.width, a_img.height, width, height
+        )
+        .into());
+    }
+    Ok(())
+}
+
+// Scales `src` into `out`, a band of output rows at a time: each band pre-shrinks
+// and filters the source rows it needs across, then filters them down.
+fn resample_into<T: Sample, O: Sample>(
+    src: &[T],
+    (src_w, src_h, channels): (usize, usize, usize),
+    out: &mut [O],
+    (dst_w, dst_h): (usize, usize),
+    filter: ResampleFilter,
+) {
+    let preshrink = |src_len: usize, dst_len: usize| {
+        if filter == ResampleFilter::Nearest {
+            1
+        } else {
+            (src_len / (dst_len * PRESHRINK_HEADROOM)).max(1)
+        }
+    };
+    let (fx, fy) = (preshrink(src_w, dst_w), preshrink(src_h, dst_h));
+    let (w, h) = (src_w.div_ceil(fx), src_h.div_ceil(fy));
+    if fx > 1 || fy > 1 {
+        debug!(
+            "Box pre-shrink {}x{} by {}x{} to {}x{}",
+            src_w, src_h, fx, fy, w, h
+        );
+    }
+
+    let h_taps = axis_taps(w, dst_w, filter);
+    let v_taps = axis_taps(h, dst_h, filter);
+    let stride = dst_w * channels;
+    let band_rows = dst_h
+        .div_ceil(rayon::current_num_threads())
+        .clamp(1, MAX_BAND_ROWS);
+
+    out.par_chunks_mut(band_rows * stride)
+        .enumerate()
+        .for_each_init(
+            || Scratch {
+                line: vec![0.0; src_w * channels],
+                sums: vec![0.0; src_w * channels],
+                row: vec![0.0; w * channels],
+                band: Vec::new(),
+                acc: vec![0.0; stride],
+            },
+            |scratch, (band, out_band)| {
+                let taps = &v_taps[band * band_rows..][..out_band.len() / stride];
+                // Tap windows only move down the source as the output does
+                let first = taps[0].start;
+                let end = taps
+                    .iter()
+                    .map(|t| t.start + t.weights.len())
+                    .max()
+                    .unwrap_or(first);
+
+                let mut rows = std::mem::take(&mut scratch.band);
+                rows.resize((end - first) * stride, 0.0);
+                for (y, filtered) in (first..end).zip(rows.chunks_exact_mut(stride)) {
+                    shrunk_row(src, (src_w, src_h, channels), (fx, fy), y, scratch);
+                    horizontal_pass(&scratch.row, &h_taps, channels, filtered);
+                }
+
+                for (tap, out_row) in taps.iter().zip(out_band.chunks_exact_mut(stride)) {
+                    scratch.acc.fill(0.0);
+                    for (k, &weight) in tap.weights.iter().enumerate() {
+                        let y = tap.start + k - first;
+                        accumulate_row(
+                            &rows[y * stride..(y + 1) * stride],
+                            weight,
+                            &mut scratch.acc,
+                        );
+                    }
+                    store_row(&scratch.acc, out_row, channels);
+                }
+                scratch.band = rows;
+            },
+        );
+}
+
+/// Scales `a_img` to `width` x `height`, replacing its pixels. Large reductions are
+/// box-averaged down to a few times the target first, so a 60MP to thumbnail scale
+/// only runs the final filter over a small image, and rows are filtered a band at a
+/// time, so beyond the output only a little scratch is allocated. Alpha is
+/// resampled premultiplied.
+pub fn resample_image<T: Sample>(
+    a_img: &mut AgnoImage,
+    width: u32,
+    height: u32,
+    filter: ResampleFilter,
+) -> Result<(), Box<dyn Error>> {
+    check_sizes(a_img, width, height)?;
+    let (src_w, src_h) = (a_img.width as usize, a_img.height as usize);
+    let (dst_w, dst_h) = (width as usize, height as usize);
+    let channels = a_img.channels as usize;
+    let src = a_img.as_samples::<T>().unwrap_or_default();
+    let mut out = vec![T::from_unit(0.0); dst_w * dst_h * channels];
+    resample_into(
+        src,
+        (src_w, src_h, channels),
+        &mut out,
+        (dst_w, dst_h),
+        filter,
+    );
+
+    if !a_img.reshape(width as u64, height as u64) {
+        return Err(format!("Failed to allocate a {}x{} image", width, height).into());
+    }
+    a_img
+        .as_samples_mut::<T>()
+        .unwrap_or_default()
+        .copy_from_slice(&out);
+
+    Ok(())
+}
+
+/// Like `resample_image`, but leaves `a_img` alone and returns the scaled pixels as
+/// interleaved samples in [0, 1] (gamma encoded or linear, as the image is).
+pub fn resample_to_unit<T: Sample>(
+    a_img: &AgnoImage,
+    width: u32,
+    height: u32,
+    filter: ResampleFilter,
+) -> Result<Vec<f32>, Box<dyn Error>> {
+    check_sizes(a_img, width, height)?;
+    let (src_w, src_h) = (a_img.width as usize, a_img.height as usize);
+    let (dst_w, dst_h) = (width as usize, height as usize);
+    let channels = a_img.channels as usize;
+    let src = a_img.as_samples::<T>().unwrap_or_default();
+
+    let mut out = vec![0.0f32; dst_w * dst_h * channels];
+    resample_into(
+        src,
+        (src_w, src_h, channels),
+        &mut out,
+        (dst_w, dst_h),
+        filter,
+    );
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exif::ExifContext;
+
+    const FILTERS: [ResampleFilter; 5] = [
+        ResampleFilter::Nearest,
+        ResampleFilter::Bilinear,
+        ResampleFilter::CatmullRom,
+        ResampleFilter::Mitchell,
+        ResampleFilter::Lanczos3,
+    ];
+
+    #[test]
+    fn every_filter_keeps_a_flat_image_flat() {
+        let (w, h) = (120u64, 90u64);
+        // Big enough reductions to box pre-shrink, an enlargement, and a mix
+        let sizes = [(7, 5), (40, 300), (300, 200), (120, 90)];
+        for filter in FILTERS {
+            for (dw, dh) in sizes {
+                let mut img = AgnoImage::from_samples(
+                    &vec![51u8; (w * h * 3) as usize],
+                    w,
+                    h,
+                    ExifContext::new(),
+                );
+                let unit = resample_to_unit::<u8>(&img, dw, dh, filter).unwrap();
+                assert_eq!(unit.len(), (dw * dh * 3) as usize);
+                assert!(
+                    unit.iter().all(|v| (v - 0.2).abs() < 1e-5),
+                    "{:?} to {}x{}",
+                    filter,
+                    dw,
+                    dh
+                );
+
+                resample_image::<u8>(&mut img, dw, dh, filter).unwrap();
+                assert_eq!((img.width, img.height), (dw as u64, dh as u64));
+                let samples = img.as_samples::<u8>().unwrap();
+                assert_eq!(samples.len(), (dw * dh * 3) as usize);
+                assert!(
+                    samples.iter().all(|&v| v == 51),
+                    "{:?} to {}x{}",
+                    filter,
+                    dw,
+                    dh
+                );
+                AgnoImage::free(&img);
+            }
+        }
+    }
+
+    #[test]
+    fn nearest_halving_picks_every_other_pixel_across_bands() {
+        // Tall enough that the output spans several bands
+        let (w, h) = (6usize, 400usize);
+        let src: Vec<u16> = (0..h)
+            .flat_map(|y| (0..w).flat_map(move |x| [(y * 100 + x) as u16; 3]))
+            .collect();
+        let mut img = AgnoImage::from_samples(&src, w as u64, h as u64, ExifContext::new());
+        resample_image::<u16>(&mut img, 3, 200, ResampleFilter::Nearest).unwrap();
+
+        let out = img.as_samples::<u16>().unwrap();
+        for y in 0..200 {
+            for x in 0..3 {
+                assert_eq!(out[(y * 3 + x) * 3], src[((2 * y + 1) * w + 2 * x + 1) * 3]);
+            }
+        }
+        AgnoImage::free(&img);
+    }
+
+    #[test]
+    fn transparent_pixels_keep_their_colour_out_of_the_result() {
+        // Opaque red columns between fully transparent green ones
+        let (w, h) = (12u64, 8u64);
+        let src: Vec<u8> = (0..w * h)
+            .flat_map(|i| match i % w % 2 {
+                0 => [255, 0, 0, 255],
+                _ => [0, 255, 0, 0],
+            })
+            .collect();
+        // A box pre-shrink, plain reductions, and enlargements
+        let sizes = [(2, 2), (6, 4), (5, 3), (30, 16)];
+        for filter in FILTERS {
+            for (dw, dh) in sizes {
+                let mut img = AgnoImage::from_channels(&src, w, h, 4, ExifContext::new());
+                resample_image::<u8>(&mut img, dw, dh, filter).unwrap();
+                for px in img.as_samples::<u8>().unwrap().chunks_exact(4) {
+                    let expected = if px[3] == 0 { [0, 0, 0] } else { [255, 0, 0] };
+                    assert_eq!(px[..3], expected, "{:?} to {}x{}: {:?}", filter, dw, dh, px);
+                }
+                AgnoImage::free(&img);
+            }
+        }
+
+        // Filtering thins the alpha rather than the colour
+        let img = AgnoImage::from_channels(&src, w, h, 4, ExifContext::new());
+        let unit = resample_to_unit::<u8>(&img, 6, 4, ResampleFilter::Bilinear).unwrap();
+        for px in unit.chunks_exact(4) {
+            assert!((px[0] - 1.0).abs() < 1e-5 && px[1] == 0.0, "{:?}", px);
+            assert!(px[3] > 0.3 && px[3] < 0.7, "{:?}", px);
+        }
+        AgnoImage::free(&img);
+    }
+}
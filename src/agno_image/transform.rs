@@ -1,92 +1,1043 @@
-use std::error::Error;
+use std::{error::Error, f32::consts::PI};
 
-use image::{RgbImage, imageops};
 use log::debug;
+use rayon::{
+    iter::{IndexedParallelIterator, ParallelIterator},
+    slice::{ParallelSlice, ParallelSliceMut},
+};
 
 use crate::{
-    agno_image::AgnoImage,
+    agno_image::{
+        AgnoImage, PixelFormat, ResampleFilter, Sample, resample_image, resample_to_unit,
+        unpremultiply,
+    },
     exif::{ExifContext, ExifValue, spec::ORIENTATION},
-    sony_decoder::{DecodeError, Dimensions},
+    tone::TransferFn,
 };
 
 pub fn scale_image(
-    a_img: AgnoImage,
+    mut a_img: AgnoImage,
     new_width: u32,
     new_height: u32,
+    filter: ResampleFilter,
 ) -> Result<AgnoImage, Box<dyn Error>> {
     debug!(
-        "Scaling image from {}x{} to {}x{}",
-        a_img.width, a_img.height, new_width, new_height
+        "Scaling {:?} image from {}x{} to {}x{} with {:?}",
+        a_img.format, a_img.width, a_img.height, new_width, new_height, filter
     );
 
-    let rgb = RgbImage::from_raw(
-        a_img.width as u32,
-        a_img.height as u32,
-        a_img.as_slice().to_vec(),
-    )
-    .ok_or(DecodeError::CorruptData(
-        "Failed to create image from RGB data",
-    ))?;
-
-    let resized_img = image::imageops::resize(
-        &rgb,
-        new_width,
-        new_height,
-        image::imageops::FilterType::Lanczos3,
+    let resampled = match a_img.format {
+        PixelFormat::U8 => resample_image::<u8>(&mut a_img, new_width, new_height, filter),
+        PixelFormat::U16 => resample_image::<u16>(&mut a_img, new_width, new_height, filter),
+        PixelFormat::F32 => resample_image::<f32>(&mut a_img, new_width, new_height, filter),
+    };
+
+    match resampled {
+        Ok(()) => Ok(a_img),
+        Err(e) => {
+            AgnoImage::free(&a_img);
+            Err(e)
+        }
+    }
+}
+
+// A new image holding `rgb`, with the channels, metadata and profiles of `a_img`
+fn with_pixels<T: Sample>(a_img: &AgnoImage, rgb: &[T], width: usize, height: usize) -> AgnoImage {
+    let mut out = AgnoImage::from_channels(
+        rgb,
+        width as u64,
+        height as u64,
+        a_img.channels,
+        a_img.exif.clone(),
     );
+    out.icc_profile = a_img.icc_profile.clone();
+    out.color_profile = a_img.color_profile.clone();
+    out
+}
+
+/// EXIF orientation (1-8) of an image, 1 when the tag is missing or invalid.
+pub fn exif_orientation(ctx: &ExifContext) -> u16 {
+    match ctx.get_tag_value(ORIENTATION) {
+        Some(ExifValue::Short(v)) if (1..=8).contains(&v.first().copied().unwrap_or(0)) => v[0],
+        _ => 1,
+    }
+}
+
+/// Turns interleaved pixels of `channels` samples stored with EXIF `orientation` upright, returning the
+/// pixels and their new width and height. 2 and 4 mirror, 3, 6 and 8 rotate by
+/// 180, 90 and 270 degrees clockwise, and 5 and 7 flip across a diagonal.
+pub fn orient_samples<T: Sample>(
+    rgb: &[T],
+    width: usize,
+    height: usize,
+    channels: usize,
+    orientation: u16,
+) -> (Vec<T>, usize, usize) {
+    let (w, h) = (width, height);
+    let (out_w, out_h) = if orientation >= 5 { (h, w) } else { (w, h) };
+    // Source pixel for each output position
+    let source = |ox: usize, oy: usize| -> (usize, usize) {
+        match orientation {
+            2 => (w - 1 - ox, oy),
+            3 => (w - 1 - ox, h - 1 - oy),
+            4 => (ox, h - 1 - oy),
+            5 => (oy, ox),
+            6 => (oy, h - 1 - ox),
+            7 => (w - 1 - oy, h - 1 - ox),
+            8 => (w - 1 - oy, ox),
+            _ => (ox, oy),
+        }
+    };
+
+    if orientation <= 1 || orientation > 8 || w == 0 || h == 0 {
+        return (rgb.to_vec(), w, h);
+    }
+
+    let mut out = vec![T::from_unit(0.0); w * h * channels];
+    out.par_chunks_mut(out_w * channels)
+        .enumerate()
+        .for_each(|(oy, line)| {
+            for (ox, px) in line.chunks_exact_mut(channels).enumerate() {
+                let (ix, iy) = source(ox, oy);
+                let at = (iy * w + ix) * channels;
+                px.copy_from_slice(&rgb[at..at + channels]);
+            }
+        });
+    (out, out_w, out_h)
+}
+
+fn orient_image<T: Sample>(a_img: &AgnoImage, orientation: u16) -> AgnoImage {
+    let (rgb, width, height) = orient_samples(
+        a_img.as_samples::<T>().unwrap_or_default(),
+        a_img.width as usize,
+        a_img.height as usize,
+        a_img.channels as usize,
+        orientation,
+    );
+    with_pixels(a_img, &rgb, width, height)
+}
+
+// Orientation-style remap of any format, consuming the image
+fn remap(a_img: AgnoImage, orientation: u16) -> AgnoImage {
+    let out = match a_img.format {
+        PixelFormat::U8 => orient_image::<u8>(&a_img, orientation),
+        PixelFormat::U16 => orient_image::<u16>(&a_img, orientation),
+        PixelFormat::F32 => orient_image::<f32>(&a_img, orientation),
+    };
+    AgnoImage::free(&a_img);
+    out
+}
+
+/// Applies the image's EXIF orientation to its pixels and resets the tag to 1,
+/// so nothing downstream rotates it again.
+pub fn auto_orient(a_img: AgnoImage) -> AgnoImage {
+    let orientation = exif_orientation(&a_img.exif);
+    if orientation == 1 {
+        return a_img;
+    }
+    debug!("Applying EXIF orientation {}", orientation);
+
+    let mut oriented = remap(a_img, orientation);
+    oriented
+        .exif
+        .set_tag_value(ORIENTATION, ExifValue::Short(vec![1]));
+    oriented
+}
 
-    let exif = a_img.exif.clone();
+/// Which way `flip_image` mirrors.
+#[repr(C)]
+#[allow(dead_code)] // variants are constructed from C
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FlipAxis {
+    /// Left and right swap
+    #[default]
+    Horizontal = 0,
+    /// Top and bottom swap
+    Vertical = 1,
+}
+
+/// What `rotate_image_by` does with the corners a rotation uncovers.
+#[repr(C)]
+#[allow(dead_code)] // variants are constructed from C
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RotateMode {
+    /// Grow the canvas to hold the whole rotated image and fill the corners
+    #[default]
+    Fill = 0,
+    /// Keep the largest centred rectangle of the original aspect ratio that has no corners
+    AutoCrop = 1,
+}
+
+fn crop_samples<T: Sample>(
+    a_img: &AgnoImage,
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+) -> AgnoImage {
+    let src = a_img.as_samples::<T>().unwrap_or_default();
+    let channels = a_img.channels as usize;
+    let stride = a_img.width as usize * channels;
+    let rgb: Vec<T> = src
+        .chunks_exact(stride)
+        .skip(y)
+        .take(height)
+        .flat_map(|line| &line[x * channels..(x + width) * channels])
+        .copied()
+        .collect();
+    with_pixels(a_img, &rgb, width, height)
+}
 
+/// Cuts out the `width` x `height` rectangle whose top-left corner is at (x, y).
+/// The rectangle must lie inside the image. Consumes `a_img` either way.
+pub fn crop_image(
+    a_img: AgnoImage,
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+) -> Result<AgnoImage, Box<dyn Error>> {
+    let (img_w, img_h) = (a_img.width as usize, a_img.height as usize);
+    if width == 0 || height == 0 || x + width > img_w || y + height > img_h {
+        AgnoImage::free(&a_img);
+        return Err(format!(
+            "Crop {}x{} at ({}, {}) is outside the {}x{} image",
+            width, height, x, y, img_w, img_h
+        )
+        .into());
+    }
+
+    let cropped = match a_img.format {
+        PixelFormat::U8 => crop_samples::<u8>(&a_img, x, y, width, height),
+        PixelFormat::U16 => crop_samples::<u16>(&a_img, x, y, width, height),
+        PixelFormat::F32 => crop_samples::<f32>(&a_img, x, y, width, height),
+    };
     AgnoImage::free(&a_img);
 
-    Ok(AgnoImage::new(
-        resized_img.into_raw(),
-        new_width as u64,
-        new_height as u64,
-        exif,
-    ))
-}
-
-pub fn auto_rotate_image(
-    ctx: &mut ExifContext,
-    rgb: &[u8],
-    dims: &mut Dimensions,
-) -> Result<Vec<u8>, Box<dyn Error>> {
-    let rot = ctx.get_tag_value(ORIENTATION);
-    let img = match rot {
-        Some(ExifValue::Short(v)) if !v.is_empty() => {
-            let exif_orient = v[0] as u8;
-            match exif_orient {
-                1 | 2 | 3 | 4 | 5 | 7 => rgb, // No rotation
-                6 => {
-                    // 90-degree rotation
-                    (dims.output_width, dims.output_height) =
-                        (dims.output_height, dims.output_width);
-
-                    rgb
+    Ok(cropped)
+}
+
+/// Rotates clockwise by a multiple of 90 degrees. Consumes `a_img` either way.
+pub fn rotate_image(a_img: AgnoImage, degrees: i32) -> Result<AgnoImage, Box<dyn Error>> {
+    // Same pixel moves as the EXIF orientations that undo these rotations
+    let orientation = match degrees.rem_euclid(360) {
+        0 => return Ok(a_img),
+        90 => 6,
+        180 => 3,
+        270 => 8,
+        _ => {
+            AgnoImage::free(&a_img);
+            return Err(format!("Not a quarter turn: {} degrees", degrees).into());
+        }
+    };
+    Ok(remap(a_img, orientation))
+}
+
+/// Mirrors the image along `axis`. Consumes `a_img`.
+pub fn flip_image(a_img: AgnoImage, axis: FlipAxis) -> AgnoImage {
+    match axis {
+        FlipAxis::Horizontal => remap(a_img, 2),
+        FlipAxis::Vertical => remap(a_img, 4),
+    }
+}
+
+fn rotate_samples<T: Sample>(
+    a_img: &AgnoImage,
+    radians: f32,
+    mode: RotateMode,
+    background: [f32; 3],
+) -> AgnoImage {
+    let src = a_img.as_samples::<T>().unwrap_or_default();
+    let (w, h) = (a_img.width as usize, a_img.height as usize);
+    let (sin, cos) = radians.sin_cos();
+    let (abs_sin, abs_cos) = (sin.abs(), cos.abs());
+    let (wf, hf) = (w as f32, h as f32);
+
+    let (out_w, out_h) = match mode {
+        RotateMode::Fill => (
+            (wf * abs_cos + hf * abs_sin).round().max(1.0) as usize,
+            (wf * abs_sin + hf * abs_cos).round().max(1.0) as usize,
+        ),
+        // The crop's rotated bounding box has to fit inside the original frame
+        RotateMode::AutoCrop => {
+            let scale =
+                (wf / (wf * abs_cos + hf * abs_sin)).min(hf / (wf * abs_sin + hf * abs_cos));
+            (
+                (wf * scale).floor().max(1.0) as usize,
+                (hf * scale).floor().max(1.0) as usize,
+            )
+        }
+    };
+
+    // Colour is interpolated premultiplied so transparent pixels don't bleed into edges
+    let channels = a_img.channels as usize;
+    let at = |x: usize, y: usize, c: usize| {
+        let px = &src[(y * w + x) * channels..][..channels];
+        if channels == 4 && c < 3 {
+            px[c].to_unit() * px[3].to_unit()
+        } else {
+            px[c].to_unit()
+        }
+    };
+    // With alpha the uncovered corners are transparent instead; grey images take
+    // the background's luma
+    let fill = match channels {
+        1 => {
+            let [r, g, b] = background;
+            [0.299 * r + 0.587 * g + 0.114 * b, 0.0, 0.0, 0.0]
+        }
+        _ => [background[0], background[1], background[2], 0.0],
+    }
+    .map(T::from_unit);
+    let mut out = vec![T::from_unit(0.0); out_w * out_h * channels];
+    out.par_chunks_mut(out_w * channels)
+        .enumerate()
+        .for_each(|(oy, line)| {
+            let dy = oy as f32 + 0.5 - out_h as f32 * 0.5;
+            for (ox, px) in line.chunks_exact_mut(channels).enumerate() {
+                let dx = ox as f32 + 0.5 - out_w as f32 * 0.5;
+                // Back through the rotation to the source pixel centre
+                let sx = dx * cos + dy * sin + wf * 0.5 - 0.5;
+                let sy = -dx * sin + dy * cos + hf * 0.5 - 0.5;
+                if sx < -0.5 || sy < -0.5 || sx > wf - 0.5 || sy > hf - 0.5 {
+                    px.copy_from_slice(&fill[..channels]);
+                    continue;
+                }
+
+                let (x, y) = (sx.clamp(0.0, wf - 1.0), sy.clamp(0.0, hf - 1.0));
+                let (x0, y0) = (x as usize, y as usize);
+                let (x1, y1) = ((x0 + 1).min(w - 1), (y0 + 1).min(h - 1));
+                let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+                let mut v = [0.0f32; 4];
+                for (c, v) in v[..channels].iter_mut().enumerate() {
+                    let top = at(x0, y0, c) + (at(x1, y0, c) - at(x0, y0, c)) * fx;
+                    let bottom = at(x0, y1, c) + (at(x1, y1, c) - at(x0, y1, c)) * fx;
+                    *v = top + (bottom - top) * fy;
                 }
-                8 => {
-                    let img = RgbImage::from_raw(
-                        dims.output_width as u32,
-                        dims.raw_height as u32,
-                        rgb.to_vec(),
-                    )
-                    .ok_or(DecodeError::CorruptData(
-                        "Failed to create image from RGB data",
-                    ))
-                    .unwrap();
-
-                    // 270-degree rotation
-                    (dims.output_width, dims.output_height) =
-                        (dims.output_height, dims.output_width);
-
-                    &imageops::rotate270(&img)
+                if channels == 4 {
+                    v = unpremultiply(v);
                 }
-                _ => rgb,
+                px.iter_mut().zip(v).for_each(|(d, v)| *d = T::from_unit(v));
             }
+        });
+
+    with_pixels(a_img, &out, out_w, out_h)
+}
+
+/// Rotates clockwise by any angle with bilinear resampling. Quarter turns take the
+/// lossless path of `rotate_image`.
+/// - background: RGB in [0, 1] (linear for float images) for the corners `RotateMode::Fill`
+///   uncovers; RGBA images leave them transparent
+pub fn rotate_image_by(
+    a_img: AgnoImage,
+    degrees: f32,
+    mode: RotateMode,
+    background: [f32; 3],
+) -> Result<AgnoImage, Box<dyn Error>> {
+    if !degrees.is_finite() {
+        AgnoImage::free(&a_img);
+        return Err(format!("Invalid rotation angle: {}", degrees).into());
+    }
+    if degrees.rem_euclid(90.0) == 0.0 {
+        return rotate_image(a_img, degrees as i32);
+    }
+    if a_img.width == 0 || a_img.height == 0 {
+        return Ok(a_img);
+    }
+
+    let radians = degrees.to_radians();
+    let rotated = match a_img.format {
+        PixelFormat::U8 => rotate_samples::<u8>(&a_img, radians, mode, background),
+        PixelFormat::U16 => rotate_samples::<u16>(&a_img, radians, mode, background),
+        PixelFormat::F32 => rotate_samples::<f32>(&a_img, radians, mode, background),
+    };
+    AgnoImage::free(&a_img);
+
+    Ok(rotated)
+}
+
+/// How `resize_with_spec` fits an image to the requested size.
+#[repr(C)]
+#[allow(dead_code)] // variants are constructed from C
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ResizeMode {
+    /// Exactly `width` x `height`, stretching if the aspect ratio differs
+    Exact = 0,
+    /// As large as fits inside `width` x `height`, keeping the aspect ratio
+    #[default]
+    Fit = 1,
+    /// Covers `width` x `height` keeping the aspect ratio, cropping the overflow by `gravity`
+    Cover = 2,
+    /// Longest edge becomes `width`
+    LongestEdge = 3,
+    /// Shortest edge becomes `width`
+    ShortestEdge = 4,
+}
+
+/// Which part of the image `ResizeMode::Cover` keeps.
+#[repr(C)]
+#[allow(dead_code)] // variants are constructed from C
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Gravity {
+    #[default]
+    Center = 0,
+    /// Centred horizontally, anchored to the top edge
+    Top = 1,
+    /// The window with the busiest luma histogram
+    Entropy = 2,
+}
+
+/// Target size for `resize_with_spec`. Mirrored as `struct ResizeSpec` in agno.h.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ResizeSpec {
+    pub mode: ResizeMode,
+    /// Box width, or the edge length for the edge modes
+    pub width: u32,
+    /// Box height, unused by the edge modes
+    pub height: u32,
+    pub gravity: Gravity,
+    /// Leave images already smaller than the target at their size. `Exact`
+    /// scales the box down until it fits inside the image instead.
+    pub never_upscale: bool,
+    pub filter: ResampleFilter,
+}
+
+// Candidate crop positions tried per axis for `Gravity::Entropy`
+const ENTROPY_CANDIDATES: usize = 24;
+
+// Samples per window the entropy search looks at, spread evenly
+const ENTROPY_SAMPLES: usize = 1 << 16;
+
+// Shannon entropy of the 8-bit luma histogram over a window
+fn window_entropy<T: Sample>(
+    src: &[T],
+    stride: usize,
+    channels: usize,
+    (x, y, w, h): (usize, usize, usize, usize),
+) -> f32 {
+    let step = ((w * h / ENTROPY_SAMPLES) as f32).sqrt().max(1.0) as usize;
+    let mut hist = [0u32; 256];
+    let mut count = 0u32;
+    for row in (y..y + h).step_by(step) {
+        for col in (x..x + w).step_by(step) {
+            let px = &src[(row * stride + col) * channels..][..channels];
+            let luma = match channels {
+                1 => px[0].to_unit(),
+                _ => 0.299 * px[0].to_unit() + 0.587 * px[1].to_unit() + 0.114 * px[2].to_unit(),
+            };
+            hist[(luma.clamp(0.0, 1.0) * 255.0) as usize] += 1;
+            count += 1;
         }
-        _ => rgb,
+    }
+    hist.iter()
+        .filter(|&&n| n > 0)
+        .map(|&n| {
+            let p = n as f32 / count as f32;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+fn entropy_offset<T: Sample>(a_img: &AgnoImage, crop_w: usize, crop_h: usize) -> (usize, usize) {
+    let src = a_img.as_samples::<T>().unwrap_or_default();
+    let (w, h) = (a_img.width as usize, a_img.height as usize);
+    let channels = a_img.channels as usize;
+    let (slack_x, slack_y) = (w - crop_w, h - crop_h);
+
+    // Cover leaves slack along one axis; the other stays centred
+    let candidates = |slack: usize| {
+        let n = ENTROPY_CANDIDATES.min(slack + 1);
+        (0..n).map(move |i| {
+            if n == 1 {
+                slack / 2
+            } else {
+                i * slack / (n - 1)
+            }
+        })
+    };
+    let windows: Vec<(usize, usize)> = if slack_x >= slack_y {
+        candidates(slack_x).map(|x| (x, slack_y / 2)).collect()
+    } else {
+        candidates(slack_y).map(|y| (slack_x / 2, y)).collect()
     };
 
-    Ok(img.to_vec())
+    windows
+        .into_iter()
+        .map(|(x, y)| {
+            let entropy = window_entropy(src, w, channels, (x, y, crop_w, crop_h));
+            ((x, y), entropy)
+        })
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .map_or((slack_x / 2, slack_y / 2), |(pos, _)| pos)
+}
+
+// Top-left of a crop_w x crop_h window placed by `gravity`
+fn gravity_offset(
+    a_img: &AgnoImage,
+    crop_w: usize,
+    crop_h: usize,
+    gravity: Gravity,
+) -> (usize, usize) {
+    let (slack_x, slack_y) = (
+        a_img.width as usize - crop_w,
+        a_img.height as usize - crop_h,
+    );
+    match gravity {
+        Gravity::Center => (slack_x / 2, slack_y / 2),
+        Gravity::Top => (slack_x / 2, 0),
+        Gravity::Entropy => match a_img.format {
+            PixelFormat::U8 => entropy_offset::<u8>(a_img, crop_w, crop_h),
+            PixelFormat::U16 => entropy_offset::<u16>(a_img, crop_w, crop_h),
+            PixelFormat::F32 => entropy_offset::<f32>(a_img, crop_w, crop_h),
+        },
+    }
+}
+
+/// Resizes by a `ResizeSpec`, working out the aspect ratio maths. Consumes `a_img`
+/// either way.
+pub fn resize_with_spec(a_img: AgnoImage, spec: &ResizeSpec) -> Result<AgnoImage, Box<dyn Error>> {
+    let edge_mode = matches!(
+        spec.mode,
+        ResizeMode::LongestEdge | ResizeMode::ShortestEdge
+    );
+    if spec.width == 0 || (spec.height == 0 && !edge_mode) || a_img.width == 0 || a_img.height == 0
+    {
+        AgnoImage::free(&a_img);
+        return Err(format!("Invalid resize target {:?}", spec).into());
+    }
+
+    let (w, h) = (a_img.width as f64, a_img.height as f64);
+    let (box_w, box_h) = (spec.width as f64, spec.height as f64);
+    let limit = |scale: f64| {
+        if spec.never_upscale {
+            scale.min(1.0)
+        } else {
+            scale
+        }
+    };
+    let size = |scale: f64| {
+        (
+            (w * scale).round().max(1.0) as u32,
+            (h * scale).round().max(1.0) as u32,
+        )
+    };
+
+    let (target_w, target_h) = match spec.mode {
+        ResizeMode::Exact if spec.never_upscale => {
+            // Shrink the box as a whole, so the output keeps the requested aspect ratio
+            let scale = limit((w / box_w).min(h / box_h));
+            (
+                (box_w * scale).round().max(1.0) as u32,
+                (box_h * scale).round().max(1.0) as u32,
+            )
+        }
+        ResizeMode::Exact => (spec.width, spec.height),
+        ResizeMode::Fit => size(limit((box_w / w).min(box_h / h))),
+        ResizeMode::LongestEdge => size(limit(box_w / w.max(h))),
+        ResizeMode::ShortestEdge => size(limit(box_w / w.min(h))),
+        ResizeMode::Cover => {
+            let scale = limit((box_w / w).max(box_h / h));
+            let (out_w, out_h) = (box_w.min(w * scale), box_h.min(h * scale));
+
+            // Crop the source to the output's aspect ratio first, then scale that
+            let crop_w = ((out_w / scale).round() as usize).clamp(1, a_img.width as usize);
+            let crop_h = ((out_h / scale).round() as usize).clamp(1, a_img.height as usize);
+            let (x, y) = gravity_offset(&a_img, crop_w, crop_h, spec.gravity);
+            debug!(
+                "Cover crop {}x{} at ({}, {}) with {:?} gravity",
+                crop_w, crop_h, x, y, spec.gravity
+            );
+            let cropped = if (crop_w as u64, crop_h as u64) == (a_img.width, a_img.height) {
+                a_img
+            } else {
+                crop_image(a_img, x, y, crop_w, crop_h)?
+            };
+            return resize_if_needed(
+                cropped,
+                out_w.round() as u32,
+                out_h.round() as u32,
+                spec.filter,
+            );
+        }
+    };
+
+    resize_if_needed(a_img, target_w.max(1), target_h.max(1), spec.filter)
+}
+
+fn resize_if_needed(
+    a_img: AgnoImage,
+    width: u32,
+    height: u32,
+    filter: ResampleFilter,
+) -> Result<AgnoImage, Box<dyn Error>> {
+    if (width as u64, height as u64) == (a_img.width, a_img.height) {
+        return Ok(a_img);
+    }
+    scale_image(a_img, width, height, filter)
+}
+
+fn flatten_samples<T: Sample>(a_img: &AgnoImage, background: [f32; 3]) -> AgnoImage {
+    let src = a_img.as_samples::<T>().unwrap_or_default();
+    let mut rgb = vec![T::from_unit(0.0); src.len() / 4 * 3];
+    rgb.par_chunks_mut(3)
+        .zip(src.par_chunks(4))
+        .for_each(|(out, px)| {
+            let alpha = px[3].to_unit().clamp(0.0, 1.0);
+            for c in 0..3 {
+                out[c] = T::from_unit(px[c].to_unit() * alpha + background[c] * (1.0 - alpha));
+            }
+        });
+
+    let mut out = AgnoImage::from_samples(&rgb, a_img.width, a_img.height, a_img.exif.clone());
+    out.icc_profile = a_img.icc_profile.clone();
+    out.color_profile = a_img.color_profile.clone();
+    out
+}
+
+/// Composites an RGBA image over a solid background, leaving RGB. Images without
+/// alpha are returned as they are. Consumes `a_img`.
+/// - background: RGB in [0, 1] (linear for float images)
+pub fn flatten_alpha(a_img: AgnoImage, background: [f32; 3]) -> AgnoImage {
+    if !a_img.has_alpha() {
+        return a_img;
+    }
+
+    let flat = match a_img.format {
+        PixelFormat::U8 => flatten_samples::<u8>(&a_img, background),
+        PixelFormat::U16 => flatten_samples::<u16>(&a_img, background),
+        PixelFormat::F32 => flatten_samples::<f32>(&a_img, background),
+    };
+    AgnoImage::free(&a_img);
+    flat
+}
+
+// Longest side of the downscale placeholders are computed from; ThumbHash takes at
+// most 100 pixels a side
+const PLACEHOLDER_SIZE: u32 = 100;
+
+// Gamma-encoded RGBA pixels in [0, 1] with their width and height
+type SmallRgba = (Vec<[f32; 4]>, usize, usize);
+
+// The image fitted inside PLACEHOLDER_SIZE, opaque when it has no alpha
+fn placeholder_pixels(a_img: &AgnoImage) -> Result<SmallRgba, Box<dyn Error>> {
+    let scale = (PLACEHOLDER_SIZE as f64 / a_img.width.max(a_img.height) as f64).min(1.0);
+    let w = ((a_img.width as f64 * scale).round() as u32).max(1);
+    let h = ((a_img.height as f64 * scale).round() as u32).max(1);
+    let filter = ResampleFilter::Bilinear;
+    let small = match a_img.format {
+        PixelFormat::U8 => resample_to_unit::<u8>(a_img, w, h, filter)?,
+        PixelFormat::U16 => resample_to_unit::<u16>(a_img, w, h, filter)?,
+        PixelFormat::F32 => resample_to_unit::<f32>(a_img, w, h, filter)?,
+    };
+
+    let srgb = TransferFn::srgb();
+    let encode = |v: f32| {
+        let v = v.clamp(0.0, 1.0);
+        if a_img.format.is_linear() {
+            srgb.encode(v)
+        } else {
+            v
+        }
+    };
+    let pixels = small
+        .chunks_exact(a_img.channels as usize)
+        .map(|px| match *px {
+            [l] => [encode(l), encode(l), encode(l), 1.0],
+            [r, g, b, a] => [encode(r), encode(g), encode(b), a.clamp(0.0, 1.0)],
+            _ => [encode(px[0]), encode(px[1]), encode(px[2]), 1.0],
+        })
+        .collect();
+    Ok((pixels, w as usize, h as usize))
+}
+
+const BASE83: &[u8; 83] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+fn push_base83(hash: &mut String, value: u32, digits: u32) {
+    for i in (0..digits).rev() {
+        hash.push(BASE83[(value / 83u32.pow(i) % 83) as usize] as char);
+    }
+}
+
+/// BlurHash of the image with `x_components` x `y_components` (1-9 each) cosine
+/// terms, computed from a small downscale. BlurHash has no alpha, so transparent
+/// areas show their colour; flatten first to choose what shows through.
+pub fn blurhash(
+    a_img: &AgnoImage,
+    x_components: u32,
+    y_components: u32,
+) -> Result<String, Box<dyn Error>> {
+    if !(1..=9).contains(&x_components) || !(1..=9).contains(&y_components) {
+        return Err(format!(
+            "BlurHash takes 1 to 9 components per axis, not {}x{}",
+            x_components, y_components
+        )
+        .into());
+    }
+    let (pixels, w, h) = placeholder_pixels(a_img)?;
+    let srgb = TransferFn::srgb();
+    let linear: Vec<[f32; 3]> = pixels
+        .iter()
+        .map(|px| [px[0], px[1], px[2]].map(|v| srgb.decode(v)))
+        .collect();
+
+    let factors: Vec<[f32; 3]> = (0..y_components)
+        .flat_map(|j| (0..x_components).map(move |i| (i, j)))
+        .map(|(i, j)| {
+            let norm = if i == 0 && j == 0 { 1.0 } else { 2.0 };
+            let mut sum = [0.0f32; 3];
+            for (y, row) in linear.chunks_exact(w).enumerate() {
+                let fy = (PI * j as f32 * y as f32 / h as f32).cos();
+                for (x, px) in row.iter().enumerate() {
+                    let basis = norm * fy * (PI * i as f32 * x as f32 / w as f32).cos();
+                    (0..3).for_each(|c| sum[c] += px[c] * basis);
+                }
+            }
+            sum.map(|v| v / (w * h) as f32)
+        })
+        .collect();
+
+    let mut hash = String::new();
+    push_base83(&mut hash, (x_components - 1) + (y_components - 1) * 9, 1);
+
+    // AC terms are quantised against their largest magnitude, which leads the hash
+    let (dc, ac) = (factors[0], &factors[1..]);
+    let (quantised_max, max_value) = if ac.is_empty() {
+        (0, 1.0)
+    } else {
+        let actual = ac.iter().flatten().fold(0.0f32, |m, v| m.max(v.abs()));
+        let quantised = (actual * 166.0 - 0.5).floor().clamp(0.0, 82.0) as u32;
+        (quantised, (quantised + 1) as f32 / 166.0)
+    };
+    push_base83(&mut hash, quantised_max, 1);
+
+    let [r, g, b] = dc.map(|v| (srgb.encode(v.clamp(0.0, 1.0)) * 255.0 + 0.5) as u32);
+    push_base83(&mut hash, (r << 16) | (g << 8) | b, 4);
+    for f in ac {
+        let [r, g, b] = f.map(|v| {
+            let v = v / max_value;
+            (v.signum() * v.abs().sqrt() * 9.0 + 9.5)
+                .floor()
+                .clamp(0.0, 18.0) as u32
+        });
+        push_base83(&mut hash, r * 19 * 19 + g * 19 + b, 2);
+    }
+    Ok(hash)
+}
+
+// DCT of one ThumbHash channel: the DC term, the AC terms normalized to [0, 1] and
+// their scale. Only the triangle of low frequencies with cx/nx + cy/ny < 1 is kept.
+fn thumbhash_channel(
+    channel: &[f64],
+    (w, h): (usize, usize),
+    nx: usize,
+    ny: usize,
+) -> (f64, Vec<f64>, f64) {
+    use std::f64::consts::PI;
+
+    let (mut dc, mut ac, mut scale) = (0.0, Vec::new(), 0.0f64);
+    let mut fx = vec![0.0f64; w];
+    for cy in 0..ny {
+        let mut cx = 0;
+        while cx * ny < nx * (ny - cy) {
+            fx.iter_mut().enumerate().for_each(|(x, f)| {
+                *f = (PI / w as f64 * cx as f64 * (x as f64 + 0.5)).cos();
+            });
+            let mut f = 0.0;
+            for (y, row) in channel.chunks_exact(w).enumerate() {
+                let fy = (PI / h as f64 * cy as f64 * (y as f64 + 0.5)).cos();
+                f += row.iter().zip(&fx).map(|(v, fx)| v * fx * fy).sum::<f64>();
+            }
+            f /= (w * h) as f64;
+            if cx > 0 || cy > 0 {
+                ac.push(f);
+                scale = scale.max(f.abs());
+            } else {
+                dc = f;
+            }
+            cx += 1;
+        }
+    }
+    if scale > 0.0 {
+        ac.iter_mut().for_each(|f| *f = 0.5 + 0.5 / scale * *f);
+    }
+    (dc, ac, scale)
+}
+
+/// ThumbHash of the image, computed from a small downscale. Unlike BlurHash it keeps
+/// the aspect ratio and alpha.
+pub fn thumbhash(a_img: &AgnoImage) -> Result<Vec<u8>, Box<dyn Error>> {
+    let (pixels, w, h) = placeholder_pixels(a_img)?;
+
+    // Transparent pixels are composited over the average colour of the opaque ones
+    let mut avg = [0.0f64; 4];
+    for px in &pixels {
+        let px = px.map(f64::from);
+        (0..3).for_each(|c| avg[c] += px[3] * px[c]);
+        avg[3] += px[3];
+    }
+    if avg[3] > 0.0 {
+        (0..3).for_each(|c| avg[c] /= avg[3]);
+    }
+
+    let has_alpha = avg[3] < (w * h) as f64;
+    // Fewer luminance terms when there's alpha to leave room for it
+    let l_limit = if has_alpha { 5.0 } else { 7.0 };
+    let longest = w.max(h) as f64;
+    let lx = ((l_limit * w as f64 / longest).round() as usize).max(1);
+    let ly = ((l_limit * h as f64 / longest).round() as usize).max(1);
+
+    // Luminance, yellow-blue, red-green and alpha
+    let n = w * h;
+    let (mut l, mut p, mut q, mut a) = (vec![0.0; n], vec![0.0; n], vec![0.0; n], vec![0.0; n]);
+    for (i, px) in pixels.iter().enumerate() {
+        let px = px.map(f64::from);
+        let alpha = px[3];
+        let [r, g, b] = [0, 1, 2].map(|c| avg[c] * (1.0 - alpha) + alpha * px[c]);
+        l[i] = (r + g + b) / 3.0;
+        p[i] = (r + g) / 2.0 - b;
+        q[i] = r - g;
+        a[i] = alpha;
+    }
+
+    let (l_dc, l_ac, l_scale) = thumbhash_channel(&l, (w, h), lx.max(3), ly.max(3));
+    let (p_dc, p_ac, p_scale) = thumbhash_channel(&p, (w, h), 3, 3);
+    let (q_dc, q_ac, q_scale) = thumbhash_channel(&q, (w, h), 3, 3);
+
+    let round = |v: f64| v.round() as u32;
+    let landscape = w > h;
+    let header24 = round(63.0 * l_dc)
+        | (round(31.5 + 31.5 * p_dc) << 6)
+        | (round(31.5 + 31.5 * q_dc) << 12)
+        | (round(31.0 * l_scale) << 18)
+        | ((has_alpha as u32) << 23);
+    let header16 = (if landscape { ly } else { lx }) as u32
+        | (round(63.0 * p_scale) << 3)
+        | (round(63.0 * q_scale) << 9)
+        | ((landscape as u32) << 15);
+    let mut hash = vec![
+        header24 as u8,
+        (header24 >> 8) as u8,
+        (header24 >> 16) as u8,
+        header16 as u8,
+        (header16 >> 8) as u8,
+    ];
+
+    let mut terms = vec![l_ac, p_ac, q_ac];
+    if has_alpha {
+        let (a_dc, a_ac, a_scale) = thumbhash_channel(&a, (w, h), 5, 5);
+        hash.push((round(15.0 * a_dc) | (round(15.0 * a_scale) << 4)) as u8);
+        terms.push(a_ac);
+    }
+
+    // Two 4-bit AC terms per byte, low nibble first
+    for (i, f) in terms.iter().flatten().enumerate() {
+        if i % 2 == 0 {
+            hash.push(0);
+        }
+        *hash.last_mut().unwrap() |= (round(15.0 * f) << ((i % 2) * 4)) as u8;
+    }
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orientations_turn_a_labelled_image_upright() {
+        // Stored as   A B C    with A..F labelled 1..6
+        //             D E F
+        let expected: [(u16, usize, usize, &[u8]); 8] = [
+            (1, 3, 2, &[1, 2, 3, 4, 5, 6]),
+            (2, 3, 2, &[3, 2, 1, 6, 5, 4]),
+            (3, 3, 2, &[6, 5, 4, 3, 2, 1]),
+            (4, 3, 2, &[4, 5, 6, 1, 2, 3]),
+            (5, 2, 3, &[1, 4, 2, 5, 3, 6]),
+            (6, 2, 3, &[4, 1, 5, 2, 6, 3]),
+            (7, 2, 3, &[6, 3, 5, 2, 4, 1]),
+            (8, 2, 3, &[3, 6, 2, 5, 1, 4]),
+        ];
+        // Two samples per pixel, so a mix-up of pixels and samples would show
+        let interleave =
+            |labels: &[u8]| -> Vec<u8> { labels.iter().flat_map(|&l| [l, l * 10]).collect() };
+        let stored = interleave(&[1, 2, 3, 4, 5, 6]);
+
+        for (orientation, width, height, labels) in expected {
+            let (out, w, h) = orient_samples(&stored, 3, 2, 2, orientation);
+            assert_eq!((w, h), (width, height), "orientation {}", orientation);
+            assert_eq!(out, interleave(labels), "orientation {}", orientation);
+        }
+    }
+
+    // 64x40 of hashed noise over gradients: small enough to be hashed without a
+    // downscale, busy enough that every component counts
+    fn noise() -> AgnoImage {
+        let (w, h) = (64u32, 40u32);
+        let mut rgb = Vec::with_capacity((w * h * 3) as usize);
+        for y in 0..h {
+            for x in 0..w {
+                let r = |k: u32| {
+                    ((x * 7919 + y * 104729 + k * 31337).wrapping_mul(2654435761) >> 13) as u8
+                };
+                rgb.extend([
+                    (r(1) / 2).wrapping_add((x * 2) as u8),
+                    (r(2) / 3).wrapping_add((y * 3) as u8),
+                    r(3),
+                ]);
+            }
+        }
+        AgnoImage::from_samples(&rgb, w as u64, h as u64, ExifContext::new())
+    }
+
+    // Expected values come from the reference BlurHash and ThumbHash encoders
+    #[test]
+    fn placeholders_match_the_reference_encoders() {
+        let img = noise();
+        assert_eq!(
+            blurhash(&img, 4, 3).unwrap(),
+            "LTF}dL2aslS#mIayfPf8kUbFfPfR"
+        );
+        assert_eq!(blurhash(&img, 1, 1).unwrap(), "00F}dL");
+        assert_eq!(
+            thumbhash(&img).unwrap(),
+            [
+                157, 55, 6, 28, 140, 112, 135, 135, 112, 136, 119, 120, 135, 119, 128, 129, 7, 247,
+                135
+            ]
+        );
+        assert!(blurhash(&img, 0, 3).is_err());
+        assert!(blurhash(&img, 4, 10).is_err());
+        AgnoImage::free(&img);
+    }
+
+    #[test]
+    fn exact_never_upscale_keeps_the_box_aspect_ratio() {
+        let resize = |w: u64, h: u64, box_w: u32, box_h: u32| {
+            let img = AgnoImage::from_samples(
+                &vec![128u8; (w * h * 3) as usize],
+                w,
+                h,
+                ExifContext::new(),
+            );
+            let spec = ResizeSpec {
+                mode: ResizeMode::Exact,
+                width: box_w,
+                height: box_h,
+                never_upscale: true,
+                ..Default::default()
+            };
+            let out = resize_with_spec(img, &spec).unwrap();
+            let size = (out.width, out.height);
+            AgnoImage::free(&out);
+            size
+        };
+        // Too tall for the source: both axes shrink by the same factor
+        assert_eq!(resize(1000, 500, 800, 800), (500, 500));
+        assert_eq!(resize(1000, 500, 2000, 400), (1000, 200));
+        // Fits already: exactly as asked
+        assert_eq!(resize(1000, 500, 400, 300), (400, 300));
+        assert_eq!(resize(1000, 500, 1000, 500), (1000, 500));
+    }
+
+    // Every sample distinct, with the channel count of `channels`
+    fn labelled(w: u64, h: u64, channels: u32) -> AgnoImage {
+        let samples: Vec<u16> = (0..w * h * channels as u64)
+            .map(|i| (i * 257 + 3) as u16)
+            .collect();
+        AgnoImage::from_channels(&samples, w, h, channels, ExifContext::new())
+    }
+
+    // Size, channels and samples, freeing the image
+    fn contents(img: AgnoImage) -> (u64, u64, u32, Vec<u16>) {
+        let out = (
+            img.width,
+            img.height,
+            img.channels,
+            img.as_samples::<u16>().unwrap().to_vec(),
+        );
+        AgnoImage::free(&img);
+        out
+    }
+
+    #[test]
+    fn quarter_turns_and_flips_round_trip() {
+        for channels in [1, 3, 4] {
+            let original = contents(labelled(5, 3, channels));
+
+            let turned = rotate_image(labelled(5, 3, channels), 90).unwrap();
+            assert_eq!((turned.width, turned.height), (3, 5));
+            // Clockwise: the bottom-left pixel ends up top-left
+            let first = &turned.as_samples::<u16>().unwrap()[..channels as usize];
+            assert_eq!(
+                first,
+                &original.3[10 * channels as usize..][..channels as usize]
+            );
+            let back = rotate_image(turned, -90).unwrap();
+            assert_eq!(contents(back), original);
+
+            let mut img = labelled(5, 3, channels);
+            for _ in 0..4 {
+                img = rotate_image(img, 90).unwrap();
+            }
+            assert_eq!(contents(img), original);
+
+            for axis in [FlipAxis::Horizontal, FlipAxis::Vertical] {
+                let flipped = flip_image(labelled(5, 3, channels), axis);
+                assert_ne!(
+                    contents(flip_image(labelled(5, 3, channels), axis)).3,
+                    original.3
+                );
+                assert_eq!(contents(flip_image(flipped, axis)), original);
+            }
+
+            // A half turn is both flips
+            let half = contents(rotate_image(labelled(5, 3, channels), 180).unwrap());
+            let both = flip_image(
+                flip_image(labelled(5, 3, channels), FlipAxis::Horizontal),
+                FlipAxis::Vertical,
+            );
+            assert_eq!(contents(both), half);
+
+            // Quarter angles take the lossless path
+            let by = rotate_image_by(labelled(5, 3, channels), -270.0, RotateMode::Fill, [0.0; 3]);
+            assert_eq!(
+                contents(by.unwrap()),
+                contents(rotate_image(labelled(5, 3, channels), 90).unwrap())
+            );
+        }
+        assert!(rotate_image(labelled(5, 3, 3), 45).is_err());
+    }
+
+    #[test]
+    fn crops_cut_the_requested_rectangle() {
+        let (w, h, _, samples) = contents(labelled(6, 4, 3));
+        let (cw, ch, channels, cropped) =
+            contents(crop_image(labelled(6, 4, 3), 1, 2, 4, 2).unwrap());
+        assert_eq!((cw, ch, channels), (4, 2, 3));
+        let expected: Vec<u16> = (2..4)
+            .flat_map(|y| samples[(y * w as usize + 1) * 3..][..4 * 3].to_vec())
+            .collect();
+        assert_eq!(cropped, expected);
+
+        // The whole frame is the identity
+        assert_eq!(
+            contents(crop_image(labelled(6, 4, 3), 0, 0, 6, 4).unwrap()).3,
+            samples
+        );
+
+        // Cropping a mirrored image is mirroring the mirrored crop
+        let flipped = flip_image(labelled(6, 4, 3), FlipAxis::Horizontal);
+        let crop_of_flip = crop_image(flipped, w as usize - 1 - 4, 2, 4, 2).unwrap();
+        let flip_of_crop = flip_image(
+            crop_image(labelled(6, 4, 3), 1, 2, 4, 2).unwrap(),
+            FlipAxis::Horizontal,
+        );
+        assert_eq!(contents(crop_of_flip), contents(flip_of_crop));
+
+        assert!(crop_image(labelled(6, 4, 3), 3, 0, 4, 4).is_err());
+        assert!(crop_image(labelled(6, 4, 3), 0, 1, 6, h as usize).is_err());
+        assert!(crop_image(labelled(6, 4, 3), 0, 0, 0, 4).is_err());
+    }
 }
@@ -0,0 +1,311 @@
+use std::{collections::HashSet, fs, path::Path};
+
+use log::debug;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use crate::{
+    black_level::BlackLevels,
+    demosaic::{BayerPattern, cfa_color_at, cfa_index},
+    exif::{ExifContext, ExifValue, spec::DATE_TIME_ORIGINAL},
+    sony_decoder::Dimensions,
+};
+
+// Same-colour neighbours of a Bayer site: the 3x3 grid of sites two pixels apart
+const NEIGHBOURS: [(isize, isize); 8] = [
+    (-2, -2),
+    (-2, 0),
+    (-2, 2),
+    (0, -2),
+    (0, 2),
+    (2, -2),
+    (2, 0),
+    (2, 2),
+];
+
+// Deviation from the neighbour median, in multiples of the neighbours' spread, that marks a pixel bad
+const DEFAULT_THRESHOLD: f32 = 4.0;
+
+// Spread assumed for flat, noiseless areas, as a fraction of the black-to-white range
+const NOISE_FLOOR: f32 = 0.01;
+
+/// Which pixels get repaired before demosaicing. A defect map, when given, is
+/// applied in every mode.
+#[repr(C)]
+#[allow(dead_code)] // variants are constructed from C
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BadPixelMode {
+    /// Only the pixels listed in the defect map
+    #[default]
+    Off = 0,
+    /// Also hot and dead pixels found by comparing each site with its same-colour neighbours
+    Auto = 1,
+}
+
+/// One entry of a dcraw `-P` defect map.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Defect {
+    pub col: usize,
+    pub row: usize,
+    /// Unix time the defect appeared; shots taken earlier leave the pixel alone
+    pub since: i64,
+}
+
+/// Parses dcraw's defect map format: one `col row timestamp` triple per line,
+/// with anything after a `#` ignored. Lines that don't hold three numbers are skipped.
+pub fn parse_defect_map(text: &str) -> Vec<Defect> {
+    text.lines()
+        .filter_map(|line| {
+            let line = line.split('#').next().unwrap_or("");
+            let mut fields = line.split_whitespace().map(|f| f.parse::<i64>());
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some(Ok(col)), Some(Ok(row)), Some(Ok(since))) if col >= 0 && row >= 0 => {
+                    Some(Defect {
+                        col: col as usize,
+                        row: row as usize,
+                        since,
+                    })
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+pub fn load_defect_map(path: impl AsRef<Path>) -> Result<Vec<Defect>, std::io::Error> {
+    Ok(parse_defect_map(&fs::read_to_string(path)?))
+}
+
+// Days from 1970-01-01 to a proleptic Gregorian date (Howard Hinnant's days_from_civil)
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// DateTimeOriginal as Unix time, reading the camera's local time as UTC like
+/// dcraw does. None when the tag is missing or malformed.
+pub fn shot_timestamp(ctx: &ExifContext) -> Option<i64> {
+    let Some(ExifValue::Ascii(s)) = ctx.get_tag_value(DATE_TIME_ORIGINAL) else {
+        return None;
+    };
+
+    // "YYYY:MM:DD HH:MM:SS"
+    let n: Vec<i64> = s
+        .trim_end_matches('\0')
+        .split([':', ' '])
+        .map(|f| f.trim().parse().ok())
+        .collect::<Option<_>>()?;
+    if n.len() != 6 || n[1] == 0 || n[2] == 0 {
+        return None;
+    }
+
+    Some(days_from_civil(n[0], n[1], n[2]) * 86400 + n[3] * 3600 + n[4] * 60 + n[5])
+}
+
+/// Defect map entries that apply to a shot: inside the image and already present
+/// when it was taken. Without a timestamp every entry applies.
+pub fn defects_for_shot(
+    defects: &[Defect],
+    dims: Dimensions,
+    taken: Option<i64>,
+) -> Vec<(usize, usize)> {
+    defects
+        .iter()
+        .filter(|d| d.col < dims.output_width && d.row < dims.output_height)
+        .filter(|d| taken.is_none_or(|t| d.since <= t))
+        .map(|d| (d.row, d.col))
+        .collect()
+}
+
+/// Finds stuck, hot and dead sites: values outside the range of all eight same-colour
+/// neighbours and far from their median relative to how much the neighbours vary.
+/// - threshold: multiples of the neighbours' spread, 0 or less for the default
+/// - returns (row, col) pairs
+pub fn detect_bad_pixels(
+    raw: &[u16],
+    dims: Dimensions,
+    black_level: BlackLevels,
+    white_level: u16,
+    threshold: f32,
+) -> Vec<(usize, usize)> {
+    let (w, h) = (dims.output_width, dims.output_height);
+    if w < 5 || h < 5 {
+        return Vec::new();
+    }
+
+    let k = if threshold > 0.0 {
+        threshold
+    } else {
+        DEFAULT_THRESHOLD
+    };
+    let stride = dims.raw_width;
+
+    (2..h - 2)
+        .into_par_iter()
+        .flat_map_iter(|row| {
+            (2..w - 2).filter_map(move |col| {
+                let v = raw[row * stride + col];
+                let mut n = NEIGHBOURS.map(|(dy, dx)| {
+                    raw[(row as isize + dy) as usize * stride + (col as isize + dx) as usize]
+                });
+
+                // Cheap rejection first: most pixels sit inside their neighbours' range
+                let (lo, hi) = (*n.iter().min().unwrap(), *n.iter().max().unwrap());
+                if v >= lo && v <= hi {
+                    return None;
+                }
+
+                n.sort_unstable();
+                let median = (n[3] as f32 + n[4] as f32) * 0.5;
+                // Range of the middle six ignores one outlier neighbour on either side
+                let spread = (n[6] - n[1]) as f32;
+                let floor = white_level.saturating_sub(black_level[cfa_index(row, col)]) as f32
+                    * NOISE_FLOOR;
+                let limit = k * spread.max(floor);
+
+                ((v as f32 - median).abs() > limit).then_some((row, col))
+            })
+        })
+        .collect()
+}
+
+/// Replaces each listed site with the mean of its nearest same-colour neighbours
+/// that aren't listed themselves, the way dcraw's bad_pixels does: the ring one
+/// pixel out first (greens have diagonal neighbours), then two pixels out.
+pub fn correct_bad_pixels(
+    raw: &mut [u16],
+    dims: Dimensions,
+    pattern: BayerPattern,
+    bad: &[(usize, usize)],
+) {
+    if bad.is_empty() {
+        return;
+    }
+
+    let (w, h) = (dims.output_width, dims.output_height);
+    let stride = dims.raw_width;
+    let listed: HashSet<(usize, usize)> = bad.iter().copied().collect();
+
+    let fixes: Vec<(usize, u16)> = bad
+        .iter()
+        .filter_map(|&(row, col)| {
+            let colour = cfa_color_at(row, col, pattern);
+
+            (1..=2).find_map(|rad: usize| {
+                let (mut total, mut count) = (0u32, 0u32);
+                for r in row.saturating_sub(rad)..=(row + rad).min(h - 1) {
+                    for c in col.saturating_sub(rad)..=(col + rad).min(w - 1) {
+                        if (r, c) != (row, col)
+                            && cfa_color_at(r, c, pattern) == colour
+                            && !listed.contains(&(r, c))
+                        {
+                            total += raw[r * stride + c] as u32;
+                            count += 1;
+                        }
+                    }
+                }
+                (count > 0).then(|| (row * stride + col, (total / count) as u16))
+            })
+        })
+        .collect();
+
+    debug!("Corrected {} of {} bad pixels", fixes.len(), bad.len());
+
+    for (i, v) in fixes {
+        raw[i] = v;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::demosaic::CfaColor;
+
+    #[test]
+    fn defect_map_skips_comments_and_malformed_lines() {
+        let map = "# col row since\n\
+                   10 20 0\n\
+                   \n\
+                   5 6 1600000000 # appeared later\n\
+                   7 8\n\
+                   x 1 2\n\
+                   -1 3 4\n\
+                   \t 30   40   12 extra\n";
+        assert_eq!(
+            parse_defect_map(map),
+            vec![
+                Defect {
+                    col: 10,
+                    row: 20,
+                    since: 0
+                },
+                Defect {
+                    col: 5,
+                    row: 6,
+                    since: 1600000000
+                },
+                Defect {
+                    col: 30,
+                    row: 40,
+                    since: 12
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn civil_dates_count_days_from_the_epoch() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+    }
+
+    #[test]
+    fn shot_timestamp_reads_date_time_original_as_utc() {
+        let mut ctx = ExifContext::new();
+        assert_eq!(shot_timestamp(&ctx), None);
+
+        ctx.set_tag_value(
+            DATE_TIME_ORIGINAL,
+            ExifValue::Ascii("2021:03:14 15:09:26\0".to_string()),
+        );
+        assert_eq!(shot_timestamp(&ctx), Some(1615734566));
+
+        // Cameras without a clock write zeros
+        ctx.set_tag_value(
+            DATE_TIME_ORIGINAL,
+            ExifValue::Ascii("0000:00:00 00:00:00".to_string()),
+        );
+        assert_eq!(shot_timestamp(&ctx), None);
+    }
+
+    #[test]
+    fn hot_pixel_is_found_and_repaired_from_its_own_colour() {
+        let (w, h) = (16, 12);
+        let dims = Dimensions {
+            raw_width: w,
+            raw_height: h,
+            output_width: w,
+            output_height: h,
+        };
+        let pattern = BayerPattern::RGGB;
+        let level = |row, col| match cfa_color_at(row, col, pattern) {
+            CfaColor::R => 1000,
+            CfaColor::G => 2000,
+            CfaColor::B => 1500,
+        };
+        let mut raw: Vec<u16> = (0..w * h).map(|i| level(i / w, i % w)).collect();
+        let clean = raw.clone();
+        // A green site, whose nearest greens are diagonal
+        raw[6 * w + 7] = 16000;
+
+        let bad = detect_bad_pixels(&raw, dims, [512; 4], 16383, 0.0);
+        assert_eq!(bad, vec![(6, 7)]);
+
+        correct_bad_pixels(&mut raw, dims, pattern, &bad);
+        assert_eq!(raw, clean);
+    }
+}
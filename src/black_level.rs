@@ -0,0 +1,277 @@
+use log::debug;
+
+use crate::{
+    exif::{
+        ExifContext, ExifValue,
+        spec::{ACTIVE_AREA, BLACK_LEVEL, DNG_BLACK_LEVEL, SONY_CROP_SIZE, SONY_CROP_TOP_LEFT},
+    },
+    sony_decoder::Dimensions,
+};
+
+// Black levels per 2x2 CFA position, indexed by (row & 1) * 2 + (col & 1)
+pub type BlackLevels = [u16; 4];
+
+// Used when neither the tags nor the masked border give us anything
+const DEFAULT_BLACK_LEVEL: u16 = 512;
+
+// Masked pixels are noisy and sometimes hold a few hot columns; ignore blocks that are too small
+const MIN_MASKED_SAMPLES: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ActiveArea {
+    pub top: usize,
+    pub left: usize,
+    pub bottom: usize, // exclusive
+    pub right: usize,  // exclusive
+}
+
+fn exif_numbers(value: &ExifValue) -> Vec<u32> {
+    match value {
+        ExifValue::Short(v) => v.iter().map(|&n| n as u32).collect(),
+        ExifValue::Long(v) => v.clone(),
+        ExifValue::Rational(v) => v
+            .iter()
+            .map(|&(n, d)| n.checked_div(d).unwrap_or(0))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Reads per-channel black levels from Sony's BlackLevel (0x7310) or the DNG BlackLevel tag.
+/// A single value is replicated across all four CFA positions; any count other
+/// than one or four can't be mapped onto the CFA and is ignored.
+pub fn black_levels_from_exif(ctx: &ExifContext) -> Option<BlackLevels> {
+    let value = ctx
+        .get_tag_value(BLACK_LEVEL)
+        .or_else(|| ctx.get_tag_value(DNG_BLACK_LEVEL))?;
+
+    let n = exif_numbers(value);
+    match n.len() {
+        1 => Some([n[0] as u16; 4]),
+        4 => Some([n[0] as u16, n[1] as u16, n[2] as u16, n[3] as u16]),
+        count => {
+            debug!("Ignoring black level tag with {} values", count);
+            None
+        }
+    }
+}
+
+/// The area of the raster holding real image data. Everything outside it is
+/// masked (optical black) or padding.
+pub fn active_area_from_exif(ctx: &ExifContext, dims: Dimensions) -> Option<ActiveArea> {
+    let area = if let Some(v) = ctx.get_tag_value(ACTIVE_AREA) {
+        // DNG ActiveArea: top, left, bottom, right
+        let n = exif_numbers(v);
+        if n.len() < 4 {
+            return None;
+        }
+        ActiveArea {
+            top: n[0] as usize,
+            left: n[1] as usize,
+            bottom: n[2] as usize,
+            right: n[3] as usize,
+        }
+    } else {
+        // Sony crop: left, top and width, height
+        let top_left = exif_numbers(ctx.get_tag_value(SONY_CROP_TOP_LEFT)?);
+        let size = exif_numbers(ctx.get_tag_value(SONY_CROP_SIZE)?);
+        if top_left.len() < 2 || size.len() < 2 {
+            return None;
+        }
+        let (left, top) = (top_left[0] as usize, top_left[1] as usize);
+        ActiveArea {
+            top,
+            left,
+            bottom: top + size[1] as usize,
+            right: left + size[0] as usize,
+        }
+    };
+
+    if area.bottom <= area.top
+        || area.right <= area.left
+        || area.bottom > dims.output_height
+        || area.right > dims.output_width
+    {
+        debug!("Ignoring out-of-bounds active area {:?}", area);
+        return None;
+    }
+
+    Some(area)
+}
+
+/// Measures the black level of each CFA position from the masked border around
+/// the active area, using the median so hot pixels in the border don't skew it.
+pub fn estimate_black_from_masked(
+    raw: &[u16],
+    dims: Dimensions,
+    area: ActiveArea,
+) -> Option<BlackLevels> {
+    let mut samples: [Vec<u16>; 4] = Default::default();
+
+    for row in 0..dims.output_height {
+        let row_masked = row < area.top || row >= area.bottom;
+        for col in 0..dims.output_width {
+            if row_masked || col < area.left || col >= area.right {
+                let pos = ((row & 1) << 1) | (col & 1);
+                samples[pos].push(raw[row * dims.raw_width + col]);
+            }
+        }
+    }
+
+    let mut levels = [0u16; 4];
+    for (level, s) in levels.iter_mut().zip(samples.iter_mut()) {
+        if s.len() < MIN_MASKED_SAMPLES {
+            return None;
+        }
+        let mid = s.len() / 2;
+        *level = *s.select_nth_unstable(mid).1;
+    }
+
+    Some(levels)
+}
+
+// Tags that are all zero or at/above the white point are left over from firmware that never filled them in
+fn plausible(levels: &BlackLevels, white_level: u16) -> bool {
+    levels.iter().any(|&b| b != 0) && levels.iter().all(|&b| b < white_level / 2)
+}
+
+/// Picks the black levels to render with: the EXIF tags when they look sane,
+/// otherwise an estimate from masked pixels, otherwise the historical default.
+pub fn resolve_black_levels(
+    ctx: &ExifContext,
+    raw: &[u16],
+    dims: Dimensions,
+    white_level: u16,
+) -> BlackLevels {
+    if let Some(levels) = black_levels_from_exif(ctx) {
+        if plausible(&levels, white_level) {
+            return levels;
+        }
+        debug!("Ignoring implausible black level tag {:?}", levels);
+    }
+
+    if let Some(levels) = active_area_from_exif(ctx, dims)
+        .and_then(|area| estimate_black_from_masked(raw, dims, area))
+    {
+        debug!("Estimated black levels from masked pixels: {:?}", levels);
+        return levels;
+    }
+
+    [DEFAULT_BLACK_LEVEL; 4]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exif::spec::ACTIVE_AREA;
+
+    const WIDTH: usize = 40;
+    const HEIGHT: usize = 30;
+    const WHITE: u16 = 16383;
+    const MASKED: BlackLevels = [510, 512, 514, 516];
+
+    fn dims() -> Dimensions {
+        Dimensions {
+            raw_width: WIDTH,
+            raw_height: HEIGHT,
+            output_width: WIDTH,
+            output_height: HEIGHT,
+        }
+    }
+
+    // Image data inside the area, each CFA position's black level outside it,
+    // with every 17th masked pixel hot
+    fn raster(area: ActiveArea) -> Vec<u16> {
+        (0..WIDTH * HEIGHT)
+            .map(|i| {
+                let (row, col) = (i / WIDTH, i % WIDTH);
+                let active = (area.top..area.bottom).contains(&row)
+                    && (area.left..area.right).contains(&col);
+                match (active, i % 17) {
+                    (true, _) => 3000,
+                    (false, 0) => WHITE,
+                    (false, _) => MASKED[((row & 1) << 1) | (col & 1)],
+                }
+            })
+            .collect()
+    }
+
+    fn area(top: usize) -> ActiveArea {
+        ActiveArea {
+            top,
+            left: 4,
+            bottom: HEIGHT,
+            right: WIDTH,
+        }
+    }
+
+    fn context(black_level: Option<ExifValue>, area: Option<ActiveArea>) -> ExifContext {
+        let mut ctx = ExifContext::new();
+        if let Some(value) = black_level {
+            ctx.set_tag_value(BLACK_LEVEL, value);
+        }
+        if let Some(a) = area {
+            let bounds = [a.top, a.left, a.bottom, a.right].map(|n| n as u16);
+            ctx.set_tag_value(ACTIVE_AREA, ExifValue::Short(bounds.to_vec()));
+        }
+        ctx
+    }
+
+    #[test]
+    fn tags_with_one_or_four_values_are_read() {
+        let read = |v: Vec<u16>| black_levels_from_exif(&context(Some(ExifValue::Short(v)), None));
+        assert_eq!(read(vec![600]), Some([600; 4]));
+        assert_eq!(read(vec![600, 601, 602, 603]), Some([600, 601, 602, 603]));
+        assert_eq!(read(vec![]), None);
+        assert_eq!(read(vec![600, 601]), None);
+        assert_eq!(read(vec![600, 601, 602]), None);
+        assert_eq!(read(vec![600, 601, 602, 603, 604]), None);
+    }
+
+    #[test]
+    fn masked_border_gives_the_median_per_position() {
+        // 66 masked samples per CFA position: four full rows plus four columns
+        let area = area(4);
+        assert_eq!(
+            estimate_black_from_masked(&raster(area), dims(), area),
+            Some(MASKED)
+        );
+    }
+
+    #[test]
+    fn too_small_a_border_is_not_trusted() {
+        // Two rows and four columns leave 48 samples per position
+        let area = area(2);
+        assert_eq!(
+            estimate_black_from_masked(&raster(area), dims(), area),
+            None
+        );
+    }
+
+    #[test]
+    fn resolve_prefers_plausible_tags_then_the_border_then_the_default() {
+        let raw = raster(area(4));
+        let resolve = |tag: Option<ExifValue>, area: Option<ActiveArea>| {
+            resolve_black_levels(&context(tag, area), &raw, dims(), WHITE)
+        };
+
+        let tag = || Some(ExifValue::Short(vec![600]));
+        assert_eq!(resolve(tag(), Some(area(4))), [600; 4]);
+        // All zero, or above half the white point: left over, not measured
+        assert_eq!(
+            resolve(Some(ExifValue::Short(vec![0; 4])), Some(area(4))),
+            MASKED
+        );
+        assert_eq!(
+            resolve(Some(ExifValue::Short(vec![9000])), Some(area(4))),
+            MASKED
+        );
+        assert_eq!(resolve(None, Some(area(4))), MASKED);
+        // Nothing to measure from
+        assert_eq!(
+            resolve(Some(ExifValue::Short(vec![0])), None),
+            [DEFAULT_BLACK_LEVEL; 4]
+        );
+        assert_eq!(resolve(None, Some(area(2))), [DEFAULT_BLACK_LEVEL; 4]);
+    }
+}
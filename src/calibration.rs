@@ -0,0 +1,336 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom},
+    path::Path,
+};
+
+use log::debug;
+use rayon::{
+    iter::{
+        IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator,
+        IntoParallelRefMutIterator, ParallelIterator,
+    },
+    slice::{ParallelSlice, ParallelSliceMut},
+};
+
+use crate::{
+    agno_image::load::decode_sony_raw,
+    black_level::{BlackLevels, resolve_black_levels},
+    demosaic::cfa_index,
+    dng_writer::{DngCompression, write_dng},
+    exif::{ExifContext, ExifValue, spec::ISO},
+    sony_decoder::{Dimensions, SonyLoadResult},
+    tiff::{TiffRawInfo, detect_sony_raw},
+};
+
+// Flat-field sites darker than this fraction of their channel mean are dust or dead
+// and would blow up on division, so they're left uncorrected
+const MIN_FLAT_GAIN: f32 = 0.05;
+
+/// A decoded mosaic with what's needed to calibrate against it or save it as a master.
+pub struct RawFrame {
+    pub pixels: Vec<u16>,
+    pub dims: Dimensions,
+    pub white_level: u16,
+    pub black_level: BlackLevels,
+    pub iso: Option<u32>,
+    pub raw_info: TiffRawInfo,
+    pub ctx: ExifContext,
+}
+
+pub fn iso(ctx: &ExifContext) -> Option<u32> {
+    match ctx.get_tag_value(ISO)? {
+        ExifValue::Short(v) => v.first().map(|&n| n as u32),
+        ExifValue::Long(v) => v.first().copied(),
+        _ => None,
+    }
+}
+
+/// Decodes a raw (or a master saved by `write_master_frame`) the same way
+/// `load_sony_raw` does, stopping at the mosaic.
+pub fn load_raw_frame(path: impl AsRef<Path>) -> Result<RawFrame, Box<dyn Error>> {
+    let mut file = File::open(path)?;
+    let det = detect_sony_raw(&mut file)?;
+
+    file.seek(SeekFrom::Start(0))?;
+    let ctx = ExifContext::from_reader_auto(&mut file)?;
+    let (decoded, dims) = decode_sony_raw(&det, &mut file, &ctx)?;
+    let black_level = resolve_black_levels(&ctx, &decoded.pixels, dims, decoded.white_level);
+
+    Ok(RawFrame {
+        pixels: decoded.pixels,
+        dims,
+        white_level: decoded.white_level,
+        black_level,
+        iso: iso(&ctx),
+        raw_info: det.raw,
+        ctx,
+    })
+}
+
+/// Calibration frames only make sense for shots with the same sensor readout:
+/// same raster, and same ISO when both files record it.
+pub fn check_compatible(
+    frame: &RawFrame,
+    dims: Dimensions,
+    iso: Option<u32>,
+    what: &str,
+) -> Result<(), Box<dyn Error>> {
+    let d = frame.dims;
+    if (d.raw_width, d.output_width, d.output_height)
+        != (dims.raw_width, dims.output_width, dims.output_height)
+    {
+        return Err(format!(
+            "{} is {}x{}, expected {}x{}",
+            what, d.output_width, d.output_height, dims.output_width, dims.output_height
+        )
+        .into());
+    }
+
+    if let (Some(a), Some(b)) = (frame.iso, iso)
+        && a != b
+    {
+        return Err(format!("{} was shot at ISO {}, expected ISO {}", what, a, b).into());
+    }
+
+    Ok(())
+}
+
+/// Averages several frames of the same kind (darks or flats) into a master,
+/// which carries the metadata of the first.
+pub fn build_master_frame(paths: &[&str]) -> Result<RawFrame, Box<dyn Error>> {
+    let Some((first, rest)) = paths.split_first() else {
+        return Err("No calibration frames given".into());
+    };
+
+    let mut master = load_raw_frame(first)?;
+    let mut sums: Vec<u32> = master.pixels.iter().map(|&v| v as u32).collect();
+
+    for path in rest {
+        let frame = load_raw_frame(path)?;
+        check_compatible(&frame, master.dims, master.iso, path)?;
+        sums.par_iter_mut()
+            .zip(frame.pixels.par_iter())
+            .for_each(|(s, &v)| *s += v as u32);
+    }
+
+    let n = paths.len() as u32;
+    master
+        .pixels
+        .par_iter_mut()
+        .zip(sums.par_iter())
+        .for_each(|(v, &s)| *v = ((s + n / 2) / n) as u16);
+
+    debug!("Averaged {} calibration frames", n);
+    Ok(master)
+}
+
+/// Saves a master as an uncompressed DNG, which `load_raw_frame` reads back.
+pub fn write_master_frame(frame: &RawFrame, path: &str) -> Result<(), Box<dyn Error>> {
+    let width = frame.raw_info.width as usize;
+    let height = frame.raw_info.height as usize;
+    // The DNG writer wants the mosaic packed at its own width
+    let pixels = frame
+        .pixels
+        .par_chunks(frame.dims.raw_width)
+        .take(height)
+        .flat_map_iter(|row| row[..width].iter().copied())
+        .collect();
+    let result = SonyLoadResult {
+        pixels,
+        white_level: frame.white_level,
+    };
+
+    let mut out = BufWriter::new(File::create(path)?);
+    write_dng(
+        &mut out,
+        &result,
+        &frame.raw_info,
+        &frame.ctx,
+        DngCompression::Uncompressed,
+    )?;
+    Ok(())
+}
+
+// Per-CFA-position mean of a flat above its black level
+fn flat_means(flat: &RawFrame) -> [f32; 4] {
+    let d = flat.dims;
+    let sums = (0..d.output_height)
+        .into_par_iter()
+        .map(|row| {
+            let mut sums = [0f64; 4];
+            let line = &flat.pixels[row * d.raw_width..row * d.raw_width + d.output_width];
+            for (col, &v) in line.iter().enumerate() {
+                let pos = cfa_index(row, col);
+                sums[pos] += v.saturating_sub(flat.black_level[pos]) as f64;
+            }
+            sums
+        })
+        .reduce(
+            || [0f64; 4],
+            |a, b| [a[0] + b[0], a[1] + b[1], a[2] + b[2], a[3] + b[3]],
+        );
+
+    let per_pos = (d.output_width * d.output_height / 4).max(1) as f64;
+    sums.map(|s| (s / per_pos) as f32)
+}
+
+/// Dark subtraction and flat-field division on the mosaic, before anything else
+/// touches it. The dark is subtracted whole (it holds the black level plus thermal
+/// signal) and `black_level` added back, so the rest of the pipeline is unchanged.
+/// Each flat channel is normalized to its own mean so colour balance is kept.
+/// Clipped samples are left clipped.
+pub fn apply_calibration(
+    raw: &mut [u16],
+    dims: Dimensions,
+    black_level: BlackLevels,
+    white_level: u16,
+    dark: Option<&RawFrame>,
+    flat: Option<&RawFrame>,
+) {
+    if dark.is_none() && flat.is_none() {
+        return;
+    }
+
+    let flat_means = flat.map(flat_means);
+    let stride = dims.raw_width;
+
+    raw.par_chunks_mut(stride)
+        .take(dims.output_height)
+        .enumerate()
+        .for_each(|(row, line)| {
+            for (col, v) in line[..dims.output_width].iter_mut().enumerate() {
+                if *v >= white_level {
+                    continue;
+                }
+
+                let pos = cfa_index(row, col);
+                let i = row * stride + col;
+                let black = black_level[pos] as f32;
+
+                let mut signal = match dark {
+                    Some(d) => *v as f32 - d.pixels[i] as f32,
+                    None => *v as f32 - black,
+                };
+
+                if let (Some(f), Some(means)) = (flat, flat_means) {
+                    let gain =
+                        f.pixels[i].saturating_sub(f.black_level[pos]) as f32 / means[pos].max(1.0);
+                    if gain >= MIN_FLAT_GAIN {
+                        signal /= gain;
+                    }
+                }
+
+                *v = (black + signal).clamp(0.0, white_level as f32).round() as u16;
+            }
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WIDTH: usize = 8;
+    const HEIGHT: usize = 4;
+    const BLACK: u16 = 500;
+    const WHITE: u16 = 4000;
+
+    fn dims() -> Dimensions {
+        Dimensions {
+            raw_width: WIDTH,
+            raw_height: HEIGHT,
+            output_width: WIDTH,
+            output_height: HEIGHT,
+        }
+    }
+
+    fn frame(pixel: impl Fn(usize, usize) -> u16) -> RawFrame {
+        RawFrame {
+            pixels: (0..WIDTH * HEIGHT)
+                .map(|i| pixel(i / WIDTH, i % WIDTH))
+                .collect(),
+            dims: dims(),
+            white_level: WHITE,
+            black_level: [BLACK; 4],
+            iso: None,
+            raw_info: TiffRawInfo {
+                make: None,
+                model: None,
+                dng_version: None,
+                width: WIDTH as u32,
+                height: HEIGHT as u32,
+                bits_per_sample: 14,
+                samples_per_pixel: 1,
+                compression: 1,
+                strip_offsets: Vec::new(),
+                strip_byte_counts: Vec::new(),
+                total_bytes: 0,
+                is_sony: true,
+            },
+            ctx: ExifContext::new(),
+        }
+    }
+
+    // Warm-up glow growing across the frame, as a long exposure's dark picks up
+    fn thermal(row: usize, col: usize) -> u16 {
+        (row * 7 + col * 11) as u16
+    }
+
+    // The optics pass 80% on the left half and 120% on the right, 100% on average
+    fn vignette(_row: usize, col: usize) -> f32 {
+        if col < WIDTH / 2 { 0.8 } else { 1.2 }
+    }
+
+    fn calibrate(mut raw: Vec<u16>, dark: Option<&RawFrame>, flat: Option<&RawFrame>) -> Vec<u16> {
+        apply_calibration(&mut raw, dims(), [BLACK; 4], WHITE, dark, flat);
+        raw
+    }
+
+    #[test]
+    fn dark_frames_are_subtracted_with_the_black_level_kept() {
+        let dark = frame(|row, col| BLACK + thermal(row, col));
+        let mut raw = frame(|row, col| BLACK + 1000 + thermal(row, col)).pixels;
+        raw[3] = WHITE;
+        // Noise took this one below its dark
+        raw[9] = BLACK + thermal(1, 1) - 40;
+
+        let out = calibrate(raw, Some(&dark), None);
+        assert_eq!(out[3], WHITE, "clipped samples stay clipped");
+        assert_eq!(out[9], BLACK - 40);
+        let mut rest = out.iter().enumerate().filter(|&(i, _)| i != 3 && i != 9);
+        assert!(rest.all(|(_, &v)| v == BLACK + 1000), "{:?}", out);
+    }
+
+    #[test]
+    fn flats_divide_out_each_channels_falloff() {
+        let flat = frame(|row, col| BLACK + (2000.0 * vignette(row, col)) as u16);
+        let raw = frame(|row, col| BLACK + (1000.0 * vignette(row, col)) as u16).pixels;
+        let out = calibrate(raw, None, Some(&flat));
+        assert!(out.iter().all(|&v| v == BLACK + 1000), "{:?}", out);
+
+        // Together: the dark comes off before the flat divides
+        let dark = frame(|row, col| BLACK + thermal(row, col));
+        let raw =
+            frame(|row, col| BLACK + thermal(row, col) + (1000.0 * vignette(row, col)) as u16)
+                .pixels;
+        let out = calibrate(raw, Some(&dark), Some(&flat));
+        assert!(out.iter().all(|&v| v == BLACK + 1000), "{:?}", out);
+    }
+
+    #[test]
+    fn dead_flat_pixels_are_left_uncorrected() {
+        // One flat site reads black: dividing by it would blow the pixel up
+        let flat = frame(|row, col| {
+            if (row, col) == (2, 2) {
+                BLACK
+            } else {
+                BLACK + 2000
+            }
+        });
+        let raw = frame(|_, _| BLACK + 1000).pixels;
+        let out = calibrate(raw, None, Some(&flat));
+        assert_eq!(out[2 * WIDTH + 2], BLACK + 1000);
+        assert!(out.iter().all(|&v| v < WHITE), "{:?}", out);
+    }
+}
@@ -0,0 +1,216 @@
+use crate::exif::{
+    ExifContext, ExifValue,
+    spec::{CALIBRATION_ILLUMINANT1, CALIBRATION_ILLUMINANT2, COLOR_MATRIX1, COLOR_MATRIX2, MODEL},
+};
+
+pub type Matrix3 = [[f32; 3]; 3];
+
+pub const IDENTITY: Matrix3 = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+// Linear sRGB (D65) to XYZ
+const SRGB_TO_XYZ: Matrix3 = [
+    [0.412_456_4, 0.357_576_1, 0.180_437_5],
+    [0.212_672_9, 0.715_152_2, 0.072_175],
+    [0.019_333_9, 0.119_192, 0.950_304_1],
+];
+
+// Linear Display P3 (D65) to XYZ
+const DISPLAY_P3_TO_XYZ: Matrix3 = [
+    [0.486_570_9, 0.265_667_7, 0.198_217_3],
+    [0.228_974_6, 0.691_738_5, 0.079_286_9],
+    [0.0, 0.045_113_4, 1.043_944_4],
+];
+
+// Linear Adobe RGB (1998) (D65) to XYZ
+const ADOBE_RGB_TO_XYZ: Matrix3 = [
+    [0.576_730_9, 0.185_554, 0.188_185_2],
+    [0.297_376_9, 0.627_349_1, 0.075_274_1],
+    [0.027_034_3, 0.070_687_2, 0.991_108_5],
+];
+
+// Linear Rec. 2020 (D65) to XYZ
+const REC2020_TO_XYZ: Matrix3 = [
+    [0.636_958, 0.144_616_9, 0.168_881],
+    [0.262_700_2, 0.677_998_1, 0.059_301_7],
+    [0.0, 0.028_072_7, 1.060_985_1],
+];
+
+// XYZ -> camera matrices (D65) from Adobe's DNG converter, scaled by 10000 like dcraw's adobe_coeff
+#[rustfmt::skip]
+const SONY_XYZ_TO_CAM: &[(&str, [i16; 9])] = &[
+    ("ILCE-1", [8161, -2947, -739, -4811, 12668, 2389, -437, 1229, 6524]),
+    ("ILCE-6000", [5991, -1456, -455, -4764, 12135, 2980, -707, 1425, 6701]),
+    ("ILCE-7", [5271, -712, -347, -6153, 13653, 2763, -1601, 2366, 7242]),
+    ("ILCE-7M2", [5271, -712, -347, -6153, 13653, 2763, -1601, 2366, 7242]),
+    ("ILCE-7M3", [7374, -2389, -551, -5435, 13162, 2519, -1006, 1795, 6552]),
+    ("ILCE-7M4", [7460, -2365, -588, -5687, 13442, 2474, -624, 1156, 6584]),
+    ("ILCE-7R", [4913, -541, -202, -6130, 13513, 2906, -1564, 2151, 7183]),
+    ("ILCE-7RM2", [6629, -1900, -483, -4618, 12349, 2550, -622, 1381, 6514]),
+    ("ILCE-7RM3", [6640, -1847, -503, -5238, 13010, 2474, -993, 1673, 6527]),
+    ("ILCE-7RM4", [7662, -2686, -660, -5240, 12965, 2530, -796, 1508, 6167]),
+    ("ILCE-7S", [5838, -1430, -246, -3497, 11477, 2297, -748, 1885, 5778]),
+    ("ILCE-7SM2", [5838, -1430, -246, -3497, 11477, 2297, -748, 1885, 5778]),
+    ("ILCE-7SM3", [6912, -2127, -469, -4470, 12175, 2587, -398, 1478, 6492]),
+];
+
+// Bodies we have no entry for get the A7 III matrix, which is close for most recent sensors
+const DEFAULT_SONY_MODEL: &str = "ILCE-7M3";
+
+/// Colour space the raw develop renders into.
+#[repr(C)]
+#[allow(dead_code)] // variants are constructed from C
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputColorSpace {
+    /// White-balanced camera RGB with no matrix applied
+    Camera = 0,
+    #[default]
+    Srgb = 1,
+    DisplayP3 = 2,
+    AdobeRgb = 3,
+    Rec2020 = 4,
+}
+
+impl OutputColorSpace {
+    // XYZ -> linear output RGB, None for camera space
+    fn xyz_to_linear(self) -> Option<Matrix3> {
+        match self {
+            OutputColorSpace::Camera => None,
+            OutputColorSpace::Srgb => invert(&SRGB_TO_XYZ),
+            OutputColorSpace::DisplayP3 => invert(&DISPLAY_P3_TO_XYZ),
+            OutputColorSpace::AdobeRgb => invert(&ADOBE_RGB_TO_XYZ),
+            OutputColorSpace::Rec2020 => invert(&REC2020_TO_XYZ),
+        }
+    }
+}
+
+pub fn multiply(a: &Matrix3, b: &Matrix3) -> Matrix3 {
+    let mut out = [[0.0f32; 3]; 3];
+    for (i, row) in out.iter_mut().enumerate() {
+        for (j, v) in row.iter_mut().enumerate() {
+            *v = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    out
+}
+
+#[inline(always)]
+pub fn apply(m: &Matrix3, v: [f32; 3]) -> [f32; 3] {
+    m.map(|row| row[0] * v[0] + row[1] * v[1] + row[2] * v[2])
+}
+
+pub fn invert(m: &Matrix3) -> Option<Matrix3> {
+    let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+        - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    let inv_det = 1.0 / det;
+
+    Some([
+        [
+            (m[1][1] * m[2][2] - m[1][2] * m[2][1]) * inv_det,
+            (m[0][2] * m[2][1] - m[0][1] * m[2][2]) * inv_det,
+            (m[0][1] * m[1][2] - m[0][2] * m[1][1]) * inv_det,
+        ],
+        [
+            (m[1][2] * m[2][0] - m[1][0] * m[2][2]) * inv_det,
+            (m[0][0] * m[2][2] - m[0][2] * m[2][0]) * inv_det,
+            (m[0][2] * m[1][0] - m[0][0] * m[1][2]) * inv_det,
+        ],
+        [
+            (m[1][0] * m[2][1] - m[1][1] * m[2][0]) * inv_det,
+            (m[0][1] * m[2][0] - m[0][0] * m[2][1]) * inv_det,
+            (m[0][0] * m[1][1] - m[0][1] * m[1][0]) * inv_det,
+        ],
+    ])
+}
+
+fn ratio(n: f32, d: f32) -> f32 {
+    if d == 0.0 { 0.0 } else { n / d }
+}
+
+fn srational_matrix(value: &ExifValue) -> Option<Matrix3> {
+    let v: Vec<f32> = match value {
+        ExifValue::SRational(v) => v.iter().map(|&(n, d)| ratio(n as f32, d as f32)).collect(),
+        ExifValue::Rational(v) => v.iter().map(|&(n, d)| ratio(n as f32, d as f32)).collect(),
+        _ => return None,
+    };
+    if v.len() < 9 {
+        return None;
+    }
+    Some([[v[0], v[1], v[2]], [v[3], v[4], v[5]], [v[6], v[7], v[8]]])
+}
+
+/// EXIF LightSource code for D65, the illuminant our Sony table matrices are for.
+pub const ILLUMINANT_D65: u16 = 21;
+
+/// XYZ -> camera matrix for the file: the DNG ColorMatrix when present (ColorMatrix2 is
+/// the D65 one), otherwise our Sony table keyed on the EXIF model.
+pub fn xyz_to_camera(ctx: &ExifContext) -> Matrix3 {
+    xyz_to_camera_calibrated(ctx).0
+}
+
+/// Like `xyz_to_camera`, with the EXIF LightSource code of the illuminant the matrix
+/// was calibrated under: the CalibrationIlluminant paired with the DNG ColorMatrix
+/// picked (None if the file doesn't say), or D65 for our table.
+pub fn xyz_to_camera_calibrated(ctx: &ExifContext) -> (Matrix3, Option<u16>) {
+    for (matrix, illuminant) in [
+        (COLOR_MATRIX2, CALIBRATION_ILLUMINANT2),
+        (COLOR_MATRIX1, CALIBRATION_ILLUMINANT1),
+    ] {
+        if let Some(m) = ctx.get_tag_value(matrix).and_then(srational_matrix) {
+            let illuminant = match ctx.get_tag_value(illuminant) {
+                Some(ExifValue::Short(v)) => v.first().copied(),
+                _ => None,
+            };
+            return (m, illuminant);
+        }
+    }
+
+    let model = match ctx.get_tag_value(MODEL) {
+        Some(ExifValue::Ascii(s)) => s.trim().trim_end_matches('\0'),
+        _ => DEFAULT_SONY_MODEL,
+    };
+
+    let coeffs = SONY_XYZ_TO_CAM
+        .iter()
+        .find(|(m, _)| *m == model)
+        .or_else(|| {
+            SONY_XYZ_TO_CAM
+                .iter()
+                .find(|(m, _)| *m == DEFAULT_SONY_MODEL)
+        })
+        .map(|(_, c)| c)
+        .unwrap();
+
+    let c = coeffs.map(|v| v as f32 / 10000.0);
+    (
+        [[c[0], c[1], c[2]], [c[3], c[4], c[5]], [c[6], c[7], c[8]]],
+        Some(ILLUMINANT_D65),
+    )
+}
+
+/// Matrix taking white-balanced camera RGB to linear RGB in `space`.
+/// Follows dcraw's cam_xyz_coeff: rows of camera->sRGB are normalized so a
+/// white-balanced neutral stays neutral, then inverted.
+pub fn camera_to_output(xyz_to_cam: &Matrix3, space: OutputColorSpace) -> Matrix3 {
+    let Some(from_xyz) = space.xyz_to_linear() else {
+        return IDENTITY;
+    };
+
+    let mut cam_rgb = multiply(xyz_to_cam, &SRGB_TO_XYZ);
+    for row in cam_rgb.iter_mut() {
+        let sum: f32 = row.iter().sum();
+        if sum.abs() > 1e-6 {
+            row.iter_mut().for_each(|v| *v /= sum);
+        }
+    }
+
+    let Some(rgb_cam) = invert(&cam_rgb) else {
+        return IDENTITY;
+    };
+
+    // rgb_cam lands in linear sRGB; re-target if another space was asked for
+    multiply(&multiply(&from_xyz, &SRGB_TO_XYZ), &rgb_cam)
+}
@@ -1,10 +1,21 @@
 use rayon::{
     iter::{IndexedParallelIterator, ParallelIterator},
-    slice::ParallelSliceMut,
+    slice::{ParallelSlice, ParallelSliceMut},
 };
 
 // Minimal dependencies: adjust imports/types to your crate as needed.
-use crate::sony_decoder::Dimensions;
+use crate::{
+    agno_image::Sample,
+    black_level::BlackLevels,
+    color::{self, Matrix3},
+    highlight::HighlightMode,
+    simd,
+    sony_decoder::Dimensions,
+    tone::ToneLut,
+};
+
+// Output rows per parallel task in the row-based renderers; each band normalizes two extra rows
+const BAND_ROWS: usize = 16;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum BayerPattern {
@@ -14,6 +25,35 @@ pub enum BayerPattern {
     // GBRG,
 }
 
+/// Interpolation used for full-size raw renders.
+#[repr(C)]
+#[allow(dead_code)] // variants are constructed from C
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DemosaicAlgorithm {
+    #[default]
+    Bilinear = 0,
+    /// Malvar-He-Cutler gradient-corrected bilinear
+    MalvarHeCutler = 1,
+}
+
+/// Everything the renderers need to turn raw mosaic codes into output pixels.
+#[derive(Clone, Debug)]
+pub struct RenderParams {
+    pub pattern: BayerPattern,
+    /// One level per 2x2 CFA position (see `cfa_index`)
+    pub black_level: BlackLevels,
+    /// The sensor’s maximum code value (e.g., 0x3FFF for 14-bit)
+    pub white_level: u16,
+    /// Gains [R,G,B], e.g. from AsShotNeutral or a gray-world estimate
+    pub wb: [f32; 3],
+    /// Linear -> encoded curve for the integer output formats
+    pub tone: ToneLut,
+    /// Applied to the white-balanced pixel before anything clamps it
+    pub highlight: HighlightMode,
+    /// White-balanced camera RGB -> linear output RGB
+    pub color_matrix: Matrix3,
+}
+
 #[inline(always)]
 fn clamp_i32(x: i32, lo: i32, hi: i32) -> i32 {
     if x < lo {
@@ -25,20 +65,45 @@ fn clamp_i32(x: i32, lo: i32, hi: i32) -> i32 {
     }
 }
 
+// Mirrors out-of-range coordinates back inside by an even distance, so the CFA colour is kept
+#[inline(always)]
+fn reflect(i: i32, n: usize) -> usize {
+    let last = n as i32 - 1;
+    let r = if i < 0 {
+        -i
+    } else if i > last {
+        2 * last - i
+    } else {
+        i
+    };
+    clamp_i32(r, 0, last) as usize
+}
+
 #[inline(always)]
 fn idx(row: usize, col: usize, stride: usize) -> usize {
     row * stride + col
 }
 
+// Position inside the 2x2 CFA quad, used to index per-channel black levels
+#[inline(always)]
+pub fn cfa_index(row: usize, col: usize) -> usize {
+    ((row & 1) << 1) | (col & 1)
+}
+
+// 1 / (white - black) for each CFA position
+fn inv_ranges(black_level: BlackLevels, white_level: u16) -> [f32; 4] {
+    black_level.map(|b| 1.0 / white_level.saturating_sub(b).max(1) as f32)
+}
+
 #[derive(Clone, Copy, PartialEq)]
-enum CfaColor {
+pub enum CfaColor {
     R,
     G,
     B,
 }
 
 #[inline(always)]
-fn cfa_color_at(row: usize, col: usize, pattern: BayerPattern) -> CfaColor {
+pub fn cfa_color_at(row: usize, col: usize, pattern: BayerPattern) -> CfaColor {
     let r = row & 1;
     let c = col & 1;
     match pattern {
@@ -69,11 +134,21 @@ fn cfa_color_at(row: usize, col: usize, pattern: BayerPattern) -> CfaColor {
     }
 }
 
+// Encodes a linear value for output: the tone curve for the integer formats, untouched for float
 #[inline(always)]
-fn tone_u8(v: f32, gamma: f32) -> u8 {
-    // v is linear and roughly in [0,1], but may exceed 1 slightly after WB
-    let n = v.max(0.0).min(1.0);
-    (n.powf(1.0 / gamma.max(0.001)) * 255.0 + 0.5).floor() as u8
+fn tone<T: Sample>(v: f32, lut: &ToneLut) -> T {
+    if T::FORMAT.is_linear() {
+        return T::from_unit(v);
+    }
+    T::from_unit(lut.apply(v))
+}
+
+// Everything after interpolation: highlight handling, colour matrix, and clamping negatives.
+#[inline(always)]
+fn develop(rgb: [f32; 3], params: &RenderParams) -> [f32; 3] {
+    let rgb = params.highlight.apply(rgb, params.wb);
+    // Clamp negatives the colour matrix can produce for saturated colours
+    color::apply(&params.color_matrix, rgb).map(|v| v.max(0.0))
 }
 
 // Returns the black-subtracted, normalized, WB-scaled value at (y,x) as f32 (linear).
@@ -84,11 +159,12 @@ fn sample_wb(
     col: usize,
     stride: usize,
     pattern: BayerPattern,
-    black: u16,
-    inv_range: f32,
+    black: BlackLevels,
+    inv_range: [f32; 4],
     wb: [f32; 3],
 ) -> f32 {
-    let v = raw[idx(row, col, stride)].saturating_sub(black) as f32 * inv_range;
+    let pos = cfa_index(row, col);
+    let v = raw[idx(row, col, stride)].saturating_sub(black[pos]) as f32 * inv_range[pos];
     let gain = match cfa_color_at(row, col, pattern) {
         CfaColor::R => wb[0],
         CfaColor::G => wb[1],
@@ -98,108 +174,470 @@ fn sample_wb(
 }
 
 /// Compact bilinear demosaic with WB applied BEFORE interpolation.
+/// Renders into any `Sample` type: tone-encoded integers or linear f32.
+/// Works on bands of rows: each mosaic row is normalized once into a padded f32 row,
+/// then interpolated and developed a whole row at a time by the `simd` kernels.
 /// - raw: u16 mosaic buffer with stride dims.raw_width
 /// - dims.output_width/height are the image dimensions you want to render
-/// - white_level should be the sensor’s maximum code value (e.g., 0x3FFF for 14-bit)
-/// - wb: gains [R,G,B], e.g. from AsShotNeutral or a gray-world estimate
-pub fn demosaic_bilinear_to_rgb8(
+pub fn demosaic_bilinear<T: Sample>(
     raw: &[u16],
     dims: Dimensions,
-    pattern: BayerPattern,
-    black_level: u16,
-    white_level: u16,
-    wb: [f32; 3],
-    gamma: f32,
-) -> Vec<u8> {
+    params: &RenderParams,
+) -> Vec<T> {
+    let RenderParams {
+        pattern,
+        black_level,
+        white_level,
+        wb,
+        ..
+    } = *params;
+
     let w = dims.output_width;
     let h = dims.output_height;
     let stride = dims.raw_width;
 
+    let mut out = vec![T::DEFAULT_MIN_VALUE; w * h * 3];
+    if w == 0 || h == 0 {
+        return out;
+    }
+
     // Normalization (after black subtraction)
-    let range = white_level.saturating_sub(black_level).max(1) as f32;
-    let inv_range = 1.0 / range;
+    let inv_range = inv_ranges(black_level, white_level);
+    // Row y's normalized samples, with the edge samples repeated once on either side
+    let padded = w + 2;
+    let normalize = |y: usize, dst: &mut [f32]| {
+        let pos = [cfa_index(y, 0), cfa_index(y, 1)];
+        let gain = [0, 1].map(|c| match cfa_color_at(y, c, pattern) {
+            CfaColor::R => wb[0],
+            CfaColor::G => wb[1],
+            CfaColor::B => wb[2],
+        });
+        simd::normalize_row(
+            &raw[y * stride..y * stride + w],
+            pos.map(|p| black_level[p] as f32),
+            pos.map(|p| inv_range[p]),
+            gain,
+            &mut dst[1..=w],
+        );
+        dst[0] = dst[1];
+        dst[w + 1] = dst[w];
+    };
+
+    out.par_chunks_mut(w * 3 * BAND_ROWS)
+        .enumerate()
+        .for_each(|(band, out_band)| {
+            let first = band * BAND_ROWS;
+            let rows = out_band.len() / (w * 3);
 
-    let mut out = vec![0u8; w * h * 3];
+            // Rows first-1 ..= first+rows, clamped at the image edges
+            let mut norm = vec![0f32; (rows + 2) * padded];
+            for (i, dst) in norm.chunks_exact_mut(padded).enumerate() {
+                let y = clamp_i32((first + i) as i32 - 1, 0, (h - 1) as i32) as usize;
+                normalize(y, dst);
+            }
+
+            let mut planes = vec![0f32; 3 * w];
+            for (i, out_row) in out_band.chunks_exact_mut(w * 3).enumerate() {
+                let row = first + i;
+                let red_row = match pattern {
+                    BayerPattern::RGGB => row & 1 == 0,
+                };
+
+                let (r, gb) = planes.split_at_mut(w);
+                let (g, b) = gb.split_at_mut(w);
+                simd::bilinear_row(
+                    &norm[i * padded..(i + 1) * padded],
+                    &norm[(i + 1) * padded..(i + 2) * padded],
+                    &norm[(i + 2) * padded..(i + 3) * padded],
+                    red_row,
+                    [&mut *r, &mut *g, &mut *b],
+                );
+                simd::develop_row([&mut *r, &mut *g, &mut *b], params);
+                if !T::FORMAT.is_linear() {
+                    for plane in [&mut *r, &mut *g, &mut *b] {
+                        params.tone.apply_row(plane);
+                    }
+                }
+
+                for (x, px) in out_row.chunks_exact_mut(3).enumerate() {
+                    px[0] = T::from_unit(r[x]);
+                    px[1] = T::from_unit(g[x]);
+                    px[2] = T::from_unit(b[x]);
+                }
+            }
+        });
+
+    out
+}
+
+/// Malvar-He-Cutler demosaic: bilinear plus a Laplacian correction taken from the
+/// channel sampled at the centre, which keeps edges much sharper for a 5x5 footprint.
+/// WB is applied per sample before interpolation, like the bilinear path.
+pub fn demosaic_mhc<T: Sample>(raw: &[u16], dims: Dimensions, params: &RenderParams) -> Vec<T> {
+    let RenderParams {
+        pattern,
+        black_level,
+        white_level,
+        wb,
+        ..
+    } = *params;
+
+    let w = dims.output_width;
+    let h = dims.output_height;
+    let stride = dims.raw_width;
+    let inv_range = inv_ranges(black_level, white_level);
+
+    let mut out = vec![T::DEFAULT_MIN_VALUE; w * h * 3];
+    if w == 0 || h == 0 {
+        return out;
+    }
 
     out.par_chunks_mut(w * 3)
         .enumerate()
         .for_each(|(row, out_row)| {
-            let y0 = clamp_i32(row as i32 - 1, 0, (h - 1) as i32) as usize;
-            let y2 = clamp_i32(row as i32 + 1, 0, (h - 1) as i32) as usize;
+            let ys = [-2, -1, 0, 1, 2].map(|d| reflect(row as i32 + d, h));
 
             for x in 0..w {
-                let x0 = clamp_i32(x as i32 - 1, 0, (w - 1) as i32) as usize;
-                let x2 = clamp_i32(x as i32 + 1, 0, (w - 1) as i32) as usize;
+                let xs = [-2, -1, 0, 1, 2].map(|d| reflect(x as i32 + d, w));
+                let s = |dy: usize, dx: usize| {
+                    sample_wb(
+                        raw,
+                        ys[dy],
+                        xs[dx],
+                        stride,
+                        pattern,
+                        black_level,
+                        inv_range,
+                        wb,
+                    )
+                };
+
+                let c = s(2, 2);
+                let horiz1 = s(2, 1) + s(2, 3);
+                let vert1 = s(1, 2) + s(3, 2);
+                let diag = s(1, 1) + s(1, 3) + s(3, 1) + s(3, 3);
+                let horiz2 = s(2, 0) + s(2, 4);
+                let vert2 = s(0, 2) + s(4, 2);
 
-                // WB applied per mosaic sample (pre-demosaic)
-                let here = sample_wb(raw, row, x, stride, pattern, black_level, inv_range, wb);
-                let up = sample_wb(raw, y0, x, stride, pattern, black_level, inv_range, wb);
-                let down = sample_wb(raw, y2, x, stride, pattern, black_level, inv_range, wb);
-                let left = sample_wb(raw, row, x0, stride, pattern, black_level, inv_range, wb);
-                let right = sample_wb(raw, row, x2, stride, pattern, black_level, inv_range, wb);
-
-                // Bilinear interpolation with correct G orientation
-                let (mut r, mut g, mut b) = match cfa_color_at(row, x, pattern) {
-                    CfaColor::R => {
-                        let ul =
-                            sample_wb(raw, y0, x0, stride, pattern, black_level, inv_range, wb);
-                        let ur =
-                            sample_wb(raw, y0, x2, stride, pattern, black_level, inv_range, wb);
-                        let dl =
-                            sample_wb(raw, y2, x0, stride, pattern, black_level, inv_range, wb);
-                        let dr =
-                            sample_wb(raw, y2, x2, stride, pattern, black_level, inv_range, wb);
-
-                        let g = (up + down + left + right) * 0.25;
-                        let b = (ul + ur + dl + dr) * 0.25;
-                        (here, g, b)
+                let here = cfa_color_at(row, x, pattern);
+                let (r, g, b) = if here == CfaColor::G {
+                    // Estimates for the colour to the sides and the colour above/below
+                    let h_val = (5.0 * c + 4.0 * horiz1 - diag - horiz2 + 0.5 * vert2) / 8.0;
+                    let v_val = (5.0 * c + 4.0 * vert1 - diag - vert2 + 0.5 * horiz2) / 8.0;
+                    if cfa_color_at(row, x ^ 1, pattern) == CfaColor::R {
+                        (h_val, c, v_val)
+                    } else {
+                        (v_val, c, h_val)
                     }
-                    CfaColor::B => {
-                        let ul =
-                            sample_wb(raw, y0, x0, stride, pattern, black_level, inv_range, wb);
-                        let ur =
-                            sample_wb(raw, y0, x2, stride, pattern, black_level, inv_range, wb);
-                        let dl =
-                            sample_wb(raw, y2, x0, stride, pattern, black_level, inv_range, wb);
-                        let dr =
-                            sample_wb(raw, y2, x2, stride, pattern, black_level, inv_range, wb);
-
-                        let g = (up + down + left + right) * 0.25;
-                        let r = (ul + ur + dl + dr) * 0.25;
-                        (r, g, here)
+                } else {
+                    let g = (4.0 * c + 2.0 * (horiz1 + vert1) - (horiz2 + vert2)) / 8.0;
+                    // The opposite colour of an R site is B and vice versa
+                    let other = (6.0 * c + 2.0 * diag - 1.5 * (horiz2 + vert2)) / 8.0;
+                    if here == CfaColor::R {
+                        (c, g, other)
+                    } else {
+                        (other, g, c)
                     }
-                    CfaColor::G => {
-                        // Decide which color lies horizontally around this G site
-                        let horiz = cfa_color_at(row, x ^ 1, pattern);
-                        let r_val_h = (left + right) * 0.5;
-                        let r_val_v = (up + down) * 0.5;
-                        let b_val_h = r_val_h; // same averages, different assignment
-                        let b_val_v = r_val_v;
-                        let r = if horiz == CfaColor::R {
-                            r_val_h
-                        } else {
-                            r_val_v
-                        };
-                        let b = if horiz == CfaColor::B {
-                            b_val_h
-                        } else {
-                            b_val_v
+                };
+
+                let [r, g, b] = develop([r, g, b], params);
+
+                let o = x * 3;
+                out_row[o] = tone(r, &params.tone);
+                out_row[o + 1] = tone(g, &params.tone);
+                out_row[o + 2] = tone(b, &params.tone);
+            }
+        });
+
+    out
+}
+
+/// Half-size render: every 2x2 CFA quad collapses straight into one RGB pixel
+/// (R, mean of both greens, B) with no interpolation at all.
+/// - quads: quads averaged along each axis per output pixel (1 = half, 2 = quarter, 4 = eighth)
+/// - returns the RGB buffer together with its width and height
+pub fn bin_quads<T: Sample>(
+    raw: &[u16],
+    dims: Dimensions,
+    params: &RenderParams,
+    quads: usize,
+) -> (Vec<T>, usize, usize) {
+    let RenderParams {
+        pattern,
+        black_level,
+        white_level,
+        wb,
+        ..
+    } = *params;
+
+    let block = 2 * quads.max(1);
+    let w = dims.output_width / block;
+    let h = dims.output_height / block;
+    let stride = dims.raw_width;
+
+    if w == 0 || h == 0 {
+        return (Vec::new(), 0, 0);
+    }
+
+    let inv_range = inv_ranges(black_level, white_level);
+
+    let mut out = vec![T::DEFAULT_MIN_VALUE; w * h * 3];
+
+    out.par_chunks_mut(w * 3)
+        .enumerate()
+        .for_each(|(row, out_row)| {
+            let y_start = row * block;
+
+            for x in 0..w {
+                let x_start = x * block;
+                let mut sums = [0f32; 3];
+                let mut counts = [0u32; 3];
+
+                for y in y_start..y_start + block {
+                    for c in x_start..x_start + block {
+                        let pos = cfa_index(y, c);
+                        let v = raw[idx(y, c, stride)].saturating_sub(black_level[pos]) as f32
+                            * inv_range[pos];
+                        let ch = match cfa_color_at(y, c, pattern) {
+                            CfaColor::R => 0,
+                            CfaColor::G => 1,
+                            CfaColor::B => 2,
                         };
-                        (r, here, b)
+                        sums[ch] += v;
+                        counts[ch] += 1;
                     }
-                };
+                }
 
-                // Clamp negatives that might occur from WB/matrix later (if you add one)
-                r = r.max(0.0);
-                g = g.max(0.0);
-                b = b.max(0.0);
+                let rgb = [0, 1, 2].map(|ch| sums[ch] / counts[ch].max(1) as f32 * wb[ch]);
+                let rgb = develop(rgb, params);
 
                 let o = x * 3;
-                out_row[o] = tone_u8(r, gamma);
-                out_row[o + 1] = tone_u8(g, gamma);
-                out_row[o + 2] = tone_u8(b, gamma);
+                for (dst, v) in out_row[o..o + 3].iter_mut().zip(rgb) {
+                    *dst = tone(v, &params.tone);
+                }
             }
         });
 
+    (out, w, h)
+}
+
+/// Develops camera RGB that needed no interpolation, like a pixel-shift composite:
+/// already black-subtracted and normalized, one `[r, g, b]` per output pixel.
+pub fn develop_rgb<T: Sample>(rgb: &[[f32; 3]], width: usize, params: &RenderParams) -> Vec<T> {
+    let mut out = vec![T::DEFAULT_MIN_VALUE; rgb.len() * 3];
+    if width == 0 {
+        return out;
+    }
+
+    out.par_chunks_mut(width * 3)
+        .zip(rgb.par_chunks(width))
+        .for_each(|(out_row, in_row)| {
+            for (dst, px) in out_row.chunks_exact_mut(3).zip(in_row) {
+                let v = develop([0, 1, 2].map(|ch| px[ch] * params.wb[ch]), params);
+                for (d, v) in dst.iter_mut().zip(v) {
+                    *d = tone(v, &params.tone);
+                }
+            }
+        });
     out
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tone::ToneCurve;
+
+    // The per-pixel bilinear the row kernels replaced, kept as the reference they must match
+    fn reference_bilinear<T: Sample>(
+        raw: &[u16],
+        dims: Dimensions,
+        params: &RenderParams,
+    ) -> Vec<T> {
+        let RenderParams {
+            pattern,
+            black_level,
+            white_level,
+            wb,
+            ..
+        } = *params;
+        let (w, h, stride) = (dims.output_width, dims.output_height, dims.raw_width);
+        let inv_range = inv_ranges(black_level, white_level);
+        let s =
+            |y: usize, x: usize| sample_wb(raw, y, x, stride, pattern, black_level, inv_range, wb);
+
+        let mut out = Vec::with_capacity(w * h * 3);
+        for row in 0..h {
+            let y0 = clamp_i32(row as i32 - 1, 0, (h - 1) as i32) as usize;
+            let y2 = clamp_i32(row as i32 + 1, 0, (h - 1) as i32) as usize;
+            for x in 0..w {
+                let x0 = clamp_i32(x as i32 - 1, 0, (w - 1) as i32) as usize;
+                let x2 = clamp_i32(x as i32 + 1, 0, (w - 1) as i32) as usize;
+
+                let here = s(row, x);
+                let cross = (s(y0, x) + s(y2, x) + s(row, x0) + s(row, x2)) * 0.25;
+                let diagonal = (s(y0, x0) + s(y0, x2) + s(y2, x0) + s(y2, x2)) * 0.25;
+                let horizontal = (s(row, x0) + s(row, x2)) * 0.5;
+                let vertical = (s(y0, x) + s(y2, x)) * 0.5;
+                let rgb = match cfa_color_at(row, x, pattern) {
+                    CfaColor::R => [here, cross, diagonal],
+                    CfaColor::B => [diagonal, cross, here],
+                    CfaColor::G if cfa_color_at(row, x ^ 1, pattern) == CfaColor::R => {
+                        [horizontal, here, vertical]
+                    }
+                    CfaColor::G => [vertical, here, horizontal],
+                };
+                out.extend(develop(rgb, params).map(|v| tone::<T>(v, &params.tone)));
+            }
+        }
+        out
+    }
+
+    fn params() -> RenderParams {
+        RenderParams {
+            pattern: BayerPattern::RGGB,
+            black_level: [512, 510, 514, 511],
+            white_level: 16383,
+            wb: [2.1, 1.0, 1.6],
+            tone: ToneLut::new(ToneCurve::Srgb, 2.2, 0.0),
+            highlight: HighlightMode::Blend,
+            color_matrix: [[1.6, -0.5, -0.1], [-0.2, 1.4, -0.2], [0.0, -0.4, 1.4]],
+        }
+    }
+
+    // Pseudo-random 14-bit samples, a few of them clipped, with a stride wider than the image
+    fn mosaic(dims: Dimensions) -> Vec<u16> {
+        (0..dims.raw_width * dims.raw_height)
+            .map(|i| match i % 29 {
+                0 => 16383,
+                _ => ((i as u32).wrapping_mul(2654435761) >> 18) as u16,
+            })
+            .collect()
+    }
+
+    fn max_difference<T: Sample + Into<f64>>(a: &[T], b: &[T]) -> f64 {
+        assert_eq!(a.len(), b.len());
+        a.iter()
+            .zip(b)
+            .map(|(&a, &b)| (a.into() - b.into()).abs())
+            .fold(0.0, f64::max)
+    }
+
+    #[test]
+    fn bilinear_matches_the_per_pixel_reference() {
+        let modes = [
+            HighlightMode::Clip,
+            HighlightMode::Unclip,
+            HighlightMode::Blend,
+            HighlightMode::Reconstruct,
+        ];
+        // Widths that leave a scalar tail after the vector lanes, heights spanning bands
+        for (w, h) in [(13, 5), (37, 19), (71, 35), (3, 2)] {
+            let dims = Dimensions {
+                raw_width: w + 3,
+                raw_height: h,
+                output_width: w,
+                output_height: h,
+            };
+            let raw = mosaic(dims);
+            for highlight in modes {
+                let params = RenderParams {
+                    highlight,
+                    ..params()
+                };
+
+                let diff = max_difference(
+                    &demosaic_bilinear::<u16>(&raw, dims, &params),
+                    &reference_bilinear::<u16>(&raw, dims, &params),
+                );
+                assert!(
+                    diff <= 1.0,
+                    "{}x{} {:?} u16 output differs by {}",
+                    w,
+                    h,
+                    highlight,
+                    diff
+                );
+                let diff = max_difference(
+                    &demosaic_bilinear::<u8>(&raw, dims, &params),
+                    &reference_bilinear::<u8>(&raw, dims, &params),
+                );
+                assert!(
+                    diff <= 1.0,
+                    "{}x{} {:?} u8 output differs by {}",
+                    w,
+                    h,
+                    highlight,
+                    diff
+                );
+                let diff = max_difference(
+                    &demosaic_bilinear::<f32>(&raw, dims, &params),
+                    &reference_bilinear::<f32>(&raw, dims, &params),
+                );
+                assert!(
+                    diff <= 1e-5,
+                    "{}x{} {:?} f32 output differs by {}",
+                    w,
+                    h,
+                    highlight,
+                    diff
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn bin_quads_averages_each_block_per_channel() {
+        let dims = Dimensions {
+            raw_width: 12,
+            raw_height: 6,
+            output_width: 10,
+            output_height: 6,
+        };
+        // R and B count the quads, the two greens differ within every quad
+        let raw: Vec<u16> = (0..dims.raw_width * dims.raw_height)
+            .map(|i| {
+                let (y, x) = (i / dims.raw_width, i % dims.raw_width);
+                let quad = (y / 2 * 10 + x / 2) as u16;
+                match (y & 1, x & 1) {
+                    (0, 0) => 100 + quad,
+                    (1, 1) => 500 + quad,
+                    (0, _) => 200,
+                    _ => 400,
+                }
+            })
+            .collect();
+        let params = RenderParams {
+            black_level: [0; 4],
+            white_level: 1000,
+            wb: [1.0; 3],
+            highlight: HighlightMode::Unclip,
+            color_matrix: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+            ..params()
+        };
+        let expected = |quad: f32| [(100.0 + quad) / 1000.0, 0.3, (500.0 + quad) / 1000.0];
+        let assert_close = |got: &[f32], want: [f32; 3]| {
+            assert!(
+                got.iter().zip(want).all(|(g, w)| (g - w).abs() < 1e-6),
+                "{:?} vs {:?}",
+                got,
+                want
+            );
+        };
+
+        // Half size: one pixel per quad, the crop column dropped
+        let (out, w, h) = bin_quads::<f32>(&raw, dims, &params, 1);
+        assert_eq!((w, h), (5, 3));
+        for (i, px) in out.chunks_exact(3).enumerate() {
+            assert_close(px, expected(((i / 5) * 10 + i % 5) as f32));
+        }
+
+        // Quarter size: 2x2 quads per pixel, the partial block at the edge dropped
+        let (out, w, h) = bin_quads::<f32>(&raw, dims, &params, 2);
+        assert_eq!((w, h), (2, 1));
+        assert_close(&out[..3], expected((1 + 10 + 11) as f32 / 4.0));
+        assert_close(&out[3..], expected((2 + 3 + 12 + 13) as f32 / 4.0));
+
+        // Eighth size needs an 8x8 block the image doesn't have
+        assert_eq!(bin_quads::<f32>(&raw, dims, &params, 4), (Vec::new(), 0, 0));
+    }
+}
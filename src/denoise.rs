@@ -0,0 +1,335 @@
+use log::debug;
+use rayon::{
+    iter::{
+        IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator,
+        IntoParallelRefMutIterator, ParallelIterator,
+    },
+    slice::{ParallelSlice, ParallelSliceMut},
+};
+
+use crate::{agno_image::Sample, black_level::BlackLevels, sony_decoder::Dimensions};
+
+// dcraw's per-level noise of the hat wavelet, relative to the first
+const WAVELET_NOISE: [f32; 5] = [0.8002, 0.2735, 0.1202, 0.0585, 0.0291];
+
+// Below this ISO the sensor is clean enough that denoising only costs detail
+const AUTO_BASE_ISO: f32 = 800.0;
+
+// Auto strengths grow per stop above the base ISO, up to these
+const AUTO_WAVELET_PER_STOP: f32 = 100.0;
+const MAX_AUTO_WAVELET: f32 = 1000.0;
+const AUTO_CHROMA_STOPS: f32 = 4.0;
+
+// Chroma filter radius in pixels at zero and full strength
+const MIN_CHROMA_RADIUS: usize = 2;
+const MAX_CHROMA_RADIUS: usize = 6;
+
+// Luma difference that stops chroma from bleeding across an edge
+const LUMA_SIGMA: f32 = 0.1;
+
+// Rec. 601 luma weights for splitting colour from brightness
+const LUMA_WEIGHTS: [f32; 3] = [0.299, 0.587, 0.114];
+
+/// Noise reduction for raw develops.
+#[repr(C)]
+#[allow(dead_code)] // variants are constructed from C
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum NoiseReduction {
+    #[default]
+    Off = 0,
+    /// Strengths picked from the ISO the shot was taken at
+    Auto = 1,
+    /// `wavelet_threshold` and `chroma_denoise` from the settings
+    Manual = 2,
+}
+
+/// Wavelet threshold (dcraw `-n` units) and chroma strength in [0, 1] for a shot.
+pub fn resolve_denoise(
+    mode: NoiseReduction,
+    wavelet_threshold: f32,
+    chroma_denoise: f32,
+    iso: Option<u32>,
+) -> (f32, f32) {
+    match mode {
+        NoiseReduction::Off => (0.0, 0.0),
+        NoiseReduction::Manual => (wavelet_threshold.max(0.0), chroma_denoise.clamp(0.0, 1.0)),
+        NoiseReduction::Auto => {
+            let stops = iso.map_or(0.0, |iso| (iso as f32 / AUTO_BASE_ISO).log2());
+            if stops <= 0.0 {
+                return (0.0, 0.0);
+            }
+            let wavelet = (AUTO_WAVELET_PER_STOP * (stops + 1.0)).min(MAX_AUTO_WAVELET);
+            let chroma = (stops / AUTO_CHROMA_STOPS).min(1.0);
+            debug!(
+                "Auto denoise for ISO {:?}: wavelet {}, chroma {}",
+                iso, wavelet, chroma
+            );
+            (wavelet, chroma)
+        }
+    }
+}
+
+// Reflects an index back into 0..n, the way dcraw's hat transform handles the edges
+fn mirror(i: isize, n: usize) -> usize {
+    let n = n as isize;
+    if n == 1 {
+        return 0;
+    }
+    let period = 2 * (n - 1);
+    let i = i.rem_euclid(period);
+    (if i < n { i } else { period - i }) as usize
+}
+
+// One level of the à trous hat filter, 1-2-1 taps `scale` samples apart, rows then columns
+fn hat_smooth(src: &[f32], w: usize, h: usize, scale: usize) -> Vec<f32> {
+    let sc = scale as isize;
+    let mut rows = vec![0f32; w * h];
+    rows.par_chunks_mut(w)
+        .zip(src.par_chunks(w))
+        .for_each(|(out, line)| {
+            for (x, o) in out.iter_mut().enumerate() {
+                let x = x as isize;
+                *o = (2.0 * line[x as usize] + line[mirror(x - sc, w)] + line[mirror(x + sc, w)])
+                    * 0.25;
+            }
+        });
+
+    let mut out = vec![0f32; w * h];
+    out.par_chunks_mut(w).enumerate().for_each(|(y, line)| {
+        let here = &rows[y * w..(y + 1) * w];
+        let up = &rows[mirror(y as isize - sc, h) * w..][..w];
+        let down = &rows[mirror(y as isize + sc, h) * w..][..w];
+        for (x, o) in line.iter_mut().enumerate() {
+            *o = (2.0 * here[x] + up[x] + down[x]) * 0.25;
+        }
+    });
+    out
+}
+
+// Soft-thresholds the detail of each wavelet level, in place
+fn denoise_plane(plane: &mut [f32], w: usize, h: usize, threshold: f32) {
+    let mut low = plane.to_vec();
+    let mut details = vec![0f32; w * h];
+
+    for (lev, noise) in WAVELET_NOISE.iter().enumerate() {
+        let next = hat_smooth(&low, w, h, 1 << lev);
+        let thold = threshold * noise;
+        details
+            .par_iter_mut()
+            .zip(low.par_iter().zip(next.par_iter()))
+            .for_each(|(d, (&l, &n))| {
+                let detail = l - n;
+                *d += if detail < -thold {
+                    detail + thold
+                } else if detail > thold {
+                    detail - thold
+                } else {
+                    0.0
+                };
+            });
+        low = next;
+    }
+
+    plane
+        .par_iter_mut()
+        .zip(details.par_iter().zip(low.par_iter()))
+        .for_each(|(p, (&d, &l))| *p = d + l);
+}
+
+/// Wavelet threshold denoising on the mosaic, like dcraw's `-n`: each CFA position
+/// is taken as its own half-size image, square-rooted so noise is even across
+/// brightness, and soft-thresholded over five levels of the hat wavelet.
+/// - threshold: dcraw `-n` units; 100 is gentle, 1000 strong
+pub fn wavelet_denoise(
+    raw: &mut [u16],
+    dims: Dimensions,
+    black_level: BlackLevels,
+    white_level: u16,
+    threshold: f32,
+) {
+    let (w, h) = (dims.output_width, dims.output_height);
+    // With no white level every sample counts as clipped, so there's nothing to do
+    if threshold <= 0.0 || w < 2 || h < 2 || white_level == 0 {
+        return;
+    }
+
+    // Samples are scaled up to fill 16 bits so thresholds mean the same at any bit depth
+    let scale = white_level.leading_zeros();
+    let stride = dims.raw_width;
+
+    for (pos, &black) in black_level.iter().enumerate() {
+        let (dy, dx) = (pos >> 1, pos & 1);
+        let (pw, ph) = ((w - dx).div_ceil(2), (h - dy).div_ceil(2));
+        let at = |py: usize, px: usize| (2 * py + dy) * stride + 2 * px + dx;
+
+        let src: &[u16] = raw;
+        let mut plane: Vec<f32> = (0..ph)
+            .into_par_iter()
+            .flat_map_iter(|py| {
+                (0..pw).map(move |px| {
+                    let v = src[at(py, px)].saturating_sub(black) as f32;
+                    256.0 * (v * (1 << scale) as f32).sqrt()
+                })
+            })
+            .collect();
+
+        denoise_plane(&mut plane, pw, ph, threshold);
+
+        raw.par_chunks_mut(stride)
+            .enumerate()
+            .skip(dy)
+            .step_by(2)
+            .take(ph)
+            .for_each(|(row, line)| {
+                let py = row / 2;
+                for px in 0..pw {
+                    let v = &mut line[2 * px + dx];
+                    // Clipped sites carry no noise worth removing, and smearing them would dim highlights
+                    if *v >= white_level {
+                        continue;
+                    }
+                    let f = plane[py * pw + px];
+                    let signal = f * f / 65536.0 / (1 << scale) as f32;
+                    *v = (black as f32 + signal)
+                        .round()
+                        .min(white_level as f32 - 1.0) as u16;
+                }
+            });
+    }
+}
+
+// Luma and two colour differences, which chroma denoising smooths separately
+fn to_ycc(rgb: [f32; 3]) -> [f32; 3] {
+    let y = LUMA_WEIGHTS[0] * rgb[0] + LUMA_WEIGHTS[1] * rgb[1] + LUMA_WEIGHTS[2] * rgb[2];
+    [y, rgb[2] - y, rgb[0] - y]
+}
+
+fn from_ycc([y, cb, cr]: [f32; 3]) -> [f32; 3] {
+    let r = y + cr;
+    let b = y + cb;
+    let g = (y - LUMA_WEIGHTS[0] * r - LUMA_WEIGHTS[2] * b) / LUMA_WEIGHTS[1];
+    [r, g, b]
+}
+
+/// Edge-aware (bilateral) smoothing of colour only, after demosaicing: luma is left
+/// alone, and neighbours across a luma edge or of clearly different colour count
+/// for little. Larger strengths widen the window and accept bigger colour differences.
+/// - strength: 0 to 1
+pub fn chroma_denoise<T: Sample>(rgb: &[T], width: usize, height: usize, strength: f32) -> Vec<T> {
+    if strength <= 0.0 || width == 0 {
+        return rgb.to_vec();
+    }
+
+    let radius = MIN_CHROMA_RADIUS
+        + ((MAX_CHROMA_RADIUS - MIN_CHROMA_RADIUS) as f32 * strength).round() as usize;
+    // Wide windows are sampled sparsely; chroma noise is low-frequency enough
+    let step = radius.div_ceil(3);
+    let spatial_sigma = radius as f32 * 0.5;
+    let chroma_sigma = 0.02 + 0.08 * strength;
+
+    let taps: Vec<(isize, isize, f32)> = (-(radius as isize)..=radius as isize)
+        .step_by(step)
+        .flat_map(|dy| {
+            (-(radius as isize)..=radius as isize)
+                .step_by(step)
+                .map(move |dx| {
+                    let d2 = (dy * dy + dx * dx) as f32;
+                    (dy, dx, (-d2 / (2.0 * spatial_sigma * spatial_sigma)).exp())
+                })
+        })
+        .collect();
+
+    let ycc: Vec<[f32; 3]> = rgb
+        .par_chunks(3)
+        .map(|px| to_ycc([px[0].to_unit(), px[1].to_unit(), px[2].to_unit()]))
+        .collect();
+
+    let inv_chroma = 1.0 / (2.0 * chroma_sigma * chroma_sigma);
+    let inv_luma = 1.0 / (2.0 * LUMA_SIGMA * LUMA_SIGMA);
+
+    let mut out = rgb.to_vec();
+    out.par_chunks_mut(width * 3)
+        .enumerate()
+        .take(height)
+        .for_each(|(row, line)| {
+            for (col, px) in line.chunks_exact_mut(3).enumerate() {
+                let [y, cb, cr] = ycc[row * width + col];
+                let (mut sum_cb, mut sum_cr, mut total) = (0f32, 0f32, 0f32);
+                for &(dy, dx, ws) in &taps {
+                    let r = mirror(row as isize + dy, height);
+                    let c = mirror(col as isize + dx, width);
+                    let [ny, ncb, ncr] = ycc[r * width + c];
+                    let dc = (ncb - cb) * (ncb - cb) + (ncr - cr) * (ncr - cr);
+                    let dl = (ny - y) * (ny - y);
+                    let wgt = ws * (-dc * inv_chroma - dl * inv_luma).exp();
+                    sum_cb += ncb * wgt;
+                    sum_cr += ncr * wgt;
+                    total += wgt;
+                }
+                let smoothed = from_ycc([y, sum_cb / total, sum_cr / total]);
+                for (dst, v) in px.iter_mut().zip(smoothed) {
+                    *dst = T::from_unit(v);
+                }
+            }
+        });
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auto_strength_follows_stops_above_base_iso() {
+        let auto = |iso| resolve_denoise(NoiseReduction::Auto, 0.0, 0.0, iso);
+        assert_eq!(auto(None), (0.0, 0.0));
+        assert_eq!(auto(Some(100)), (0.0, 0.0));
+        assert_eq!(auto(Some(800)), (0.0, 0.0));
+        assert_eq!(auto(Some(1600)), (200.0, 0.25));
+        assert_eq!(auto(Some(12800)), (500.0, 1.0));
+        // Both strengths saturate at very high ISOs
+        assert_eq!(auto(Some(409600)), (1000.0, 1.0));
+        assert_eq!(auto(Some(u32::MAX)), (1000.0, 1.0));
+    }
+
+    #[test]
+    fn manual_strengths_are_clamped_and_off_ignores_them() {
+        assert_eq!(
+            resolve_denoise(NoiseReduction::Manual, 300.0, 0.5, Some(25600)),
+            (300.0, 0.5)
+        );
+        assert_eq!(
+            resolve_denoise(NoiseReduction::Manual, -10.0, 2.0, None),
+            (0.0, 1.0)
+        );
+        assert_eq!(
+            resolve_denoise(NoiseReduction::Off, 300.0, 0.5, Some(25600)),
+            (0.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn mirror_reflects_without_repeating_the_edge() {
+        let reflected: Vec<usize> = (-4..9).map(|i| mirror(i, 5)).collect();
+        assert_eq!(reflected, [4, 3, 2, 1, 0, 1, 2, 3, 4, 3, 2, 1, 0]);
+        // Offsets wider than the image keep bouncing between the edges
+        assert_eq!(mirror(-7, 3), 1);
+        assert_eq!(mirror(6, 2), 0);
+        assert!((-20..20).all(|i| mirror(i, 1) == 0));
+    }
+
+    #[test]
+    fn flat_images_survive_smoothing_and_chroma_denoise() {
+        let (w, h) = (3, 2);
+        let plane = vec![0.25f32; w * h];
+        for scale in [1, 2, 4, 8] {
+            assert_eq!(hat_smooth(&plane, w, h, scale), plane);
+        }
+
+        let rgb: Vec<f32> = [0.6, 0.3, 0.1].repeat(w * h);
+        let out = chroma_denoise(&rgb, w, h, 1.0);
+        for (a, b) in out.iter().zip(&rgb) {
+            assert!((a - b).abs() < 1e-5, "{a} vs {b}");
+        }
+    }
+}
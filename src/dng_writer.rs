@@ -0,0 +1,509 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+};
+
+use crate::{
+    agno_image::load::decode_sony_raw,
+    black_level::{active_area_from_exif, resolve_black_levels},
+    color::{Matrix3, OutputColorSpace, camera_to_output, xyz_to_camera_calibrated},
+    demosaic::{BayerPattern, RenderParams, bin_quads},
+    exif::{
+        ExifContext, ExifValue,
+        spec::{
+            ARTIST, AS_SHOT_NEUTRAL, BITS_PER_SAMPLE, BLACK_LEVEL_REPEAT_DIM,
+            CALIBRATION_ILLUMINANT1, CFAPATTERN2, CFAREPEAT_PATTERN_DIM, COLOR_MATRIX1,
+            COMPRESSION, COPYRIGHT, DEFAULT_CROP_ORIGIN, DEFAULT_CROP_SIZE, DNG_BLACK_LEVEL,
+            DNGBACKWARD_VERSION, DNGVERSION, EXIF_OFFSET, ExifField, ExifSection, IMAGE_HEIGHT,
+            IMAGE_WIDTH, MAKE, MODEL, MODIFY_DATE, ORIENTATION, PHOTOMETRIC_INTERPRETATION,
+            PLANAR_CONFIGURATION, ROWS_PER_STRIP, SAMPLES_PER_PIXEL, SOFTWARE, STRIP_BYTE_COUNTS,
+            STRIP_OFFSETS, SUB_IFDS, SUBFILE_TYPE, UNIQUE_CAMERA_MODEL, WHITE_LEVEL,
+            get_exif_field,
+        },
+    },
+    highlight::HighlightMode,
+    ljpeg,
+    sony_decoder::{DecodeError, Dimensions, SonyLoadResult},
+    tiff::{TiffRawInfo, detect_sony_raw},
+    tone::{ToneCurve, ToneLut},
+    white_balance::as_shot_gains,
+};
+
+// Long edge of the embedded preview, in pixels (at most; binning only divides by whole quads)
+const PREVIEW_MAX_EDGE: usize = 1024;
+
+// Scale for writing float matrices and neutrals as (s)rationals
+const RATIONAL_SCALE: f32 = 10000.0;
+
+// TIFF field types
+const TYPE_BYTE: u16 = 1;
+const TYPE_ASCII: u16 = 2;
+const TYPE_SHORT: u16 = 3;
+const TYPE_LONG: u16 = 4;
+const TYPE_RATIONAL: u16 = 5;
+const TYPE_UNDEFINED: u16 = 7;
+const TYPE_SLONG: u16 = 9;
+const TYPE_SRATIONAL: u16 = 10;
+
+// TIFF / DNG tag values
+const COMPRESSION_NONE: u16 = 1;
+const COMPRESSION_LJPEG: u16 = 7;
+const PHOTOMETRIC_RGB: u16 = 2;
+const PHOTOMETRIC_CFA: u16 = 32803;
+const SUBFILE_PREVIEW: u32 = 1;
+const SUBFILE_MAIN: u32 = 0;
+
+/// How the raw mosaic is stored inside the DNG.
+#[repr(C)]
+#[allow(dead_code)] // variants are constructed from C
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DngCompression {
+    /// 16-bit little-endian samples, one strip
+    #[default]
+    Uncompressed = 0,
+    /// Lossless JPEG (compression 7), usually about half the size
+    LosslessJpeg = 1,
+}
+
+type Entry = (u16, ExifValue);
+
+fn entry(field: ExifField, value: ExifValue) -> Entry {
+    (field.tag, value)
+}
+
+fn too_large() -> DecodeError {
+    DecodeError::CorruptData("DNG output exceeds 4 GB")
+}
+
+fn to_u32(v: u64) -> Result<u32, DecodeError> {
+    u32::try_from(v).map_err(|_| too_large())
+}
+
+// TIFF type, value count and little-endian payload of one entry
+fn encode_value(value: &ExifValue, byte_type: u16) -> (u16, usize, Vec<u8>) {
+    match value {
+        ExifValue::Byte(v) => (byte_type, v.len(), v.clone()),
+        ExifValue::Ascii(s) => {
+            let mut bytes = s.trim_end_matches('\0').as_bytes().to_vec();
+            bytes.push(0);
+            (TYPE_ASCII, bytes.len(), bytes)
+        }
+        ExifValue::Short(v) => (
+            TYPE_SHORT,
+            v.len(),
+            v.iter().flat_map(|n| n.to_le_bytes()).collect(),
+        ),
+        ExifValue::Long(v) => (
+            TYPE_LONG,
+            v.len(),
+            v.iter().flat_map(|n| n.to_le_bytes()).collect(),
+        ),
+        ExifValue::Rational(v) => (
+            TYPE_RATIONAL,
+            v.len(),
+            v.iter()
+                .flat_map(|(n, d)| n.to_le_bytes().into_iter().chain(d.to_le_bytes()))
+                .collect(),
+        ),
+        ExifValue::SLong(v) => (
+            TYPE_SLONG,
+            v.len(),
+            v.iter().flat_map(|n| n.to_le_bytes()).collect(),
+        ),
+        ExifValue::SRational(v) => (
+            TYPE_SRATIONAL,
+            v.len(),
+            v.iter()
+                .flat_map(|(n, d)| n.to_le_bytes().into_iter().chain(d.to_le_bytes()))
+                .collect(),
+        ),
+    }
+}
+
+// Keeps IFDs and their out-of-line values on word boundaries, as TIFF requires
+fn pad_to_even<W: Write + Seek>(w: &mut W, base: u64) -> Result<(), DecodeError> {
+    if !(w.stream_position()? - base).is_multiple_of(2) {
+        w.write_all(&[0])?;
+    }
+    Ok(())
+}
+
+/// Writes one IFD (with no next IFD) followed by the values that don't fit in their
+/// entries, and returns its offset from `base`.
+/// - byte_type: field type for `ExifValue::Byte`, BYTE or UNDEFINED depending on the IFD
+fn write_ifd<W: Write + Seek>(
+    w: &mut W,
+    base: u64,
+    mut entries: Vec<Entry>,
+    byte_type: u16,
+) -> Result<u32, DecodeError> {
+    pad_to_even(w, base)?;
+    entries.sort_by_key(|(tag, _)| *tag);
+
+    let start = w.stream_position()? - base;
+    let mut data_pos = start + 2 + 12 * entries.len() as u64 + 4;
+    let mut table = Vec::with_capacity(2 + 12 * entries.len() + 4);
+    let mut data = Vec::new();
+
+    table.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    for (tag, value) in &entries {
+        let (typ, count, mut bytes) = encode_value(value, byte_type);
+        table.extend_from_slice(&tag.to_le_bytes());
+        table.extend_from_slice(&typ.to_le_bytes());
+        table.extend_from_slice(&(count as u32).to_le_bytes());
+
+        if bytes.len() <= 4 {
+            bytes.resize(4, 0);
+            table.extend_from_slice(&bytes);
+        } else {
+            table.extend_from_slice(&to_u32(data_pos)?.to_le_bytes());
+            if !bytes.len().is_multiple_of(2) {
+                bytes.push(0);
+            }
+            data_pos += bytes.len() as u64;
+            data.extend_from_slice(&bytes);
+        }
+    }
+    table.extend_from_slice(&0u32.to_le_bytes());
+
+    to_u32(data_pos)?;
+    w.write_all(&table)?;
+    w.write_all(&data)?;
+
+    to_u32(start)
+}
+
+// Writes a blob of strip data and returns its offset from `base`
+fn write_strip<W: Write + Seek>(w: &mut W, base: u64, bytes: &[u8]) -> Result<u32, DecodeError> {
+    pad_to_even(w, base)?;
+    let offset = to_u32(w.stream_position()? - base)?;
+    w.write_all(bytes)?;
+    to_u32(w.stream_position()? - base)?;
+    Ok(offset)
+}
+
+fn float_to_srational(v: f32) -> (i32, i32) {
+    ((v * RATIONAL_SCALE).round() as i32, RATIONAL_SCALE as i32)
+}
+
+fn float_to_rational(v: f32) -> (u32, u32) {
+    (
+        (v.max(0.0) * RATIONAL_SCALE).round() as u32,
+        RATIONAL_SCALE as u32,
+    )
+}
+
+fn matrix_value(m: &Matrix3) -> ExifValue {
+    ExifValue::SRational(m.iter().flatten().map(|&v| float_to_srational(v)).collect())
+}
+
+fn ascii(ctx: &ExifContext, field: ExifField) -> Option<&str> {
+    match ctx.get_tag_value(field) {
+        Some(ExifValue::Ascii(s)) => Some(s.trim().trim_end_matches('\0')),
+        _ => None,
+    }
+}
+
+// Smallest bit depth holding every sample, which is what the LJPEG stream declares
+fn sample_precision(samples: &[u16]) -> u32 {
+    let max = samples.iter().copied().max().unwrap_or(0);
+    (u16::BITS - max.leading_zeros()).max(8)
+}
+
+// Half-size-or-smaller sRGB render of the mosaic for viewers that don't develop raws
+fn render_preview(raw: &[u16], dims: Dimensions, params: &RenderParams) -> (Vec<u8>, usize, usize) {
+    let long_edge = dims.output_width.max(dims.output_height);
+    let quads = long_edge.div_ceil(2 * PREVIEW_MAX_EDGE).max(1);
+    bin_quads::<u8>(raw, dims, params, quads)
+}
+
+/// Writes a decoded raw as a DNG: a CFA SubIFD holding the mosaic, an RGB preview in
+/// IFD0 and the source's EXIF IFD copied alongside.
+/// - result: the decoded mosaic, `raw_info.width` samples per row
+/// - raw_info: dimensions of the mosaic as detected in the source file
+/// - exif: metadata of the source file; camera matrix, WB and black levels come from here
+pub fn write_dng<W: Write + Seek>(
+    w: &mut W,
+    result: &SonyLoadResult,
+    raw_info: &TiffRawInfo,
+    exif: &ExifContext,
+    compression: DngCompression,
+) -> Result<(), DecodeError> {
+    let width = raw_info.width as usize;
+    let height = raw_info.height as usize;
+    if width == 0 || height == 0 || result.pixels.len() < width * height {
+        return Err(DecodeError::CorruptData(
+            "Raw buffer is smaller than the image it describes",
+        ));
+    }
+    let pixels = &result.pixels[..width * height];
+
+    let dims = Dimensions {
+        raw_width: width,
+        raw_height: height,
+        output_width: width,
+        output_height: height,
+    };
+
+    let white_level = result.white_level;
+    let black_level = resolve_black_levels(exif, pixels, dims, white_level);
+    let (xyz_to_cam, illuminant) = xyz_to_camera_calibrated(exif);
+    let wb = as_shot_gains(exif).unwrap_or([1.0; 3]);
+
+    let params = RenderParams {
+        pattern: BayerPattern::RGGB,
+        black_level,
+        white_level,
+        wb,
+        tone: ToneLut::new(ToneCurve::Srgb, 2.2, 0.0),
+        highlight: HighlightMode::Clip,
+        color_matrix: camera_to_output(&xyz_to_cam, OutputColorSpace::Srgb),
+    };
+    let (preview, preview_w, preview_h) = render_preview(pixels, dims, &params);
+
+    let (raw_compression, raw_bytes) = match compression {
+        DngCompression::Uncompressed => (
+            COMPRESSION_NONE,
+            pixels.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        ),
+        DngCompression::LosslessJpeg => (
+            COMPRESSION_LJPEG,
+            ljpeg::encode(pixels, width, height, sample_precision(pixels))?,
+        ),
+    };
+
+    let base = w.stream_position()?;
+    // Little-endian header; the IFD0 offset is patched in once everything else is placed
+    w.write_all(b"II*\0")?;
+    w.write_all(&0u32.to_le_bytes())?;
+
+    let preview_offset = write_strip(w, base, &preview)?;
+    let raw_offset = write_strip(w, base, &raw_bytes)?;
+
+    // Only tags the spec places in the Exif IFD; MakerNote and pointers aren't in the spec
+    let exif_entries: Vec<Entry> = exif
+        .tags()
+        .filter(|(tag, _)| get_exif_field(*tag).is_some_and(|f| f.section == ExifSection::ExifIFD))
+        .map(|(tag, value)| (tag, value.clone()))
+        .collect();
+    let exif_offset = if exif_entries.is_empty() {
+        None
+    } else {
+        Some(write_ifd(w, base, exif_entries, TYPE_UNDEFINED)?)
+    };
+
+    let mut raw_entries = vec![
+        entry(SUBFILE_TYPE, ExifValue::Long(vec![SUBFILE_MAIN])),
+        entry(IMAGE_WIDTH, ExifValue::Long(vec![raw_info.width])),
+        entry(IMAGE_HEIGHT, ExifValue::Long(vec![raw_info.height])),
+        entry(BITS_PER_SAMPLE, ExifValue::Short(vec![16])),
+        entry(COMPRESSION, ExifValue::Short(vec![raw_compression])),
+        entry(
+            PHOTOMETRIC_INTERPRETATION,
+            ExifValue::Short(vec![PHOTOMETRIC_CFA]),
+        ),
+        entry(STRIP_OFFSETS, ExifValue::Long(vec![raw_offset])),
+        entry(SAMPLES_PER_PIXEL, ExifValue::Short(vec![1])),
+        entry(ROWS_PER_STRIP, ExifValue::Long(vec![raw_info.height])),
+        entry(
+            STRIP_BYTE_COUNTS,
+            ExifValue::Long(vec![to_u32(raw_bytes.len() as u64)?]),
+        ),
+        entry(PLANAR_CONFIGURATION, ExifValue::Short(vec![1])),
+        entry(CFAREPEAT_PATTERN_DIM, ExifValue::Short(vec![2, 2])),
+        // 0 = red, 1 = green, 2 = blue
+        entry(CFAPATTERN2, ExifValue::Byte(vec![0, 1, 1, 2])),
+        entry(BLACK_LEVEL_REPEAT_DIM, ExifValue::Short(vec![2, 2])),
+        entry(
+            DNG_BLACK_LEVEL,
+            ExifValue::Long(black_level.iter().map(|&b| b as u32).collect()),
+        ),
+        entry(WHITE_LEVEL, ExifValue::Long(vec![white_level as u32])),
+    ];
+    if let Some(area) = active_area_from_exif(exif, dims) {
+        raw_entries.push(entry(
+            DEFAULT_CROP_ORIGIN,
+            ExifValue::Long(vec![area.left as u32, area.top as u32]),
+        ));
+        raw_entries.push(entry(
+            DEFAULT_CROP_SIZE,
+            ExifValue::Long(vec![
+                (area.right - area.left) as u32,
+                (area.bottom - area.top) as u32,
+            ]),
+        ));
+    }
+    let raw_ifd_offset = write_ifd(w, base, raw_entries, TYPE_BYTE)?;
+
+    let make = ascii(exif, MAKE).unwrap_or("SONY");
+    let model = ascii(exif, MODEL).unwrap_or("");
+    let unique_model = format!("{} {}", make, model).trim().to_string();
+
+    let mut ifd0_entries = vec![
+        entry(SUBFILE_TYPE, ExifValue::Long(vec![SUBFILE_PREVIEW])),
+        entry(IMAGE_WIDTH, ExifValue::Long(vec![preview_w as u32])),
+        entry(IMAGE_HEIGHT, ExifValue::Long(vec![preview_h as u32])),
+        entry(BITS_PER_SAMPLE, ExifValue::Short(vec![8, 8, 8])),
+        entry(COMPRESSION, ExifValue::Short(vec![COMPRESSION_NONE])),
+        entry(
+            PHOTOMETRIC_INTERPRETATION,
+            ExifValue::Short(vec![PHOTOMETRIC_RGB]),
+        ),
+        entry(MAKE, ExifValue::Ascii(make.to_string())),
+        entry(MODEL, ExifValue::Ascii(model.to_string())),
+        entry(STRIP_OFFSETS, ExifValue::Long(vec![preview_offset])),
+        entry(SAMPLES_PER_PIXEL, ExifValue::Short(vec![3])),
+        entry(ROWS_PER_STRIP, ExifValue::Long(vec![preview_h as u32])),
+        entry(
+            STRIP_BYTE_COUNTS,
+            ExifValue::Long(vec![preview.len() as u32]),
+        ),
+        entry(PLANAR_CONFIGURATION, ExifValue::Short(vec![1])),
+        entry(
+            SOFTWARE,
+            ExifValue::Ascii(format!("agno {}", env!("CARGO_PKG_VERSION"))),
+        ),
+        entry(SUB_IFDS, ExifValue::Long(vec![raw_ifd_offset])),
+        entry(DNGVERSION, ExifValue::Byte(vec![1, 4, 0, 0])),
+        entry(DNGBACKWARD_VERSION, ExifValue::Byte(vec![1, 1, 0, 0])),
+        entry(UNIQUE_CAMERA_MODEL, ExifValue::Ascii(unique_model)),
+        entry(COLOR_MATRIX1, matrix_value(&xyz_to_cam)),
+        // AsShotNeutral is the camera response to white, i.e. the inverse of the WB gains
+        entry(
+            AS_SHOT_NEUTRAL,
+            ExifValue::Rational(
+                wb.iter()
+                    .map(|&g| float_to_rational(if g > 0.0 { 1.0 / g } else { 1.0 }))
+                    .collect(),
+            ),
+        ),
+    ];
+    // Labelled with the illuminant of whichever source matrix was copied
+    if let Some(illuminant) = illuminant {
+        ifd0_entries.push(entry(
+            CALIBRATION_ILLUMINANT1,
+            ExifValue::Short(vec![illuminant]),
+        ));
+    }
+    for field in [ORIENTATION, MODIFY_DATE, ARTIST, COPYRIGHT] {
+        if let Some(value) = exif.get_tag_value(field) {
+            ifd0_entries.push(entry(field, value.clone()));
+        }
+    }
+    if let Some(offset) = exif_offset {
+        ifd0_entries.push(entry(EXIF_OFFSET, ExifValue::Long(vec![offset])));
+    }
+    let ifd0_offset = write_ifd(w, base, ifd0_entries, TYPE_BYTE)?;
+
+    let end = w.stream_position()?;
+    w.seek(SeekFrom::Start(base + 4))?;
+    w.write_all(&ifd0_offset.to_le_bytes())?;
+    w.seek(SeekFrom::Start(end))?;
+    w.flush()?;
+
+    Ok(())
+}
+
+/// Decodes the raw at `src` and writes it to `dst` as a DNG.
+pub fn convert_raw_to_dng(
+    src: &str,
+    dst: &str,
+    compression: DngCompression,
+) -> Result<(), Box<dyn Error>> {
+    let mut file = File::open(src)?;
+    let det = detect_sony_raw(&mut file)?;
+
+    file.seek(SeekFrom::Start(0))?;
+    let ctx = ExifContext::from_reader_auto(&mut file)?;
+    let (decoded, _) = decode_sony_raw(&det, &mut file, &ctx)?;
+
+    let mut out = BufWriter::new(File::create(dst)?);
+    write_dng(&mut out, &decoded, &det.raw, &ctx, compression)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufWriter;
+
+    use super::*;
+    use crate::{black_level::black_levels_from_exif, tiff::SonyVariant};
+
+    // A 14-bit RGGB mosaic of pseudo-random samples, so every bit of every pixel counts
+    fn mosaic(width: u32, height: u32) -> SonyLoadResult {
+        SonyLoadResult {
+            pixels: (0..width * height)
+                .map(|i| (i.wrapping_mul(2654435761) >> 18) as u16)
+                .collect(),
+            white_level: 16383,
+        }
+    }
+
+    fn raw_info(width: u32, height: u32) -> TiffRawInfo {
+        TiffRawInfo {
+            make: None,
+            model: None,
+            dng_version: None,
+            width,
+            height,
+            bits_per_sample: 14,
+            samples_per_pixel: 1,
+            compression: 1,
+            strip_offsets: Vec::new(),
+            strip_byte_counts: Vec::new(),
+            total_bytes: 0,
+            is_sony: true,
+        }
+    }
+
+    #[test]
+    fn round_trips_through_the_raw_loader() {
+        let (width, height) = (38, 22);
+        let source = mosaic(width, height);
+        let neutral = vec![(5000, 10000), (10000, 10000), (2500, 10000)];
+        let mut exif = ExifContext::new();
+        exif.set_tag_value(DNG_BLACK_LEVEL, ExifValue::Long(vec![512, 510, 511, 513]));
+        exif.set_tag_value(AS_SHOT_NEUTRAL, ExifValue::Rational(neutral.clone()));
+
+        for (compression, variant) in [
+            (DngCompression::Uncompressed, SonyVariant::DngUncompressed),
+            (DngCompression::LosslessJpeg, SonyVariant::DngLjpeg),
+        ] {
+            let path = std::env::temp_dir().join(format!(
+                "agno-roundtrip-{}-{:?}.dng",
+                std::process::id(),
+                compression
+            ));
+            let mut out = BufWriter::new(File::create(&path).unwrap());
+            write_dng(
+                &mut out,
+                &source,
+                &raw_info(width, height),
+                &exif,
+                compression,
+            )
+            .unwrap();
+            drop(out);
+
+            let mut file = File::open(&path).unwrap();
+            let det = detect_sony_raw(&mut file).unwrap();
+            assert_eq!(det.variant, variant);
+            file.seek(SeekFrom::Start(0)).unwrap();
+            let ctx = ExifContext::from_reader_auto(&mut file).unwrap();
+            let (decoded, _) = decode_sony_raw(&det, &mut file, &ctx).unwrap();
+            std::fs::remove_file(&path).unwrap();
+
+            assert_eq!(decoded.pixels, source.pixels);
+            assert_eq!(decoded.white_level, source.white_level);
+            assert!(
+                matches!(ctx.get_tag_value(WHITE_LEVEL), Some(ExifValue::Long(v)) if v == &[16383])
+            );
+            assert_eq!(black_levels_from_exif(&ctx), Some([512, 510, 511, 513]));
+            assert!(
+                matches!(ctx.get_tag_value(AS_SHOT_NEUTRAL), Some(ExifValue::Rational(v)) if v == &neutral)
+            );
+        }
+    }
+}
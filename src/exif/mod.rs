@@ -250,7 +250,11 @@ impl ExifContext {
             Ok(typ) => match typ {
                 ImageType::Jpeg => Self::from_jpeg(reader)?,
                 ImageType::Png => Self::from_png(reader)?,
-                ImageType::Webp => return Ok(Self::new()),
+                // Most WebPs carry no EXIF at all
+                ImageType::Webp => match Self::from_webp(reader) {
+                    Err(ExifError::NotExif) => return Ok(Self::new()),
+                    parsed => parsed?,
+                },
                 ImageType::Pdf => return Ok(Self::new()),
                 ImageType::SonyRaw(_) => Self::from_tiff(reader, 0)?,
             },
@@ -350,14 +354,65 @@ impl ExifContext {
         let width = u32::from_be_bytes(size_buf[0..4].try_into().unwrap());
         let height = u32::from_be_bytes(size_buf[4..8].try_into().unwrap());
 
-        let values = HashMap::from([
+        let mut values = HashMap::from([
             (spec::IMAGE_WIDTH.tag, ExifValue::Long(vec![width])),
             (spec::IMAGE_HEIGHT.tag, ExifValue::Long(vec![height])),
         ]);
 
+        // An eXIf chunk holds a plain TIFF stream; skip IHDR's data and CRC to reach the next chunk
+        let mut pos = 33;
+        loop {
+            reader.seek(SeekFrom::Start(pos))?;
+            let mut header = [0u8; 8];
+            if reader.read_exact(&mut header).is_err() {
+                break;
+            }
+            let len = u32::from_be_bytes(header[0..4].try_into().unwrap()) as u64;
+            match &header[4..8] {
+                b"eXIf" => {
+                    let (tiff_base, endian, exif) = Self::from_tiff(reader, pos + 8)?;
+                    values.extend(exif);
+                    return Ok((tiff_base, endian, values));
+                }
+                b"IEND" => break,
+                _ => pos += 12 + len,
+            }
+        }
+
         return Ok((0, Endian::Big, values));
     }
 
+    // Parse EXIF from a WebP's EXIF chunk
+    fn from_webp(reader: &mut File) -> Result<(u64, Endian, HashMap<u16, ExifValue>), ExifError> {
+        reader.seek(SeekFrom::Start(0))?;
+        let header = read_exact_vec(reader, 12)?;
+        if &header[0..4] != b"RIFF" || &header[8..12] != b"WEBP" {
+            return Err(ExifError::NotExif);
+        }
+
+        let mut pos = 12;
+        loop {
+            reader.seek(SeekFrom::Start(pos))?;
+            let mut chunk = [0u8; 8];
+            if reader.read_exact(&mut chunk).is_err() {
+                return Err(ExifError::NotExif);
+            }
+            let len = u32::from_le_bytes(chunk[4..8].try_into().unwrap()) as u64;
+            if &chunk[0..4] == b"EXIF" {
+                // Some writers keep the JPEG APP1 "Exif\0\0" prefix
+                let prefix = read_exact_vec(reader, 6)?;
+                let tiff_base = if &prefix == b"Exif\0\0" {
+                    pos + 14
+                } else {
+                    pos + 8
+                };
+                return Self::from_tiff(reader, tiff_base);
+            }
+            // Chunks are padded to an even length
+            pos += 8 + len + (len & 1);
+        }
+    }
+
     // Parse TIFF-like EXIF at tiff_base (0 for pure TIFF files, or the offset into a JPEG APP1)
     fn from_tiff(
         reader: &mut File,
@@ -457,6 +512,16 @@ impl ExifContext {
     pub fn get_tag_value_by_tag(&self, tag: u16) -> Option<&ExifValue> {
         self.exif_values.get(&tag)
     }
+
+    /// Replaces (or adds) a tag, e.g. to reset Orientation once pixels are upright.
+    pub fn set_tag_value(&mut self, field: ExifField, value: ExifValue) {
+        self.exif_values.insert(field.tag, value);
+    }
+
+    /// Every parsed tag, in no particular order.
+    pub fn tags(&self) -> impl Iterator<Item = (u16, &ExifValue)> {
+        self.exif_values.iter().map(|(&tag, value)| (tag, value))
+    }
 }
 
 fn read_value_bytes(
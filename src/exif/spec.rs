@@ -97,9 +97,11 @@ exif_tags![
     (IMAGE_DESCRIPTION, 0x010e, IFD0, "ImageDescription"),
     (MAKE, 0x010f, IFD0, "Make"),
     (MODEL, 0x0110, IFD0, "Model"),
+    (STRIP_OFFSETS, 0x0111, IFD0, "StripOffsets"),
     (ORIENTATION, 0x0112, IFD0, "Orientation"),
     (SAMPLES_PER_PIXEL, 0x0115, IFD0, "SamplesPerPixel"),
     (ROWS_PER_STRIP, 0x0116, IFD0, "RowsPerStrip"),
+    (STRIP_BYTE_COUNTS, 0x0117, IFD0, "StripByteCounts"),
     (MIN_SAMPLE_VALUE, 0x0118, IFD0, "MinSampleValue"),
     (MAX_SAMPLE_VALUE, 0x0119, IFD0, "MaxSampleValue"),
     (XRESOLUTION, 0x011a, IFD0, "XResolution"),
@@ -144,6 +146,7 @@ exif_tags![
         NONE,
         "ConsecutiveBadFaxLines"
     ),
+    (SUB_IFDS, 0x014a, IFD0, "SubIFDs"),
     (INK_SET, 0x014c, IFD0, "InkSet"),
     (INK_NAMES, 0x014d, NONE, "InkNames"),
     (NUMBEROF_INKS, 0x014e, NONE, "NumberofInks"),
@@ -413,6 +416,27 @@ exif_tags![
     (SONY_RAW_IMAGE_SIZE, 0x7038, SubIFD, "SonyRawImageSize"),
     (BLACK_LEVEL, 0x7310, SubIFD, "BlackLevel"),
     (WB_RGGBLEVELS, 0x7313, SubIFD, "WB_RGGBLevels"),
+    (
+        WB_RGBLEVELS_DAYLIGHT,
+        0x7480,
+        SubIFD,
+        "WB_RGBLevelsDaylight"
+    ),
+    (WB_RGBLEVELS_CLOUDY, 0x7481, SubIFD, "WB_RGBLevelsCloudy"),
+    (
+        WB_RGBLEVELS_TUNGSTEN,
+        0x7482,
+        SubIFD,
+        "WB_RGBLevelsTungsten"
+    ),
+    (WB_RGBLEVELS_FLASH, 0x7483, SubIFD, "WB_RGBLevelsFlash"),
+    (WB_RGBLEVELS_4500K, 0x7484, SubIFD, "WB_RGBLevels4500K"),
+    (
+        WB_RGBLEVELS_FLUORESCENT,
+        0x7486,
+        SubIFD,
+        "WB_RGBLevelsFluorescent"
+    ),
     (SONY_CROP_TOP_LEFT, 0x74c7, SubIFD, "SonyCropTopLeft"),
     (SONY_CROP_SIZE, 0x74c8, SubIFD, "SonyCropSize"),
     (IMAGE_ID, 0x800d, NONE, "ImageID"),
@@ -840,6 +864,7 @@ exif_tags![
         SubIFD,
         "BlackLevelRepeatDim"
     ),
+    (DNG_BLACK_LEVEL, 0xc61a, SubIFD, "BlackLevel"),
     (BLACK_LEVEL_DELTA_H, 0xc61b, SubIFD, "BlackLevelDeltaH"),
     (BLACK_LEVEL_DELTA_V, 0xc61c, SubIFD, "BlackLevelDeltaV"),
     (WHITE_LEVEL, 0xc61d, SubIFD, "WhiteLevel"),
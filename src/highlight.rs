@@ -0,0 +1,157 @@
+// Opponent-space transforms from dcraw's blend_highlights (3-colour case)
+pub const TRANS: [[f32; 3]; 3] = [
+    [1.0, 1.0, 1.0],
+    [1.732_050_8, -1.732_050_8, 0.0],
+    [-1.0, -1.0, 2.0],
+];
+pub const ITRANS: [[f32; 3]; 3] = [
+    [1.0, 0.866_025_4, -0.5],
+    [1.0, -0.866_025_4, -0.5],
+    [1.0, 0.0, 1.0],
+];
+
+// A channel within this fraction of its saturation point counts as clipped
+pub const CLIP_MARGIN: f32 = 0.99;
+
+/// How clipped raw channels are handled, numbered like LibRaw's `highlight`
+/// option (0-2) with our own reconstruction as 3.
+#[repr(C)]
+#[allow(dead_code)] // variants are constructed from C
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HighlightMode {
+    /// Clip every channel at the lowest saturation point so blown areas go neutral white
+    #[default]
+    Clip = 0,
+    /// Leave values alone; clipped areas keep whatever tint WB gives them
+    Unclip = 1,
+    /// Blend clipped and unclipped values in an opponent space for a soft roll to white
+    Blend = 2,
+    /// Rebuild clipped channels from the channels that still hold detail
+    Reconstruct = 3,
+}
+
+impl HighlightMode {
+    /// Applies the mode to one linear, white-balanced pixel.
+    /// - sat: where each channel clips after WB (the WB gain, since raw white normalizes to 1.0)
+    #[inline(always)]
+    pub fn apply(self, rgb: [f32; 3], sat: [f32; 3]) -> [f32; 3] {
+        match self {
+            HighlightMode::Clip => {
+                let clip = sat[0].min(sat[1]).min(sat[2]);
+                rgb.map(|v| v.min(clip))
+            }
+            HighlightMode::Unclip => rgb,
+            HighlightMode::Blend => blend(rgb, sat),
+            HighlightMode::Reconstruct => reconstruct(rgb, sat),
+        }
+    }
+}
+
+// Port of dcraw's blend_highlights: keep the luminance of the unclipped pixel but
+// shrink its chroma to that of the clipped one.
+fn blend(rgb: [f32; 3], sat: [f32; 3]) -> [f32; 3] {
+    let clip = sat[0].min(sat[1]).min(sat[2]);
+    if rgb.iter().all(|&v| v <= clip) {
+        return rgb;
+    }
+
+    let clipped = rgb.map(|v| v.min(clip));
+    let to_lab = |cam: [f32; 3]| TRANS.map(|t| t[0] * cam[0] + t[1] * cam[1] + t[2] * cam[2]);
+
+    let mut lab = to_lab(rgb);
+    let lab_clipped = to_lab(clipped);
+
+    let chroma = lab[1] * lab[1] + lab[2] * lab[2];
+    let chroma_clipped = lab_clipped[1] * lab_clipped[1] + lab_clipped[2] * lab_clipped[2];
+    if chroma > 0.0 {
+        let ratio = (chroma_clipped / chroma).sqrt();
+        lab[1] *= ratio;
+        lab[2] *= ratio;
+    }
+
+    ITRANS.map(|t| (t[0] * lab[0] + t[1] * lab[1] + t[2] * lab[2]) / 3.0)
+}
+
+// Highlights are close to neutral after WB, so a clipped channel can't be darker than
+// the channels that survived: raise it to their mean. Fully clipped pixels go white.
+fn reconstruct(rgb: [f32; 3], sat: [f32; 3]) -> [f32; 3] {
+    let clipped = [0, 1, 2].map(|c| rgb[c] >= sat[c] * CLIP_MARGIN);
+
+    let (sum, count) = (0..3)
+        .filter(|&c| !clipped[c])
+        .fold((0.0f32, 0u32), |(s, n), c| (s + rgb[c], n + 1));
+
+    if count == 0 {
+        let white = sat[0].max(sat[1]).max(sat[2]);
+        return [white; 3];
+    }
+
+    let mean = sum / count as f32;
+    [0, 1, 2].map(|c| if clipped[c] { rgb[c].max(mean) } else { rgb[c] })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // WB gains, so where each channel clips after WB
+    const SAT: [f32; 3] = [2.0, 1.0, 1.5];
+
+    fn chroma(rgb: [f32; 3]) -> f32 {
+        let lab = TRANS.map(|t| t[0] * rgb[0] + t[1] * rgb[1] + t[2] * rgb[2]);
+        (lab[1] * lab[1] + lab[2] * lab[2]).sqrt()
+    }
+
+    #[test]
+    fn clip_cuts_every_channel_at_the_lowest_saturation() {
+        assert_eq!(
+            HighlightMode::Clip.apply([1.8, 0.6, 1.2], SAT),
+            [1.0, 0.6, 1.0]
+        );
+    }
+
+    #[test]
+    fn unclip_leaves_the_pixel_alone() {
+        assert_eq!(
+            HighlightMode::Unclip.apply([1.8, 0.6, 1.2], SAT),
+            [1.8, 0.6, 1.2]
+        );
+    }
+
+    #[test]
+    fn blend_keeps_luminance_and_takes_the_clipped_chroma() {
+        let rgb = [1.8, 0.6, 1.2];
+        let out = HighlightMode::Blend.apply(rgb, SAT);
+        let sum = |v: [f32; 3]| v.iter().sum::<f32>();
+        assert!((sum(out) - sum(rgb)).abs() < 1e-5, "{:?}", out);
+        assert!(
+            (chroma(out) - chroma([1.0, 0.6, 1.0])).abs() < 1e-5,
+            "{:?}",
+            out
+        );
+        // Nothing over the clip point: untouched
+        assert_eq!(
+            HighlightMode::Blend.apply([0.9, 0.6, 0.3], SAT),
+            [0.9, 0.6, 0.3]
+        );
+    }
+
+    #[test]
+    fn reconstruct_raises_clipped_channels_to_the_survivors() {
+        // Green is clipped; red and blue still hold detail
+        assert_eq!(
+            HighlightMode::Reconstruct.apply([1.5, 1.0, 1.2], SAT),
+            [1.5, 1.35, 1.2]
+        );
+        // A clipped channel already above the mean stays where it is
+        assert_eq!(
+            HighlightMode::Reconstruct.apply([2.0, 0.6, 0.9], SAT),
+            [2.0, 0.6, 0.9]
+        );
+        // Nothing left to go on: white at the highest saturation point
+        assert_eq!(
+            HighlightMode::Reconstruct.apply([2.0, 1.0, 1.5], SAT),
+            [2.0; 3]
+        );
+    }
+}
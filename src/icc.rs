@@ -0,0 +1,611 @@
+use std::{error::Error, fs, io::Cursor};
+
+use image::{ImageDecoder, codecs::png::PngDecoder};
+use log::{debug, warn};
+use moxcms::{
+    ColorProfile, DataColorSpace, Layout, LocalizableString, ProfileText, ToneReprCurve,
+    TransformExecutor, TransformOptions,
+};
+use rayon::{
+    iter::{IndexedParallelIterator, ParallelIterator},
+    slice::{ParallelSlice, ParallelSliceMut},
+};
+
+use crate::{
+    agno_image::{AgnoImage, PixelFormat, Sample, load::ImageLoadSettings},
+    color::OutputColorSpace,
+    tone::TransferFn,
+};
+
+// Rows handed to each parallel transform call
+const TRANSFORM_ROWS: usize = 64;
+
+// Pixels per parallel transform call where rows don't matter
+const TRANSFORM_PIXELS: usize = 1 << 16;
+
+// JPEG APP2 segments carrying a profile start with this, then a 1-based sequence
+// number and the segment count
+const JPEG_ICC_SIGNATURE: &[u8] = b"ICC_PROFILE\0";
+
+// TIFF tag holding an embedded profile
+const TIFF_ICC_TAG: u16 = 34675;
+
+/// What non-raw images with an embedded ICC profile are converted to on load.
+#[repr(C)]
+#[allow(dead_code)] // variants are constructed from C
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ColorTarget {
+    #[default]
+    Srgb = 0,
+    /// Leave the decoded values in the embedded profile's space
+    Original = 1,
+    /// The ICC profile at `ImageLoadSettings::target_profile`
+    Profile = 2,
+}
+
+fn be16(b: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(b.get(at..at + 2)?.try_into().ok()?))
+}
+
+fn be32(b: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(b.get(at..at + 4)?.try_into().ok()?))
+}
+
+// Reassembles a profile split over APP2 segments, which may arrive in any order
+fn jpeg_icc(data: &[u8]) -> Option<Vec<u8>> {
+    let mut chunks: Vec<(u8, u8, &[u8])> = Vec::new();
+    let mut i = 2;
+
+    while i + 4 <= data.len() {
+        if data[i] != 0xFF {
+            return None;
+        }
+        let marker = data[i + 1];
+        match marker {
+            // Fill bytes before a marker
+            0xFF => {
+                i += 1;
+                continue;
+            }
+            // Markers without a payload
+            0x01 | 0xD0..=0xD7 => {
+                i += 2;
+                continue;
+            }
+            // Profiles live in the header; scan data and the end hold none
+            0xD9 | 0xDA => break,
+            _ => {}
+        }
+
+        let len = be16(data, i + 2)? as usize;
+        let segment = data.get(i + 4..i + 2 + len)?;
+        if marker == 0xE2 && segment.len() > 14 && segment.starts_with(JPEG_ICC_SIGNATURE) {
+            chunks.push((segment[12], segment[13], &segment[14..]));
+        }
+        i += 2 + len;
+    }
+
+    if chunks.is_empty() {
+        return None;
+    }
+    chunks.sort_by_key(|&(seq, _, _)| seq);
+    let count = chunks[0].1 as usize;
+    let complete = chunks.len() == count
+        && chunks
+            .iter()
+            .enumerate()
+            .all(|(i, &(seq, _, _))| seq as usize == i + 1);
+    if !complete {
+        warn!(
+            "Incomplete ICC profile: {} of {} APP2 segments",
+            chunks.len(),
+            count
+        );
+        return None;
+    }
+
+    Some(
+        chunks
+            .iter()
+            .flat_map(|&(_, _, c)| c.iter().copied())
+            .collect(),
+    )
+}
+
+// The iCCP chunk is zlib compressed, so the png decoder unpacks it
+fn png_icc(data: &[u8]) -> Option<Vec<u8>> {
+    let mut decoder = PngDecoder::new(Cursor::new(data)).ok()?;
+    decoder.icc_profile().ok()?
+}
+
+// RIFF chunks after the "WEBP" form type, each padded to an even length
+fn webp_icc(data: &[u8]) -> Option<Vec<u8>> {
+    let mut i = 12;
+    while i + 8 <= data.len() {
+        let fourcc = &data[i..i + 4];
+        let size = u32::from_le_bytes(data[i + 4..i + 8].try_into().ok()?) as usize;
+        let payload = data.get(i + 8..i + 8 + size)?;
+        if fourcc == b"ICCP" {
+            return Some(payload.to_vec());
+        }
+        i += 8 + size + (size & 1);
+    }
+    None
+}
+
+// Tag 34675 in the first IFD
+fn tiff_icc(data: &[u8]) -> Option<Vec<u8>> {
+    let little = data.starts_with(b"II");
+    let u16_at = |at: usize| -> Option<u16> {
+        let b: [u8; 2] = data.get(at..at + 2)?.try_into().ok()?;
+        Some(if little {
+            u16::from_le_bytes(b)
+        } else {
+            u16::from_be_bytes(b)
+        })
+    };
+    let u32_at = |at: usize| -> Option<u32> {
+        let b: [u8; 4] = data.get(at..at + 4)?.try_into().ok()?;
+        Some(if little {
+            u32::from_le_bytes(b)
+        } else {
+            u32::from_be_bytes(b)
+        })
+    };
+
+    let ifd = u32_at(4)? as usize;
+    let entries = u16_at(ifd)? as usize;
+    (0..entries).find_map(|n| {
+        let entry = ifd + 2 + n * 12;
+        if u16_at(entry)? != TIFF_ICC_TAG {
+            return None;
+        }
+        let count = u32_at(entry + 4)? as usize;
+        let start = if count <= 4 {
+            entry + 8
+        } else {
+            u32_at(entry + 8)? as usize
+        };
+        data.get(start..start + count).map(|p| p.to_vec())
+    })
+}
+
+// ISO BMFF boxes in `data` as (type, payload)
+fn bmff_boxes(data: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut i = 0;
+    std::iter::from_fn(move || {
+        let size = be32(data, i)? as usize;
+        let kind = data.get(i + 4..i + 8)?;
+        let (header, size) = match size {
+            // 64-bit size follows the type
+            1 => {
+                let hi = be32(data, i + 8)? as u64;
+                let lo = be32(data, i + 12)? as u64;
+                (16, ((hi << 32) | lo) as usize)
+            }
+            // Runs to the end of the enclosing box
+            0 => (8, data.len() - i),
+            n => (8, n),
+        };
+        let payload = data.get(i + header..i + size)?;
+        i += size.max(header);
+        Some((kind, payload))
+    })
+}
+
+fn find_box<'a>(data: &'a [u8], kind: &[u8]) -> Option<&'a [u8]> {
+    bmff_boxes(data).find(|&(k, _)| k == kind).map(|(_, p)| p)
+}
+
+// HEIF and AVIF keep profiles in a `colr` item property: meta > iprp > ipco > colr
+fn heif_icc(data: &[u8]) -> Option<Vec<u8>> {
+    // meta is a full box: version and flags come before its children
+    let meta = find_box(data, b"meta")?.get(4..)?;
+    let ipco = find_box(find_box(meta, b"iprp")?, b"ipco")?;
+    bmff_boxes(ipco)
+        .filter(|&(k, _)| k == b"colr")
+        .find_map(|(_, colr)| match colr.get(..4)? {
+            b"prof" | b"rICC" => Some(colr[4..].to_vec()),
+            _ => None,
+        })
+}
+
+/// Finds the ICC profile embedded in a JPEG, PNG, WebP, TIFF or HEIF/AVIF file.
+pub fn extract_icc_profile(data: &[u8]) -> Option<Vec<u8>> {
+    let profile = match data {
+        [0xFF, 0xD8, ..] => jpeg_icc(data),
+        [0x89, b'P', b'N', b'G', ..] => png_icc(data),
+        [b'R', b'I', b'F', b'F', ..] if data.get(8..12) == Some(b"WEBP") => webp_icc(data),
+        [b'I', b'I', 42, 0, ..] | [b'M', b'M', 0, 42, ..] => tiff_icc(data),
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => heif_icc(data),
+        _ => None,
+    };
+    profile.filter(|p| !p.is_empty())
+}
+
+pub fn icc_profile_from_file(path: &str) -> Result<Option<Vec<u8>>, std::io::Error> {
+    Ok(extract_icc_profile(&fs::read(path)?))
+}
+
+// Transforms in place, `chunk_len` samples (whole pixels) per parallel call
+fn transform_in_place<T: Sample + Copy + Default>(
+    samples: &mut [T],
+    chunk_len: usize,
+    transform: &(dyn TransformExecutor<T> + Send + Sync),
+) -> Result<(), Box<dyn Error>> {
+    samples
+        .par_chunks_mut(chunk_len.max(1))
+        .try_for_each(|chunk| {
+            let src = chunk.to_vec();
+            transform.transform(&src, chunk)
+        })
+        .map_err(|e| format!("Colour transform failed: {:?}", e).into())
+}
+
+// Something converted for the settings, with the ICC bytes describing it (None for sRGB)
+type WithProfile<T> = (T, Option<Vec<u8>>);
+
+// The profile to convert into for the settings, with its file's bytes unless it's
+// sRGB. `ColorTarget::Original` falls back to sRGB.
+fn target_profile(
+    settings: &ImageLoadSettings,
+) -> Result<WithProfile<ColorProfile>, Box<dyn Error>> {
+    if settings.color_target != ColorTarget::Profile {
+        return Ok((ColorProfile::new_srgb(), None));
+    }
+    let Some(path) = &settings.target_profile else {
+        return Err("ColorTarget::Profile needs a target_profile path".into());
+    };
+    let bytes = fs::read(path)?;
+    let target = ColorProfile::new_from_slice(&bytes)
+        .map_err(|e| format!("Unreadable target profile {}: {:?}", path.display(), e))?;
+    Ok((target, Some(bytes)))
+}
+
+/// Converts a decoded image from its embedded profile to the target in the settings,
+/// returning the profile that describes the pixels afterwards (None for sRGB).
+/// A profile that doesn't parse or isn't RGB leaves the pixels as they are.
+pub fn convert_from_profile(
+    img: &mut AgnoImage,
+    profile: &[u8],
+    settings: &ImageLoadSettings,
+) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    // Grey pixels stay grey, described by their own profile
+    if img.channels == 1 {
+        return Ok(ColorProfile::new_from_slice(profile)
+            .is_ok_and(|p| p.color_space == DataColorSpace::Gray)
+            .then(|| profile.to_vec()));
+    }
+
+    let source = match ColorProfile::new_from_slice(profile) {
+        Ok(p) if p.color_space == DataColorSpace::Rgb => p,
+        Ok(p) => {
+            debug!("Not converting from a {:?} profile", p.color_space);
+            return Ok(None);
+        }
+        Err(e) => {
+            warn!("Ignoring unreadable embedded ICC profile: {:?}", e);
+            return Ok(None);
+        }
+    };
+
+    if settings.color_target == ColorTarget::Original {
+        return Ok(Some(profile.to_vec()));
+    }
+    let (target, target_bytes) = target_profile(settings)?;
+
+    // Alpha passes through the transform untouched
+    let layout = if img.has_alpha() {
+        Layout::Rgba
+    } else {
+        Layout::Rgb
+    };
+    let chunk_len = img.width as usize * img.channels as usize * TRANSFORM_ROWS;
+    let options = TransformOptions::default();
+    match img.format {
+        PixelFormat::U8 => {
+            let t = source.create_transform_8bit(layout, &target, layout, options)?;
+            transform_in_place(
+                img.as_samples_mut::<u8>().unwrap_or_default(),
+                chunk_len,
+                &*t,
+            )?;
+        }
+        PixelFormat::U16 => {
+            let t = source.create_transform_16bit(layout, &target, layout, options)?;
+            transform_in_place(
+                img.as_samples_mut::<u16>().unwrap_or_default(),
+                chunk_len,
+                &*t,
+            )?;
+        }
+        // Float pixels are linear, which no embedded profile describes
+        PixelFormat::F32 => {
+            debug!("Not converting linear float pixels");
+            return Ok(Some(profile.to_vec()));
+        }
+    }
+    Ok(target_bytes)
+}
+
+/// Converts 8-bit CMYK ink amounts (0 is no ink) to RGB in the settings' target
+/// space, returning the pixels and the profile that describes them (None for sRGB).
+/// Goes through `profile` when it's a CMYK profile; without one the inks are
+/// taken as ideal and mixed straight into sRGB.
+pub fn cmyk_to_rgb(
+    cmyk: &[u8],
+    profile: Option<&[u8]>,
+    settings: &ImageLoadSettings,
+) -> Result<WithProfile<Vec<u8>>, Box<dyn Error>> {
+    let source = match profile.map(ColorProfile::new_from_slice) {
+        Some(Ok(p)) if p.color_space == DataColorSpace::Cmyk => Some(p),
+        Some(Ok(p)) => {
+            debug!("Ignoring a {:?} profile on CMYK pixels", p.color_space);
+            None
+        }
+        Some(Err(e)) => {
+            warn!("Ignoring unreadable embedded ICC profile: {:?}", e);
+            None
+        }
+        None => None,
+    };
+    // RGB pixels can't stay in a CMYK profile, so `Original` means sRGB here
+    let (target, target_bytes) = target_profile(settings)?;
+    let options = TransformOptions::default();
+    let mut rgb = vec![0u8; cmyk.len() / 4 * 3];
+
+    match source {
+        Some(source) => {
+            let t = source.create_transform_8bit(Layout::Rgba, &target, Layout::Rgb, options)?;
+            cmyk.par_chunks(4 * TRANSFORM_PIXELS)
+                .zip(rgb.par_chunks_mut(3 * TRANSFORM_PIXELS))
+                .try_for_each(|(src, dst)| t.transform(src, dst))
+                .map_err(|e| format!("Colour transform failed: {:?}", e))?;
+        }
+        None => {
+            rgb.par_chunks_mut(3)
+                .zip(cmyk.par_chunks(4))
+                .for_each(|(out, ink)| {
+                    let white = 255 - ink[3] as u32;
+                    for c in 0..3 {
+                        out[c] = ((255 - ink[c] as u32) * white / 255) as u8;
+                    }
+                });
+            if target_bytes.is_some() {
+                let t = ColorProfile::new_srgb().create_transform_8bit(
+                    Layout::Rgb,
+                    &target,
+                    Layout::Rgb,
+                    options,
+                )?;
+                transform_in_place(&mut rgb, 3 * TRANSFORM_PIXELS, &*t)?;
+            }
+        }
+    }
+    Ok((rgb, target_bytes))
+}
+
+// Adobe RGB (1998) is a pure power law of 563/256
+const ADOBE_RGB_GAMMA: f32 = 2.199_218_8;
+
+// Marks profiles whose curve isn't the one their space is defined with
+const CUSTOM_CURVE_SUFFIX: &str = " (custom tone curve)";
+
+// Primaries and defining transfer function of each output space
+fn space_profile(space: OutputColorSpace) -> Option<(ColorProfile, TransferFn)> {
+    match space {
+        OutputColorSpace::Camera => None,
+        OutputColorSpace::Srgb => Some((ColorProfile::new_srgb(), TransferFn::srgb())),
+        OutputColorSpace::DisplayP3 => Some((ColorProfile::new_display_p3(), TransferFn::srgb())),
+        OutputColorSpace::AdobeRgb => Some((
+            ColorProfile::new_adobe_rgb(),
+            TransferFn::new(ADOBE_RGB_GAMMA, 0.0),
+        )),
+        OutputColorSpace::Rec2020 => Some((ColorProfile::new_bt2020(), TransferFn::rec709())),
+    }
+}
+
+fn with_transfer(mut profile: ColorProfile, transfer: TransferFn) -> ColorProfile {
+    let curve = ToneReprCurve::Parametric(transfer.icc_parametric());
+    let name = match &profile.description {
+        Some(ProfileText::Localizable(names)) => names
+            .first()
+            .map(|n| n.value.trim_end_matches(CUSTOM_CURVE_SUFFIX).to_string()),
+        _ => None,
+    };
+    profile.red_trc = Some(curve.clone());
+    profile.green_trc = Some(curve.clone());
+    profile.blue_trc = Some(curve);
+    // The CICP tag would name the stock curve
+    profile.cicp = None;
+    profile.description = Some(ProfileText::Localizable(vec![LocalizableString::new(
+        "en".to_string(),
+        "US".to_string(),
+        format!("{}{}", name.unwrap_or_default(), CUSTOM_CURVE_SUFFIX),
+    )]));
+    profile
+}
+
+/// ICC profile for a raw develop into `space` encoded with `transfer`, None for camera
+/// space (which has no profile) and for plain sRGB, which needs no tag.
+pub fn output_profile(space: OutputColorSpace, transfer: TransferFn) -> Option<Vec<u8>> {
+    if space == OutputColorSpace::Srgb && transfer == TransferFn::srgb() {
+        return None;
+    }
+    let (profile, standard) = space_profile(space)?;
+    let profile = if transfer == standard {
+        profile
+    } else {
+        with_transfer(profile, transfer)
+    };
+    profile
+        .encode()
+        .inspect_err(|e| warn!("Failed to encode output ICC profile: {:?}", e))
+        .ok()
+}
+
+/// The profile to tag `img` with when it's written out in `format`. Float pixels
+/// are sRGB-encoded on the way to the integer formats, so their profile's curve
+/// is swapped to match.
+pub fn profile_for_format(img: &AgnoImage, format: PixelFormat) -> Option<Vec<u8>> {
+    let profile = img.color_profile.as_deref()?;
+    if !img.format.is_linear() || format.is_linear() {
+        return Some(profile.to_vec());
+    }
+    let encoded = with_transfer(
+        ColorProfile::new_from_slice(profile).ok()?,
+        TransferFn::srgb(),
+    );
+    encoded.encode().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{ExtendedColorType, ImageEncoder, codecs::png::PngEncoder};
+
+    use super::*;
+
+    fn profile(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + 3) as u8).collect()
+    }
+
+    fn segment(out: &mut Vec<u8>, marker: u8, payload: &[u8]) {
+        out.extend_from_slice(&[0xFF, marker]);
+        out.extend_from_slice(&(payload.len() as u16 + 2).to_be_bytes());
+        out.extend_from_slice(payload);
+    }
+
+    fn app2(seq: u8, count: u8, part: &[u8]) -> Vec<u8> {
+        [JPEG_ICC_SIGNATURE, &[seq, count], part].concat()
+    }
+
+    fn bmff(kind: &[u8], payload: &[u8]) -> Vec<u8> {
+        [&(payload.len() as u32 + 8).to_be_bytes(), kind, payload].concat()
+    }
+
+    #[test]
+    fn jpeg_segments_are_joined_in_sequence_order() {
+        let icc = profile(300);
+        let (first, second) = icc.split_at(200);
+
+        let mut jpeg = vec![0xFF, 0xD8];
+        segment(&mut jpeg, 0xE0, b"JFIF\0\x01\x02");
+        segment(&mut jpeg, 0xE2, &app2(2, 2, second));
+        // A fill byte ahead of a marker is skipped
+        jpeg.push(0xFF);
+        segment(&mut jpeg, 0xE2, &app2(1, 2, first));
+        segment(&mut jpeg, 0xDA, &[0; 8]);
+        assert_eq!(extract_icc_profile(&jpeg), Some(icc.clone()));
+
+        // Losing a segment loses the profile
+        let mut partial = vec![0xFF, 0xD8];
+        segment(&mut partial, 0xE2, &app2(1, 2, first));
+        segment(&mut partial, 0xDA, &[0; 8]);
+        assert_eq!(extract_icc_profile(&partial), None);
+    }
+
+    #[test]
+    fn png_iccp_chunk_is_inflated() {
+        let icc = profile(500);
+        let mut png = Vec::new();
+        let mut enc = PngEncoder::new(&mut png);
+        enc.set_icc_profile(icc.clone()).unwrap();
+        enc.write_image(&[10, 20, 30, 40, 50, 60], 2, 1, ExtendedColorType::Rgb8)
+            .unwrap();
+        assert_eq!(extract_icc_profile(&png), Some(icc));
+    }
+
+    #[test]
+    fn webp_iccp_chunk_is_found_past_padded_chunks() {
+        let icc = profile(41);
+        let mut body = b"WEBP".to_vec();
+        for (fourcc, payload) in [
+            (&b"VP8X"[..], &[0x20; 10][..]),
+            (b"EXIF", &[1; 5]),
+            (b"ICCP", &icc),
+        ] {
+            body.extend_from_slice(fourcc);
+            body.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+            body.extend_from_slice(payload);
+            if payload.len() % 2 == 1 {
+                body.push(0);
+            }
+        }
+        let webp = [b"RIFF", &(body.len() as u32).to_le_bytes()[..], &body].concat();
+        assert_eq!(extract_icc_profile(&webp), Some(icc));
+
+        let no_icc = [&b"RIFF\0\0\0\0WEBPVP8 "[..], &[0; 4]].concat();
+        assert_eq!(extract_icc_profile(&no_icc), None);
+    }
+
+    #[test]
+    fn tiff_icc_tag_is_read_in_either_byte_order() {
+        let icc = profile(64);
+        for little in [true, false] {
+            let u16b = |v: u16| {
+                if little {
+                    v.to_le_bytes()
+                } else {
+                    v.to_be_bytes()
+                }
+            };
+            let u32b = |v: u32| {
+                if little {
+                    v.to_le_bytes()
+                } else {
+                    v.to_be_bytes()
+                }
+            };
+            let mut tiff = if little {
+                b"II".to_vec()
+            } else {
+                b"MM".to_vec()
+            };
+            tiff.extend_from_slice(&u16b(42));
+            tiff.extend_from_slice(&u32b(8));
+            tiff.extend_from_slice(&u16b(2));
+            // ImageWidth, then the profile stored past the IFD
+            tiff.extend_from_slice(&u16b(256));
+            tiff.extend_from_slice(&u16b(4));
+            tiff.extend_from_slice(&u32b(1));
+            tiff.extend_from_slice(&u32b(16));
+            tiff.extend_from_slice(&u16b(TIFF_ICC_TAG));
+            tiff.extend_from_slice(&u16b(7));
+            tiff.extend_from_slice(&u32b(icc.len() as u32));
+            tiff.extend_from_slice(&u32b(8 + 2 + 2 * 12 + 4));
+            tiff.extend_from_slice(&[0; 4]);
+            tiff.extend_from_slice(&icc);
+            assert_eq!(
+                extract_icc_profile(&tiff),
+                Some(icc.clone()),
+                "little {little}"
+            );
+        }
+    }
+
+    #[test]
+    fn heif_colr_property_holds_the_profile() {
+        let icc = profile(90);
+        let nclx = bmff(b"colr", b"nclx\0\x01\0\x0d\0\x01\x80");
+        let prof = bmff(b"colr", &[&b"prof"[..], &icc].concat());
+        let ipco = bmff(b"ipco", &[bmff(b"ispe", &[0; 12]), nclx, prof].concat());
+        let iprp = bmff(b"iprp", &ipco);
+        let meta = bmff(
+            b"meta",
+            &[&[0; 4][..], &bmff(b"hdlr", &[0; 24]), &iprp].concat(),
+        );
+        let heif = [bmff(b"ftyp", b"heic\0\0\0\0mif1heic"), meta].concat();
+        assert_eq!(extract_icc_profile(&heif), Some(icc));
+    }
+
+    #[test]
+    fn unknown_containers_and_empty_profiles_give_nothing() {
+        assert_eq!(extract_icc_profile(b"GIF89a\x01\0\x01\0"), None);
+        assert_eq!(extract_icc_profile(&[]), None);
+
+        let mut jpeg = vec![0xFF, 0xD8];
+        segment(&mut jpeg, 0xE2, &app2(1, 1, &[]));
+        segment(&mut jpeg, 0xDA, &[0; 8]);
+        assert_eq!(extract_icc_profile(&jpeg), None);
+    }
+}
@@ -0,0 +1,278 @@
+use log::debug;
+use rayon::{
+    iter::{IndexedParallelIterator, ParallelIterator},
+    slice::ParallelSliceMut,
+};
+
+use crate::{
+    agno_image::Sample,
+    black_level::BlackLevels,
+    demosaic::cfa_index,
+    exif::{
+        ExifContext, ExifValue,
+        spec::{
+            CHROMATIC_ABERRATION_CORR_PARAMS, DISTORTION_CORR_PARAMS, ExifField,
+            VIGNETTING_CORR_PARAMS,
+        },
+    },
+    sony_decoder::Dimensions,
+};
+
+/// Flags for `RawDevelopSettings::lens_corrections`, mirrored as `enum LensCorrection` in agno.h.
+pub const LENS_CORRECT_DISTORTION: u32 = 1;
+pub const LENS_CORRECT_VIGNETTING: u32 = 2;
+pub const LENS_CORRECT_CA: u32 = 4;
+
+// Sony stores at most 16 knots per spline
+const MAX_KNOTS: usize = 16;
+
+/// Correction splines the camera embeds for the mounted lens. Each is sampled at
+/// knots evenly spaced over the radius, 0 at the centre and 1 at the corners.
+#[derive(Clone, Debug, Default)]
+pub struct LensProfile {
+    /// Source radius over output radius
+    pub distortion: Option<Vec<f32>>,
+    /// Red and blue radius scales relative to green
+    pub ca: Option<[Vec<f32>; 2]>,
+    /// Light falloff, 1 at the centre
+    pub vignetting: Option<Vec<f32>>,
+}
+
+// The *CorrParams tags are int16s arrays led by their value count
+fn corr_params(ctx: &ExifContext, field: ExifField) -> Option<Vec<i16>> {
+    let Some(ExifValue::Short(v)) = ctx.get_tag_value(field) else {
+        return None;
+    };
+    let (&n, values) = v.split_first()?;
+    let n = n as usize;
+    (n >= 2 && n <= values.len()).then(|| values[..n].iter().map(|&x| x as i16).collect())
+}
+
+impl LensProfile {
+    /// Decodes the Sony SubIFD correction parameters (tags 0x7032, 0x7035 and 0x7037)
+    /// with the scaling darktable uses for them.
+    pub fn from_exif(ctx: &ExifContext) -> Self {
+        let distortion = corr_params(ctx, DISTORTION_CORR_PARAMS)
+            .filter(|p| p.len() <= MAX_KNOTS)
+            .map(|p| p.iter().map(|&v| v as f32 / 16384.0 + 1.0).collect());
+
+        // Red knots followed by blue knots
+        let ca = corr_params(ctx, CHROMATIC_ABERRATION_CORR_PARAMS)
+            .filter(|p| p.len().is_multiple_of(2) && p.len() <= 2 * MAX_KNOTS && p.len() >= 4)
+            .map(|p| {
+                let (r, b) = p.split_at(p.len() / 2);
+                [r, b].map(|c| c.iter().map(|&v| v as f32 / 2097152.0 + 1.0).collect())
+            });
+
+        let vignetting = corr_params(ctx, VIGNETTING_CORR_PARAMS)
+            .filter(|p| p.len() <= MAX_KNOTS)
+            .map(|p| {
+                p.iter()
+                    .map(|&v| 2f32.powf(0.5 - 2f32.powf(v as f32 / 8192.0 - 1.0)))
+                    .collect()
+            });
+
+        LensProfile {
+            distortion,
+            ca,
+            vignetting,
+        }
+    }
+
+    /// Keeps only the corrections selected by a `LENS_CORRECT_*` mask.
+    pub fn select(mut self, mask: u32) -> Self {
+        if mask & LENS_CORRECT_DISTORTION == 0 {
+            self.distortion = None;
+        }
+        if mask & LENS_CORRECT_CA == 0 {
+            self.ca = None;
+        }
+        if mask & LENS_CORRECT_VIGNETTING == 0 {
+            self.vignetting = None;
+        }
+        self
+    }
+}
+
+// Piecewise linear spline over knots evenly spaced on [0, 1], flat past the corners
+fn spline(knots: &[f32], r: f32) -> f32 {
+    let t = r.clamp(0.0, 1.0) * (knots.len() - 1) as f32;
+    let i = (t as usize).min(knots.len() - 2);
+    let f = t - i as f32;
+    knots[i] + (knots[i + 1] - knots[i]) * f
+}
+
+/// Brightens the mosaic by the inverse of the lens falloff, radius measured over the
+/// output area. Clipped samples stay clipped; lifted ones may land above the white
+/// level, which the renderer treats like any other bright sample.
+pub fn correct_vignetting(
+    raw: &mut [u16],
+    dims: Dimensions,
+    black_level: BlackLevels,
+    white_level: u16,
+    falloff: &[f32],
+) {
+    let (w, h) = (dims.output_width, dims.output_height);
+    let (cx, cy) = (w as f32 * 0.5, h as f32 * 0.5);
+    let inv_radius = 1.0 / (cx * cx + cy * cy).sqrt().max(1.0);
+
+    raw.par_chunks_mut(dims.raw_width)
+        .take(h)
+        .enumerate()
+        .for_each(|(row, line)| {
+            let dy = row as f32 + 0.5 - cy;
+            for (col, v) in line[..w].iter_mut().enumerate() {
+                if *v >= white_level {
+                    continue;
+                }
+                let dx = col as f32 + 0.5 - cx;
+                let r = (dx * dx + dy * dy).sqrt() * inv_radius;
+                let black = black_level[cfa_index(row, col)] as f32;
+                let gain = 1.0 / spline(falloff, r).max(0.1);
+                let lifted = black + (*v as f32 - black).max(0.0) * gain;
+                *v = lifted.min(u16::MAX as f32).round() as u16;
+            }
+        });
+}
+
+// Bilinear read of one channel of an interleaved RGB buffer, clamped to the edges
+fn sample<T: Sample>(rgb: &[T], w: usize, h: usize, x: f32, y: f32, c: usize) -> f32 {
+    let x = x.clamp(0.0, (w - 1) as f32);
+    let y = y.clamp(0.0, (h - 1) as f32);
+    let (x0, y0) = (x as usize, y as usize);
+    let (x1, y1) = ((x0 + 1).min(w - 1), (y0 + 1).min(h - 1));
+    let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+    let at = |xx: usize, yy: usize| rgb[(yy * w + xx) * 3 + c].to_unit();
+    let top = at(x0, y0) + (at(x1, y0) - at(x0, y0)) * fx;
+    let bottom = at(x0, y1) + (at(x1, y1) - at(x0, y1)) * fx;
+    top + (bottom - top) * fy
+}
+
+/// Undoes radial distortion and lateral chromatic aberration on interleaved RGB.
+/// Each output channel is read from its own radius: distortion moves all three,
+/// the CA splines move red and blue against green. The result is zoomed just
+/// enough that no corner samples outside the frame.
+pub fn warp<T: Sample>(rgb: &[T], width: usize, height: usize, profile: &LensProfile) -> Vec<T> {
+    if profile.distortion.is_none() && profile.ca.is_none() || width < 2 || height < 2 {
+        return rgb.to_vec();
+    }
+
+    let dist = |r: f32| profile.distortion.as_deref().map_or(1.0, |k| spline(k, r));
+    let scale_of = |c: usize, r: f32| {
+        let ca = match (&profile.ca, c) {
+            (Some([red, _]), 0) => spline(red, r),
+            (Some([_, blue]), 2) => spline(blue, r),
+            _ => 1.0,
+        };
+        dist(r) * ca
+    };
+
+    // Sources reach furthest out where the radius scale peaks
+    let peak = (0..=64)
+        .map(|i| i as f32 / 64.0)
+        .flat_map(|r| (0..3).map(move |c| scale_of(c, r)))
+        .fold(1.0f32, f32::max);
+    let zoom = 1.0 / peak;
+    debug!("Lens correction zoom {:.4}", zoom);
+
+    let (cx, cy) = (width as f32 * 0.5, height as f32 * 0.5);
+    let radius = (cx * cx + cy * cy).sqrt();
+
+    let mut out = vec![T::from_unit(0.0); width * height * 3];
+    out.par_chunks_mut(width * 3)
+        .enumerate()
+        .for_each(|(row, line)| {
+            let dy = (row as f32 + 0.5 - cy) * zoom;
+            for (col, px) in line.chunks_exact_mut(3).enumerate() {
+                let dx = (col as f32 + 0.5 - cx) * zoom;
+                let r = (dx * dx + dy * dy).sqrt() / radius;
+                for (c, v) in px.iter_mut().enumerate() {
+                    let s = scale_of(c, r);
+                    let (x, y) = (cx + dx * s - 0.5, cy + dy * s - 0.5);
+                    *v = T::from_unit(sample(rgb, width, height, x, y, c));
+                }
+            }
+        });
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient(w: usize, h: usize) -> Vec<f32> {
+        (0..w * h * 3)
+            .map(|i| {
+                let (px, c) = (i / 3, i % 3);
+                let (x, y) = ((px % w) as f32, (px / w) as f32);
+                0.2 + 0.6 * (x / w as f32) * (0.5 + 0.5 * (y * 0.3 + c as f32).sin())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn spline_interpolates_between_knots_and_holds_past_the_corners() {
+        let knots = [1.0, 0.5, 0.25];
+        assert_eq!(spline(&knots, 0.0), 1.0);
+        assert_eq!(spline(&knots, 0.25), 0.75);
+        assert_eq!(spline(&knots, 0.5), 0.5);
+        assert_eq!(spline(&knots, 1.0), 0.25);
+        assert_eq!(spline(&knots, 1.5), 0.25);
+        assert_eq!(spline(&knots, -0.5), 1.0);
+    }
+
+    #[test]
+    fn warp_without_a_profile_returns_the_input() {
+        let rgb = gradient(17, 11);
+        assert_eq!(warp(&rgb, 17, 11, &LensProfile::default()), rgb);
+
+        // Vignetting alone never touches the demosaiced image
+        let profile = LensProfile {
+            vignetting: Some(vec![1.0, 0.5]),
+            ..Default::default()
+        };
+        assert_eq!(warp(&rgb, 17, 11, &profile), rgb);
+    }
+
+    #[test]
+    fn flat_splines_warp_to_the_identity() {
+        let (w, h) = (17, 11);
+        let rgb = gradient(w, h);
+        let profile = LensProfile {
+            distortion: Some(vec![1.0; 4]),
+            ca: Some([vec![1.0; 3], vec![1.0; 3]]),
+            vignetting: None,
+        };
+        let out = warp(&rgb, w, h, &profile);
+        for (a, b) in out.iter().zip(&rgb) {
+            assert!((a - b).abs() < 1e-5, "{a} vs {b}");
+        }
+    }
+
+    #[test]
+    fn flat_falloff_leaves_the_mosaic_alone() {
+        let dims = Dimensions {
+            raw_width: 10,
+            raw_height: 6,
+            output_width: 8,
+            output_height: 6,
+        };
+        let raw: Vec<u16> = (0..60).map(|i| 600 + i * 97 % 4000).collect();
+        let mut out = raw.clone();
+        correct_vignetting(&mut out, dims, [512; 4], 4000, &[1.0, 1.0]);
+        assert_eq!(out, raw);
+    }
+
+    #[test]
+    fn select_drops_unrequested_corrections() {
+        let profile = LensProfile {
+            distortion: Some(vec![1.0, 1.01]),
+            ca: Some([vec![1.0, 1.0], vec![1.0, 1.0]]),
+            vignetting: Some(vec![1.0, 0.8]),
+        }
+        .select(LENS_CORRECT_VIGNETTING);
+        assert!(profile.distortion.is_none());
+        assert!(profile.ca.is_none());
+        assert!(profile.vignetting.is_some());
+    }
+}
@@ -1,8 +1,24 @@
 mod agno_image;
 mod lib_interface;
 
+mod bad_pixels;
+mod black_level;
+mod calibration;
+mod color;
 mod demosaic;
+mod denoise;
+mod dng_writer;
 mod exif;
+mod highlight;
+mod icc;
+mod lens_correction;
+mod ljpeg;
+mod phash;
+mod pixel_shift;
+mod simd;
 mod sony_decoder;
 mod sony_jpeg;
+mod stats;
 mod tiff;
+mod tone;
+mod white_balance;
@@ -1,11 +1,37 @@
-use std::{fs::File, ptr::null_mut};
+use std::{borrow::Cow, error::Error, fs::File, path::PathBuf, ptr::null_mut};
 
 use log::{LevelFilter, info};
+use tiff::encoder::colortype;
 
 use crate::{
-    agno_image::{AgnoImage, load::load_agno_image_from_file, scale_image},
+    agno_image::{
+        AgnoImage, FlipAxis, Gravity, PixelFormat, ResampleFilter, ResizeMode, ResizeSpec,
+        RotateMode, blurhash, crop_image, flatten_alpha, flip_image,
+        load::{
+            ImageLoadSettings, LoadOptions, RawDevelopSettings, RawScale,
+            load_agno_image_from_file, load_agno_image_with_options, load_pixel_shift,
+        },
+        resize_with_spec, rotate_image, rotate_image_by, scale_image, thumbhash,
+    },
+    bad_pixels::BadPixelMode,
+    calibration::{build_master_frame, write_master_frame},
+    color::OutputColorSpace,
+    demosaic::DemosaicAlgorithm,
+    denoise::NoiseReduction,
+    dng_writer::{DngCompression, convert_raw_to_dng},
     exif::ExifData,
-    sony_jpeg::write_webp_from_rgb8_writer,
+    highlight::HighlightMode,
+    icc::{ColorTarget, icc_profile_from_file, profile_for_format},
+    phash::{ImageHashKind, cluster_near_duplicates, hamming_distance, image_hash},
+    sony_jpeg::{
+        write_jpeg_from_gray8_writer, write_jpeg_from_rgb8_writer, write_png_from_gray8_writer,
+        write_png_from_gray16_writer, write_png_from_rgb8_writer, write_png_from_rgb16_writer,
+        write_png_from_rgba8_writer, write_png_from_rgba16_writer, write_tiff_writer,
+        write_webp_from_rgb8_writer, write_webp_from_rgba8_writer,
+    },
+    stats::{image_stats, raw_stats_from_file},
+    tone::ToneCurve,
+    white_balance::WhiteBalanceMode,
 };
 
 macro_rules! ok_or_null {
@@ -47,6 +73,246 @@ impl CString {
     }
 }
 
+// Enums arrive from C as plain ints; anything out of range is rejected here, as
+// reading it straight into the Rust enum would be undefined behaviour
+macro_rules! c_enum_try_from {
+    ($($name:ident { $($variant:ident),+ $(,)? })+) => {
+        $(
+            impl TryFrom<i32> for $name {
+                type Error = Box<dyn Error>;
+
+                fn try_from(value: i32) -> Result<Self, Self::Error> {
+                    $(
+                        if value == $name::$variant as i32 {
+                            return Ok($name::$variant);
+                        }
+                    )+
+                    Err(format!("{} is not a valid {}", value, stringify!($name)).into())
+                }
+            }
+        )+
+    };
+}
+
+c_enum_try_from! {
+    WhiteBalanceMode {
+        AsShot, Custom, None, GrayWorld, WhitePatch, Temperature, Daylight, Cloudy,
+        Tungsten, Flash, Fluorescent,
+    }
+    DemosaicAlgorithm { Bilinear, MalvarHeCutler }
+    HighlightMode { Clip, Unclip, Blend, Reconstruct }
+    OutputColorSpace { Camera, Srgb, DisplayP3, AdobeRgb, Rec2020 }
+    ToneCurve { Srgb, Rec709, Linear, Gamma, Filmic }
+    RawScale { Full, Half, Quarter, Eighth }
+    BadPixelMode { Off, Auto }
+    NoiseReduction { Off, Auto, Manual }
+    ColorTarget { Srgb, Original, Profile }
+    ResizeMode { Exact, Fit, Cover, LongestEdge, ShortestEdge }
+    Gravity { Center, Top, Entropy }
+    ResampleFilter { Nearest, Bilinear, CatmullRom, Mitchell, Lanczos3 }
+    ImageHashKind { Average, Difference, Perceptual }
+    FlipAxis { Horizontal, Vertical }
+    RotateMode { Fill, AutoCrop }
+    DngCompression { Uncompressed, LosslessJpeg }
+}
+
+/// `struct RawDevelopSettings` as C lays it out, with enums as plain ints.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct CRawDevelopSettings {
+    wb_mode: i32,
+    wb_multipliers: [f32; 4],
+    wb_temperature: f32,
+    wb_tint: f32,
+    exposure_ev: f32,
+    black_level: i32,
+    white_level: i32,
+    demosaic: i32,
+    highlight_mode: i32,
+    color_space: i32,
+    tone_curve: i32,
+    gamma: f32,
+    toe_slope: f32,
+    bit_depth: u32,
+    scale: i32,
+    bad_pixel_mode: i32,
+    bad_pixel_threshold: f32,
+    defect_map: *const u8,
+    defect_map_len: usize,
+    dark_frame: *const u8,
+    dark_frame_len: usize,
+    flat_field: *const u8,
+    flat_field_len: usize,
+    lens_corrections: u32,
+    pixel_shift_motion_threshold: f32,
+    noise_reduction: i32,
+    wavelet_threshold: f32,
+    chroma_denoise: f32,
+}
+
+// A path from C as a pointer and byte length, like everywhere else in the API; null
+// or empty for none.
+// Safety: a non-null `ptr` must point to `len` readable bytes.
+unsafe fn c_path(ptr: *const u8, len: usize) -> Result<Option<PathBuf>, Box<dyn Error>> {
+    if ptr.is_null() || len == 0 {
+        return Ok(None);
+    }
+    let bytes = unsafe { std::slice::from_raw_parts(ptr, len) };
+    Ok(Some(PathBuf::from(std::str::from_utf8(bytes)?)))
+}
+
+impl CRawDevelopSettings {
+    // Safety: each path pointer must be null or point to its length in bytes
+    unsafe fn to_settings(self) -> Result<RawDevelopSettings, Box<dyn Error>> {
+        Ok(RawDevelopSettings {
+            wb_mode: self.wb_mode.try_into()?,
+            wb_multipliers: self.wb_multipliers,
+            wb_temperature: self.wb_temperature,
+            wb_tint: self.wb_tint,
+            exposure_ev: self.exposure_ev,
+            black_level: self.black_level,
+            white_level: self.white_level,
+            demosaic: self.demosaic.try_into()?,
+            highlight_mode: self.highlight_mode.try_into()?,
+            color_space: self.color_space.try_into()?,
+            tone_curve: self.tone_curve.try_into()?,
+            gamma: self.gamma,
+            toe_slope: self.toe_slope,
+            bit_depth: self.bit_depth,
+            scale: self.scale.try_into()?,
+            bad_pixel_mode: self.bad_pixel_mode.try_into()?,
+            bad_pixel_threshold: self.bad_pixel_threshold,
+            defect_map: unsafe { c_path(self.defect_map, self.defect_map_len)? },
+            dark_frame: unsafe { c_path(self.dark_frame, self.dark_frame_len)? },
+            flat_field: unsafe { c_path(self.flat_field, self.flat_field_len)? },
+            lens_corrections: self.lens_corrections,
+            pixel_shift_motion_threshold: self.pixel_shift_motion_threshold,
+            noise_reduction: self.noise_reduction.try_into()?,
+            wavelet_threshold: self.wavelet_threshold,
+            chroma_denoise: self.chroma_denoise,
+        })
+    }
+}
+
+/// `struct ImageLoadSettings` as C lays it out, with enums as plain ints and bools as bytes.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct CImageLoadSettings {
+    color_target: i32,
+    target_profile: *const u8,
+    target_profile_len: usize,
+    keep_grayscale: u8,
+}
+
+impl CImageLoadSettings {
+    // Safety: `target_profile` must be null or point to `target_profile_len` bytes
+    unsafe fn to_settings(self) -> Result<ImageLoadSettings, Box<dyn Error>> {
+        Ok(ImageLoadSettings {
+            color_target: self.color_target.try_into()?,
+            target_profile: unsafe { c_path(self.target_profile, self.target_profile_len)? },
+            keep_grayscale: self.keep_grayscale != 0,
+        })
+    }
+}
+
+/// `struct LoadOptions` as C lays it out; see `CRawDevelopSettings`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct CLoadOptions {
+    raw: CRawDevelopSettings,
+    image: CImageLoadSettings,
+    keep_orientation: u8,
+}
+
+// Checks and copies options from C, a null pointer meaning the defaults.
+// Safety: a non-null `options` must point to a `struct LoadOptions` whose paths are
+// null or point to their length in bytes.
+unsafe fn read_load_options(options: *const CLoadOptions) -> Result<LoadOptions, Box<dyn Error>> {
+    if options.is_null() {
+        return Ok(LoadOptions::default());
+    }
+    let c = unsafe { *options };
+    Ok(LoadOptions {
+        raw: unsafe { c.raw.to_settings()? },
+        image: unsafe { c.image.to_settings()? },
+        keep_orientation: c.keep_orientation != 0,
+    })
+}
+
+/// `struct ResizeSpec` as C lays it out, with enums as plain ints and bools as bytes.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct CResizeSpec {
+    mode: i32,
+    width: u32,
+    height: u32,
+    gravity: i32,
+    never_upscale: u8,
+    filter: i32,
+}
+
+const _: () = assert!(size_of::<CResizeSpec>() == size_of::<ResizeSpec>());
+
+impl TryFrom<CResizeSpec> for ResizeSpec {
+    type Error = Box<dyn Error>;
+
+    fn try_from(c: CResizeSpec) -> Result<Self, Self::Error> {
+        Ok(ResizeSpec {
+            mode: c.mode.try_into()?,
+            width: c.width,
+            height: c.height,
+            gravity: c.gravity.try_into()?,
+            never_upscale: c.never_upscale != 0,
+            filter: c.filter.try_into()?,
+        })
+    }
+}
+
+/// Bytes handed to C in a malloc'd buffer, released with free_agno_buffer.
+/// Text (e.g. JSON) is nul-terminated; `len` doesn't count the terminator.
+#[repr(C)]
+pub struct AgnoBuffer {
+    pub data: *mut u8,
+    pub len: usize,
+}
+
+impl AgnoBuffer {
+    pub fn null() -> Self {
+        AgnoBuffer {
+            data: null_mut(),
+            len: 0,
+        }
+    }
+
+    pub fn from_str(s: &str) -> Self {
+        Self::from_bytes(s.as_bytes())
+    }
+
+    // NUL-terminated too, so text can be read as a C string
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let len = bytes.len();
+        let data = unsafe { libc::malloc(len + 1) as *mut u8 };
+        if data.is_null() {
+            return AgnoBuffer::null();
+        }
+        unsafe {
+            data.copy_from_nonoverlapping(bytes.as_ptr(), len);
+            *data.add(len) = 0;
+        }
+        AgnoBuffer { data, len }
+    }
+
+    pub fn json<T: serde::Serialize>(value: &T) -> Self {
+        match serde_json::to_string(value) {
+            Ok(json) => AgnoBuffer::from_str(&json),
+            Err(e) => {
+                info!("Error occurred, returning null buffer: {:?}", e);
+                AgnoBuffer::null()
+            }
+        }
+    }
+}
+
 #[unsafe(no_mangle)]
 pub extern "C" fn load_image_from_path(path: *const u8, len: usize) -> *mut AgnoImage {
     let wrapped_path = CString::new(path, len);
@@ -54,18 +320,264 @@ pub extern "C" fn load_image_from_path(path: *const u8, len: usize) -> *mut Agno
     ok_or_null!(load_agno_image_from_file(wrapped_path.as_str()))
 }
 
+// A null options pointer loads with the defaults, same as load_image_from_path; an
+// out-of-range enum or non-UTF-8 path in them returns null
+#[unsafe(no_mangle)]
+pub extern "C" fn load_image_with_options(
+    path: *const u8,
+    len: usize,
+    options: *const CLoadOptions,
+) -> *mut AgnoImage {
+    let wrapped_path = CString::new(path, len);
+
+    ok_or_null!(
+        unsafe { read_load_options(options) }
+            .and_then(|options| load_agno_image_with_options(wrapped_path.as_str(), &options))
+    )
+}
+
 #[unsafe(no_mangle)]
 pub extern "C" fn write_agno_image_to_webp(path: *const u8, len: usize, img: &mut AgnoImage) {
     let wrapped_path = CString::new(path, len);
     let mut file = File::create(wrapped_path.as_str()).unwrap();
+    let (width, height) = (img.width as u32, img.height as u32);
+
+    // WebP is 8-bit only
+    let samples = match img.format {
+        PixelFormat::U8 => Cow::Borrowed(img.as_slice()),
+        _ => Cow::Owned(img.to_samples::<u8>()),
+    };
+    let icc = profile_for_format(img, PixelFormat::U8);
+
+    let _ = match img.channels {
+        4 => write_webp_from_rgba8_writer(&mut file, &samples, width, height, 90, icc.as_deref()),
+        // WebP has no grey layout, and a grey profile can't tag RGB
+        1 => {
+            let rgb: Vec<u8> = samples.iter().flat_map(|&v| [v; 3]).collect();
+            write_webp_from_rgb8_writer(&mut file, &rgb, width, height, 90, None)
+        }
+        _ => write_webp_from_rgb8_writer(&mut file, &samples, width, height, 90, icc.as_deref()),
+    };
+}
+
+// JPEG is 8-bit only, like WebP, and has no alpha: it's dropped, so flatten first
+// to choose what shows through transparent areas
+#[unsafe(no_mangle)]
+pub extern "C" fn write_agno_image_to_jpeg(path: *const u8, len: usize, img: &mut AgnoImage) {
+    let wrapped_path = CString::new(path, len);
+    let mut file = File::create(wrapped_path.as_str()).unwrap();
+
+    let mut rgb = match img.format {
+        PixelFormat::U8 => Cow::Borrowed(img.as_slice()),
+        _ => Cow::Owned(img.to_samples::<u8>()),
+    };
+    if img.has_alpha() {
+        rgb = Cow::Owned(
+            rgb.chunks_exact(4)
+                .flat_map(|px| &px[..3])
+                .copied()
+                .collect(),
+        );
+    }
+    let icc = profile_for_format(img, PixelFormat::U8);
+    let (width, height) = (img.width as u32, img.height as u32);
+
+    let _ = if img.channels == 1 {
+        write_jpeg_from_gray8_writer(&mut file, &rgb, width, height, 90, icc.as_deref())
+    } else {
+        write_jpeg_from_rgb8_writer(&mut file, &rgb, width, height, 90, icc.as_deref())
+    };
+}
+
+// 8-bit images are written as 8-bit PNG, everything else as 16-bit
+#[unsafe(no_mangle)]
+pub extern "C" fn write_agno_image_to_png(path: *const u8, len: usize, img: &mut AgnoImage) {
+    let wrapped_path = CString::new(path, len);
+    let mut file = File::create(wrapped_path.as_str()).unwrap();
+    let (width, height) = (img.width as u32, img.height as u32);
+
+    let _ = match img.format {
+        PixelFormat::U8 => {
+            let icc = profile_for_format(img, PixelFormat::U8);
+            let (samples, icc) = (img.as_slice(), icc.as_deref());
+            match img.channels {
+                1 => write_png_from_gray8_writer(&mut file, samples, width, height, icc),
+                4 => write_png_from_rgba8_writer(&mut file, samples, width, height, icc),
+                _ => write_png_from_rgb8_writer(&mut file, samples, width, height, icc),
+            }
+        }
+        _ => {
+            let icc = profile_for_format(img, PixelFormat::U16);
+            let (samples, icc) = (img.to_samples::<u16>(), icc.as_deref());
+            match img.channels {
+                1 => write_png_from_gray16_writer(&mut file, &samples, width, height, icc),
+                4 => write_png_from_rgba16_writer(&mut file, &samples, width, height, icc),
+                _ => write_png_from_rgb16_writer(&mut file, &samples, width, height, icc),
+            }
+        }
+    };
+}
+
+// TIFF keeps the image's own format and channels, including linear float and alpha
+#[unsafe(no_mangle)]
+pub extern "C" fn write_agno_image_to_tiff(path: *const u8, len: usize, img: &mut AgnoImage) {
+    let wrapped_path = CString::new(path, len);
+    let mut file = File::create(wrapped_path.as_str()).unwrap();
+    let (width, height) = (img.width as u32, img.height as u32);
+    let icc = img.color_profile.as_deref();
+    let f = &mut file;
+
+    let _ = match (img.format, img.channels) {
+        (PixelFormat::U8, 1) => {
+            write_tiff_writer::<colortype::Gray8, _>(f, img.as_slice(), width, height, icc)
+        }
+        (PixelFormat::U8, 4) => {
+            write_tiff_writer::<colortype::RGBA8, _>(f, img.as_slice(), width, height, icc)
+        }
+        (PixelFormat::U8, _) => {
+            write_tiff_writer::<colortype::RGB8, _>(f, img.as_slice(), width, height, icc)
+        }
+        (PixelFormat::U16, 1) => write_tiff_writer::<colortype::Gray16, _>(
+            f,
+            img.as_samples().unwrap_or_default(),
+            width,
+            height,
+            icc,
+        ),
+        (PixelFormat::U16, 4) => write_tiff_writer::<colortype::RGBA16, _>(
+            f,
+            img.as_samples().unwrap_or_default(),
+            width,
+            height,
+            icc,
+        ),
+        (PixelFormat::U16, _) => write_tiff_writer::<colortype::RGB16, _>(
+            f,
+            img.as_samples().unwrap_or_default(),
+            width,
+            height,
+            icc,
+        ),
+        (PixelFormat::F32, 1) => write_tiff_writer::<colortype::Gray32Float, _>(
+            f,
+            img.as_samples().unwrap_or_default(),
+            width,
+            height,
+            icc,
+        ),
+        (PixelFormat::F32, 4) => write_tiff_writer::<colortype::RGBA32Float, _>(
+            f,
+            img.as_samples().unwrap_or_default(),
+            width,
+            height,
+            icc,
+        ),
+        (PixelFormat::F32, _) => write_tiff_writer::<colortype::RGB32Float, _>(
+            f,
+            img.as_samples().unwrap_or_default(),
+            width,
+            height,
+            icc,
+        ),
+    };
+}
+
+// Returns false (and logs why) when the source can't be decoded, the DNG can't be written
+// or the compression is out of range
+#[unsafe(no_mangle)]
+pub extern "C" fn convert_to_dng(
+    path: *const u8,
+    len: usize,
+    out_path: *const u8,
+    out_len: usize,
+    compression: i32,
+) -> bool {
+    let wrapped_path = CString::new(path, len);
+    let wrapped_out_path = CString::new(out_path, out_len);
+
+    match DngCompression::try_from(compression).and_then(|compression| {
+        convert_raw_to_dng(
+            wrapped_path.as_str(),
+            wrapped_out_path.as_str(),
+            compression,
+        )
+    }) {
+        Ok(()) => true,
+        Err(e) => {
+            info!("DNG conversion failed: {:?}", e);
+            false
+        }
+    }
+}
+
+// Averages `count` dark or flat raws into a master DNG that can be passed back in
+// RawDevelopSettings. Returns false (and logs why) if any frame fails to load or doesn't match.
+#[unsafe(no_mangle)]
+pub extern "C" fn create_master_frame(
+    paths: *const *const u8,
+    lens: *const usize,
+    count: usize,
+    out_path: *const u8,
+    out_len: usize,
+) -> bool {
+    if paths.is_null() || lens.is_null() {
+        return false;
+    }
+
+    let (paths, lens) = unsafe {
+        (
+            std::slice::from_raw_parts(paths, count),
+            std::slice::from_raw_parts(lens, count),
+        )
+    };
+    let wrapped_paths: Vec<CString> = paths
+        .iter()
+        .zip(lens)
+        .map(|(&p, &len)| CString::new(p, len))
+        .collect();
+    let paths: Vec<&str> = wrapped_paths.iter().map(|p| p.as_str()).collect();
+    let wrapped_out_path = CString::new(out_path, out_len);
+
+    match build_master_frame(&paths)
+        .and_then(|master| write_master_frame(&master, wrapped_out_path.as_str()))
+    {
+        Ok(()) => true,
+        Err(e) => {
+            info!("Master frame failed: {:?}", e);
+            false
+        }
+    }
+}
+
+// Composes a Pixel Shift sequence from `count` ARWs in shooting order, or one ARQ.
+#[unsafe(no_mangle)]
+pub extern "C" fn load_pixel_shift_images(
+    paths: *const *const u8,
+    lens: *const usize,
+    count: usize,
+    options: *const CLoadOptions,
+) -> *mut AgnoImage {
+    if paths.is_null() || lens.is_null() {
+        return AgnoImage::null();
+    }
+
+    let (paths, lens) = unsafe {
+        (
+            std::slice::from_raw_parts(paths, count),
+            std::slice::from_raw_parts(lens, count),
+        )
+    };
+    let wrapped_paths: Vec<CString> = paths
+        .iter()
+        .zip(lens)
+        .map(|(&p, &len)| CString::new(p, len))
+        .collect();
+    let paths: Vec<&str> = wrapped_paths.iter().map(|p| p.as_str()).collect();
 
-    let _ = write_webp_from_rgb8_writer(
-        &mut file,
-        img.as_slice(),
-        img.width as u32,
-        img.height as u32,
-        90,
-    );
+    ok_or_null!(
+        unsafe { read_load_options(options) }
+            .and_then(|options| load_pixel_shift(&paths, &options))
+    )
 }
 
 #[unsafe(no_mangle)]
@@ -80,12 +592,308 @@ pub extern "C" fn resize_image(
 
     unsafe {
         let real_img = Box::from_raw(img);
-        let new_img = ok_or_null!(scale_image(*real_img, new_width as u32, new_height as u32));
+        let new_img = ok_or_null!(scale_image(
+            *real_img,
+            new_width as u32,
+            new_height as u32,
+            ResampleFilter::default()
+        ));
 
         new_img
     }
 }
 
+// Takes ownership of img like resize_image, scaling with the given filter
+#[unsafe(no_mangle)]
+pub extern "C" fn resize_image_with_filter(
+    img: *mut AgnoImage,
+    new_width: usize,
+    new_height: usize,
+    filter: i32,
+) -> *mut AgnoImage {
+    if img.is_null() {
+        return AgnoImage::null();
+    }
+
+    let real_img = unsafe { Box::from_raw(img) };
+    let filter = match ResampleFilter::try_from(filter) {
+        Ok(filter) => filter,
+        Err(e) => {
+            AgnoImage::free(&real_img);
+            info!("Error occurred, returning null pointer: {:?}", e);
+            return AgnoImage::null();
+        }
+    };
+    ok_or_null!(scale_image(
+        *real_img,
+        new_width as u32,
+        new_height as u32,
+        filter
+    ))
+}
+
+// Takes ownership of img like resize_image; sizes it by spec instead of exact dimensions
+#[unsafe(no_mangle)]
+pub extern "C" fn resize_image_with_spec(
+    img: *mut AgnoImage,
+    spec: *const CResizeSpec,
+) -> *mut AgnoImage {
+    if img.is_null() {
+        return AgnoImage::null();
+    }
+
+    let real_img = unsafe { Box::from_raw(img) };
+    if spec.is_null() {
+        AgnoImage::free(&real_img);
+        return AgnoImage::null();
+    }
+    let spec = match ResizeSpec::try_from(unsafe { *spec }) {
+        Ok(spec) => spec,
+        Err(e) => {
+            AgnoImage::free(&real_img);
+            info!("Error occurred, returning null pointer: {:?}", e);
+            return AgnoImage::null();
+        }
+    };
+    ok_or_null!(resize_with_spec(*real_img, &spec))
+}
+
+// Like resize_image, these take ownership of img and return a new image, or null on failure
+#[unsafe(no_mangle)]
+pub extern "C" fn crop_agno_image(
+    img: *mut AgnoImage,
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+) -> *mut AgnoImage {
+    if img.is_null() {
+        return AgnoImage::null();
+    }
+
+    let real_img = unsafe { Box::from_raw(img) };
+    ok_or_null!(crop_image(*real_img, x, y, width, height))
+}
+
+// Clockwise by 90, 180 or 270 degrees
+#[unsafe(no_mangle)]
+pub extern "C" fn rotate_agno_image(img: *mut AgnoImage, degrees: i32) -> *mut AgnoImage {
+    if img.is_null() {
+        return AgnoImage::null();
+    }
+
+    let real_img = unsafe { Box::from_raw(img) };
+    ok_or_null!(rotate_image(*real_img, degrees))
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn flip_agno_image(img: *mut AgnoImage, axis: i32) -> *mut AgnoImage {
+    if img.is_null() {
+        return AgnoImage::null();
+    }
+
+    let real_img = unsafe { Box::from_raw(img) };
+    match FlipAxis::try_from(axis) {
+        Ok(axis) => Box::into_raw(Box::new(flip_image(*real_img, axis))),
+        Err(e) => {
+            AgnoImage::free(&real_img);
+            info!("Error occurred, returning null pointer: {:?}", e);
+            AgnoImage::null()
+        }
+    }
+}
+
+// Clockwise by any angle; the background (0-1 per channel) fills ROTATE_FILL's corners
+#[unsafe(no_mangle)]
+pub extern "C" fn rotate_agno_image_by(
+    img: *mut AgnoImage,
+    degrees: f32,
+    mode: i32,
+    bg_r: f32,
+    bg_g: f32,
+    bg_b: f32,
+) -> *mut AgnoImage {
+    if img.is_null() {
+        return AgnoImage::null();
+    }
+
+    let real_img = unsafe { Box::from_raw(img) };
+    let mode = match RotateMode::try_from(mode) {
+        Ok(mode) => mode,
+        Err(e) => {
+            AgnoImage::free(&real_img);
+            info!("Error occurred, returning null pointer: {:?}", e);
+            return AgnoImage::null();
+        }
+    };
+    ok_or_null!(rotate_image_by(
+        *real_img,
+        degrees,
+        mode,
+        [bg_r, bg_g, bg_b]
+    ))
+}
+
+// Composites RGBA over the background (0-1 per channel), leaving RGB; takes ownership of img
+#[unsafe(no_mangle)]
+pub extern "C" fn flatten_agno_image(
+    img: *mut AgnoImage,
+    bg_r: f32,
+    bg_g: f32,
+    bg_b: f32,
+) -> *mut AgnoImage {
+    if img.is_null() {
+        return AgnoImage::null();
+    }
+
+    let real_img = unsafe { Box::from_raw(img) };
+    Box::into_raw(Box::new(flatten_alpha(*real_img, [bg_r, bg_g, bg_b])))
+}
+
+// Per-CFA-channel histograms and clipping of the raw mosaic at path, as JSON
+#[unsafe(no_mangle)]
+pub extern "C" fn get_raw_stats(path: *const u8, len: usize) -> AgnoBuffer {
+    let wrapped_path = CString::new(path, len);
+
+    match raw_stats_from_file(wrapped_path.as_str()) {
+        Ok(stats) => AgnoBuffer::json(&stats),
+        Err(e) => {
+            info!("Error occurred, returning null buffer: {:?}", e);
+            AgnoBuffer::null()
+        }
+    }
+}
+
+// RGB and luma histograms of a rendered image, as JSON
+#[unsafe(no_mangle)]
+pub extern "C" fn get_image_stats(img: &AgnoImage) -> AgnoBuffer {
+    AgnoBuffer::json(&image_stats(img))
+}
+
+// BlurHash of the image as a nul-terminated string, a null buffer when the
+// component counts are outside 1-9
+#[unsafe(no_mangle)]
+pub extern "C" fn get_blurhash(
+    img: &AgnoImage,
+    x_components: u32,
+    y_components: u32,
+) -> AgnoBuffer {
+    match blurhash(img, x_components, y_components) {
+        Ok(hash) => AgnoBuffer::from_str(&hash),
+        Err(e) => {
+            info!("Error occurred, returning null buffer: {:?}", e);
+            AgnoBuffer::null()
+        }
+    }
+}
+
+// ThumbHash bytes of the image
+#[unsafe(no_mangle)]
+pub extern "C" fn get_thumbhash(img: &AgnoImage) -> AgnoBuffer {
+    match thumbhash(img) {
+        Ok(hash) => AgnoBuffer::from_bytes(&hash),
+        Err(e) => {
+            info!("Error occurred, returning null buffer: {:?}", e);
+            AgnoBuffer::null()
+        }
+    }
+}
+
+// 64-bit perceptual hash of the image, upright by its EXIF orientation; 0 if it
+// can't be computed
+#[unsafe(no_mangle)]
+pub extern "C" fn get_image_hash(img: &AgnoImage, kind: i32) -> u64 {
+    ImageHashKind::try_from(kind)
+        .and_then(|kind| image_hash(img, kind))
+        .unwrap_or_else(|e| {
+            info!("Image hash failed: {:?}", e);
+            0
+        })
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn image_hash_distance(a: u64, b: u64) -> u32 {
+    hamming_distance(a, b)
+}
+
+// Near-duplicate clusters among `count` files and each file's hash, as JSON
+#[unsafe(no_mangle)]
+pub extern "C" fn find_near_duplicates(
+    paths: *const *const u8,
+    lens: *const usize,
+    count: usize,
+    kind: i32,
+    threshold: u32,
+    options: *const CLoadOptions,
+) -> AgnoBuffer {
+    if paths.is_null() || lens.is_null() {
+        return AgnoBuffer::null();
+    }
+
+    let (paths, lens) = unsafe {
+        (
+            std::slice::from_raw_parts(paths, count),
+            std::slice::from_raw_parts(lens, count),
+        )
+    };
+    let wrapped_paths: Vec<CString> = paths
+        .iter()
+        .zip(lens)
+        .map(|(&p, &len)| CString::new(p, len))
+        .collect();
+    let paths: Vec<&str> = wrapped_paths.iter().map(|p| p.as_str()).collect();
+    let clusters = ImageHashKind::try_from(kind).and_then(|kind| {
+        let options = unsafe { read_load_options(options) }?;
+        Ok(cluster_near_duplicates(&paths, kind, threshold, &options))
+    });
+
+    match clusters {
+        Ok(clusters) => AgnoBuffer::json(&clusters),
+        Err(e) => {
+            info!("Error occurred, returning null buffer: {:?}", e);
+            AgnoBuffer::null()
+        }
+    }
+}
+
+// ICC profile the image was loaded with, a null buffer if the file had none
+#[unsafe(no_mangle)]
+pub extern "C" fn get_icc_profile(img: &AgnoImage) -> AgnoBuffer {
+    img.icc_profile
+        .as_deref()
+        .map_or_else(AgnoBuffer::null, AgnoBuffer::from_bytes)
+}
+
+// ICC profile describing the image's pixels, a null buffer for sRGB
+#[unsafe(no_mangle)]
+pub extern "C" fn get_color_profile(img: &AgnoImage) -> AgnoBuffer {
+    img.color_profile
+        .as_deref()
+        .map_or_else(AgnoBuffer::null, AgnoBuffer::from_bytes)
+}
+
+// ICC profile embedded in the file at path (JPEG, PNG, WebP, TIFF or HEIF), without decoding it
+#[unsafe(no_mangle)]
+pub extern "C" fn get_file_icc_profile(path: *const u8, len: usize) -> AgnoBuffer {
+    let wrapped_path = CString::new(path, len);
+
+    match icc_profile_from_file(wrapped_path.as_str()) {
+        Ok(Some(profile)) => AgnoBuffer::from_bytes(&profile),
+        Ok(None) => AgnoBuffer::null(),
+        Err(e) => {
+            info!("Error occurred, returning null buffer: {:?}", e);
+            AgnoBuffer::null()
+        }
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn free_agno_buffer(buf: AgnoBuffer) {
+    if !buf.data.is_null() {
+        unsafe { libc::free(buf.data as *mut libc::c_void) };
+    }
+}
+
 #[unsafe(no_mangle)]
 pub extern "C" fn get_exif_value(img: &AgnoImage, img_tag: u16) -> ExifData {
     let data = img.exif.get_tag_value_by_tag(img_tag);
@@ -120,3 +928,115 @@ pub extern "C" fn init_agno() {
 
     info!("Agno initialized");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // What a C caller filling in the defaults by hand would pass
+    fn c_settings() -> CRawDevelopSettings {
+        CRawDevelopSettings {
+            wb_mode: 0,
+            wb_multipliers: [1.0; 4],
+            wb_temperature: 5500.0,
+            wb_tint: 0.0,
+            exposure_ev: 0.0,
+            black_level: -1,
+            white_level: 0,
+            demosaic: 0,
+            highlight_mode: 0,
+            color_space: 1,
+            tone_curve: 0,
+            gamma: 2.2,
+            toe_slope: 0.0,
+            bit_depth: 8,
+            scale: 0,
+            bad_pixel_mode: 0,
+            bad_pixel_threshold: 0.0,
+            defect_map: std::ptr::null(),
+            defect_map_len: 0,
+            dark_frame: std::ptr::null(),
+            dark_frame_len: 0,
+            flat_field: std::ptr::null(),
+            flat_field_len: 0,
+            lens_corrections: 0,
+            pixel_shift_motion_threshold: 0.0,
+            noise_reduction: 0,
+            wavelet_threshold: 0.0,
+            chroma_denoise: 0.0,
+        }
+    }
+
+    #[test]
+    fn c_defaults_match_the_rust_defaults() {
+        let settings = unsafe { c_settings().to_settings() }.unwrap();
+        assert_eq!(
+            format!("{:?}", settings),
+            format!("{:?}", RawDevelopSettings::default())
+        );
+        let options = unsafe { read_load_options(std::ptr::null()) }.unwrap();
+        assert_eq!(
+            format!("{:?}", options),
+            format!("{:?}", LoadOptions::default())
+        );
+    }
+
+    #[test]
+    fn c_settings_carry_every_enum_and_path() {
+        let dark = "/tmp/dark.ARW";
+        let c = CRawDevelopSettings {
+            wb_mode: 5,
+            highlight_mode: 3,
+            tone_curve: 4,
+            scale: 2,
+            noise_reduction: 2,
+            dark_frame: dark.as_ptr(),
+            dark_frame_len: dark.len(),
+            ..c_settings()
+        };
+        let settings = unsafe { c.to_settings() }.unwrap();
+        assert_eq!(settings.wb_mode, WhiteBalanceMode::Temperature);
+        assert_eq!(settings.highlight_mode, HighlightMode::Reconstruct);
+        assert_eq!(settings.tone_curve, ToneCurve::Filmic);
+        assert_eq!(settings.scale, RawScale::Quarter);
+        assert_eq!(settings.noise_reduction, NoiseReduction::Manual);
+        assert_eq!(settings.dark_frame, Some(PathBuf::from(dark)));
+        assert_eq!(settings.flat_field, None);
+    }
+
+    #[test]
+    fn out_of_range_enums_and_bad_paths_are_rejected() {
+        let bad = [
+            CRawDevelopSettings {
+                highlight_mode: 4,
+                ..c_settings()
+            },
+            CRawDevelopSettings {
+                demosaic: -1,
+                ..c_settings()
+            },
+            CRawDevelopSettings {
+                color_space: 5,
+                ..c_settings()
+            },
+        ];
+        for c in bad {
+            assert!(unsafe { c.to_settings() }.is_err());
+        }
+
+        let not_utf8 = [0x2f, 0xff, 0xfe];
+        let c = CRawDevelopSettings {
+            defect_map: not_utf8.as_ptr(),
+            defect_map_len: not_utf8.len(),
+            ..c_settings()
+        };
+        assert!(unsafe { c.to_settings() }.is_err());
+        // A length of zero means no path, whatever the pointer
+        let c = CRawDevelopSettings {
+            defect_map: not_utf8.as_ptr(),
+            defect_map_len: 0,
+            ..c_settings()
+        };
+        assert_eq!(unsafe { c.to_settings() }.unwrap().defect_map, None);
+    }
+}
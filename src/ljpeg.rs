@@ -0,0 +1,614 @@
+use std::io::Read;
+
+use crate::sony_decoder::{DecodeError, Dimensions, JpegBitstream};
+
+const SOI: u8 = 0xd8;
+const EOI: u8 = 0xd9;
+const SOF3: u8 = 0xc3;
+const DHT: u8 = 0xc4;
+const SOS: u8 = 0xda;
+
+// JpegBitstream::ljpeg_diff always looks 15 bits ahead, so no code may be longer
+const MAX_CODE_LEN: usize = 15;
+
+// Difference categories 0..=16; 16 (a diff of exactly 32768, with no extra bits)
+// only occurs at 16-bit precision
+const CATEGORIES: usize = 17;
+
+// ====================== Encoder ======================
+
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u32,
+    bits: u32,
+}
+
+impl BitWriter {
+    fn new(out: Vec<u8>) -> Self {
+        BitWriter {
+            out,
+            acc: 0,
+            bits: 0,
+        }
+    }
+
+    #[inline(always)]
+    fn put(&mut self, value: u32, len: u32) {
+        if len == 0 {
+            return;
+        }
+        self.acc = (self.acc << len) | (value & ((1 << len) - 1));
+        self.bits += len;
+        while self.bits >= 8 {
+            self.bits -= 8;
+            let byte = (self.acc >> self.bits) as u8;
+            self.out.push(byte);
+            // Byte stuffing so data never looks like a marker
+            if byte == 0xff {
+                self.out.push(0);
+            }
+        }
+        self.acc &= (1 << self.bits) - 1;
+    }
+
+    // Pads the last byte with ones, as the spec asks
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            let pad = 8 - self.bits;
+            self.put((1 << pad) - 1, pad);
+        }
+        self.out
+    }
+}
+
+#[inline(always)]
+fn category(diff: i32) -> u32 {
+    32 - diff.unsigned_abs().leading_zeros()
+}
+
+// Differences are taken modulo 2^16 (H.1.2.1), so at 16-bit precision they stay in
+// -32768..=32767 and decoders wrap them back. Below that they never leave the range.
+#[inline(always)]
+fn wrap_diff(diff: i32) -> i32 {
+    diff as i16 as i32
+}
+
+// JPEG Annex K.2: Huffman code lengths from symbol frequencies, limited to MAX_CODE_LEN.
+// Returns BITS (codes per length, index 1..=16) and HUFFVAL (symbols by increasing length).
+fn build_huffman(freq: &[u64; CATEGORIES]) -> ([u8; 17], Vec<u8>) {
+    // One reserved symbol with frequency 1 keeps the all-ones code unused
+    let n = CATEGORIES + 1;
+    let mut freq: Vec<u64> = freq.iter().copied().chain([1]).collect();
+    let mut codesize = vec![0usize; n];
+    let mut others = vec![None::<usize>; n];
+
+    loop {
+        // The two least frequent (non-zero) symbols, v1 < v2 in frequency
+        let mut v1 = None;
+        let mut v2 = None;
+        for i in 0..n {
+            if freq[i] == 0 {
+                continue;
+            }
+            match v1 {
+                Some(a) if freq[i] > freq[a] => match v2 {
+                    Some(b) if freq[i] > freq[b] => {}
+                    _ => v2 = Some(i),
+                },
+                _ => {
+                    v2 = v1;
+                    v1 = Some(i);
+                }
+            }
+        }
+        let (Some(v1), Some(v2)) = (v1, v2) else {
+            break;
+        };
+
+        freq[v1] += freq[v2];
+        freq[v2] = 0;
+
+        let mut i = v1;
+        loop {
+            codesize[i] += 1;
+            match others[i] {
+                Some(next) => i = next,
+                None => break,
+            }
+        }
+        others[i] = Some(v2);
+
+        let mut i = v2;
+        loop {
+            codesize[i] += 1;
+            match others[i] {
+                Some(next) => i = next,
+                None => break,
+            }
+        }
+    }
+
+    let mut bits = [0u32; 33];
+    for &size in codesize.iter().filter(|&&s| s > 0) {
+        bits[size] += 1;
+    }
+
+    // Annex K.3: fold codes longer than the limit back in
+    let mut i = 32;
+    while i > MAX_CODE_LEN {
+        while bits[i] > 0 {
+            let mut j = i - 2;
+            while bits[j] == 0 {
+                j -= 1;
+            }
+            bits[i] -= 2;
+            bits[i - 1] += 1;
+            bits[j + 1] += 2;
+            bits[j] -= 1;
+        }
+        i -= 1;
+    }
+    // Drop the reserved symbol from the longest length
+    while bits[i] == 0 {
+        i -= 1;
+    }
+    bits[i] -= 1;
+
+    let mut counts = [0u8; 17];
+    for (dst, &b) in counts.iter_mut().zip(bits.iter()).skip(1) {
+        *dst = b as u8;
+    }
+
+    // Symbols sorted by code size, reserved one excluded
+    let mut values = Vec::new();
+    for size in 1..=32 {
+        for (sym, &cs) in codesize.iter().enumerate().take(CATEGORIES) {
+            if cs == size {
+                values.push(sym as u8);
+            }
+        }
+    }
+
+    (counts, values)
+}
+
+// Canonical codes (Annex C) as (code, length) per symbol
+fn canonical_codes(counts: &[u8; 17], values: &[u8]) -> [(u32, u32); CATEGORIES] {
+    let mut codes = [(0u32, 0u32); CATEGORIES];
+    let mut code = 0u32;
+    let mut k = 0;
+    for len in 1..=16u32 {
+        for _ in 0..counts[len as usize] {
+            codes[values[k] as usize] = (code, len);
+            code += 1;
+            k += 1;
+        }
+        code <<= 1;
+    }
+    codes
+}
+
+// Predictor 1 (left neighbour of the same component); the first sample of a row uses
+// the one above it, and the very first sample 2^(precision - 1)
+#[inline(always)]
+fn predict(samples: &[u16], row: usize, col: usize, width: usize, precision: u32) -> i32 {
+    if col >= 2 {
+        samples[row * width + col - 2] as i32
+    } else if row > 0 {
+        samples[(row - 1) * width + col] as i32
+    } else {
+        1 << (precision - 1)
+    }
+}
+
+/// Encodes a CFA image as a lossless JPEG (SOF3) stream, the way DNG stores it: two
+/// interleaved components of half the width, so every prediction comes from the same
+/// CFA colour.
+/// - samples: `width * height` values, row-major and densely packed
+/// - precision: bits per sample, 2 to 16; every sample must fit in it
+pub fn encode(
+    samples: &[u16],
+    width: usize,
+    height: usize,
+    precision: u32,
+) -> Result<Vec<u8>, DecodeError> {
+    if !width.is_multiple_of(2) || width == 0 || height == 0 {
+        return Err(DecodeError::CorruptData(
+            "Lossless JPEG needs a non-empty image of even width",
+        ));
+    }
+    if !(2..=16).contains(&precision) || width / 2 > u16::MAX as usize || height > u16::MAX as usize
+    {
+        return Err(DecodeError::CorruptData(
+            "Image out of range for lossless JPEG",
+        ));
+    }
+    if samples[..width * height]
+        .iter()
+        .any(|&v| (v as u32) >> precision != 0)
+    {
+        return Err(DecodeError::CorruptData(
+            "Sample out of range for lossless JPEG precision",
+        ));
+    }
+
+    let diff_at = |row: usize, col: usize| {
+        wrap_diff(samples[row * width + col] as i32 - predict(samples, row, col, width, precision))
+    };
+
+    let mut freq = [0u64; CATEGORIES];
+    for row in 0..height {
+        for col in 0..width {
+            freq[category(diff_at(row, col)) as usize] += 1;
+        }
+    }
+    let (counts, values) = build_huffman(&freq);
+    let codes = canonical_codes(&counts, &values);
+
+    let mut header = vec![0xff, SOI];
+
+    // DHT: one DC table, id 0
+    header.extend_from_slice(&[0xff, DHT]);
+    header.extend_from_slice(&(2 + 1 + 16 + values.len() as u16).to_be_bytes());
+    header.push(0x00);
+    header.extend_from_slice(&counts[1..]);
+    header.extend_from_slice(&values);
+
+    // SOF3: two components of width / 2
+    header.extend_from_slice(&[0xff, SOF3, 0, 14, precision as u8]);
+    header.extend_from_slice(&(height as u16).to_be_bytes());
+    header.extend_from_slice(&((width / 2) as u16).to_be_bytes());
+    header.extend_from_slice(&[2, 1, 0x11, 0, 2, 0x11, 0]);
+
+    // SOS: both components on table 0, predictor 1, no point transform
+    header.extend_from_slice(&[0xff, SOS, 0, 10, 2, 1, 0x00, 2, 0x00, 1, 0, 0]);
+
+    let mut bw = BitWriter::new(header);
+    for row in 0..height {
+        for col in 0..width {
+            let diff = diff_at(row, col);
+            let cat = category(diff);
+            let (code, len) = codes[cat as usize];
+            bw.put(code, len);
+            // Negative differences are stored as diff - 1 in `cat` bits; category 16 has none
+            if cat < 16 {
+                let extra = if diff < 0 { diff - 1 } else { diff };
+                bw.put(extra as u32, cat);
+            }
+        }
+    }
+
+    let mut out = bw.finish();
+    out.extend_from_slice(&[0xff, EOI]);
+    Ok(out)
+}
+
+// ====================== Decoder ======================
+
+struct Frame {
+    precision: u32,
+    height: usize,
+    width: usize,
+    // Huffman table id per component, in scan order
+    tables: Vec<usize>,
+    predictor: u32,
+    point_transform: u32,
+}
+
+fn read_u8<R: Read>(r: &mut R) -> Result<u8, DecodeError> {
+    let mut b = [0u8; 1];
+    r.read_exact(&mut b)?;
+    Ok(b[0])
+}
+
+fn read_u16_be<R: Read>(r: &mut R) -> Result<u16, DecodeError> {
+    let mut b = [0u8; 2];
+    r.read_exact(&mut b)?;
+    Ok(u16::from_be_bytes(b))
+}
+
+fn next_marker<R: Read>(r: &mut R) -> Result<u8, DecodeError> {
+    while read_u8(r)? != 0xff {}
+    let mut m = read_u8(r)?;
+    while m == 0xff {
+        m = read_u8(r)?;
+    }
+    Ok(m)
+}
+
+// Lookup table in the layout JpegBitstream::ljpeg_diff expects: indexed by the next
+// 15 bits, each entry (code length << 8) | symbol
+fn decoder_table(counts: &[u8; 17], values: &[u8]) -> Result<Vec<u16>, DecodeError> {
+    let mut table = vec![0u16; 1 << MAX_CODE_LEN];
+    let mut pos = 0usize;
+    let mut k = 0usize;
+    for (len, &count) in counts.iter().enumerate().skip(1) {
+        for _ in 0..count {
+            if len > MAX_CODE_LEN {
+                return Err(DecodeError::CorruptData("Lossless JPEG: code too long"));
+            }
+            let sym = *values
+                .get(k)
+                .ok_or(DecodeError::CorruptData("Lossless JPEG: short DHT"))?;
+            let span = 1 << (MAX_CODE_LEN - len);
+            if pos + span > table.len() {
+                return Err(DecodeError::CorruptData("Lossless JPEG: bad DHT"));
+            }
+            table[pos..pos + span].fill(((len as u16) << 8) | sym as u16);
+            pos += span;
+            k += 1;
+        }
+    }
+    Ok(table)
+}
+
+// Parses markers up to and including SOS. None once the stream has no more images.
+fn read_headers<R: Read>(
+    r: &mut R,
+    tables: &mut [Option<Vec<u16>>; 4],
+) -> Result<Option<Frame>, DecodeError> {
+    // Skip the tail of the previous image (padding, stuffed bytes, its EOI) up to the next SOI
+    loop {
+        match next_marker(r) {
+            Ok(SOI) => break,
+            Ok(_) => {}
+            Err(DecodeError::Io(_)) => return Ok(None),
+            Err(e) => return Err(e),
+        }
+    }
+
+    let mut frame = None;
+    let mut components: Vec<u8> = Vec::new();
+
+    loop {
+        let marker = next_marker(r)?;
+        if marker == EOI {
+            return Ok(None);
+        }
+        let len = read_u16_be(r)? as usize;
+        let mut seg = vec![0u8; len.saturating_sub(2)];
+        r.read_exact(&mut seg)?;
+
+        match marker {
+            DHT => {
+                let mut p = 0;
+                while p + 17 <= seg.len() {
+                    let id = (seg[p] & 0x0f) as usize;
+                    let mut counts = [0u8; 17];
+                    counts[1..].copy_from_slice(&seg[p + 1..p + 17]);
+                    let n: usize = counts.iter().map(|&c| c as usize).sum();
+                    let values = seg
+                        .get(p + 17..p + 17 + n)
+                        .ok_or(DecodeError::CorruptData("Lossless JPEG: short DHT"))?;
+                    if id < 4 {
+                        tables[id] = Some(decoder_table(&counts, values)?);
+                    }
+                    p += 17 + n;
+                }
+            }
+            SOF3 => {
+                if seg.len() < 6 {
+                    return Err(DecodeError::CorruptData("Lossless JPEG: short SOF3"));
+                }
+                if !(2..=16).contains(&seg[0]) {
+                    return Err(DecodeError::CorruptData(
+                        "Lossless JPEG: unsupported precision",
+                    ));
+                }
+                let n = seg[5] as usize;
+                components = (0..n).filter_map(|i| seg.get(6 + i * 3).copied()).collect();
+                frame = Some((
+                    seg[0] as u32,
+                    u16::from_be_bytes([seg[1], seg[2]]) as usize,
+                    u16::from_be_bytes([seg[3], seg[4]]) as usize,
+                ));
+            }
+            0xc0..=0xcf => {
+                return Err(DecodeError::CorruptData(
+                    "Lossless JPEG: not a lossless (SOF3) stream",
+                ));
+            }
+            SOS => {
+                let (precision, height, width) =
+                    frame.ok_or(DecodeError::CorruptData("Lossless JPEG: SOS before SOF3"))?;
+                let ns = *seg.first().unwrap_or(&0) as usize;
+                if ns != components.len() || seg.len() < 1 + ns * 2 + 3 {
+                    return Err(DecodeError::CorruptData("Lossless JPEG: bad SOS"));
+                }
+                let tables = (0..ns)
+                    .map(|i| (seg[2 + i * 2] >> 4) as usize & 3)
+                    .collect();
+                let predictor = seg[1 + ns * 2] as u32;
+                let point_transform = (seg[3 + ns * 2] & 0x0f) as u32;
+                if point_transform >= precision {
+                    return Err(DecodeError::CorruptData(
+                        "Lossless JPEG: point transform exceeds precision",
+                    ));
+                }
+                return Ok(Some(Frame {
+                    precision,
+                    height,
+                    width,
+                    tables,
+                    predictor,
+                    point_transform,
+                }));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Decodes one or more concatenated lossless JPEG streams (one per DNG strip) into
+/// a raw buffer. Samples fill the active area row by row in stream order, so any
+/// component interleave works as long as it adds up to the raster width.
+pub fn decode<R: Read>(reader: &mut R, dims: Dimensions) -> Result<Vec<u16>, DecodeError> {
+    let mut pixels = vec![0u16; dims.raw_width * dims.raw_height];
+    let total = dims.output_width * dims.output_height;
+    let mut filled = 0usize;
+    let mut tables: [Option<Vec<u16>>; 4] = Default::default();
+
+    while filled < total {
+        let Some(frame) = read_headers(reader, &mut tables)? else {
+            break;
+        };
+        let clrs = frame.tables.len();
+        let jwide = frame.width * clrs;
+        if clrs == 0 || jwide == 0 || !(1..=7).contains(&frame.predictor) {
+            return Err(DecodeError::CorruptData("Lossless JPEG: unsupported frame"));
+        }
+        let huff: Vec<&[u16]> = frame
+            .tables
+            .iter()
+            .map(|&t| tables[t].as_deref())
+            .collect::<Option<_>>()
+            .ok_or(DecodeError::CorruptData(
+                "Lossless JPEG: missing Huffman table",
+            ))?;
+
+        let mask = (1i32 << frame.precision) - 1;
+        let mut prev = vec![0i32; jwide];
+        let mut cur = vec![0i32; jwide];
+
+        let mut bs = JpegBitstream::new(&mut *reader);
+        bs.set_zero_after_ff(true);
+        bs.reset_state();
+
+        for jrow in 0..frame.height {
+            for jcol in 0..jwide {
+                let c = jcol % clrs;
+                let diff = bs.ljpeg_diff(huff[c])?;
+
+                let pred = if jcol < clrs {
+                    if jrow == 0 {
+                        1 << (frame.precision - frame.point_transform - 1)
+                    } else {
+                        prev[jcol]
+                    }
+                } else if jrow == 0 {
+                    cur[jcol - clrs]
+                } else {
+                    let (a, b, d) = (cur[jcol - clrs], prev[jcol], prev[jcol - clrs]);
+                    match frame.predictor {
+                        1 => a,
+                        2 => b,
+                        3 => d,
+                        4 => a + b - d,
+                        5 => a + ((b - d) >> 1),
+                        6 => b + ((a - d) >> 1),
+                        _ => (a + b) >> 1,
+                    }
+                };
+
+                let v = (pred + diff) & mask;
+                cur[jcol] = v;
+
+                if filled < total {
+                    let (row, col) = (filled / dims.output_width, filled % dims.output_width);
+                    pixels[row * dims.raw_width + col] = (v << frame.point_transform) as u16;
+                    filled += 1;
+                }
+            }
+            std::mem::swap(&mut prev, &mut cur);
+        }
+    }
+
+    if filled < total {
+        return Err(DecodeError::CorruptData(
+            "Lossless JPEG: image data ends early",
+        ));
+    }
+
+    Ok(pixels)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn dims(width: usize, height: usize) -> Dimensions {
+        Dimensions {
+            raw_width: width,
+            raw_height: height,
+            output_width: width,
+            output_height: height,
+        }
+    }
+
+    // Pseudo-random samples masked to `precision` bits
+    fn samples(width: usize, height: usize, precision: u32) -> Vec<u16> {
+        (0..(width * height) as u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 16) as u16 & ((1u32 << precision) - 1) as u16)
+            .collect()
+    }
+
+    fn round_trip(samples: &[u16], width: usize, height: usize, precision: u32) -> Vec<u16> {
+        let stream = encode(samples, width, height, precision).unwrap();
+        decode(&mut Cursor::new(stream), dims(width, height)).unwrap()
+    }
+
+    #[test]
+    fn round_trips_at_every_precision() {
+        let (width, height) = (38, 11);
+        for precision in 2..=16 {
+            let source = samples(width, height, precision);
+            assert_eq!(
+                round_trip(&source, width, height, precision),
+                source,
+                "precision {}",
+                precision
+            );
+        }
+    }
+
+    #[test]
+    fn sixteen_bit_steps_of_half_the_range_round_trip() {
+        // Same-colour neighbours 32768 apart need difference category 16
+        let (width, height) = (8, 4);
+        let source: Vec<u16> = (0..width * height)
+            .map(|i| match (i / 2) % 4 {
+                0 => 0,
+                1 => 32768,
+                2 => 65535,
+                _ => 32767,
+            })
+            .collect();
+        assert_eq!(round_trip(&source, width, height, 16), source);
+    }
+
+    #[test]
+    fn samples_wider_than_the_precision_are_rejected() {
+        let mut source = samples(8, 2, 12);
+        source[5] = 4096;
+        assert!(encode(&source, 8, 2, 12).is_err());
+        assert!(encode(&source, 8, 2, 17).is_err());
+    }
+
+    // Offset of a marker's first byte in an encoded stream
+    fn marker(stream: &[u8], marker: u8) -> usize {
+        stream.windows(2).position(|w| w == [0xff, marker]).unwrap()
+    }
+
+    #[test]
+    fn malformed_frame_headers_are_errors() {
+        let (width, height) = (8, 2);
+        let stream = encode(&samples(width, height, 14), width, height, 14).unwrap();
+        let decode_patched = |at: usize, value: u8| {
+            let mut patched = stream.clone();
+            patched[at] = value;
+            decode(&mut Cursor::new(patched), dims(width, height))
+        };
+
+        // SOF3 precision follows the marker and segment length
+        let precision_at = marker(&stream, SOF3) + 4;
+        assert!(decode_patched(precision_at, 0).is_err());
+        assert!(decode_patched(precision_at, 1).is_err());
+        assert!(decode_patched(precision_at, 17).is_err());
+        // SOS ends with the successive approximation byte holding the point transform
+        let transform_at = marker(&stream, SOS) + 11;
+        assert!(decode_patched(transform_at, 14).is_err());
+        assert!(decode_patched(transform_at, 0).is_ok());
+    }
+}
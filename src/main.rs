@@ -1,12 +1,28 @@
 use crate::agno_image::load::load_agno_image_from_file;
 
 mod agno_image;
+mod bad_pixels;
+mod black_level;
+mod calibration;
+mod color;
 mod demosaic;
+mod denoise;
+mod dng_writer;
 mod exif;
+mod highlight;
+mod icc;
+mod lens_correction;
+mod ljpeg;
+mod phash;
+mod pixel_shift;
 
+mod simd;
 mod sony_decoder;
 mod sony_jpeg;
+mod stats;
 mod tiff;
+mod tone;
+mod white_balance;
 
 // extern crate log;
 // extern crate rayon;
@@ -0,0 +1,313 @@
+use std::{error::Error, f32::consts::PI};
+
+use log::info;
+use serde::Serialize;
+
+use crate::{
+    agno_image::{
+        AgnoImage, PixelFormat, ResampleFilter, exif_orientation,
+        load::{LoadOptions, RawScale, load_agno_image_with_options},
+        orient_samples, resample_to_unit,
+    },
+    tone::TransferFn,
+};
+
+/// 64-bit perceptual hash to compute; hashes of the same kind are compared by
+/// Hamming distance.
+#[repr(C)]
+#[allow(dead_code)] // variants are constructed from C
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ImageHashKind {
+    /// 8x8 grey thumbnail against its mean
+    Average = 0,
+    /// 9x8 grey thumbnail, each pixel against its right-hand neighbour
+    Difference = 1,
+    /// Low frequencies of a 32x32 DCT against their median: the most robust to
+    /// re-encoding, scaling and tone changes
+    #[default]
+    Perceptual = 2,
+}
+
+// The pHash DCT runs over a DCT_SIZE grey thumbnail and keeps the DCT_KEEP x DCT_KEEP
+// lowest frequencies
+const DCT_SIZE: usize = 32;
+const DCT_KEEP: usize = 8;
+
+// Grey thumbnail of `width` x `height`, turned upright by the EXIF orientation and
+// gamma encoded, so linear and encoded renders of the same shot hash alike
+fn grey_thumbnail(
+    a_img: &AgnoImage,
+    width: usize,
+    height: usize,
+) -> Result<Vec<f32>, Box<dyn Error>> {
+    let orientation = exif_orientation(&a_img.exif);
+    // Scale to the stored shape; turning it upright then gives width x height
+    let (w, h) = if orientation >= 5 {
+        (height, width)
+    } else {
+        (width, height)
+    };
+    let filter = ResampleFilter::Bilinear;
+    let small = match a_img.format {
+        PixelFormat::U8 => resample_to_unit::<u8>(a_img, w as u32, h as u32, filter)?,
+        PixelFormat::U16 => resample_to_unit::<u16>(a_img, w as u32, h as u32, filter)?,
+        PixelFormat::F32 => resample_to_unit::<f32>(a_img, w as u32, h as u32, filter)?,
+    };
+
+    let srgb = TransferFn::srgb();
+    let grey: Vec<f32> = small
+        .chunks_exact(a_img.channels as usize)
+        .map(|px| {
+            let encode = |v: f32| {
+                if a_img.format.is_linear() {
+                    srgb.encode(v.clamp(0.0, 1.0))
+                } else {
+                    v
+                }
+            };
+            match px.len() {
+                1 => encode(px[0]),
+                _ => 0.299 * encode(px[0]) + 0.587 * encode(px[1]) + 0.114 * encode(px[2]),
+            }
+        })
+        .collect();
+    Ok(orient_samples(&grey, w, h, 1, orientation).0)
+}
+
+// Packs bits into a hash, first bit most significant
+fn pack_bits(bits: impl Iterator<Item = bool>) -> u64 {
+    bits.fold(0, |hash, bit| (hash << 1) | bit as u64)
+}
+
+// Lowest DCT-II frequencies of a DCT_SIZE square, row-major
+fn low_frequencies(grey: &[f32]) -> Vec<f32> {
+    let basis: Vec<[f32; DCT_SIZE]> = (0..DCT_KEEP)
+        .map(|k| {
+            std::array::from_fn(|x| {
+                (PI * (2 * x + 1) as f32 * k as f32 / (2 * DCT_SIZE) as f32).cos()
+            })
+        })
+        .collect();
+
+    // Along the rows, then down the columns of what they leave
+    let rows: Vec<[f32; DCT_KEEP]> = grey
+        .chunks_exact(DCT_SIZE)
+        .map(|row| std::array::from_fn(|k| row.iter().zip(&basis[k]).map(|(v, b)| v * b).sum()))
+        .collect();
+    (0..DCT_KEEP * DCT_KEEP)
+        .map(|i| {
+            let (ky, kx) = (i / DCT_KEEP, i % DCT_KEEP);
+            rows.iter()
+                .zip(&basis[ky])
+                .map(|(row, b)| row[kx] * b)
+                .sum()
+        })
+        .collect()
+}
+
+/// 64-bit perceptual hash of the image, upright per its EXIF orientation whether
+/// or not it was loaded with `keep_orientation`.
+pub fn image_hash(a_img: &AgnoImage, kind: ImageHashKind) -> Result<u64, Box<dyn Error>> {
+    Ok(match kind {
+        ImageHashKind::Average => {
+            let grey = grey_thumbnail(a_img, 8, 8)?;
+            let mean = grey.iter().sum::<f32>() / grey.len() as f32;
+            pack_bits(grey.iter().map(|&v| v > mean))
+        }
+        ImageHashKind::Difference => {
+            let grey = grey_thumbnail(a_img, 9, 8)?;
+            pack_bits(
+                grey.chunks_exact(9)
+                    .flat_map(|row| row.windows(2).map(|pair| pair[1] > pair[0])),
+            )
+        }
+        ImageHashKind::Perceptual => {
+            let coefficients = low_frequencies(&grey_thumbnail(a_img, DCT_SIZE, DCT_SIZE)?);
+            let mut sorted = coefficients.clone();
+            sorted.sort_by(f32::total_cmp);
+            let mid = sorted.len() / 2;
+            let median = (sorted[mid - 1] + sorted[mid]) / 2.0;
+            pack_bits(coefficients.iter().map(|&v| v > median))
+        }
+    })
+}
+
+/// Number of differing bits between two hashes; 0 is identical, and pHashes of the
+/// same picture typically stay within 10.
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Files grouped by near-duplicate, as indices into the paths they were found among.
+#[derive(Clone, Debug, Serialize)]
+pub struct DuplicateClusters {
+    /// Every file that could be hashed, each in exactly one cluster, in order of
+    /// their first file; files without a near duplicate are alone in theirs
+    pub clusters: Vec<Vec<usize>>,
+    /// Each file's hash as 16 hex digits, None where it couldn't be loaded
+    pub hashes: Vec<Option<String>>,
+}
+
+fn find_root(parent: &mut [usize], mut i: usize) -> usize {
+    while parent[i] != i {
+        parent[i] = parent[parent[i]];
+        i = parent[i];
+    }
+    i
+}
+
+// Union-find over every pair of hashes within the threshold; clusters are listed in
+// order of their first index and leave out the missing hashes
+fn cluster_hashes(hashes: &[Option<u64>], threshold: u32) -> Vec<Vec<usize>> {
+    let mut parent: Vec<usize> = (0..hashes.len()).collect();
+    for (i, a) in hashes.iter().enumerate() {
+        let Some(a) = a else { continue };
+        for (j, b) in hashes.iter().enumerate().skip(i + 1) {
+            if let Some(b) = b
+                && hamming_distance(*a, *b) <= threshold
+            {
+                let (ri, rj) = (find_root(&mut parent, i), find_root(&mut parent, j));
+                parent[ri.max(rj)] = ri.min(rj);
+            }
+        }
+    }
+
+    let mut clusters: Vec<Vec<usize>> = Vec::new();
+    let mut cluster_of_root: Vec<Option<usize>> = vec![None; hashes.len()];
+    for i in (0..hashes.len()).filter(|&i| hashes[i].is_some()) {
+        let root = find_root(&mut parent, i);
+        match cluster_of_root[root] {
+            Some(c) => clusters[c].push(i),
+            None => {
+                cluster_of_root[root] = Some(clusters.len());
+                clusters.push(vec![i]);
+            }
+        }
+    }
+    clusters
+}
+
+/// Hashes every file and clusters those within `threshold` bits of each other. Links
+/// chain, so A and C share a cluster when both are near B. Raws are developed at
+/// `RawScale::Eighth` whatever `options` say, as the hash only needs a thumbnail.
+/// Files that fail to load are logged and left out of the clusters.
+pub fn cluster_near_duplicates(
+    paths: &[&str],
+    kind: ImageHashKind,
+    threshold: u32,
+    options: &LoadOptions,
+) -> DuplicateClusters {
+    let mut options = options.clone();
+    options.raw.scale = RawScale::Eighth;
+
+    // One file at a time: decoding is parallel already, and full-size images add up
+    let hashes: Vec<Option<u64>> = paths
+        .iter()
+        .map(|path| {
+            let hash = load_agno_image_with_options(path, &options).and_then(|img| {
+                let hash = image_hash(&img, kind);
+                AgnoImage::free(&img);
+                hash
+            });
+            match hash {
+                Ok(hash) => Some(hash),
+                Err(e) => {
+                    info!("Leaving {} out of duplicate detection: {:?}", path, e);
+                    None
+                }
+            }
+        })
+        .collect();
+
+    DuplicateClusters {
+        clusters: cluster_hashes(&hashes, threshold),
+        hashes: hashes
+            .iter()
+            .map(|h| h.map(|h| format!("{:016x}", h)))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use image::{
+        RgbImage,
+        codecs::jpeg::JpegEncoder,
+        imageops::{self, FilterType},
+    };
+
+    use super::*;
+    use crate::exif::ExifContext;
+
+    // Smooth shading with a bright disc, shifted around by `seed`
+    fn scene(width: u32, height: u32, seed: f32) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, y| {
+            let (u, v) = (x as f32 / width as f32, y as f32 / height as f32);
+            let a = ((u * 7.0 + seed).sin() * (v * 5.0 - seed).cos() * 0.5 + 0.5) * 200.0;
+            let d = ((u - 0.3 * seed.cos().abs()).powi(2) + (v - 0.6).powi(2)).sqrt();
+            let c = if d < 0.2 { 255.0 } else { a };
+            image::Rgb([c as u8, (a * 0.7 + v * 60.0) as u8, (255.0 - c) as u8])
+        })
+    }
+
+    fn hash(rgb: &RgbImage, kind: ImageHashKind) -> u64 {
+        let img = AgnoImage::from_samples(
+            rgb.as_raw(),
+            rgb.width() as u64,
+            rgb.height() as u64,
+            ExifContext::new(),
+        );
+        let hash = image_hash(&img, kind).unwrap();
+        AgnoImage::free(&img);
+        hash
+    }
+
+    #[test]
+    fn copies_stay_within_the_threshold_and_other_images_do_not() {
+        let original = scene(640, 480, 1.0);
+        // Half size, then through a low-quality JPEG
+        let mut jpeg = Vec::new();
+        JpegEncoder::new_with_quality(&mut jpeg, 40)
+            .encode_image(&imageops::resize(&original, 320, 240, FilterType::Triangle))
+            .unwrap();
+        let copy = image::load_from_memory(&jpeg).unwrap().to_rgb8();
+        let other = scene(640, 480, 2.5);
+
+        for kind in [
+            ImageHashKind::Average,
+            ImageHashKind::Difference,
+            ImageHashKind::Perceptual,
+        ] {
+            let (a, b, c) = (hash(&original, kind), hash(&copy, kind), hash(&other, kind));
+            assert!(
+                hamming_distance(a, b) <= 10,
+                "{:?} copy: {}",
+                kind,
+                hamming_distance(a, b)
+            );
+            assert!(
+                hamming_distance(a, c) > 10,
+                "{:?} other: {}",
+                kind,
+                hamming_distance(a, c)
+            );
+        }
+    }
+
+    #[test]
+    fn near_duplicates_chain_into_one_cluster() {
+        let a = 0x0123_4567_89ab_cdef;
+        // B is 6 bits from A and C another 6 from B, so A and C are 12 apart
+        let b = a ^ 0x3f;
+        let c = b ^ (0x3f << 8);
+        let far = !a;
+        assert!(hamming_distance(a, c) > 10);
+
+        let hashes = [Some(a), Some(far), None, Some(c), Some(b)];
+        assert_eq!(cluster_hashes(&hashes, 10), vec![vec![0, 3, 4], vec![1]]);
+        assert_eq!(
+            cluster_hashes(&hashes, 5),
+            vec![vec![0], vec![1], vec![3], vec![4]]
+        );
+    }
+}
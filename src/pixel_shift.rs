@@ -0,0 +1,507 @@
+use log::{debug, warn};
+use rayon::{
+    iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator},
+    slice::ParallelSliceMut,
+};
+
+use crate::{
+    calibration::RawFrame,
+    demosaic::{BayerPattern, CfaColor, cfa_color_at, cfa_index},
+};
+
+// Relative difference between a pixel's two green samples above which the scene
+// is taken to have moved between frames
+const DEFAULT_MOTION_THRESHOLD: f32 = 0.15;
+
+// Differences below this fraction of the range are noise, however dark the pixel
+const MOTION_FLOOR: f32 = 0.01;
+
+// Sensor offsets are searched within this many steps of the first frame
+const MAX_OFFSET: isize = 1;
+
+// Every few pixels is plenty for matching frames that differ by a pixel or two
+const MATCH_STEP: usize = 3;
+
+// Border left out of offset matching so shifted reads stay inside the frame
+const MATCH_MARGIN: usize = 4;
+
+/// Sensor offset of each frame in a 4-shot group from the first, in shooting order:
+/// the camera steps one pixel right, down, then back left, so every site is read
+/// through red, both greens and blue. The four groups of a 16-shot sequence step
+/// through the same pattern in half pixels.
+pub const SHIFT_ORDER: [(isize, isize); 4] = [(0, 0), (0, 1), (1, 1), (1, 0)];
+
+/// Camera RGB composed from several exposures, black-subtracted and normalized.
+pub struct Composite {
+    pub rgb: Vec<[f32; 3]>,
+    pub width: usize,
+    pub height: usize,
+}
+
+fn channel(row: usize, col: usize) -> usize {
+    match cfa_color_at(row, col, BayerPattern::RGGB) {
+        CfaColor::R => 0,
+        CfaColor::G => 1,
+        CfaColor::B => 2,
+    }
+}
+
+fn normalized(frame: &RawFrame, row: usize, col: usize) -> f32 {
+    let pos = cfa_index(row, col);
+    let black = frame.black_level[pos];
+    let range = frame.white_level.saturating_sub(black).max(1) as f32;
+    frame.pixels[row * frame.dims.raw_width + col].saturating_sub(black) as f32 / range
+}
+
+// Green at any site away from the border: the sample itself, or its four green neighbours
+fn green_at(frame: &RawFrame, row: usize, col: usize) -> f32 {
+    if channel(row, col) == 1 {
+        return normalized(frame, row, col);
+    }
+    (normalized(frame, row - 1, col)
+        + normalized(frame, row + 1, col)
+        + normalized(frame, row, col - 1)
+        + normalized(frame, row, col + 1))
+        * 0.25
+}
+
+// Bilinear demosaic of a single site, edges clamped. Stands in where frames disagree.
+fn bilinear_at(frame: &RawFrame, row: usize, col: usize) -> [f32; 3] {
+    let (w, h) = (frame.dims.output_width, frame.dims.output_height);
+    let mut sums = [0f32; 3];
+    let mut counts = [0u32; 3];
+    for r in row.saturating_sub(1)..=(row + 1).min(h - 1) {
+        for c in col.saturating_sub(1)..=(col + 1).min(w - 1) {
+            let ch = channel(r, c);
+            // Each channel comes from its nearest ring only
+            if ch == 1 && channel(row, col) != 1 && r != row && c != col {
+                continue;
+            }
+            sums[ch] += normalized(frame, r, c);
+            counts[ch] += 1;
+        }
+    }
+    let here = channel(row, col);
+    sums[here] = normalized(frame, row, col);
+    counts[here] = 1;
+    [0, 1, 2].map(|ch| sums[ch] / counts[ch].max(1) as f32)
+}
+
+/// Finds how far a frame's sensor sat from the reference's, in whole pixels, by
+/// matching its green sites against the reference's greens: scene point
+/// `p` lands on sensor site `p - offset`. Only a sanity check on `SHIFT_ORDER`, as
+/// flat or repetitive scenes can match at the wrong offset.
+pub fn estimate_offset(reference: &RawFrame, frame: &RawFrame) -> (isize, isize) {
+    let (w, h) = (reference.dims.output_width, reference.dims.output_height);
+    if w <= 2 * MATCH_MARGIN || h <= 2 * MATCH_MARGIN {
+        return (0, 0);
+    }
+
+    let candidates: Vec<(isize, isize)> = (-MAX_OFFSET..=MAX_OFFSET)
+        .flat_map(|dy| (-MAX_OFFSET..=MAX_OFFSET).map(move |dx| (dy, dx)))
+        .collect();
+
+    let errors = (MATCH_MARGIN..h - MATCH_MARGIN)
+        .into_par_iter()
+        .step_by(MATCH_STEP)
+        .map(|row| {
+            let mut errors = vec![0f64; candidates.len()];
+            for col in (MATCH_MARGIN..w - MATCH_MARGIN).step_by(MATCH_STEP) {
+                if channel(row, col) != 1 {
+                    continue;
+                }
+                let v = normalized(frame, row, col);
+                for (e, &(dy, dx)) in errors.iter_mut().zip(&candidates) {
+                    let r = (row as isize + dy) as usize;
+                    let c = (col as isize + dx) as usize;
+                    *e += (v - green_at(reference, r, c)).abs() as f64;
+                }
+            }
+            errors
+        })
+        .reduce(
+            || vec![0f64; candidates.len()],
+            |a, b| a.iter().zip(b).map(|(x, y)| x + y).collect(),
+        );
+
+    let best = errors
+        .iter()
+        .enumerate()
+        .min_by(|a, b| a.1.total_cmp(b.1))
+        .map_or(0, |(i, _)| i);
+    candidates[best]
+}
+
+/// Composes frames taken one pixel apart into RGB at every site, without demosaicing.
+/// Pixels whose two green readings disagree (something moved) and the pixels around
+/// them are demosaiced from the first frame alone, as are border pixels some frame
+/// didn't cover.
+/// - offsets: per frame, from the first, usually `SHIFT_ORDER`
+/// - motion_threshold: relative green difference, 0 for the default, negative to keep every pixel
+pub fn compose_one_pixel(
+    frames: &[RawFrame],
+    offsets: &[(isize, isize)],
+    motion_threshold: f32,
+) -> Composite {
+    let reference = &frames[0];
+    let (w, h) = (reference.dims.output_width, reference.dims.output_height);
+    let threshold = if motion_threshold == 0.0 {
+        DEFAULT_MOTION_THRESHOLD
+    } else {
+        motion_threshold
+    };
+
+    // Composed values, or None where the frames can't be trusted
+    let composed: Vec<Option<[f32; 3]>> = (0..h)
+        .into_par_iter()
+        .flat_map_iter(|row| {
+            (0..w).map(move |col| {
+                let mut sums = [0f32; 3];
+                let mut counts = [0u32; 3];
+                let (mut g_lo, mut g_hi) = (f32::MAX, f32::MIN);
+
+                for (frame, &(dy, dx)) in frames.iter().zip(offsets) {
+                    let (r, c) = (row as isize - dy, col as isize - dx);
+                    if r < 0 || c < 0 || r as usize >= h || c as usize >= w {
+                        continue;
+                    }
+                    let (r, c) = (r as usize, c as usize);
+                    let ch = channel(r, c);
+                    let v = normalized(frame, r, c);
+                    sums[ch] += v;
+                    counts[ch] += 1;
+                    if ch == 1 {
+                        g_lo = g_lo.min(v);
+                        g_hi = g_hi.max(v);
+                    }
+                }
+
+                if counts.contains(&0) {
+                    return None;
+                }
+                if threshold > 0.0 && g_hi - g_lo > (threshold * g_hi).max(MOTION_FLOOR) {
+                    return None;
+                }
+                Some([0, 1, 2].map(|ch| sums[ch] / counts[ch] as f32))
+            })
+        })
+        .collect();
+
+    let mut rgb = vec![[0f32; 3]; w * h];
+    let fallbacks: usize = rgb
+        .par_chunks_mut(w.max(1))
+        .enumerate()
+        .map(|(row, out)| {
+            let mut fallbacks = 0;
+            for (col, px) in out.iter_mut().enumerate() {
+                // A moving edge smears into its neighbours, so they fall back too
+                let moved = (row.saturating_sub(1)..=(row + 1).min(h - 1)).any(|r| {
+                    (col.saturating_sub(1)..=(col + 1).min(w - 1))
+                        .any(|c| composed[r * w + c].is_none())
+                });
+                *px = match composed[row * w + col] {
+                    Some(v) if !moved => v,
+                    _ => {
+                        fallbacks += 1;
+                        bilinear_at(reference, row, col)
+                    }
+                };
+            }
+            fallbacks
+        })
+        .sum();
+
+    debug!(
+        "Pixel shift: {} of {} pixels from the first frame alone",
+        fallbacks,
+        w * h
+    );
+
+    Composite {
+        rgb,
+        width: w,
+        height: h,
+    }
+}
+
+// Green of a composite at a fractional position, bilinear, edges clamped
+fn composite_green(img: &Composite, y: f32, x: f32) -> f32 {
+    let x = x.clamp(0.0, (img.width - 1) as f32);
+    let y = y.clamp(0.0, (img.height - 1) as f32);
+    let (x0, y0) = (x as usize, y as usize);
+    let (x1, y1) = ((x0 + 1).min(img.width - 1), (y0 + 1).min(img.height - 1));
+    let (fx, fy) = (x - x0 as f32, y - y0 as f32);
+    let g = |xx: usize, yy: usize| img.rgb[yy * img.width + xx][1];
+    let top = g(x0, y0) + (g(x1, y0) - g(x0, y0)) * fx;
+    let bottom = g(x0, y1) + (g(x1, y1) - g(x0, y1)) * fx;
+    top + (bottom - top) * fy
+}
+
+/// Like `estimate_offset` for composites of the half-pixel steps of a 16-shot
+/// sequence, in half pixels.
+pub fn estimate_half_offset(reference: &Composite, img: &Composite) -> (isize, isize) {
+    let (w, h) = (reference.width, reference.height);
+    if w <= 2 * MATCH_MARGIN || h <= 2 * MATCH_MARGIN {
+        return (0, 0);
+    }
+
+    let candidates: Vec<(isize, isize)> = (-MAX_OFFSET..=MAX_OFFSET)
+        .flat_map(|dy| (-MAX_OFFSET..=MAX_OFFSET).map(move |dx| (dy, dx)))
+        .collect();
+
+    let errors = (MATCH_MARGIN..h - MATCH_MARGIN)
+        .into_par_iter()
+        .step_by(MATCH_STEP)
+        .map(|row| {
+            let mut errors = vec![0f64; candidates.len()];
+            for col in (MATCH_MARGIN..w - MATCH_MARGIN).step_by(MATCH_STEP) {
+                let v = img.rgb[row * w + col][1];
+                for (e, &(dy, dx)) in errors.iter_mut().zip(&candidates) {
+                    let y = row as f32 + dy as f32 * 0.5;
+                    let x = col as f32 + dx as f32 * 0.5;
+                    *e += (v - composite_green(reference, y, x)).abs() as f64;
+                }
+            }
+            errors
+        })
+        .reduce(
+            || vec![0f64; candidates.len()],
+            |a, b| a.iter().zip(b).map(|(x, y)| x + y).collect(),
+        );
+
+    let best = errors
+        .iter()
+        .enumerate()
+        .min_by(|a, b| a.1.total_cmp(b.1))
+        .map_or(0, |(i, _)| i);
+    candidates[best]
+}
+
+/// Interleaves four composites half a pixel apart into one at twice the resolution.
+/// Cells none of them reach (along the borders) repeat the first composite.
+pub fn interleave_half_pixel(groups: &[Composite], offsets: &[(isize, isize)]) -> Composite {
+    let (w, h) = (groups[0].width, groups[0].height);
+    let (w2, h2) = (w * 2, h * 2);
+
+    let mut rgb: Vec<[f32; 3]> = (0..w2 * h2)
+        .map(|i| groups[0].rgb[(i / w2 / 2) * w + (i % w2) / 2])
+        .collect();
+
+    for (group, &(dy, dx)) in groups.iter().zip(offsets) {
+        for row in 0..h {
+            let y = 2 * row as isize + dy;
+            if y < 0 || y as usize >= h2 {
+                continue;
+            }
+            for col in 0..w {
+                let x = 2 * col as isize + dx;
+                if x < 0 || x as usize >= w2 {
+                    continue;
+                }
+                rgb[y as usize * w2 + x as usize] = group.rgb[row * w + col];
+            }
+        }
+    }
+
+    Composite {
+        rgb,
+        width: w2,
+        height: h2,
+    }
+}
+
+// Warns about frames whose content doesn't line up with where the shooting order
+// says they were taken: frames out of order, or a scene that moved
+fn check_order(matched: impl Iterator<Item = (isize, isize)>, unit: &str) {
+    for (i, (found, expected)) in matched.zip(SHIFT_ORDER).enumerate() {
+        if found != expected {
+            warn!(
+                "Pixel shift frame {} matches best at {:?} {}, not {:?} from the shooting order",
+                i, found, unit, expected
+            );
+        }
+    }
+}
+
+/// Composes a 4-shot (one-pixel steps) or 16-shot (four 4-shot groups half a pixel
+/// apart) sequence, frames in shooting order, registered by `SHIFT_ORDER`.
+pub fn compose_pixel_shift(
+    frames: &[RawFrame],
+    motion_threshold: f32,
+) -> Result<Composite, &'static str> {
+    let compose_group = |group: &[RawFrame]| {
+        check_order(
+            group.iter().map(|f| estimate_offset(&group[0], f)),
+            "pixels",
+        );
+        compose_one_pixel(group, &SHIFT_ORDER, motion_threshold)
+    };
+
+    match frames.len() {
+        4 => Ok(compose_group(frames)),
+        16 => {
+            let groups: Vec<Composite> = frames.chunks(4).map(compose_group).collect();
+            check_order(
+                groups.iter().map(|g| estimate_half_offset(&groups[0], g)),
+                "half pixels",
+            );
+            Ok(interleave_half_pixel(&groups, &SHIFT_ORDER))
+        }
+        _ => Err("Pixel shift needs 4 or 16 frames"),
+    }
+}
+
+/// Lays an ARQ's per-pixel R/G/G/B samples (already aligned by the camera
+/// software) out as an RGGB mosaic of twice the size, one quad per pixel, so the
+/// raw pipeline can take it as-is and bin each quad back into a pixel.
+pub fn arq_mosaic(samples: &[u16], width: usize, height: usize) -> Vec<u16> {
+    let mut mosaic = vec![0u16; width * height * 4];
+    mosaic
+        .par_chunks_mut(width * 4)
+        .enumerate()
+        .for_each(|(row, pair)| {
+            let (top, bottom) = pair.split_at_mut(width * 2);
+            let line = &samples[row * width * 4..(row + 1) * width * 4];
+            for (col, s) in line.chunks_exact(4).enumerate() {
+                top[2 * col..2 * col + 2].copy_from_slice(&s[..2]);
+                bottom[2 * col..2 * col + 2].copy_from_slice(&s[2..]);
+            }
+        });
+    mosaic
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{exif::ExifContext, sony_decoder::Dimensions, tiff::TiffRawInfo};
+
+    const WIDTH: usize = 40;
+    const HEIGHT: usize = 32;
+    const BLACK: u16 = 512;
+    const WHITE: u16 = 16383;
+
+    // Smooth, textured scene so greens interpolate well and offsets match unambiguously
+    fn scene(y: f32, x: f32, ch: usize) -> f32 {
+        let ch = ch as f32;
+        0.5 + 0.3 * (x * 0.4 + ch).sin() * (y * 0.3 - ch * 0.3).cos()
+    }
+
+    // A frame whose sensor sat at `at` from the scene origin: site `s` reads scene
+    // point `(s + at) * scale`, so a scale of 2 puts the scene on the half-pixel grid
+    fn frame(at: (f32, f32), scale: f32) -> RawFrame {
+        let mut pixels = vec![0u16; WIDTH * HEIGHT];
+        for row in 0..HEIGHT {
+            for col in 0..WIDTH {
+                let v = scene(
+                    (row as f32 + at.0) * scale,
+                    (col as f32 + at.1) * scale,
+                    channel(row, col),
+                );
+                pixels[row * WIDTH + col] = BLACK + (v * (WHITE - BLACK) as f32).round() as u16;
+            }
+        }
+        RawFrame {
+            pixels,
+            dims: Dimensions {
+                raw_width: WIDTH,
+                raw_height: HEIGHT,
+                output_width: WIDTH,
+                output_height: HEIGHT,
+            },
+            white_level: WHITE,
+            black_level: [BLACK; 4],
+            iso: None,
+            raw_info: TiffRawInfo {
+                make: None,
+                model: None,
+                dng_version: None,
+                width: WIDTH as u32,
+                height: HEIGHT as u32,
+                bits_per_sample: 14,
+                samples_per_pixel: 1,
+                compression: 1,
+                strip_offsets: Vec::new(),
+                strip_byte_counts: Vec::new(),
+                total_bytes: 0,
+                is_sony: true,
+            },
+            ctx: ExifContext::new(),
+        }
+    }
+
+    fn four_shot(half: (isize, isize), scale: f32) -> Vec<RawFrame> {
+        SHIFT_ORDER
+            .iter()
+            .map(|&(dy, dx)| {
+                let at = (
+                    dy as f32 + half.0 as f32 * 0.5,
+                    dx as f32 + half.1 as f32 * 0.5,
+                );
+                frame(at, scale)
+            })
+            .collect()
+    }
+
+    fn assert_scene(img: &Composite, scale: f32, margin: usize) {
+        let tolerance = 1.0 / (WHITE - BLACK) as f32;
+        for row in margin..img.height {
+            for col in margin..img.width {
+                for ch in 0..3 {
+                    let expected = scene(row as f32 * scale, col as f32 * scale, ch);
+                    let got = img.rgb[row * img.width + col][ch];
+                    assert!(
+                        (got - expected).abs() <= tolerance,
+                        "({row}, {col}) channel {ch}: {got} vs {expected}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn offsets_match_shooting_order() {
+        let frames = four_shot((0, 0), 1.0);
+        for (f, expected) in frames.iter().zip(SHIFT_ORDER) {
+            assert_eq!(estimate_offset(&frames[0], f), expected);
+        }
+    }
+
+    #[test]
+    fn four_shot_recovers_full_color() {
+        let frames = four_shot((0, 0), 1.0);
+        let img = compose_pixel_shift(&frames, 0.0).unwrap();
+        assert_eq!((img.width, img.height), (WIDTH, HEIGHT));
+        // The first row and column miss a frame; their neighbours fall back with them
+        assert_scene(&img, 1.0, 2);
+    }
+
+    #[test]
+    fn sixteen_shot_interleaves_half_pixels() {
+        let frames: Vec<RawFrame> = SHIFT_ORDER
+            .iter()
+            .flat_map(|&half| four_shot(half, 2.0))
+            .collect();
+        let img = compose_pixel_shift(&frames, 0.0).unwrap();
+        assert_eq!((img.width, img.height), (WIDTH * 2, HEIGHT * 2));
+        assert_scene(&img, 1.0, 4);
+    }
+
+    #[test]
+    fn motion_falls_back_to_first_frame() {
+        let mut frames = four_shot((0, 0), 1.0);
+        // Something bright crossed the scene in the last frame only
+        for px in &mut frames[3].pixels[10 * WIDTH + 10..10 * WIDTH + 14] {
+            *px = WHITE;
+        }
+        let moved = compose_pixel_shift(&frames, 0.0).unwrap();
+        let kept = compose_pixel_shift(&frames, -1.0).unwrap();
+        let at = 11 * WIDTH + 12;
+        assert_eq!(moved.rgb[at], bilinear_at(&frames[0], 11, 12));
+        assert_ne!(kept.rgb[at], moved.rgb[at]);
+    }
+
+    #[test]
+    fn wrong_frame_count_is_rejected() {
+        assert!(compose_pixel_shift(&four_shot((0, 0), 1.0)[..3], 0.0).is_err());
+    }
+}
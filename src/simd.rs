@@ -0,0 +1,309 @@
+// Row kernels for the raw renderers and the resampler. With the `simd` feature they run on `wide`
+// vectors eight samples at a time; without it (and for row tails) the same
+// arithmetic runs as plain loops, in the same order so both give identical output.
+
+#[cfg(feature = "simd")]
+use wide::{CmpEq, CmpGe, CmpGt, f32x8};
+
+#[cfg(feature = "simd")]
+use crate::highlight::{CLIP_MARGIN, ITRANS, TRANS};
+use crate::{color::Matrix3, demosaic::RenderParams, highlight::HighlightMode};
+
+#[cfg(feature = "simd")]
+const LANES: usize = 8;
+
+// Lanes holding even columns, for picking per-CFA-position results out of a vector
+#[cfg(feature = "simd")]
+fn even_lanes() -> f32x8 {
+    f32x8::from([1.0, 0.0, 1.0, 0.0, 1.0, 0.0, 1.0, 0.0]).cmp_eq(f32x8::splat(1.0))
+}
+
+// Broadcasts an [even, odd] column pair across a vector
+#[cfg(feature = "simd")]
+fn alternate(pair: [f32; 2]) -> f32x8 {
+    f32x8::from([
+        pair[0], pair[1], pair[0], pair[1], pair[0], pair[1], pair[0], pair[1],
+    ])
+}
+
+#[cfg(feature = "simd")]
+#[inline(always)]
+fn load(s: &[f32], at: usize) -> f32x8 {
+    f32x8::from(<[f32; LANES]>::try_from(&s[at..at + LANES]).unwrap())
+}
+
+#[cfg(feature = "simd")]
+#[inline(always)]
+fn store(v: f32x8, d: &mut [f32], at: usize) {
+    d[at..at + LANES].copy_from_slice(v.as_array_ref());
+}
+
+// Number of leading samples the vector loops cover; the rest go through the scalar loops
+#[cfg(feature = "simd")]
+#[inline(always)]
+fn vector_len(n: usize) -> usize {
+    n - n % LANES
+}
+
+#[cfg(not(feature = "simd"))]
+#[inline(always)]
+fn vector_len(_n: usize) -> usize {
+    0
+}
+
+/// Black subtraction, normalization and white balance for one mosaic row:
+/// `dst[x] = max(src[x] - black, 0) * inv_range * gain`, each factor picked
+/// from its `[even, odd]` pair by the column's parity.
+pub fn normalize_row(
+    src: &[u16],
+    black: [f32; 2],
+    inv_range: [f32; 2],
+    gain: [f32; 2],
+    dst: &mut [f32],
+) {
+    let n = dst.len().min(src.len());
+    let split = vector_len(n);
+
+    #[cfg(feature = "simd")]
+    {
+        let (black_v, inv_v, gain_v) = (alternate(black), alternate(inv_range), alternate(gain));
+        for x in (0..split).step_by(LANES) {
+            let v = f32x8::from(std::array::from_fn::<f32, LANES, _>(|i| src[x + i] as f32));
+            store((v - black_v).max(f32x8::ZERO) * inv_v * gain_v, dst, x);
+        }
+    }
+
+    for x in split..n {
+        let p = x & 1;
+        dst[x] = (src[x] as f32 - black[p]).max(0.0) * inv_range[p] * gain[p];
+    }
+}
+
+/// Bilinear interpolation of one RGGB output row from three normalized rows,
+/// each padded with one sample on either side.
+/// - red_row: the row holds R/G sites (else G/B)
+/// - rgb: planar output rows, `up.len() - 2` samples each
+pub fn bilinear_row(up: &[f32], mid: &[f32], down: &[f32], red_row: bool, rgb: [&mut [f32]; 3]) {
+    let [r, g, b] = rgb;
+    let n = r.len();
+    let split = vector_len(n);
+
+    #[cfg(feature = "simd")]
+    {
+        let even = even_lanes();
+        let (quarter, half) = (f32x8::splat(0.25), f32x8::splat(0.5));
+        for x in (0..split).step_by(LANES) {
+            let (left, here, right) = (load(mid, x), load(mid, x + 1), load(mid, x + 2));
+            let (above, below) = (load(up, x + 1), load(down, x + 1));
+            let cross = (above + below + left + right) * quarter;
+            let diag =
+                (load(up, x) + load(up, x + 2) + load(down, x) + load(down, x + 2)) * quarter;
+            let horiz = (left + right) * half;
+            let vert = (above + below) * half;
+
+            let (rv, gv, bv) = if red_row {
+                (
+                    even.blend(here, horiz),
+                    even.blend(cross, here),
+                    even.blend(diag, vert),
+                )
+            } else {
+                (
+                    even.blend(vert, diag),
+                    even.blend(here, cross),
+                    even.blend(horiz, here),
+                )
+            };
+            store(rv, r, x);
+            store(gv, g, x);
+            store(bv, b, x);
+        }
+    }
+
+    for x in split..n {
+        let (left, here, right) = (mid[x], mid[x + 1], mid[x + 2]);
+        let (above, below) = (up[x + 1], down[x + 1]);
+        let cross = (above + below + left + right) * 0.25;
+        let diag = (up[x] + up[x + 2] + down[x] + down[x + 2]) * 0.25;
+        let horiz = (left + right) * 0.5;
+        let vert = (above + below) * 0.5;
+
+        // R sites see B on the diagonals, G sites take R/B from the row or column
+        let px = match (red_row, x & 1 == 0) {
+            (true, true) => (here, cross, diag),
+            (true, false) => (horiz, here, vert),
+            (false, true) => (vert, here, horiz),
+            (false, false) => (diag, cross, here),
+        };
+        (r[x], g[x], b[x]) = px;
+    }
+}
+
+/// Highlight handling, colour matrix and clamping of negatives for planar rows,
+/// the row form of `demosaic::develop`.
+pub fn develop_row(rgb: [&mut [f32]; 3], params: &RenderParams) {
+    let [r, g, b] = rgb;
+
+    match params.highlight {
+        HighlightMode::Unclip => {}
+        HighlightMode::Clip => {
+            let clip = params.wb[0].min(params.wb[1]).min(params.wb[2]);
+            for plane in [&mut *r, &mut *g, &mut *b] {
+                plane.iter_mut().for_each(|v| *v = v.min(clip));
+            }
+        }
+        mode => highlight_row(mode, params.wb, [&mut *r, &mut *g, &mut *b]),
+    }
+
+    matrix_row(&params.color_matrix, [r, g, b]);
+}
+
+// The opponent-space modes, a vector of pixels at a time
+fn highlight_row(mode: HighlightMode, sat: [f32; 3], rgb: [&mut [f32]; 3]) {
+    let [r, g, b] = rgb;
+    let n = r.len();
+    let split = vector_len(n);
+
+    #[cfg(feature = "simd")]
+    for x in (0..split).step_by(LANES) {
+        let v = [load(r, x), load(g, x), load(b, x)];
+        let out = match mode {
+            HighlightMode::Blend => blend_highlights(v, sat),
+            HighlightMode::Reconstruct => reconstruct_highlights(v, sat),
+            HighlightMode::Clip => v.map(|c| c.min(f32x8::splat(sat[0].min(sat[1]).min(sat[2])))),
+            HighlightMode::Unclip => v,
+        };
+        store(out[0], r, x);
+        store(out[1], g, x);
+        store(out[2], b, x);
+    }
+
+    for x in split..n {
+        [r[x], g[x], b[x]] = mode.apply([r[x], g[x], b[x]], sat);
+    }
+}
+
+// `highlight::blend` across lanes; lanes with nothing clipped pass through untouched
+#[cfg(feature = "simd")]
+fn blend_highlights(rgb: [f32x8; 3], sat: [f32; 3]) -> [f32x8; 3] {
+    let clip = f32x8::splat(sat[0].min(sat[1]).min(sat[2]));
+    let over = rgb[0].cmp_gt(clip) | rgb[1].cmp_gt(clip) | rgb[2].cmp_gt(clip);
+    if over.none() {
+        return rgb;
+    }
+
+    let clipped = rgb.map(|v| v.min(clip));
+    let to_lab = |cam: [f32x8; 3]| {
+        TRANS.map(|t| {
+            f32x8::splat(t[0]) * cam[0] + f32x8::splat(t[1]) * cam[1] + f32x8::splat(t[2]) * cam[2]
+        })
+    };
+    let lab = to_lab(rgb);
+    let lab_clipped = to_lab(clipped);
+
+    let chroma = lab[1] * lab[1] + lab[2] * lab[2];
+    let chroma_clipped = lab_clipped[1] * lab_clipped[1] + lab_clipped[2] * lab_clipped[2];
+    let ratio = chroma
+        .cmp_gt(f32x8::ZERO)
+        .blend((chroma_clipped / chroma).sqrt(), f32x8::ONE);
+    let lab = [lab[0], lab[1] * ratio, lab[2] * ratio];
+
+    let third = f32x8::splat(3.0);
+    let out = ITRANS.map(|t| {
+        (f32x8::splat(t[0]) * lab[0] + f32x8::splat(t[1]) * lab[1] + f32x8::splat(t[2]) * lab[2])
+            / third
+    });
+    [0, 1, 2].map(|c| over.blend(out[c], rgb[c]))
+}
+
+// `highlight::reconstruct` across lanes. Clipped channels add zero to the sum, which
+// leaves it exactly as skipping them does.
+#[cfg(feature = "simd")]
+fn reconstruct_highlights(rgb: [f32x8; 3], sat: [f32; 3]) -> [f32x8; 3] {
+    let clipped = [0, 1, 2].map(|c| rgb[c].cmp_ge(f32x8::splat(sat[c] * CLIP_MARGIN)));
+
+    let (mut sum, mut count) = (f32x8::ZERO, f32x8::ZERO);
+    for c in 0..3 {
+        sum += clipped[c].blend(f32x8::ZERO, rgb[c]);
+        count += clipped[c].blend(f32x8::ZERO, f32x8::ONE);
+    }
+
+    let white = f32x8::splat(sat[0].max(sat[1]).max(sat[2]));
+    let all_clipped = count.cmp_eq(f32x8::ZERO);
+    let mean = sum / count;
+    [0, 1, 2].map(|c| all_clipped.blend(white, clipped[c].blend(rgb[c].max(mean), rgb[c])))
+}
+
+/// Linear interpolation in place through a table with `scale` entries per unit of
+/// input, clamped to its last pair: the row form of `ToneLut::apply`. Positions and
+/// fractions are worked out a vector at a time; only the table reads go per sample.
+pub fn lut_row(table: &[f32], scale: f32, row: &mut [f32]) {
+    let last = (table.len() - 2) as f32;
+    let n = row.len();
+    let split = vector_len(n);
+
+    #[cfg(feature = "simd")]
+    {
+        let (scale_v, last_v) = (f32x8::splat(scale), f32x8::splat(last));
+        for x in (0..split).step_by(LANES) {
+            let pos = (load(row, x) * scale_v).max(f32x8::ZERO).min(last_v);
+            let index = pos.trunc_int();
+            let frac = pos - index.round_float();
+            let index = index.to_array();
+            let lo = f32x8::from(index.map(|i| table[i as usize]));
+            let hi = f32x8::from(index.map(|i| table[i as usize + 1]));
+            store(lo + (hi - lo) * frac, row, x);
+        }
+    }
+
+    for v in &mut row[split..] {
+        let pos = (*v * scale).clamp(0.0, last);
+        let i = pos as usize;
+        let frac = pos - i as f32;
+        *v = table[i] + (table[i + 1] - table[i]) * frac;
+    }
+}
+
+fn matrix_row(m: &Matrix3, rgb: [&mut [f32]; 3]) {
+    let [r, g, b] = rgb;
+    let n = r.len();
+    let split = vector_len(n);
+
+    #[cfg(feature = "simd")]
+    {
+        let mv = m.map(|row| row.map(f32x8::splat));
+        for x in (0..split).step_by(LANES) {
+            let v = [load(r, x), load(g, x), load(b, x)];
+            let out =
+                mv.map(|row| (row[0] * v[0] + row[1] * v[1] + row[2] * v[2]).max(f32x8::ZERO));
+            store(out[0], r, x);
+            store(out[1], g, x);
+            store(out[2], b, x);
+        }
+    }
+
+    for x in split..n {
+        let v = [r[x], g[x], b[x]];
+        let out = m.map(|row| (row[0] * v[0] + row[1] * v[1] + row[2] * v[2]).max(0.0));
+        [r[x], g[x], b[x]] = out;
+    }
+}
+
+/// Weighted row accumulation for the resampler's vertical pass and box
+/// pre-shrink: `dst[x] += src[x] * weight`.
+pub fn accumulate_row(src: &[f32], weight: f32, dst: &mut [f32]) {
+    let n = dst.len().min(src.len());
+    let split = vector_len(n);
+
+    #[cfg(feature = "simd")]
+    {
+        let weight_v = f32x8::splat(weight);
+        for x in (0..split).step_by(LANES) {
+            store(load(dst, x) + load(src, x) * weight_v, dst, x);
+        }
+    }
+
+    for x in split..n {
+        dst[x] += src[x] * weight;
+    }
+}
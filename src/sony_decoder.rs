@@ -1,6 +1,6 @@
 use std::io::{Read, Seek, SeekFrom};
 
-use crate::tiff::SonyVariant;
+use crate::{ljpeg, tiff::SonyVariant};
 
 #[derive(Debug)]
 pub enum DecodeError {
@@ -560,6 +560,63 @@ pub fn sony_uncompressed14_load_raw<R: Read>(
     })
 }
 
+// DNG uncompressed: 16-bit little-endian samples, one row after another
+pub fn dng_uncompressed16_load_raw<R: Read>(
+    reader: &mut R,
+    dims: Dimensions,
+    white_level: u16,
+) -> Result<SonyLoadResult, DecodeError> {
+    let mut pixels = vec![0u16; dims.raw_width * dims.raw_height];
+    let mut row = vec![0u8; dims.output_width * 2];
+
+    for y in 0..dims.output_height {
+        reader.read_exact(&mut row)?;
+        for (dst, src) in pixels[y * dims.raw_width..]
+            .iter_mut()
+            .zip(row.chunks_exact(2))
+        {
+            *dst = u16::from_le_bytes([src[0], src[1]]);
+        }
+    }
+
+    Ok(SonyLoadResult {
+        pixels,
+        white_level,
+    })
+}
+
+// DNG lossless JPEG (compression 7), one stream per strip
+pub fn dng_ljpeg_load_raw<R: Read>(
+    reader: &mut R,
+    dims: Dimensions,
+    white_level: u16,
+) -> Result<SonyLoadResult, DecodeError> {
+    Ok(SonyLoadResult {
+        pixels: ljpeg::decode(reader, dims)?,
+        white_level,
+    })
+}
+
+// Pixel-shift ARQ: four 16-bit little-endian samples per pixel, R, G, G, B
+// (LibRaw's sony_arq_load_raw swaps the last two into its R, G, B, G2 order).
+// `pixels` holds output_width * output_height * 4 samples rather than a mosaic;
+// `pixel_shift::arq_mosaic` turns them into one.
+pub fn sony_arq_load_raw<R: Read>(
+    reader: &mut R,
+    dims: Dimensions,
+) -> Result<SonyLoadResult, DecodeError> {
+    let mut bytes = vec![0u8; dims.output_width * dims.output_height * 8];
+    reader.read_exact(&mut bytes)?;
+
+    Ok(SonyLoadResult {
+        pixels: bytes
+            .chunks_exact(2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]))
+            .collect(),
+        white_level: 0x3fff,
+    })
+}
+
 // Helper: read all strips and concatenate into a single buffer
 pub fn read_concatenated_strips<R: Read + Seek>(
     reader: &mut R,
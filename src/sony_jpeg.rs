@@ -1,11 +1,17 @@
-use image::{RgbImage, imageops};
+use image::codecs::{jpeg::JpegEncoder, png::PngEncoder};
+use image::{ExtendedColorType, ImageEncoder, RgbImage, imageops};
+use std::borrow::Cow;
 use std::fs::File;
-use std::io::Write;
+use std::io::{Seek, Write};
 use std::path::Path;
 use tiff::encoder::*;
+use tiff::tags::{Tag, Type};
 
-use crate::demosaic::{BayerPattern, demosaic_bilinear_to_rgb8};
+use crate::color;
+use crate::demosaic::{BayerPattern, RenderParams, demosaic_bilinear};
+use crate::highlight::HighlightMode;
 use crate::sony_decoder::{DecodeError, Dimensions, SonyLoadResult};
+use crate::tone::{ToneCurve, ToneLut};
 
 pub fn write_tiff_from_sony_result_to_path<P: AsRef<Path>>(
     result: &mut SonyLoadResult,
@@ -17,15 +23,16 @@ pub fn write_tiff_from_sony_result_to_path<P: AsRef<Path>>(
     quality: u8,
     out_path: P,
 ) -> Result<(), DecodeError> {
-    let rgb = demosaic_bilinear_to_rgb8(
-        &mut result.pixels,
-        dims,
+    let params = RenderParams {
         pattern,
-        black_level,
-        result.white_level,
-        wb_gains.unwrap_or([1.0, 1.0, 1.0]),
-        gamma,
-    );
+        black_level: [black_level; 4],
+        white_level: result.white_level,
+        wb: wb_gains.unwrap_or([1.0, 1.0, 1.0]),
+        tone: ToneLut::new(ToneCurve::Gamma, gamma, 0.0),
+        highlight: HighlightMode::default(),
+        color_matrix: color::IDENTITY,
+    };
+    let rgb = demosaic_bilinear::<u8>(&mut result.pixels, dims, &params);
 
     let mut file = File::create(out_path).map_err(DecodeError::Io)?;
 
@@ -44,16 +51,287 @@ pub fn write_tiff_from_sony_result_to_path<P: AsRef<Path>>(
     Ok(())
 }
 
+// VP8X flag announcing an ICCP chunk
+const WEBP_ICC_FLAG: u8 = 0x20;
+
+// TIFF tag for an embedded ICC profile
+const TIFF_ICC_TAG: u16 = 34675;
+
+// Rewraps a simple (VP8/VP8L) WebP in the extended format, which is the only
+// one that can carry an ICCP chunk. Files already extended (lossy with alpha)
+// keep their VP8X flags and gain the ICC one.
+fn webp_with_icc(encoded: &[u8], width: u32, height: u32, icc: &[u8]) -> Vec<u8> {
+    let chunk = |out: &mut Vec<u8>, fourcc: &[u8], payload: &[u8]| {
+        out.extend_from_slice(fourcc);
+        out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        out.extend_from_slice(payload);
+        if payload.len() % 2 == 1 {
+            out.push(0);
+        }
+    };
+
+    let (mut vp8x, rest) = match encoded.get(12..30) {
+        Some([b'V', b'P', b'8', b'X', 10, 0, 0, 0, payload @ ..]) => {
+            (payload.to_vec(), &encoded[30..])
+        }
+        _ => {
+            let mut vp8x = vec![0; 4];
+            vp8x.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+            vp8x.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+            (vp8x, &encoded[12..])
+        }
+    };
+    vp8x[0] |= WEBP_ICC_FLAG;
+
+    let mut body = b"WEBP".to_vec();
+    chunk(&mut body, b"VP8X", &vp8x);
+    chunk(&mut body, b"ICCP", icc);
+    body.extend_from_slice(rest);
+
+    let mut out = b"RIFF".to_vec();
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(&body);
+    out
+}
+
+/// Lossy WebP, tagged with `icc` when given.
 pub fn write_webp_from_rgb8_writer<W: Write>(
     writer: &mut W,
     rgb: &[u8],
     width: u32,
     height: u32,
     quality: u8,
+    icc: Option<&[u8]>,
 ) -> Result<(), DecodeError> {
     let enc = webp::Encoder::new(rgb, webp::PixelLayout::Rgb, width, height);
+    write_webp(writer, enc, width, height, quality, icc)
+}
+
+/// Lossy WebP with an alpha channel, tagged with `icc` when given.
+pub fn write_webp_from_rgba8_writer<W: Write>(
+    writer: &mut W,
+    rgba: &[u8],
+    width: u32,
+    height: u32,
+    quality: u8,
+    icc: Option<&[u8]>,
+) -> Result<(), DecodeError> {
+    let enc = webp::Encoder::new(rgba, webp::PixelLayout::Rgba, width, height);
+    write_webp(writer, enc, width, height, quality, icc)
+}
+
+fn write_webp<W: Write>(
+    writer: &mut W,
+    enc: webp::Encoder,
+    width: u32,
+    height: u32,
+    quality: u8,
+    icc: Option<&[u8]>,
+) -> Result<(), DecodeError> {
     let encoded = enc.encode(quality as f32);
-    writer.write_all(&encoded)?;
+    match icc {
+        Some(icc) if encoded.len() > 12 && width > 0 && height > 0 => {
+            writer.write_all(&webp_with_icc(&encoded, width, height, icc))?
+        }
+        _ => writer.write_all(&encoded)?,
+    }
+
+    Ok(())
+}
+
+/// Baseline JPEG, tagged with `icc` (split over APP2 segments) when given.
+pub fn write_jpeg_from_rgb8_writer<W: Write>(
+    writer: &mut W,
+    rgb: &[u8],
+    width: u32,
+    height: u32,
+    quality: u8,
+    icc: Option<&[u8]>,
+) -> Result<(), DecodeError> {
+    write_jpeg(
+        writer,
+        rgb,
+        (width, height),
+        ExtendedColorType::Rgb8,
+        quality,
+        icc,
+    )
+}
+
+/// Single-component baseline JPEG, tagged with `icc` (a grey profile) when given.
+pub fn write_jpeg_from_gray8_writer<W: Write>(
+    writer: &mut W,
+    grey: &[u8],
+    width: u32,
+    height: u32,
+    quality: u8,
+    icc: Option<&[u8]>,
+) -> Result<(), DecodeError> {
+    write_jpeg(
+        writer,
+        grey,
+        (width, height),
+        ExtendedColorType::L8,
+        quality,
+        icc,
+    )
+}
+
+fn write_jpeg<W: Write>(
+    writer: &mut W,
+    samples: &[u8],
+    (width, height): (u32, u32),
+    color: ExtendedColorType,
+    quality: u8,
+    icc: Option<&[u8]>,
+) -> Result<(), DecodeError> {
+    let mut enc = JpegEncoder::new_with_quality(writer, quality);
+    if let Some(icc) = icc {
+        enc.set_icc_profile(icc.to_vec())
+            .map_err(|_| DecodeError::CorruptData("Failed to embed ICC profile"))?;
+    }
+    enc.write_image(samples, width, height, color)
+        .map_err(|_| DecodeError::CorruptData("Failed to encode JPEG"))
+}
+
+fn png_encoder<W: Write>(writer: W, icc: Option<&[u8]>) -> Result<PngEncoder<W>, DecodeError> {
+    let mut enc = PngEncoder::new(writer);
+    if let Some(icc) = icc {
+        enc.set_icc_profile(icc.to_vec())
+            .map_err(|_| DecodeError::CorruptData("Failed to embed ICC profile"))?;
+    }
+    Ok(enc)
+}
+
+pub fn write_png_from_rgb8_writer<W: Write>(
+    writer: &mut W,
+    rgb: &[u8],
+    width: u32,
+    height: u32,
+    icc: Option<&[u8]>,
+) -> Result<(), DecodeError> {
+    png_encoder(writer, icc)?
+        .write_image(rgb, width, height, ExtendedColorType::Rgb8)
+        .map_err(|_| DecodeError::CorruptData("Failed to encode PNG"))
+}
+
+pub fn write_png_from_rgba8_writer<W: Write>(
+    writer: &mut W,
+    rgba: &[u8],
+    width: u32,
+    height: u32,
+    icc: Option<&[u8]>,
+) -> Result<(), DecodeError> {
+    png_encoder(writer, icc)?
+        .write_image(rgba, width, height, ExtendedColorType::Rgba8)
+        .map_err(|_| DecodeError::CorruptData("Failed to encode PNG"))
+}
+
+pub fn write_png_from_gray8_writer<W: Write>(
+    writer: &mut W,
+    grey: &[u8],
+    width: u32,
+    height: u32,
+    icc: Option<&[u8]>,
+) -> Result<(), DecodeError> {
+    png_encoder(writer, icc)?
+        .write_image(grey, width, height, ExtendedColorType::L8)
+        .map_err(|_| DecodeError::CorruptData("Failed to encode PNG"))
+}
+
+/// 16-bit PNG from native-endian samples; the encoder swaps them to PNG's big-endian.
+pub fn write_png_from_rgb16_writer<W: Write>(
+    writer: &mut W,
+    rgb: &[u16],
+    width: u32,
+    height: u32,
+    icc: Option<&[u8]>,
+) -> Result<(), DecodeError> {
+    write_png16(writer, rgb, width, height, ExtendedColorType::Rgb16, icc)
+}
+
+/// 16-bit RGBA PNG from native-endian samples.
+pub fn write_png_from_rgba16_writer<W: Write>(
+    writer: &mut W,
+    rgba: &[u16],
+    width: u32,
+    height: u32,
+    icc: Option<&[u8]>,
+) -> Result<(), DecodeError> {
+    write_png16(writer, rgba, width, height, ExtendedColorType::Rgba16, icc)
+}
+
+/// 16-bit greyscale PNG from native-endian samples.
+pub fn write_png_from_gray16_writer<W: Write>(
+    writer: &mut W,
+    grey: &[u16],
+    width: u32,
+    height: u32,
+    icc: Option<&[u8]>,
+) -> Result<(), DecodeError> {
+    write_png16(writer, grey, width, height, ExtendedColorType::L16, icc)
+}
+
+fn write_png16<W: Write>(
+    writer: &mut W,
+    samples: &[u16],
+    width: u32,
+    height: u32,
+    color: ExtendedColorType,
+    icc: Option<&[u8]>,
+) -> Result<(), DecodeError> {
+    let bytes: Vec<u8> = samples.iter().flat_map(|v| v.to_ne_bytes()).collect();
+
+    png_encoder(writer, icc)?
+        .write_image(&bytes, width, height, color)
+        .map_err(|_| DecodeError::CorruptData("Failed to encode 16-bit PNG"))
+}
+
+// ICC profiles are stored as UNDEFINED bytes, which the tiff crate has no type for
+struct IccTag<'a>(&'a [u8]);
+
+impl TiffValue for IccTag<'_> {
+    const BYTE_LEN: u8 = 1;
+    const FIELD_TYPE: Type = Type::UNDEFINED;
+
+    fn count(&self) -> usize {
+        self.0.len()
+    }
+
+    fn data(&self) -> Cow<'_, [u8]> {
+        Cow::Borrowed(self.0)
+    }
+}
+
+/// Uncompressed TIFF in whatever sample type `C` names, e.g. `colortype::RGB16`,
+/// `colortype::RGB32Float` for linear output or `colortype::RGBA8` with alpha,
+/// tagged with `icc` when given.
+pub fn write_tiff_writer<C, W>(
+    writer: &mut W,
+    rgb: &[C::Inner],
+    width: u32,
+    height: u32,
+    icc: Option<&[u8]>,
+) -> Result<(), DecodeError>
+where
+    C: colortype::ColorType,
+    [C::Inner]: TiffValue,
+    W: Write + Seek,
+{
+    let mut tiff =
+        TiffEncoder::new(writer).map_err(|_| DecodeError::CorruptData("Failed to start TIFF"))?;
+    let mut image = tiff
+        .new_image::<C>(width, height)
+        .map_err(|_| DecodeError::CorruptData("Failed to start TIFF"))?;
+    if let Some(icc) = icc {
+        image
+            .encoder()
+            .write_tag(Tag::Unknown(TIFF_ICC_TAG), IccTag(icc))
+            .map_err(|_| DecodeError::CorruptData("Failed to embed ICC profile"))?;
+    }
+    image
+        .write_data(rgb)
+        .map_err(|_| DecodeError::CorruptData("Failed to encode TIFF"))?;
 
     Ok(())
 }
@@ -79,3 +357,126 @@ fn write_tiff_from_rgb8_writer<W: Write>(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use tiff::decoder::{Decoder, DecodingResult};
+
+    use super::*;
+
+    // Every sample distinct in both bytes, so byte order mix-ups show
+    fn ramp(len: usize) -> Vec<u16> {
+        (0..len as u32).map(|i| (i * 4099 + 258) as u16).collect()
+    }
+
+    #[test]
+    fn sixteen_bit_png_keeps_every_sample() {
+        let (width, height) = (7, 5);
+        let rgb = ramp(width * height * 3);
+        let mut png = Vec::new();
+        write_png_from_rgb16_writer(&mut png, &rgb, width as u32, height as u32, None).unwrap();
+        let decoded = image::load_from_memory(&png).unwrap();
+        assert_eq!(decoded.color(), image::ColorType::Rgb16);
+        assert_eq!(decoded.to_rgb16().into_raw(), rgb);
+
+        let grey = ramp(width * height);
+        let mut png = Vec::new();
+        write_png_from_gray16_writer(&mut png, &grey, width as u32, height as u32, None).unwrap();
+        assert_eq!(
+            image::load_from_memory(&png)
+                .unwrap()
+                .to_luma16()
+                .into_raw(),
+            grey
+        );
+    }
+
+    #[test]
+    fn sixteen_bit_tiff_keeps_every_sample() {
+        let (width, height) = (7, 5);
+        let rgb = ramp(width * height * 3);
+        let mut tiff = Cursor::new(Vec::new());
+        write_tiff_writer::<colortype::RGB16, _>(
+            &mut tiff,
+            &rgb,
+            width as u32,
+            height as u32,
+            None,
+        )
+        .unwrap();
+
+        tiff.set_position(0);
+        let mut decoder = Decoder::new(tiff).unwrap();
+        assert_eq!(decoder.dimensions().unwrap(), (width as u32, height as u32));
+        match decoder.read_image().unwrap() {
+            DecodingResult::U16(samples) => assert_eq!(samples, rgb),
+            _ => panic!("not a 16-bit TIFF"),
+        }
+    }
+
+    // (fourcc, payload) of each chunk after the WebP form type
+    fn riff_chunks(webp: &[u8]) -> Vec<(&[u8], &[u8])> {
+        let mut chunks = Vec::new();
+        let mut i = 12;
+        while i + 8 <= webp.len() {
+            let size = u32::from_le_bytes(webp[i + 4..i + 8].try_into().unwrap()) as usize;
+            chunks.push((&webp[i..i + 4], &webp[i + 8..i + 8 + size]));
+            i += 8 + size + (size & 1);
+        }
+        assert_eq!(i, webp.len(), "chunks don't fill the file");
+        chunks
+    }
+
+    #[test]
+    fn webp_icc_goes_in_an_extended_header() {
+        let (width, height) = (9u32, 6u32);
+        let rgb: Vec<u8> = (0..width * height * 3).map(|i| (i * 37) as u8).collect();
+        // Odd length, so the ICCP chunk needs a pad byte
+        let icc: Vec<u8> = (0..33).collect();
+        let mut webp = Vec::new();
+        write_webp_from_rgb8_writer(&mut webp, &rgb, width, height, 90, Some(&icc)).unwrap();
+
+        assert_eq!(&webp[..4], b"RIFF");
+        assert_eq!(&webp[8..12], b"WEBP");
+        let riff_size = u32::from_le_bytes(webp[4..8].try_into().unwrap()) as usize;
+        assert_eq!(riff_size, webp.len() - 8);
+
+        let chunks = riff_chunks(&webp);
+        let kinds: Vec<&[u8]> = chunks.iter().map(|&(k, _)| k).collect();
+        assert_eq!(kinds, [&b"VP8X"[..], b"ICCP", b"VP8 "]);
+        let vp8x = chunks[0].1;
+        assert_eq!(vp8x[0], WEBP_ICC_FLAG);
+        assert_eq!(&vp8x[4..7], &(width - 1).to_le_bytes()[..3]);
+        assert_eq!(&vp8x[7..10], &(height - 1).to_le_bytes()[..3]);
+        assert_eq!(chunks[1].1, icc);
+
+        let decoded = image::load_from_memory(&webp).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (width, height));
+        assert_eq!(crate::icc::extract_icc_profile(&webp), Some(icc));
+    }
+
+    #[test]
+    fn extended_webp_keeps_its_flags_when_tagged() {
+        let (width, height) = (8u32, 4u32);
+        let rgba: Vec<u8> = (0..width * height * 4).map(|i| (i * 53) as u8).collect();
+        let icc = vec![7u8; 20];
+        let mut plain = Vec::new();
+        write_webp_from_rgba8_writer(&mut plain, &rgba, width, height, 90, None).unwrap();
+        let mut webp = Vec::new();
+        write_webp_from_rgba8_writer(&mut webp, &rgba, width, height, 90, Some(&icc)).unwrap();
+
+        let before = riff_chunks(&plain);
+        let after = riff_chunks(&webp);
+        assert_eq!(before[0].0, b"VP8X");
+        assert_eq!(after[0].0, b"VP8X");
+        assert_eq!(after[0].1[0], before[0].1[0] | WEBP_ICC_FLAG);
+        assert_eq!(after[0].1[1..], before[0].1[1..]);
+        assert_eq!(after[1], (&b"ICCP"[..], &icc[..]));
+        assert_eq!(after[2..], before[1..]);
+
+        let decoded = image::load_from_memory(&webp).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (width, height));
+    }
+}
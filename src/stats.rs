@@ -0,0 +1,428 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{Seek, SeekFrom},
+};
+
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use serde::Serialize;
+
+use crate::{
+    agno_image::{AgnoImage, PixelFormat, Sample, load::decode_sony_raw},
+    black_level::{ActiveArea, BlackLevels, active_area_from_exif, resolve_black_levels},
+    exif::ExifContext,
+    sony_decoder::Dimensions,
+    tiff::detect_sony_raw,
+    tone::TransferFn,
+};
+
+// Raw histograms span 0..=white_level in this many buckets
+const RAW_HISTOGRAM_BINS: usize = 1024;
+// Rendered histograms use one bucket per 8-bit code, whatever the pixel format
+const IMAGE_HISTOGRAM_BINS: usize = 256;
+
+// Rec. 709 luma weights, applied to the encoded values as most histogram displays do
+const LUMA_WEIGHTS: [f32; 3] = [0.2126, 0.7152, 0.0722];
+
+// Names of the 2x2 CFA positions, in `cfa_index` order
+const CFA_NAMES: [&str; 4] = ["R", "G1", "G2", "B"];
+
+/// One CFA position measured on the undemosaiced mosaic.
+#[derive(Clone, Debug, Serialize)]
+pub struct RawChannelStats {
+    pub name: &'static str,
+    pub black_level: u16,
+    pub min: u16,
+    pub max: u16,
+    pub mean: f64,
+    /// Samples at or above the white level
+    pub clipped: u64,
+    /// Samples at or below the black level
+    pub crushed: u64,
+    /// Codes between the black level and the darkest sample (negative when noise dips below it)
+    pub black_headroom: i32,
+    /// Stops between the brightest sample and the white level, 0 when clipped
+    pub white_headroom_stops: f32,
+    /// `RAW_HISTOGRAM_BINS` buckets evenly covering 0..=white_level
+    pub histogram: Vec<u64>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RawStats {
+    pub width: usize,
+    pub height: usize,
+    pub white_level: u16,
+    /// Only the active area is measured; the masked border would skew the shadows
+    pub channels: Vec<RawChannelStats>,
+}
+
+/// Histograms of a rendered image, bucketed to 8-bit codes.
+#[derive(Clone, Debug, Serialize)]
+pub struct ImageStats {
+    pub width: u64,
+    pub height: u64,
+    /// Mean of each of R, G, B and luma in [0, 1]
+    pub mean: [f64; 4],
+    /// Samples at 0 per R, G, B
+    pub shadows_clipped: [u64; 3],
+    /// Samples at full scale (or above, for linear float) per R, G, B
+    pub highlights_clipped: [u64; 3],
+    pub red: Vec<u64>,
+    pub green: Vec<u64>,
+    pub blue: Vec<u64>,
+    pub luma: Vec<u64>,
+}
+
+#[derive(Clone)]
+struct ChannelAcc {
+    histogram: Vec<u64>,
+    min: u16,
+    max: u16,
+    sum: u64,
+    count: u64,
+    clipped: u64,
+    crushed: u64,
+}
+
+impl ChannelAcc {
+    fn new() -> Self {
+        ChannelAcc {
+            histogram: vec![0; RAW_HISTOGRAM_BINS],
+            min: u16::MAX,
+            max: 0,
+            sum: 0,
+            count: 0,
+            clipped: 0,
+            crushed: 0,
+        }
+    }
+
+    fn merge(mut self, other: ChannelAcc) -> Self {
+        self.histogram
+            .iter_mut()
+            .zip(other.histogram)
+            .for_each(|(a, b)| *a += b);
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.sum += other.sum;
+        self.count += other.count;
+        self.clipped += other.clipped;
+        self.crushed += other.crushed;
+        self
+    }
+}
+
+/// Measures each CFA position of the mosaic inside `area` (the whole output area when None).
+pub fn raw_stats(
+    raw: &[u16],
+    dims: Dimensions,
+    area: Option<ActiveArea>,
+    black_level: BlackLevels,
+    white_level: u16,
+) -> RawStats {
+    let area = area.unwrap_or(ActiveArea {
+        top: 0,
+        left: 0,
+        bottom: dims.output_height,
+        right: dims.output_width,
+    });
+    let scale = (RAW_HISTOGRAM_BINS - 1) as f32 / white_level.max(1) as f32;
+
+    let accs = (area.top..area.bottom)
+        .into_par_iter()
+        .map(|row| {
+            let mut accs: [ChannelAcc; 4] = std::array::from_fn(|_| ChannelAcc::new());
+            let line = &raw[row * dims.raw_width..];
+            for (col, &v) in line.iter().enumerate().take(area.right).skip(area.left) {
+                let pos = ((row & 1) << 1) | (col & 1);
+                let acc = &mut accs[pos];
+                let bin = ((v as f32 * scale) as usize).min(RAW_HISTOGRAM_BINS - 1);
+                acc.histogram[bin] += 1;
+                acc.min = acc.min.min(v);
+                acc.max = acc.max.max(v);
+                acc.sum += v as u64;
+                acc.count += 1;
+                acc.clipped += (v >= white_level) as u64;
+                acc.crushed += (v <= black_level[pos]) as u64;
+            }
+            accs
+        })
+        .reduce(
+            || std::array::from_fn(|_| ChannelAcc::new()),
+            |a, b| {
+                let mut b = b.into_iter();
+                a.map(|acc| acc.merge(b.next().unwrap()))
+            },
+        );
+
+    let channels = accs
+        .into_iter()
+        .enumerate()
+        .map(|(pos, acc)| {
+            let black = black_level[pos];
+            let range = white_level.saturating_sub(black) as f32;
+            let peak = acc.max.saturating_sub(black) as f32;
+            let empty = acc.count == 0;
+            RawChannelStats {
+                name: CFA_NAMES[pos],
+                black_level: black,
+                min: if empty { 0 } else { acc.min },
+                max: acc.max,
+                mean: if empty {
+                    0.0
+                } else {
+                    acc.sum as f64 / acc.count as f64
+                },
+                clipped: acc.clipped,
+                crushed: acc.crushed,
+                black_headroom: if empty {
+                    0
+                } else {
+                    acc.min as i32 - black as i32
+                },
+                white_headroom_stops: if peak > 0.0 && peak < range {
+                    (range / peak).log2()
+                } else {
+                    0.0
+                },
+                histogram: acc.histogram,
+            }
+        })
+        .collect();
+
+    RawStats {
+        width: area.right - area.left,
+        height: area.bottom - area.top,
+        white_level,
+        channels,
+    }
+}
+
+/// Decodes the raw at `path` and measures its mosaic with the black levels the
+/// renderer would use.
+pub fn raw_stats_from_file(path: &str) -> Result<RawStats, Box<dyn Error>> {
+    let mut file = File::open(path)?;
+    let det = detect_sony_raw(&mut file)?;
+
+    file.seek(SeekFrom::Start(0))?;
+    let ctx = ExifContext::from_reader_auto(&mut file)?;
+    let (decoded, dims) = decode_sony_raw(&det, &mut file, &ctx)?;
+
+    let black_level = resolve_black_levels(&ctx, &decoded.pixels, dims, decoded.white_level);
+    Ok(raw_stats(
+        &decoded.pixels,
+        dims,
+        active_area_from_exif(&ctx, dims),
+        black_level,
+        decoded.white_level,
+    ))
+}
+
+#[derive(Clone)]
+struct ImageAcc {
+    histograms: [Vec<u64>; 4],
+    sums: [f64; 4],
+    shadows: [u64; 3],
+    highlights: [u64; 3],
+}
+
+impl ImageAcc {
+    fn new() -> Self {
+        ImageAcc {
+            histograms: std::array::from_fn(|_| vec![0; IMAGE_HISTOGRAM_BINS]),
+            sums: [0.0; 4],
+            shadows: [0; 3],
+            highlights: [0; 3],
+        }
+    }
+
+    fn merge(mut self, other: ImageAcc) -> Self {
+        for (a, b) in self.histograms.iter_mut().zip(other.histograms) {
+            a.iter_mut().zip(b).for_each(|(x, y)| *x += y);
+        }
+        for i in 0..4 {
+            self.sums[i] += other.sums[i];
+        }
+        for i in 0..3 {
+            self.shadows[i] += other.shadows[i];
+            self.highlights[i] += other.highlights[i];
+        }
+        self
+    }
+}
+
+// Alpha, when there is a fourth channel, is left out of every histogram
+fn image_stats_of<T: Sample>(
+    data: &[T],
+    width: usize,
+    height: usize,
+    channels: usize,
+) -> ImageStats {
+    // A failed allocation leaves no pixels behind the reported size
+    let rows = if width == 0 {
+        0
+    } else {
+        height.min(data.len() / (width * channels))
+    };
+
+    // Linear float is sRGB encoded first so its histogram reads like the integer formats'
+    let srgb = TransferFn::srgb();
+    let encode = |v: T| {
+        let u = v.to_unit();
+        if T::FORMAT.is_linear() {
+            (srgb.encode(u.min(1.0)), u >= 1.0)
+        } else {
+            (u, u >= 1.0)
+        }
+    };
+    let bin = |u: f32| (u.clamp(0.0, 1.0) * (IMAGE_HISTOGRAM_BINS - 1) as f32 + 0.5) as usize;
+
+    let acc = (0..rows)
+        .into_par_iter()
+        .map(|row| {
+            let mut acc = ImageAcc::new();
+            let line = &data[row * width * channels..(row + 1) * width * channels];
+            for px in line.chunks_exact(channels) {
+                // Grey counts as equal red, green and blue
+                let rgb = match channels {
+                    1 => [px[0]; 3],
+                    _ => [px[0], px[1], px[2]],
+                };
+                let mut luma = 0.0;
+                for (ch, v) in rgb.into_iter().enumerate() {
+                    let (u, clipped) = encode(v);
+                    acc.histograms[ch][bin(u)] += 1;
+                    acc.sums[ch] += u as f64;
+                    acc.shadows[ch] += (u <= 0.0) as u64;
+                    acc.highlights[ch] += clipped as u64;
+                    luma += u.clamp(0.0, 1.0) * LUMA_WEIGHTS[ch];
+                }
+                acc.histograms[3][bin(luma)] += 1;
+                acc.sums[3] += luma as f64;
+            }
+            acc
+        })
+        .reduce(ImageAcc::new, ImageAcc::merge);
+
+    let count = (width * rows).max(1) as f64;
+    let [red, green, blue, luma] = acc.histograms;
+
+    ImageStats {
+        width: width as u64,
+        height: height as u64,
+        mean: acc.sums.map(|s| s / count),
+        shadows_clipped: acc.shadows,
+        highlights_clipped: acc.highlights,
+        red,
+        green,
+        blue,
+        luma,
+    }
+}
+
+/// RGB and luma histograms of a rendered image in any pixel format.
+pub fn image_stats(img: &AgnoImage) -> ImageStats {
+    let (w, h, c) = (
+        img.width as usize,
+        img.height as usize,
+        img.channels as usize,
+    );
+    match img.format {
+        PixelFormat::U8 => image_stats_of::<u8>(img.as_samples().unwrap_or_default(), w, h, c),
+        PixelFormat::U16 => image_stats_of::<u16>(img.as_samples().unwrap_or_default(), w, h, c),
+        PixelFormat::F32 => image_stats_of::<f32>(img.as_samples().unwrap_or_default(), w, h, c),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: u16 = 4000;
+    const BLACK: BlackLevels = [500, 510, 520, 530];
+
+    // 12x8 active output in a 14-wide raster; the border outside `area` reads 9999
+    // so anything measured from it would show up as clipping
+    fn mosaic(area: ActiveArea) -> (Vec<u16>, Dimensions) {
+        let dims = Dimensions {
+            raw_width: 14,
+            raw_height: 8,
+            output_width: 12,
+            output_height: 8,
+        };
+        let raw = (0..dims.raw_width * dims.raw_height)
+            .map(|i| {
+                let (row, col) = (i / dims.raw_width, i % dims.raw_width);
+                if !(area.top..area.bottom).contains(&row)
+                    || !(area.left..area.right).contains(&col)
+                {
+                    return 9999;
+                }
+                match (row & 1, col & 1, row) {
+                    // Red blown along the first active row
+                    (0, 0, 2) => WHITE,
+                    (0, 0, _) => 2000,
+                    (0, 1, _) => 1000,
+                    // G2 sits on its black level in one row and dips below it in another
+                    (1, 0, 3) => 520,
+                    (1, 0, 5) => 400,
+                    (1, 0, _) => 800,
+                    _ => 600 + col as u16,
+                }
+            })
+            .collect();
+        (raw, dims)
+    }
+
+    #[test]
+    fn raw_stats_count_and_clip_per_cfa_position() {
+        let area = ActiveArea {
+            top: 2,
+            left: 2,
+            bottom: 8,
+            right: 12,
+        };
+        let (raw, dims) = mosaic(area);
+        let stats = raw_stats(&raw, dims, Some(area), BLACK, WHITE);
+        assert_eq!((stats.width, stats.height), (10, 6));
+
+        let [r, g1, g2, b] = [0, 1, 2, 3].map(|i| &stats.channels[i]);
+        for c in [r, g1, g2, b] {
+            assert_eq!(c.histogram.iter().sum::<u64>(), 15, "{}", c.name);
+        }
+
+        assert_eq!((r.min, r.max, r.clipped, r.crushed), (2000, WHITE, 5, 0));
+        assert!((r.mean - 40000.0 / 15.0).abs() < 1e-9);
+        assert_eq!(r.white_headroom_stops, 0.0);
+        assert_eq!(r.histogram[RAW_HISTOGRAM_BINS - 1], 5);
+        assert_eq!(
+            r.histogram[(2000 * (RAW_HISTOGRAM_BINS - 1)) / WHITE as usize],
+            10
+        );
+
+        assert_eq!(
+            (g1.min, g1.max, g1.clipped, g1.black_headroom),
+            (1000, 1000, 0, 490)
+        );
+        assert!((g1.white_headroom_stops - (3490.0f32 / 490.0).log2()).abs() < 1e-5);
+
+        assert_eq!((g2.crushed, g2.min, g2.black_headroom), (10, 400, -120));
+        assert_eq!((b.min, b.max, b.crushed), (603, 611, 0));
+    }
+
+    #[test]
+    fn raw_stats_default_to_the_whole_output_area() {
+        let everything = ActiveArea {
+            top: 0,
+            left: 0,
+            bottom: 8,
+            right: 12,
+        };
+        let (raw, dims) = mosaic(everything);
+        let stats = raw_stats(&raw, dims, None, BLACK, WHITE);
+        assert_eq!((stats.width, stats.height), (12, 8));
+        // The padding columns past the output width stay out
+        assert!(stats.channels.iter().all(|c| c.max <= WHITE));
+        assert_eq!(stats.channels[0].histogram.iter().sum::<u64>(), 24);
+    }
+}
@@ -21,6 +21,7 @@ pub struct TiffRawInfo {
     pub width: u32,               // image width (pixels)
     pub height: u32,              // image height (pixels)
     pub bits_per_sample: u16,     // usually 12/14 (reported)
+    pub samples_per_pixel: u16,   // 1 for a mosaic, 4 for a pixel-shift ARQ
     pub compression: u16,         // 32767 for Sony custom
     pub strip_offsets: Vec<u64>,  // byte offsets to each strip
     pub strip_byte_counts: Vec<u64>, // sizes of each strip in bytes
@@ -30,9 +31,12 @@ pub struct TiffRawInfo {
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SonyVariant {
-    Arw2Compressed, // block-compressed, 16-pixel blocks (bytes == width*height)
-    ArwLjpeg,       // LJPEG-like differential coding with Huffman (legacy)
-    Uncompressed14, // 14-bit uncompressed (bytes == width*height*2)
+    Arw2Compressed,  // block-compressed, 16-pixel blocks (bytes == width*height)
+    ArwLjpeg,        // LJPEG-like differential coding with Huffman (legacy)
+    Uncompressed14,  // 14-bit uncompressed (bytes == width*height*2)
+    DngUncompressed, // DNG, 16-bit little-endian samples
+    DngLjpeg,        // DNG, lossless JPEG (compression 7)
+    Arq,             // pixel-shift composite, 16-bit R/G/G/B per pixel
     Unknown,
 }
 
@@ -125,10 +129,25 @@ pub fn detect_sony_raw(r: &mut File) -> Result<TiffDetectResult, DecodeError> {
         }
         0 | 1 => {
             // Uncompressed
-            if raw.dng_version.is_none() && is_sony && raw.total_bytes == pixels * 2 {
+            if raw.samples_per_pixel == 4 {
+                // Pixel-shift composite written by Imaging Edge
+                if is_sony && raw.total_bytes == pixels * 8 {
+                    variant = SonyVariant::Arq;
+                }
+            } else if raw.dng_version.is_none() && is_sony && raw.total_bytes == pixels * 2 {
                 variant = SonyVariant::Uncompressed14;
+            } else if raw.dng_version.is_some()
+                && matches!(endian, Endian::Little)
+                && raw.bits_per_sample == 16
+                && raw.total_bytes == pixels * 2
+            {
+                variant = SonyVariant::DngUncompressed;
             }
         }
+        7 if raw.dng_version.is_some() => {
+            // Lossless JPEG, as written by DNG converters
+            variant = SonyVariant::DngLjpeg;
+        }
         _ => {
             // Other compressions could map to LJPEG or tiles; not handled here
         }
@@ -377,14 +396,14 @@ fn try_extract_raw_info<R: Read + Seek>(
         Some(v) if !v.is_empty() => v[0],
         _ => 1,
     };
-    if samples_per_pixel != 1 {
-        // We want the mosaic plane (1 sample per pixel)
-        return Ok(None);
-    }
     let bits_per_sample = match read_short_array_tag(r, e, ifd, 258)? {
         Some(v) if !v.is_empty() => v[0],
         _ => 14, // common default in ARW
     };
+    // We want the mosaic plane (1 sample per pixel), or the four planes of an ARQ
+    if samples_per_pixel != 1 && !(samples_per_pixel == 4 && bits_per_sample == 16) {
+        return Ok(None);
+    }
 
     let strip_offsets = match read_long_array_tag(r, e, ifd, 273)? {
         Some(v) if !v.is_empty() => v.into_iter().map(|x| x as u64).collect::<Vec<_>>(),
@@ -411,6 +430,7 @@ fn try_extract_raw_info<R: Read + Seek>(
         width,
         height,
         bits_per_sample,
+        samples_per_pixel,
         compression,
         strip_offsets,
         strip_byte_counts,
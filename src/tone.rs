@@ -0,0 +1,255 @@
+use crate::simd;
+
+// Entries in the tone LUT; values in between are interpolated
+const LUT_SIZE: usize = 4096;
+
+// Filmic input is unbounded, so its LUT covers a few stops above the raw white point
+const FILMIC_MAX_INPUT: f32 = 16.0;
+
+// Narkowicz's ACES fit expects scene values around 0.6x ours for a matching mid grey
+const FILMIC_EXPOSURE: f32 = 0.6;
+
+/// Transfer curve applied when encoding linear light into the integer output formats.
+#[repr(C)]
+#[allow(dead_code)] // variants are constructed from C
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ToneCurve {
+    /// IEC 61966-2-1: power 1/2.4 with a linear toe of slope 12.92
+    #[default]
+    Srgb = 0,
+    /// ITU-R BT.709: power 0.45 with a linear toe of slope 4.5
+    Rec709 = 1,
+    /// No encoding at all
+    Linear = 2,
+    /// `gamma` and `toe_slope` from the develop settings; a slope of 0 is a pure power law
+    Gamma = 3,
+    /// ACES-like filmic curve that rolls highlights off instead of clipping, then sRGB
+    Filmic = 4,
+}
+
+/// A power curve with an optional linear segment near black, in the form dcraw's
+/// gamma_curve uses: `v * slope` below `toe`, `v^power * (1 + offset) - offset` above.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TransferFn {
+    power: f32,
+    slope: f32,
+    toe: f32,
+    offset: f32,
+}
+
+impl TransferFn {
+    /// Solves for the toe and offset that make the two segments meet with matching slope.
+    /// - gamma: display gamma, i.e. the curve raises to 1/gamma
+    /// - slope: gradient of the linear toe, 0 for none
+    pub fn new(gamma: f32, slope: f32) -> Self {
+        let power = 1.0 / gamma.max(0.001);
+        if slope <= 0.0 || (slope - 1.0) * (power - 1.0) > 0.0 {
+            return TransferFn {
+                power,
+                slope: 0.0,
+                toe: 0.0,
+                offset: 0.0,
+            };
+        }
+
+        // Bisect for the encoded value at the joint, as dcraw does
+        let (power64, slope64) = (power as f64, slope as f64);
+        let mut bounds = [0.0f64, 0.0];
+        bounds[(slope >= 1.0) as usize] = 1.0;
+        let mut joint = 0.0;
+        for _ in 0..48 {
+            joint = (bounds[0] + bounds[1]) / 2.0;
+            let above = ((joint / slope64).powf(-power64) - 1.0) / power64 - 1.0 / joint > -1.0;
+            bounds[above as usize] = joint;
+        }
+
+        TransferFn {
+            power,
+            slope,
+            toe: (joint / slope64) as f32,
+            offset: (joint * (1.0 / power64 - 1.0)) as f32,
+        }
+    }
+
+    pub fn srgb() -> Self {
+        Self::new(2.4, 12.92)
+    }
+
+    pub fn rec709() -> Self {
+        Self::new(1.0 / 0.45, 4.5)
+    }
+
+    /// Parameters of the ICC parametric curve (type 3, or type 0 without a toe)
+    /// that decodes this function.
+    pub fn icc_parametric(&self) -> Vec<f32> {
+        let gamma = 1.0 / self.power;
+        if self.slope <= 0.0 {
+            return vec![gamma];
+        }
+        let scale = 1.0 / (1.0 + self.offset);
+        vec![
+            gamma,
+            scale,
+            self.offset * scale,
+            1.0 / self.slope,
+            self.toe * self.slope,
+        ]
+    }
+
+    /// Linear -> encoded, both nominally in [0, 1].
+    #[inline(always)]
+    pub fn encode(&self, v: f32) -> f32 {
+        if v <= 0.0 {
+            0.0
+        } else if v < self.toe {
+            v * self.slope
+        } else {
+            v.powf(self.power) * (1.0 + self.offset) - self.offset
+        }
+    }
+
+    /// Encoded -> linear, the inverse of `encode`.
+    #[inline(always)]
+    pub fn decode(&self, v: f32) -> f32 {
+        if v <= 0.0 {
+            0.0
+        } else if v < self.toe * self.slope {
+            v / self.slope
+        } else {
+            ((v + self.offset) / (1.0 + self.offset)).powf(1.0 / self.power)
+        }
+    }
+}
+
+// Krzysztof Narkowicz's fit of the ACES RRT + ODT, maps [0, inf) onto [0, 1)
+fn aces_filmic(v: f32) -> f32 {
+    let x = v * FILMIC_EXPOSURE;
+    ((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)).clamp(0.0, 1.0)
+}
+
+impl ToneCurve {
+    /// The transfer function the curve encodes with. Filmic rolls highlights off
+    /// first but otherwise encodes as sRGB.
+    pub fn transfer_fn(self, gamma: f32, toe_slope: f32) -> TransferFn {
+        match self {
+            ToneCurve::Srgb | ToneCurve::Filmic => TransferFn::srgb(),
+            ToneCurve::Rec709 => TransferFn::rec709(),
+            ToneCurve::Linear => TransferFn::new(1.0, 0.0),
+            ToneCurve::Gamma => TransferFn::new(gamma, toe_slope),
+        }
+    }
+}
+
+/// Precomputed linear -> encoded curve so the renderers don't call `powf` per sample.
+#[derive(Clone, Debug)]
+pub struct ToneLut {
+    table: Vec<f32>,
+    // LUT steps per unit of linear input
+    scale: f32,
+}
+
+impl ToneLut {
+    pub fn new(curve: ToneCurve, gamma: f32, toe_slope: f32) -> Self {
+        let t = curve.transfer_fn(gamma, toe_slope);
+        let (max_input, f): (f32, Box<dyn Fn(f32) -> f32>) = match curve {
+            ToneCurve::Filmic => (
+                FILMIC_MAX_INPUT,
+                Box::new(move |v| t.encode(aces_filmic(v))),
+            ),
+            _ => (1.0, Box::new(move |v| t.encode(v))),
+        };
+
+        let step = max_input / LUT_SIZE as f32;
+        // One extra entry so interpolation at the top end has a right neighbour
+        let table = (0..=LUT_SIZE + 1).map(|i| f(i as f32 * step)).collect();
+
+        ToneLut {
+            table,
+            scale: LUT_SIZE as f32 / max_input,
+        }
+    }
+
+    /// Encodes one linear value; anything past the LUT's range lands on its last entry.
+    #[inline(always)]
+    pub fn apply(&self, v: f32) -> f32 {
+        let pos = (v * self.scale).clamp(0.0, LUT_SIZE as f32);
+        let i = pos as usize;
+        let frac = pos - i as f32;
+        self.table[i] + (self.table[i + 1] - self.table[i]) * frac
+    }
+
+    /// `apply` over a row in place.
+    pub fn apply_row(&self, row: &mut [f32]) {
+        simd::lut_row(&self.table, self.scale, row);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn standard_curves_meet_at_their_breakpoints() {
+        // (curve, toe slope, published offset, 18% grey encoded by the standard)
+        for (t, slope, offset, grey) in [
+            (TransferFn::srgb(), 12.92, 0.055, 0.4614),
+            (TransferFn::rec709(), 4.5, 0.099, 0.4090),
+        ] {
+            assert!((t.offset - offset).abs() < 1e-3, "{:?}", t);
+            assert!((t.encode(0.18) - grey).abs() < 1e-3, "{:?}", t);
+
+            // Same value and gradient from both sides of the toe
+            let power_at_toe = t.toe.powf(t.power) * (1.0 + t.offset) - t.offset;
+            assert!((t.toe * slope - power_at_toe).abs() < 1e-5, "{:?}", t);
+            let gradient = t.power * t.toe.powf(t.power - 1.0) * (1.0 + t.offset);
+            assert!((gradient - slope).abs() < slope * 1e-3, "{:?}", t);
+
+            assert_eq!(t.encode(0.0), 0.0);
+            assert!((t.encode(1.0) - 1.0).abs() < 1e-6);
+            assert!((t.encode(t.toe * 0.5) - t.toe * 0.5 * slope).abs() < 1e-7);
+            for i in 0..=100 {
+                let v = i as f32 / 100.0;
+                assert!((t.decode(t.encode(v)) - v).abs() < 1e-5, "{:?} at {}", t, v);
+            }
+        }
+    }
+
+    #[test]
+    fn pure_power_curves_have_no_toe() {
+        let t = TransferFn::new(2.2, 0.0);
+        assert_eq!((t.toe, t.offset), (0.0, 0.0));
+        assert_eq!(t.icc_parametric(), [2.2]);
+        assert!((t.encode(0.5) - 0.5f32.powf(1.0 / 2.2)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn tone_luts_rise_monotonically_to_white() {
+        for curve in [
+            ToneCurve::Srgb,
+            ToneCurve::Rec709,
+            ToneCurve::Linear,
+            ToneCurve::Gamma,
+            ToneCurve::Filmic,
+        ] {
+            let lut = ToneLut::new(curve, 2.2, 4.5);
+            let mut last = lut.apply(0.0);
+            assert_eq!(last, 0.0, "{:?}", curve);
+            // Across the LUT's steps and well past white
+            for i in 1..=40_000 {
+                let v = lut.apply(i as f32 * 0.0005);
+                assert!(v >= last, "{:?} falls at {}", curve, i as f32 * 0.0005);
+                last = v;
+            }
+            assert!(last <= 1.0 + 1e-6, "{:?} ends at {}", curve, last);
+            if curve != ToneCurve::Filmic {
+                assert!((lut.apply(1.0) - 1.0).abs() < 1e-5, "{:?}", curve);
+            }
+
+            // The row form gives the same values, negatives and overshoot included
+            let mut row: Vec<f32> = (-5..200).map(|i| i as f32 * 0.011).collect();
+            let expected: Vec<f32> = row.iter().map(|&v| lut.apply(v)).collect();
+            lut.apply_row(&mut row);
+            assert_eq!(row, expected, "{:?}", curve);
+        }
+    }
+}
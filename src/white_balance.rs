@@ -0,0 +1,459 @@
+use log::debug;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use crate::{
+    agno_image::load::RawDevelopSettings,
+    black_level::BlackLevels,
+    color::{self, Matrix3},
+    demosaic::{BayerPattern, CfaColor, cfa_color_at, cfa_index},
+    exif::{
+        ExifContext, ExifValue,
+        spec::{
+            AS_SHOT_NEUTRAL, WB_RGBLEVELS_CLOUDY, WB_RGBLEVELS_DAYLIGHT, WB_RGBLEVELS_FLASH,
+            WB_RGBLEVELS_FLUORESCENT, WB_RGBLEVELS_TUNGSTEN, WB_RGGBLEVELS,
+        },
+    },
+    sony_decoder::Dimensions,
+};
+
+// Quads with any sample this close to white are clipped and tell us nothing about the light
+const CLIP_FRACTION: f32 = 0.98;
+// Quads darker than this (fraction of the range) are mostly noise
+const DARK_FRACTION: f32 = 0.01;
+// White patch averages the brightest unclipped quads above this percentile
+const WHITE_PATCH_PERCENTILE: f32 = 0.98;
+const HISTOGRAM_BINS: usize = 1024;
+
+/// Where the raw develop takes its white balance from.
+#[repr(C)]
+#[allow(dead_code)] // variants are constructed from C
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WhiteBalanceMode {
+    /// DNG AsShotNeutral or the camera's WB_RGGBLevels, gray world if neither is there
+    #[default]
+    AsShot = 0,
+    /// The caller's RGGB multipliers
+    Custom = 1,
+    /// Unity gains, i.e. raw sensor colour
+    None = 2,
+    /// Average of the scene is neutral
+    GrayWorld = 3,
+    /// Brightest unclipped quads are neutral
+    WhitePatch = 4,
+    /// `wb_temperature` (K) and `wb_tint` through the camera matrix
+    Temperature = 5,
+    // Camera presets from the per-preset WB tables, with a nominal temperature as fallback
+    Daylight = 6,
+    Cloudy = 7,
+    Tungsten = 8,
+    Flash = 9,
+    Fluorescent = 10,
+}
+
+/// Turns RGGB multipliers into [R, G, B] gains normalized to green.
+pub fn gains_from_rggb(levels: [f32; 4]) -> Option<[f32; 3]> {
+    let g = (levels[1] + levels[2]) * 0.5;
+    if g <= 0.0 || levels[0] <= 0.0 || levels[3] <= 0.0 {
+        return None;
+    }
+    Some([levels[0] / g, 1.0, levels[3] / g])
+}
+
+// Gains that make a camera-space neutral [r, g, b] come out grey
+fn gains_from_neutral(neutral: [f32; 3]) -> Option<[f32; 3]> {
+    if neutral.iter().any(|&n| n <= 0.0 || !n.is_finite()) {
+        return None;
+    }
+    Some([neutral[1] / neutral[0], 1.0, neutral[1] / neutral[2]])
+}
+
+fn exif_floats(value: &ExifValue) -> Vec<f32> {
+    match value {
+        ExifValue::Short(v) => v.iter().map(|&n| n as f32).collect(),
+        ExifValue::Long(v) => v.iter().map(|&n| n as f32).collect(),
+        ExifValue::Rational(v) => v
+            .iter()
+            .map(|&(n, d)| if d == 0 { 0.0 } else { n as f32 / d as f32 })
+            .collect(),
+        ExifValue::SRational(v) => v
+            .iter()
+            .map(|&(n, d)| if d == 0 { 0.0 } else { n as f32 / d as f32 })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+// Sony stores RGGB, some bodies only RGB
+fn gains_from_levels(value: &ExifValue) -> Option<[f32; 3]> {
+    let v = exif_floats(value);
+    match v.len() {
+        3 => gains_from_rggb([v[0], v[1], v[1], v[2]]),
+        n if n >= 4 => gains_from_rggb([v[0], v[1], v[2], v[3]]),
+        _ => None,
+    }
+}
+
+pub fn as_shot_gains(ctx: &ExifContext) -> Option<[f32; 3]> {
+    // DNG AsShotNeutral is the camera-space colour of a neutral object
+    if let Some(v) = ctx.get_tag_value(AS_SHOT_NEUTRAL) {
+        let n = exif_floats(v);
+        if n.len() >= 3
+            && let Some(gains) = gains_from_neutral([n[0], n[1], n[2]])
+        {
+            return Some(gains);
+        }
+    }
+
+    ctx.get_tag_value(WB_RGGBLEVELS).and_then(gains_from_levels)
+}
+
+// Approximate CIE 1931 xy of a Planckian radiator (Kim et al. cubic spline), 1667K-25000K
+fn planckian_xy(kelvin: f32) -> (f32, f32) {
+    let t = kelvin.clamp(1667.0, 25000.0) as f64;
+    let (t2, t3) = (t * t, t * t * t);
+
+    let x = if t <= 4000.0 {
+        -0.266_123_9e9 / t3 - 0.234_358_9e6 / t2 + 0.877_695_6e3 / t + 0.179_910
+    } else {
+        -3.025_846_9e9 / t3 + 2.107_037_9e6 / t2 + 0.222_634_7e3 / t + 0.240_390
+    };
+    let (x2, x3) = (x * x, x * x * x);
+
+    let y = if t <= 2222.0 {
+        -1.106_381_4 * x3 - 1.348_110_20 * x2 + 2.185_558_32 * x - 0.202_196_83
+    } else if t <= 4000.0 {
+        -0.954_947_6 * x3 - 1.374_185_93 * x2 + 2.091_370_15 * x - 0.167_488_67
+    } else {
+        3.081_758_0 * x3 - 5.873_386_70 * x2 + 3.751_129_97 * x - 0.370_014_83
+    };
+
+    (x as f32, y as f32)
+}
+
+fn xy_to_uv((x, y): (f32, f32)) -> (f32, f32) {
+    let d = -2.0 * x + 12.0 * y + 3.0;
+    (4.0 * x / d, 6.0 * y / d)
+}
+
+fn uv_to_xy((u, v): (f32, f32)) -> (f32, f32) {
+    let d = 2.0 * u - 8.0 * v + 4.0;
+    (3.0 * u / d, 2.0 * v / d)
+}
+
+/// Chromaticity of a white at `kelvin`, pushed off the Planckian locus by `tint`:
+/// negative is greener, positive more magenta, and ±100 is a Duv of ∓0.033.
+pub fn temperature_tint_to_xy(kelvin: f32, tint: f32) -> (f32, f32) {
+    if tint == 0.0 {
+        return planckian_xy(kelvin);
+    }
+    let (u, v) = xy_to_uv(planckian_xy(kelvin));
+
+    // Unit normal to the locus, pointing towards green (+v side)
+    let (u2, v2) = xy_to_uv(planckian_xy(kelvin + 10.0));
+    let (du, dv) = (u2 - u, v2 - v);
+    let len = (du * du + dv * dv).sqrt().max(1e-9);
+    let (mut nu, mut nv) = (-dv / len, du / len);
+    if nv < 0.0 {
+        (nu, nv) = (-nu, -nv);
+    }
+
+    let duv = -tint / 3000.0;
+    uv_to_xy((u + nu * duv, v + nv * duv))
+}
+
+/// Gains for a light of `kelvin` and `tint`: its XYZ white is run through the
+/// XYZ -> camera matrix to find the camera neutral, which the gains cancel.
+pub fn temperature_gains(xyz_to_cam: &Matrix3, kelvin: f32, tint: f32) -> Option<[f32; 3]> {
+    let (x, y) = temperature_tint_to_xy(kelvin, tint);
+    if y <= 0.0 {
+        return None;
+    }
+    let xyz = [x / y, 1.0, (1.0 - x - y) / y];
+    gains_from_neutral(color::apply(xyz_to_cam, xyz))
+}
+
+// Per-quad camera RGB (black subtracted, greens averaged), None for clipped or dark quads
+#[inline(always)]
+fn quad_rgb(
+    raw: &[u16],
+    dims: Dimensions,
+    pattern: BayerPattern,
+    black_level: BlackLevels,
+    clip: u16,
+    (qy, qx): (usize, usize),
+) -> Option<[f32; 3]> {
+    let mut rgb = [0.0f32; 3];
+    for dy in 0..2 {
+        for dx in 0..2 {
+            let (row, col) = (qy * 2 + dy, qx * 2 + dx);
+            let v = raw[row * dims.raw_width + col];
+            if v >= clip {
+                return None;
+            }
+            let v = v.saturating_sub(black_level[cfa_index(row, col)]) as f32;
+            match cfa_color_at(row, col, pattern) {
+                CfaColor::R => rgb[0] += v,
+                CfaColor::G => rgb[1] += v * 0.5,
+                CfaColor::B => rgb[2] += v,
+            }
+        }
+    }
+    Some(rgb)
+}
+
+/// Gray world: the mean of every usable quad is assumed neutral.
+pub fn gray_world_gains(
+    raw: &[u16],
+    dims: Dimensions,
+    pattern: BayerPattern,
+    black_level: BlackLevels,
+    white_level: u16,
+) -> Option<[f32; 3]> {
+    let clip = (white_level as f32 * CLIP_FRACTION) as u16;
+    let dark = white_level as f32 * DARK_FRACTION;
+
+    let sums = (0..dims.output_height / 2)
+        .into_par_iter()
+        .map(|qy| {
+            let mut sums = [0f64; 3];
+            for qx in 0..dims.output_width / 2 {
+                if let Some(rgb) = quad_rgb(raw, dims, pattern, black_level, clip, (qy, qx))
+                    && rgb[1] > dark
+                {
+                    sums.iter_mut().zip(rgb).for_each(|(s, v)| *s += v as f64);
+                }
+            }
+            sums
+        })
+        .reduce(|| [0f64; 3], |a, b| [a[0] + b[0], a[1] + b[1], a[2] + b[2]]);
+
+    gains_from_neutral(sums.map(|s| s as f32))
+}
+
+/// White patch: the brightest unclipped quads (above the 98th percentile) are
+/// assumed to be a white surface. More robust than gray world for scenes
+/// dominated by one colour.
+pub fn white_patch_gains(
+    raw: &[u16],
+    dims: Dimensions,
+    pattern: BayerPattern,
+    black_level: BlackLevels,
+    white_level: u16,
+) -> Option<[f32; 3]> {
+    let clip = (white_level as f32 * CLIP_FRACTION) as u16;
+    let scale = (HISTOGRAM_BINS - 1) as f32 / (3.0 * white_level.max(1) as f32);
+    let bin =
+        |rgb: [f32; 3]| (((rgb[0] + rgb[1] + rgb[2]) * scale) as usize).min(HISTOGRAM_BINS - 1);
+
+    let hist = (0..dims.output_height / 2)
+        .into_par_iter()
+        .map(|qy| {
+            let mut hist = vec![0u64; HISTOGRAM_BINS];
+            for qx in 0..dims.output_width / 2 {
+                if let Some(rgb) = quad_rgb(raw, dims, pattern, black_level, clip, (qy, qx)) {
+                    hist[bin(rgb)] += 1;
+                }
+            }
+            hist
+        })
+        .reduce(
+            || vec![0u64; HISTOGRAM_BINS],
+            |mut a, b| {
+                a.iter_mut().zip(b).for_each(|(x, y)| *x += y);
+                a
+            },
+        );
+
+    let total: u64 = hist.iter().sum();
+    let target = (total as f32 * WHITE_PATCH_PERCENTILE) as u64;
+    let mut seen = 0u64;
+    let threshold = hist
+        .iter()
+        .position(|&n| {
+            seen += n;
+            seen > target
+        })
+        .unwrap_or(0);
+
+    let sums = (0..dims.output_height / 2)
+        .into_par_iter()
+        .map(|qy| {
+            let mut sums = [0f64; 3];
+            for qx in 0..dims.output_width / 2 {
+                if let Some(rgb) = quad_rgb(raw, dims, pattern, black_level, clip, (qy, qx))
+                    && bin(rgb) >= threshold
+                {
+                    sums.iter_mut().zip(rgb).for_each(|(s, v)| *s += v as f64);
+                }
+            }
+            sums
+        })
+        .reduce(|| [0f64; 3], |a, b| [a[0] + b[0], a[1] + b[1], a[2] + b[2]]);
+
+    gains_from_neutral(sums.map(|s| s as f32))
+}
+
+// Preset table in the makernote, and the colour temperature it nominally stands for
+fn preset_gains(
+    ctx: &ExifContext,
+    xyz_to_cam: &Matrix3,
+    mode: WhiteBalanceMode,
+) -> Option<[f32; 3]> {
+    let (field, kelvin) = match mode {
+        WhiteBalanceMode::Daylight => (WB_RGBLEVELS_DAYLIGHT, 5500.0),
+        WhiteBalanceMode::Cloudy => (WB_RGBLEVELS_CLOUDY, 6500.0),
+        WhiteBalanceMode::Tungsten => (WB_RGBLEVELS_TUNGSTEN, 3200.0),
+        WhiteBalanceMode::Flash => (WB_RGBLEVELS_FLASH, 5500.0),
+        WhiteBalanceMode::Fluorescent => (WB_RGBLEVELS_FLUORESCENT, 4000.0),
+        _ => return None,
+    };
+
+    ctx.get_tag_value(field)
+        .and_then(gains_from_levels)
+        .or_else(|| {
+            debug!("No {} table, using {}K", field.name, kelvin);
+            temperature_gains(xyz_to_cam, kelvin, 0.0)
+        })
+}
+
+/// Resolves the WB gains for the develop settings, falling back to unity when the source is missing.
+pub fn resolve_wb_gains(
+    ctx: &ExifContext,
+    settings: &RawDevelopSettings,
+    xyz_to_cam: &Matrix3,
+    raw: &[u16],
+    dims: Dimensions,
+    black_level: BlackLevels,
+    white_level: u16,
+) -> [f32; 3] {
+    // Every supported body is RGGB
+    let pattern = BayerPattern::RGGB;
+
+    let gains = match settings.wb_mode {
+        WhiteBalanceMode::AsShot => as_shot_gains(ctx).or_else(|| {
+            debug!("No as-shot WB, falling back to gray world");
+            gray_world_gains(raw, dims, pattern, black_level, white_level)
+        }),
+        WhiteBalanceMode::Custom => gains_from_rggb(settings.wb_multipliers),
+        WhiteBalanceMode::None => None,
+        WhiteBalanceMode::GrayWorld => {
+            gray_world_gains(raw, dims, pattern, black_level, white_level)
+        }
+        WhiteBalanceMode::WhitePatch => {
+            white_patch_gains(raw, dims, pattern, black_level, white_level)
+        }
+        WhiteBalanceMode::Temperature => {
+            temperature_gains(xyz_to_cam, settings.wb_temperature, settings.wb_tint)
+        }
+        WhiteBalanceMode::Daylight
+        | WhiteBalanceMode::Cloudy
+        | WhiteBalanceMode::Tungsten
+        | WhiteBalanceMode::Flash
+        | WhiteBalanceMode::Fluorescent => preset_gains(ctx, xyz_to_cam, settings.wb_mode),
+    };
+
+    gains.unwrap_or([1.0, 1.0, 1.0])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: u16 = 4000;
+    const IDENTITY: Matrix3 = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+    // 20x20 quads, each [r, g, b] from `quad` at its quad position, both greens equal
+    fn mosaic(quad: impl Fn(usize, usize) -> [u16; 3]) -> (Vec<u16>, Dimensions) {
+        let size = 40;
+        let raw = (0..size * size)
+            .map(|i| {
+                let (row, col) = (i / size, i % size);
+                let rgb = quad(row / 2, col / 2);
+                match (row & 1, col & 1) {
+                    (0, 0) => rgb[0],
+                    (1, 1) => rgb[2],
+                    _ => rgb[1],
+                }
+            })
+            .collect();
+        let dims = Dimensions {
+            raw_width: size,
+            raw_height: size,
+            output_width: size,
+            output_height: size,
+        };
+        (raw, dims)
+    }
+
+    fn assert_gains(got: Option<[f32; 3]>, want: [f32; 3]) {
+        let got = got.expect("no gains");
+        assert!(
+            got.iter().zip(want).all(|(g, w)| (g - w).abs() < 1e-4),
+            "{:?} vs {:?}",
+            got,
+            want
+        );
+    }
+
+    #[test]
+    fn gray_world_neutralizes_the_scene_mean() {
+        // A tinted scene with a clipped patch and a near-black one, both left out
+        let (raw, dims) = mosaic(|qy, qx| match (qy, qx) {
+            (0..3, _) => [WHITE, 3000, 3000],
+            (3, _) => [10, 20, 15],
+            _ => [
+                200 + qx as u16 * 10,
+                400 + qx as u16 * 20,
+                300 + qx as u16 * 15,
+            ],
+        });
+        let gains = gray_world_gains(&raw, dims, BayerPattern::RGGB, [0; 4], WHITE);
+        assert_gains(gains, [2.0, 1.0, 4.0 / 3.0]);
+    }
+
+    #[test]
+    fn white_patch_neutralizes_the_brightest_quads() {
+        // A saturated green scene with a small white card, and clipped quads brighter still
+        let (raw, dims) = mosaic(|qy, qx| match (qy, qx) {
+            (0, 0..2) => [WHITE, WHITE, WHITE],
+            (1, 0..10) => [1600, 3200, 2560],
+            _ => [300, 1200, 200],
+        });
+        let gains = white_patch_gains(&raw, dims, BayerPattern::RGGB, [0; 4], WHITE);
+        assert_gains(gains, [2.0, 1.0, 1.25]);
+    }
+
+    #[test]
+    fn kelvin_follows_the_planckian_locus() {
+        // CIE illuminant A and the locus at 6500K
+        for (kelvin, (x, y)) in [(2856.0, (0.4476, 0.4074)), (6500.0, (0.3135, 0.3237))] {
+            let (gx, gy) = temperature_tint_to_xy(kelvin, 0.0);
+            assert!(
+                (gx - x).abs() < 1e-3 && (gy - y).abs() < 1e-3,
+                "{}K: ({}, {})",
+                kelvin,
+                gx,
+                gy
+            );
+        }
+        // Positive tint is magenta, away from green
+        assert!(temperature_tint_to_xy(5000.0, 50.0).1 < temperature_tint_to_xy(5000.0, 0.0).1);
+    }
+
+    #[test]
+    fn kelvin_to_multipliers_cancels_the_light() {
+        // With XYZ as the camera space the gains are the inverse of the white's XYZ
+        let (x, y) = temperature_tint_to_xy(5000.0, 0.0);
+        assert_gains(
+            temperature_gains(&IDENTITY, 5000.0, 0.0),
+            [y / x, 1.0, y / (1.0 - x - y)],
+        );
+        // Warmer light needs more blue and less red
+        let warm = temperature_gains(&IDENTITY, 3200.0, 0.0).unwrap();
+        let cool = temperature_gains(&IDENTITY, 6500.0, 0.0).unwrap();
+        assert!(warm[2] > cool[2] && warm[0] < cool[0]);
+        // Custom multipliers are normalized to the mean green
+        assert_gains(gains_from_rggb([2.0, 1.0, 1.0, 1.5]), [2.0, 1.0, 1.5]);
+        assert_eq!(gains_from_rggb([2.0, 0.0, 0.0, 1.5]), None);
+    }
+}